@@ -0,0 +1,301 @@
+// The classic Redis model: one thread, one epoll event loop, non-blocking sockets, and a state machine per connection
+// Commands run through a Session of the same server as the tokio one, on the event loop's thread.
+// For comparison with the tokio server (src/main.rs) and the thread pool one (examples/multithreaded_redis_server.rs).
+// Linux only; epoll is called directly since std already links libc.
+//
+// Run it with `cargo run --example event_loop_redis_server -- [config file] [--<name> <value> ...]`.
+
+use std::collections::HashMap;
+use std::io::{self,ErrorKind,Read,Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use log::{info,debug};
+use redis_starter_rust::config::Config;
+use redis_starter_rust::{logging, RedisServer, RedisServerBuilder, Session};
+
+
+const CHUNK_SIZE: usize = 1024;
+const MAX_EVENTS: usize = 128;
+const LISTENER_TOKEN: u64 = 0;
+// How long epoll_wait waits while a session needs polling (see Session::needs_polling), like Redis's beforeSleep
+// handling blocked clients; otherwise it waits this long only to notice SHUTDOWN, and clients killed with CLIENT KILL
+const POLL_INTERVAL_MS: i32 = 10;
+const IDLE_CHECK_INTERVAL_MS: i32 = 100;
+
+const EPOLL_CLOEXEC: i32 = 0x80000;
+const EPOLL_CTL_ADD: i32 = 1;
+const EPOLL_CTL_DEL: i32 = 2;
+const EPOLL_CTL_MOD: i32 = 3;
+const EPOLLIN: u32 = 0x1;
+const EPOLLOUT: u32 = 0x4;
+const EPOLLERR: u32 = 0x8;
+const EPOLLHUP: u32 = 0x10;
+
+// The kernel's struct epoll_event, which is packed on x86_64 only
+#[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+#[cfg_attr(not(target_arch = "x86_64"), repr(C))]
+#[derive(Clone, Copy)]
+struct EpollEvent {
+    events: u32,
+    data: u64,
+}
+
+extern "C" {
+    fn epoll_create1(flags: i32) -> i32;
+    fn epoll_ctl(epfd: i32, op: i32, fd: i32, event: *mut EpollEvent) -> i32;
+    fn epoll_wait(epfd: i32, events: *mut EpollEvent, maxevents: i32, timeout: i32) -> i32;
+    fn close(fd: i32) -> i32;
+}
+
+struct Epoll {
+    fd: RawFd,
+}
+
+impl Epoll {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { epoll_create1(EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Epoll { fd })
+    }
+
+    fn ctl(&self, op: i32, fd: RawFd, events: u32, token: u64) -> io::Result<()> {
+        let mut event = EpollEvent { events, data: token };
+        if unsafe { epoll_ctl(self.fd, op, fd, &mut event) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn wait(&self, events: &mut [EpollEvent], timeout_ms: i32) -> io::Result<usize> {
+        /* Block until at least one registered socket is ready or the timeout passes, filling events with the ready ones */
+        loop {
+            let num_events = unsafe { epoll_wait(self.fd, events.as_mut_ptr(), events.len() as i32, timeout_ms) };
+            if num_events >= 0 {
+                return Ok(num_events as usize);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe { close(self.fd) };
+    }
+}
+
+// What a connection is waiting for; it's registered with epoll for exactly that
+#[derive(Debug, PartialEq)]
+enum ConnState {
+    // Waiting for commands
+    Reading,
+    // Replies didn't fit in the socket's send buffer, so reading stops until they've been written
+    Writing,
+    // The client is gone (or closed its side and all replies are written), so the connection can be dropped
+    Closed,
+}
+
+struct Connection {
+    stream: TcpStream,
+    state: ConnState,
+    // Keeps the commands whose remaining bytes haven't arrived yet, and runs the rest
+    session: Session,
+    write_buf: Vec<u8>,
+    num_written: usize,
+    // Set once the client closes its side (or the session is closed, e.g. by QUIT); we close ours after the last replies
+    read_closed: bool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, session: Session) -> Self {
+        Connection { stream, state: ConnState::Reading, session, write_buf: Vec::new(), num_written: 0, read_closed: false }
+    }
+
+    fn on_readable(&mut self) {
+        /* Read everything available without blocking, and run the commands it completes */
+        let mut read_buffer = [0; CHUNK_SIZE];
+        loop {
+            match self.stream.read(&mut read_buffer) {
+                Ok(0) => {
+                    self.read_closed = true;
+                    break;
+                },
+                Ok(num_bytes_read) => {
+                    debug!("Num bytes read: {}", num_bytes_read);
+                    if let Err(err) = self.session.feed(&read_buffer[..num_bytes_read]) {
+                        debug!("Error running commands: {}", err);
+                        self.state = ConnState::Closed;
+                        return;
+                    }
+                },
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    debug!("Error reading from connection: {}", err);
+                    self.state = ConnState::Closed;
+                    return;
+                },
+            }
+        }
+        self.on_writable();
+    }
+
+    fn on_poll(&mut self) {
+        /* Give the session a chance to run a command that was waiting, or pass on its messages, without reading */
+        if let Err(err) = self.session.poll() {
+            debug!("Error running commands: {}", err);
+            self.state = ConnState::Closed;
+            return;
+        }
+        self.on_writable();
+    }
+
+    fn on_writable(&mut self) {
+        /* Write as much of the pending replies as the socket takes, and pick the next state */
+        self.write_buf.extend(self.session.take_output());
+        self.read_closed |= self.session.is_closed();
+        while self.num_written < self.write_buf.len() {
+            match self.stream.write(&self.write_buf[self.num_written..]) {
+                Ok(0) => {
+                    self.state = ConnState::Closed;
+                    return;
+                },
+                Ok(num_written) => self.num_written += num_written,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    self.state = ConnState::Writing;
+                    return;
+                },
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    debug!("Error writing to connection: {}", err);
+                    self.state = ConnState::Closed;
+                    return;
+                },
+            }
+        }
+        self.write_buf.clear();
+        self.num_written = 0;
+        self.state = if self.read_closed { ConnState::Closed } else { ConnState::Reading };
+    }
+
+    fn interest(&self) -> u32 {
+        match self.state {
+            ConnState::Writing => EPOLLOUT,
+            _ => EPOLLIN,
+        }
+    }
+}
+
+fn accept_connections(server: &RedisServer, listener: &TcpListener, epoll: &Epoll, connections: &mut HashMap<u64, Connection>, next_token: &mut u64) -> io::Result<()> {
+    /* Accept every pending connection and register it for reads */
+    loop {
+        let (mut stream, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            // A failed accept only affects that one client, so keep serving everyone else
+            Err(err) => {
+                debug!("Error in accepting TCP connection: {}", err);
+                return Ok(());
+            },
+        };
+        info!("accepted new connection");
+        let session = match server.new_session(peer, stream.local_addr()?) {
+            Ok(session) => session,
+            // A fresh socket's send buffer has room for the error, so this doesn't block
+            Err(refused_err) => {
+                let _ = stream.write_all(format!("-{}\r\n", refused_err).as_bytes());
+                continue;
+            },
+        };
+        stream.set_nonblocking(true)?;
+        let token = *next_token;
+        *next_token += 1;
+        epoll.ctl(EPOLL_CTL_ADD, stream.as_raw_fd(), EPOLLIN, token)?;
+        connections.insert(token, Connection::new(stream, session));
+    }
+}
+
+fn update_registration(epoll: &Epoll, connections: &mut HashMap<u64, Connection>, token: u64, prev_interest: u32) -> io::Result<()> {
+    /* Drop a connection that was closed, or register one whose state changed for what it waits for now */
+    let Some(conn) = connections.get(&token) else {
+        return Ok(());
+    };
+    match conn.state {
+        ConnState::Closed => {
+            debug!("Connection closed");
+            epoll.ctl(EPOLL_CTL_DEL, conn.stream.as_raw_fd(), 0, token)?;
+            // Dropping the stream closes the socket, and dropping the session unregisters the client
+            connections.remove(&token);
+        },
+        _ if conn.interest() != prev_interest => epoll.ctl(EPOLL_CTL_MOD, conn.stream.as_raw_fd(), conn.interest(), token)?,
+        _ => (),
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let config = Config::from_args(std::env::args().skip(1))?;
+    logging::init(&config)?;
+
+    let server = config.addrs().iter()
+        .fold(RedisServerBuilder::new(), |builder, addr| builder.bind(addr))
+        .config(config)
+        .build()?;
+    server.start_background_jobs()?;
+    // Only the first bind address is served
+    let listener = &server.listeners()[0];
+    let epoll = Epoll::new()?;
+    epoll.ctl(EPOLL_CTL_ADD, listener.as_raw_fd(), EPOLLIN, LISTENER_TOKEN)?;
+    info!("Serving connections on {} from an event loop", listener.local_addr()?);
+
+    let mut connections: HashMap<u64, Connection> = HashMap::new();
+    let mut next_token = LISTENER_TOKEN + 1;
+    let mut events = [EpollEvent { events: 0, data: 0 }; MAX_EVENTS];
+    while !server.is_shutting_down() {
+        let needs_polling = connections.values().any(|conn| conn.session.needs_polling());
+        let num_events = epoll.wait(&mut events, if needs_polling { POLL_INTERVAL_MS } else { IDLE_CHECK_INTERVAL_MS })?;
+        for event in &events[..num_events] {
+            // Copied out, since fields of a packed struct can't be borrowed
+            let (ready, token) = (event.events, event.data);
+            if token == LISTENER_TOKEN {
+                accept_connections(&server, listener, &epoll, &mut connections, &mut next_token)?;
+                continue;
+            }
+            let Some(conn) = connections.get_mut(&token) else {
+                continue;
+            };
+            let prev_interest = conn.interest();
+            if ready & (EPOLLERR | EPOLLHUP) != 0 && ready & EPOLLIN == 0 {
+                conn.state = ConnState::Closed;
+            } else if conn.state == ConnState::Writing {
+                conn.on_writable();
+            } else {
+                conn.on_readable();
+            }
+            update_registration(&epoll, &mut connections, token, prev_interest)?;
+        }
+        // Blocked commands, paused clients and subscribers may have something to do even without their sockets being ready,
+        // and killed clients have to be disconnected
+        let polled = connections.iter()
+            .filter(|(_, conn)| conn.state == ConnState::Reading && (conn.session.needs_polling() || conn.session.is_closed()))
+            .map(|(token, _)| *token)
+            .collect::<Vec<u64>>();
+        for token in polled {
+            let Some(conn) = connections.get_mut(&token) else {
+                continue;
+            };
+            let prev_interest = conn.interest();
+            conn.on_poll();
+            update_registration(&epoll, &mut connections, token, prev_interest)?;
+        }
+    }
+
+    // Unlike the tokio server, this stops serving open connections too, since they're all served by this one loop
+    Ok(())
+}
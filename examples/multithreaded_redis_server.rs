@@ -0,0 +1,156 @@
+// The thread-per-connection model, with the threads taken from a fixed pool: each worker serves one connection at a
+// time with blocking reads and writes, running its commands through a Session of the same server as the tokio one.
+// For comparison with the tokio server (src/main.rs) and the epoll event loop (examples/event_loop_redis_server.rs).
+//
+// Run it with `cargo run --example multithreaded_redis_server -- [--workers <n>] [config file] [--<name> <value> ...]`.
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use anyhow::bail;
+use log::{info,debug};
+use redis_starter_rust::config::Config;
+use redis_starter_rust::{logging, RedisServer, RedisServerBuilder};
+
+
+const CHUNK_SIZE: usize = 1024;
+const DEFAULT_NUM_WORKERS: usize = 4;
+// How often a connection whose session needs polling (see Session::needs_polling) stops waiting for a read to poll it,
+// and how often the accept loop checks for SHUTDOWN while there's no one to accept
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+// How often any other connection checks whether it was killed (with CLIENT KILL, or for being idle too long)
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/* A fixed number of worker threads that take connections off a shared queue, one at a time
+Connections accepted while every worker is busy wait in the queue until one frees up. */
+struct ThreadPool {
+    workers: Vec<thread::JoinHandle<()>>,
+    sender: Option<mpsc::Sender<TcpStream>>,
+}
+
+impl ThreadPool {
+    fn new(num_workers: usize, server: Arc<RedisServer>) -> Self {
+        assert!(num_workers > 0, "A thread pool needs at least one worker");
+        let (sender, receiver) = mpsc::channel::<TcpStream>();
+        // Workers share the receiving end; whichever one locks it first takes the next connection
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..num_workers).map(|worker_id| {
+            let receiver = Arc::clone(&receiver);
+            let server = Arc::clone(&server);
+            thread::spawn(move || loop {
+                // The lock is released as soon as the connection is received, not after it's handled
+                let stream = receiver.lock().expect("Connection queue lock was poisoned!").recv();
+                match stream {
+                    Ok(mut stream) => {
+                        debug!("Worker {} picked up a connection", worker_id);
+                        // A panic only ends this connection; the worker carries on with the next one
+                        match panic::catch_unwind(AssertUnwindSafe(|| handle_connection(&mut stream, &server))) {
+                            Ok(Ok(())) => (),
+                            Ok(Err(err)) => debug!("Worker {} failed to handle connection: {}", worker_id, err),
+                            Err(_) => debug!("Worker {} panicked while handling connection", worker_id),
+                        }
+                    },
+                    // The pool was dropped, so no more connections are coming
+                    Err(_) => break,
+                }
+            })
+        }).collect();
+        ThreadPool { workers, sender: Some(sender) }
+    }
+
+    fn execute(&self, stream: TcpStream) {
+        /* Queue a connection for the next free worker */
+        self.sender.as_ref().expect("Thread pool was shut down").send(stream).expect("All workers have exited!");
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        /* Let the workers finish the connections already queued, then wait for them to exit */
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn handle_connection(stream: &mut TcpStream, server: &RedisServer) -> anyhow::Result<()> {
+    /*
+    Serve one client until it closes the connection, or QUIT (or CLIENT KILL) has us close it
+    Errors (e.g. the client resetting the connection mid-write) end just this connection, via ? rather than a panic.
+    */
+    stream.set_nonblocking(false)?;
+    let mut session = match server.new_session(stream.peer_addr()?, stream.local_addr()?) {
+        Ok(session) => session,
+        Err(refused_err) => {
+            stream.write_all(format!("-{}\r\n", refused_err).as_bytes())?;
+            return Ok(());
+        },
+    };
+    let mut read_buffer = [0;CHUNK_SIZE];
+    loop {
+        stream.write_all(&session.take_output())?;
+        if session.is_closed() {
+            stream.shutdown(Shutdown::Both)?;
+            return Ok(());
+        }
+        // A blocked command or a subscription can have output for the client without it sending anything
+        stream.set_read_timeout(Some(if session.needs_polling() { POLL_INTERVAL } else { IDLE_CHECK_INTERVAL }))?;
+        match stream.read(&mut read_buffer) {
+            Ok(0) => return Ok(()),
+            Ok(num_bytes_read) => {
+                debug!("Num bytes read: {}", num_bytes_read);
+                session.feed(&read_buffer[..num_bytes_read])?;
+            },
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => session.poll()?,
+            Err(err) if err.kind() == ErrorKind::Interrupted => (),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    // Number of worker threads, e.g. `--workers 8`, followed by the same arguments as the tokio server takes
+    let mut args = std::env::args().skip(1).peekable();
+    let num_workers = match args.next_if_eq("--workers") {
+        Some(_) => match args.next().map(|num_workers| num_workers.parse::<usize>()) {
+            Some(Ok(num_workers)) if num_workers > 0 => num_workers,
+            _ => bail!("--workers must be a positive integer"),
+        },
+        None => DEFAULT_NUM_WORKERS,
+    };
+    let config = Config::from_args(args)?;
+    logging::init(&config)?;
+
+    let server = config.addrs().iter()
+        .fold(RedisServerBuilder::new(), |builder, addr| builder.bind(addr))
+        .config(config)
+        .build()?;
+    let server = Arc::new(server);
+    server.start_background_jobs()?;
+    // Only the first bind address is served; the listener doesn't block, so that SHUTDOWN is noticed between accepts
+    let listener = server.listeners()[0].try_clone()?;
+    let pool = ThreadPool::new(num_workers, Arc::clone(&server));
+    info!("Serving connections on {} with {} worker threads", listener.local_addr()?, num_workers);
+
+    while !server.is_shutting_down() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                info!("accepted new connection");
+                /* Rather than a new thread per connection, hand it to the pool: the thread count stays fixed and
+                connections beyond it are queued until a worker is free */
+                pool.execute(stream);
+            },
+            Err(err) if err.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(err) if err.kind() == ErrorKind::Interrupted => (),
+            // A failed accept only affects that one client, so keep serving everyone else
+            Err(err) => debug!("Error in accepting TCP connection: {}", err),
+        }
+    }
+
+    // Like the tokio server, connections that are still open keep being served; the process exits once they're closed
+    Ok(())
+}
@@ -0,0 +1,78 @@
+// Micro-benchmark for the bare PING fast path in handle_connection
+//
+// Sends the same number of PINGs to an embedded server as the exact `*1\r\n$4\r\nPING\r\n` frame, which is answered
+// without going through the parser, and as an inline `PING\r\n`, which isn't, and reports the allocations and time per
+// PING of each. Allocations are counted process-wide, but the client side doesn't allocate while it sends PINGs.
+//
+// Run it with `cargo run --release --example ping_benchmark`.
+
+use redis_starter_rust::config::Config;
+use redis_starter_rust::RedisServerBuilder;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+const NUM_PINGS: usize = 20_000;
+
+// The system allocator, counting every allocation
+struct CountingAlloc;
+
+static NUM_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        NUM_ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+fn ping(client: &mut TcpStream, frame: &[u8]) -> (f64, f64) {
+    /* Send NUM_PINGS PINGs one at a time, returning the allocations and microseconds per PING */
+    let mut reply = [0; 7];
+    let allocs_before = NUM_ALLOCS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..NUM_PINGS {
+        client.write_all(frame).unwrap();
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+    }
+    let elapsed = start.elapsed();
+    let num_allocs = NUM_ALLOCS.load(Ordering::Relaxed) - allocs_before;
+    (num_allocs as f64 / NUM_PINGS as f64, elapsed.as_secs_f64() * 1e6 / NUM_PINGS as f64)
+}
+
+fn main() -> anyhow::Result<()> {
+    let config = Config { dbfilename: "ping-benchmark-missing.rdb".to_string(), ..Config::default() };
+    let server = Arc::new(RedisServerBuilder::new().bind("127.0.0.1:0").config(config).build()?);
+    let addr = server.local_addr()?;
+
+    let client = std::thread::spawn({
+        let server = Arc::clone(&server);
+        move || {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.set_nodelay(true).unwrap();
+            // Warm up both paths first, so that neither pays for the connection's buffers growing
+            ping(&mut client, b"PING\r\n");
+            let fast_path = ping(&mut client, b"*1\r\n$4\r\nPING\r\n");
+            let parsed = ping(&mut client, b"PING\r\n");
+            server.shutdown().unwrap();
+            (fast_path, parsed)
+        }
+    });
+    tokio::runtime::Runtime::new()?.block_on(server.run())?;
+    let ((fast_allocs, fast_us), (parsed_allocs, parsed_us)) = client.join().unwrap();
+    println!("{} PINGs each", NUM_PINGS);
+    println!("fast path: {:.2} allocations, {:.1}us per PING", fast_allocs, fast_us);
+    println!("parsed:    {:.2} allocations, {:.1}us per PING", parsed_allocs, parsed_us);
+    Ok(())
+}
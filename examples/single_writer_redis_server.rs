@@ -0,0 +1,192 @@
+// The Redis 6 io-threads model as an actor: tokio tasks on several I/O threads read and write the sockets, while a
+// single executor thread owns every client's Session and runs all commands (and the cron jobs) one at a time, in the
+// order their requests reach it over a channel. For comparison with the tokio server (src/main.rs), where each
+// connection's task runs its own commands against the sharded keyspace.
+//
+// The keyspace is the same sharded one, so its locks are still taken, but with one thread running commands they're
+// never contended and commands never interleave. Blocked commands and subscribers are polled by the executor after
+// every batch of requests, since it's the only thread that can have written to their keys or published to them.
+//
+// Run it with `cargo run --example single_writer_redis_server -- [--io-threads <n>] [config file] [--<name> <value> ...]`.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::bail;
+use log::{info,debug};
+use redis_starter_rust::config::Config;
+use redis_starter_rust::{logging, RedisServer, RedisServerBuilder, Session};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc as output_mpsc;
+
+
+const CHUNK_SIZE: usize = 1024;
+const DEFAULT_NUM_IO_THREADS: usize = 4;
+// How long the executor waits for requests while a session needs polling (see Session::needs_polling), and how often
+// the accept loop checks for SHUTDOWN
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// What the I/O tasks tell the executor, for the connection with the given token
+enum Request {
+    Connected { token: u64, peer: SocketAddr, local: SocketAddr, output: output_mpsc::UnboundedSender<Vec<u8>> },
+    Input { token: u64, input: Vec<u8> },
+    Disconnected { token: u64 },
+}
+
+// A connection as the executor sees it: its session, and where its I/O task picks up the output to write
+struct Client {
+    session: Session,
+    output: output_mpsc::UnboundedSender<Vec<u8>>,
+}
+
+fn run_executor(server: &RedisServer, requests: mpsc::Receiver<Request>) {
+    /*
+    Run every command of every connection, as their requests come in, until shutdown is requested
+    Dropping a client's output sender is what tells its I/O task to close the connection.
+    */
+    let mut clients: HashMap<u64, Client> = HashMap::new();
+    let mut next_cron = Instant::now();
+    while !server.is_shutting_down() {
+        let needs_polling = clients.values().any(|client| client.session.needs_polling());
+        let timeout = if needs_polling { POLL_INTERVAL } else { next_cron.saturating_duration_since(Instant::now()) };
+        let mut request = match requests.recv_timeout(timeout) {
+            Ok(request) => Some(request),
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        // Everything that's already queued makes up one batch
+        while let Some(next_request) = request {
+            match next_request {
+                Request::Connected { token, peer, local, output } => match server.new_session(peer, local) {
+                    Ok(session) => {
+                        clients.insert(token, Client { session, output });
+                    },
+                    Err(refused_err) => {
+                        let _ = output.send(format!("-{}\r\n", refused_err).into_bytes());
+                    },
+                },
+                Request::Input { token, input } => {
+                    let fed = clients.get_mut(&token).map(|client| client.session.feed(&input));
+                    if let Some(Err(err)) = fed {
+                        debug!("Error running commands: {}", err);
+                        clients.remove(&token);
+                    }
+                },
+                Request::Disconnected { token } => {
+                    clients.remove(&token);
+                },
+            }
+            request = requests.try_recv().ok();
+        }
+        if Instant::now() >= next_cron {
+            server.run_cron();
+            next_cron = Instant::now() + Duration::from_millis(1000 / server.hz());
+        }
+        // Hand every connection its output, polling the ones that may have something to do first
+        clients.retain(|_, client| {
+            if client.session.needs_polling() {
+                if let Err(err) = client.session.poll() {
+                    debug!("Error running commands: {}", err);
+                    return false;
+                }
+            }
+            let output = client.session.take_output();
+            if !output.is_empty() && client.output.send(output).is_err() {
+                return false;
+            }
+            !client.session.is_closed()
+        });
+    }
+}
+
+async fn serve_connection(stream: tokio::net::TcpStream, token: u64, requests: mpsc::Sender<Request>) -> anyhow::Result<()> {
+    /* Pass what the client sends on to the executor, and write what it sends back, until either side closes */
+    let (peer, local) = (stream.peer_addr()?, stream.local_addr()?);
+    let (output, mut output_receiver) = output_mpsc::unbounded_channel::<Vec<u8>>();
+    requests.send(Request::Connected { token, peer, local, output })?;
+    let (mut reader, mut writer) = stream.into_split();
+    let mut writing = tokio::spawn(async move {
+        while let Some(output) = output_receiver.recv().await {
+            writer.write_all(&output).await?;
+        }
+        // The executor closed the connection (e.g. after QUIT) once its last output was queued
+        writer.shutdown().await
+    });
+    let mut read_buffer = [0; CHUNK_SIZE];
+    loop {
+        let num_bytes_read = tokio::select! {
+            num_bytes_read = reader.read(&mut read_buffer) => num_bytes_read?,
+            _ = &mut writing => break,
+        };
+        if num_bytes_read == 0 {
+            break;
+        }
+        debug!("Num bytes read: {}", num_bytes_read);
+        requests.send(Request::Input { token, input: read_buffer[..num_bytes_read].to_vec() })?;
+    }
+    requests.send(Request::Disconnected { token })?;
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    // Number of I/O threads, e.g. `--io-threads 8`, followed by the same arguments as the tokio server takes
+    let mut args = std::env::args().skip(1).peekable();
+    let num_io_threads = match args.next_if_eq("--io-threads") {
+        Some(_) => match args.next().map(|num_io_threads| num_io_threads.parse::<usize>()) {
+            Some(Ok(num_io_threads)) if num_io_threads > 0 => num_io_threads,
+            _ => bail!("--io-threads must be a positive integer"),
+        },
+        None => DEFAULT_NUM_IO_THREADS,
+    };
+    let config = Config::from_args(args)?;
+    logging::init(&config)?;
+
+    let server = config.addrs().iter()
+        .fold(RedisServerBuilder::new(), |builder, addr| builder.bind(addr))
+        .config(config)
+        .build()?;
+    let server = Arc::new(server);
+    // The executor runs the cron jobs itself, between batches of requests
+    server.prepare()?;
+    let (requests, requests_receiver) = mpsc::channel::<Request>();
+    let executor = thread::Builder::new().name("executor".to_string()).spawn({
+        let server = Arc::clone(&server);
+        move || run_executor(&server, requests_receiver)
+    })?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(num_io_threads).enable_all().build()?;
+    runtime.block_on(async {
+        // Only the first bind address is served
+        let listener = tokio::net::TcpListener::from_std(server.listeners()[0].try_clone()?)?;
+        info!("Serving connections on {} with {} I/O threads and one executor", listener.local_addr()?, num_io_threads);
+        let next_token = AtomicU64::new(0);
+        while !server.is_shutting_down() {
+            let stream = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = tokio::time::sleep(POLL_INTERVAL) => continue,
+            };
+            match stream {
+                Ok((stream, _)) => {
+                    info!("accepted new connection");
+                    let token = next_token.fetch_add(1, Ordering::Relaxed);
+                    let requests = requests.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = serve_connection(stream, token, requests).await {
+                            debug!("Connection closed: {}", err);
+                        }
+                    });
+                },
+                // A failed accept only affects that one client, so keep serving everyone else
+                Err(err) => debug!("Error in accepting TCP connection: {}", err),
+            }
+        }
+        anyhow::Ok(())
+    })?;
+
+    // Open connections are dropped along with the runtime, once the executor has stopped running their commands
+    executor.join().map_err(|_| anyhow::anyhow!("The executor panicked"))?;
+    Ok(())
+}
@@ -0,0 +1,512 @@
+// Users and the commands they may run, for AUTH and ACL SETUSER/GETUSER/DELUSER/LIST/USERS/WHOAMI/CAT
+//
+// Like Redis's ACLs, each user is on or off, has passwords (kept as SHA-256 hashes) or `nopass`, and a set of commands
+// it may run, given as rules applied in order, e.g. `+@all -@dangerous +flushdb`. The categories are worked out from
+// the commands' flags and names rather than listed per command. Key and channel patterns aren't supported: every user
+// can access every key and channel, so only `~*`/`allkeys` and `&*`/`allchannels` are accepted.
+//
+// Connections start out as the `default` user, which is on, has `nopass` and may run every command until configured
+// otherwise; requirepass sets its password.
+//
+// With an aclfile, the users are loaded from it at startup and by ACL LOAD, and saved to it by ACL SAVE, one
+// `user <name> <rules...>` line each, the way ACL LIST describes them. Failed AUTHs and commands refused for lack of
+// permission go in the ACL log, a bounded ring like the slow log, for ACL LOG.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_USER: &str = "default";
+pub const DEFAULT_LOG_MAX_LEN: usize = 128;
+// Like in Redis, a denial that's like one logged at most this long ago counts towards that entry instead of adding one
+const LOG_GROUPING_MS: u128 = 60_000;
+pub const CATEGORIES: &[&str] = &[
+    "admin",
+    "blocking",
+    "connection",
+    "dangerous",
+    "fast",
+    "pubsub",
+    "read",
+    "slow",
+    "transaction",
+    "write",
+];
+
+pub fn command_categories(name: &str, flags: &[&str]) -> Vec<&'static str> {
+    /* The categories a command belongs to, e.g. `read` and `fast` for GET */
+    let is_dangerous = flags.contains(&"admin")
+        || matches!(name, "flushall" | "flushdb" | "keys" | "sort" | "swapdb" | "info" | "role" | "migrate" | "restore");
+    let in_category = |category: &str| match category {
+        "admin" | "blocking" | "fast" => flags.contains(&category),
+        "read" => flags.contains(&"readonly"),
+        "write" => flags.contains(&"write"),
+        "slow" => !flags.contains(&"fast"),
+        "dangerous" => is_dangerous,
+        "connection" => matches!(name, "auth" | "hello" | "ping" | "echo" | "quit" | "reset" | "select" | "client"),
+        "pubsub" => matches!(name, "subscribe" | "unsubscribe" | "psubscribe" | "punsubscribe" | "publish" | "pubsub"),
+        "transaction" => matches!(name, "multi" | "exec" | "discard" | "watch" | "unwatch"),
+        _ => false,
+    };
+    CATEGORIES.iter().copied().filter(|category| in_category(category)).collect()
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct User {
+    pub enabled: bool,
+    pub nopass: bool,
+    // Hex-encoded SHA-256 of each password
+    pub passwords: BTreeSet<String>,
+    allowed: HashSet<&'static str>,
+    // The command rules that led to `allowed`, for ACL LIST and GETUSER; `+@all` and `-@all` start them over
+    command_rules: Vec<String>,
+}
+
+impl User {
+    pub fn commands(&self) -> String {
+        match self.command_rules.is_empty() {
+            true => "-@all".to_string(),
+            false => self.command_rules.join(" "),
+        }
+    }
+
+    pub fn describe(&self, name: &str) -> String {
+        /* The user's line in ACL LIST, e.g. `user default on nopass ~* &* +@all` */
+        let mut parts = vec![format!("user {}", name), if self.enabled { "on" } else { "off" }.to_string()];
+        if self.nopass {
+            parts.push("nopass".to_string());
+        }
+        parts.extend(self.passwords.iter().map(|hash| format!("#{}", hash)));
+        parts.push("~* &*".to_string());
+        parts.push(self.commands());
+        parts.join(" ")
+    }
+}
+
+pub struct Acl {
+    users: RwLock<BTreeMap<String, User>>,
+    // Every command with its categories
+    commands: Vec<(&'static str, Vec<&'static str>)>,
+}
+
+impl Acl {
+    pub fn new(commands: Vec<(&'static str, Vec<&'static str>)>) -> Self {
+        let acl = Acl { users: RwLock::new(BTreeMap::new()), commands };
+        let default_user = acl.default_user();
+        acl.write_users().insert(DEFAULT_USER.to_string(), default_user);
+        acl
+    }
+
+    fn default_user(&self) -> User {
+        /* The `default` user as it is until configured otherwise: on, with nopass, and allowed every command */
+        let mut user = User::default();
+        for rule in ["on", "nopass", "+@all"] {
+            self.apply_rule(&mut user, rule).unwrap_or_else(|err| panic!("Invalid default user: {}", err));
+        }
+        user
+    }
+
+    fn read_users(&self) -> RwLockReadGuard<'_, BTreeMap<String, User>> {
+        self.users.read().unwrap_or_else(|err| {
+            panic!("Failed to lock ACL users for reading: {}!", err);
+        })
+    }
+
+    fn write_users(&self) -> RwLockWriteGuard<'_, BTreeMap<String, User>> {
+        self.users.write().unwrap_or_else(|err| {
+            panic!("Failed to lock ACL users for writing: {}!", err);
+        })
+    }
+
+    pub fn set_user(&self, name: &str, rules: &[String]) -> Result<(), String> {
+        /*
+        Create the user or change it with the rules, all of them or none: an invalid rule leaves the user as it was
+        The error names the rule, like Redis's `Error in ACL SETUSER modifier '<rule>': <reason>`.
+        */
+        let mut users = self.write_users();
+        let mut user = users.get(name).cloned().unwrap_or_default();
+        for rule in rules {
+            self.apply_rule(&mut user, rule).map_err(|reason| format!("Error in ACL SETUSER modifier '{}': {}", rule, reason))?;
+        }
+        users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    fn apply_rule(&self, user: &mut User, rule: &str) -> Result<(), &'static str> {
+        let lowercase_rule = rule.to_lowercase();
+        match lowercase_rule.as_str() {
+            "on" => user.enabled = true,
+            "off" => user.enabled = false,
+            "nopass" => {
+                user.nopass = true;
+                user.passwords.clear();
+            },
+            "resetpass" => {
+                user.nopass = false;
+                user.passwords.clear();
+            },
+            "reset" => *user = User::default(),
+            "~*" | "allkeys" | "&*" | "allchannels" => (),
+            "allcommands" => self.apply_rule(user, "+@all")?,
+            "nocommands" => self.apply_rule(user, "-@all")?,
+            _ => match rule.split_at(rule.chars().next().map_or(0, char::len_utf8)) {
+                (">", password) => {
+                    user.nopass = false;
+                    user.passwords.insert(sha256_hex(password.as_bytes()));
+                },
+                ("<", password) => {
+                    user.passwords.remove(&sha256_hex(password.as_bytes()));
+                },
+                ("#", hash) if hash.len() == 64 && hash.bytes().all(|byte| byte.is_ascii_hexdigit()) => {
+                    user.nopass = false;
+                    user.passwords.insert(hash.to_lowercase());
+                },
+                ("!", hash) => {
+                    user.passwords.remove(&hash.to_lowercase());
+                },
+                ("#", _) => return Err("The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters"),
+                ("~" | "&" | "%", _) => return Err("Key and channel patterns aren't supported, every user can access every key and channel"),
+                (op @ ("+" | "-"), _) => {
+                    let target = &lowercase_rule[1..];
+                    let names = match target.strip_prefix('@') {
+                        Some("all") => self.commands.iter().map(|(name, _)| *name).collect(),
+                        Some(category) if CATEGORIES.contains(&category) => self.commands_in_category(category),
+                        Some(_) => return Err("Unknown command or category name in ACL"),
+                        None => match self.commands.iter().find(|(name, _)| *name == target) {
+                            Some((name, _)) => vec![*name],
+                            None => return Err("Unknown command or category name in ACL"),
+                        },
+                    };
+                    if target == "@all" {
+                        user.command_rules.clear();
+                    }
+                    match op {
+                        "+" => user.allowed.extend(names),
+                        _ => user.allowed.retain(|name| !names.contains(name)),
+                    }
+                    // Taking commands away from a user that has none changes nothing
+                    if op == "+" || !user.command_rules.is_empty() {
+                        user.command_rules.push(lowercase_rule);
+                    }
+                },
+                _ => return Err("Syntax error"),
+            },
+        }
+        Ok(())
+    }
+
+    pub fn load(&self, contents: &str) -> Result<(), String> {
+        /*
+        Replace every user with those of an aclfile, all of them or none: an invalid line leaves the users as they were
+        Like in Redis, `default` is set up the way it starts out unless the file has a line for it.
+        */
+        let mut users = BTreeMap::new();
+        for (line_idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, rules) = match line.split_whitespace().collect::<Vec<&str>>().as_slice() {
+                ["user", name, rules @ ..] => (name.to_string(), rules.to_vec()),
+                _ => return Err(format!("line {}: should start with user keyword followed by the username", line_idx + 1)),
+            };
+            if users.contains_key(&name) {
+                return Err(format!("line {}: duplicate user '{}' found", line_idx + 1, name));
+            }
+            let mut user = User::default();
+            for rule in rules {
+                self.apply_rule(&mut user, rule).map_err(|reason| format!("line {}: {}", line_idx + 1, reason))?;
+            }
+            users.insert(name, user);
+        }
+        users.entry(DEFAULT_USER.to_string()).or_insert_with(|| self.default_user());
+        *self.write_users() = users;
+        Ok(())
+    }
+
+    pub fn save(&self) -> String {
+        /* The users as an aclfile's contents, which load reads back */
+        self.describe_users().iter().map(|line| format!("{}\n", line)).collect()
+    }
+
+    pub fn has_user(&self, name: &str) -> bool {
+        self.read_users().contains_key(name)
+    }
+
+    pub fn get_user(&self, name: &str) -> Option<User> {
+        self.read_users().get(name).cloned()
+    }
+
+    pub fn delete_user(&self, name: &str) -> bool {
+        self.write_users().remove(name).is_some()
+    }
+
+    pub fn usernames(&self) -> Vec<String> {
+        self.read_users().keys().cloned().collect()
+    }
+
+    pub fn describe_users(&self) -> Vec<String> {
+        self.read_users().iter().map(|(name, user)| user.describe(name)).collect()
+    }
+
+    pub fn check_password(&self, name: &str, password: &[u8]) -> bool {
+        /* Whether the user exists, is on, and either has nopass or this is one of its passwords */
+        let users = self.read_users();
+        let Some(user) = users.get(name).filter(|user| user.enabled) else {
+            return false;
+        };
+        // Comparing hashes takes as long whatever the password is
+        let hash = sha256_hex(password);
+        user.nopass || user.passwords.contains(&hash)
+    }
+
+    pub fn default_requires_password(&self) -> bool {
+        /* Whether connections have to AUTH before running commands, as they do unless `default` is on with nopass */
+        self.read_users().get(DEFAULT_USER).map_or(true, |user| !(user.enabled && user.nopass))
+    }
+
+    pub fn is_allowed(&self, name: &str, command: &str) -> bool {
+        self.read_users().get(name).is_some_and(|user| user.allowed.contains(command))
+    }
+
+    pub fn commands_in_category(&self, category: &str) -> Vec<&'static str> {
+        self.commands.iter()
+            .filter(|(_, categories)| categories.contains(&category))
+            .map(|(name, _)| *name)
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DenialReason {
+    // A failed AUTH, because of a wrong password or a user that's off or doesn't exist
+    Auth,
+    // A command the user doesn't have permission to run
+    Command,
+}
+
+impl DenialReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DenialReason::Auth => "auth",
+            DenialReason::Command => "command",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AclLogEntry {
+    pub id: u64,
+    // How many denials like this one there were, e.g. repeated AUTHs with a wrong password
+    pub count: u64,
+    pub reason: DenialReason,
+    // `toplevel`, or `multi` for a command queued in a transaction
+    pub context: &'static str,
+    // The refused command's name, or `AUTH`
+    pub object: String,
+    pub username: String,
+    // CLIENT INFO of the latest client denied
+    pub client_info: String,
+    // Unix times in milliseconds of the first and latest denials
+    pub created_ms: u128,
+    pub updated_ms: u128,
+}
+
+pub struct AclLog {
+    entries: Mutex<VecDeque<AclLogEntry>>,
+    next_id: AtomicU64,
+    pub max_len: AtomicUsize,
+}
+
+impl AclLog {
+    pub fn new(max_len: usize) -> Self {
+        AclLog { entries: Mutex::new(VecDeque::new()), next_id: AtomicU64::new(0), max_len: AtomicUsize::new(max_len) }
+    }
+
+    fn lock_entries(&self) -> MutexGuard<'_, VecDeque<AclLogEntry>> {
+        self.entries.lock().unwrap_or_else(|err| {
+            panic!("Failed to lock ACL log mutex: {}!", err);
+        })
+    }
+
+    pub fn record(&self, reason: DenialReason, context: &'static str, object: &str, username: &str, client_info: String) {
+        /* Add a denial, newest first, or count it towards a recent entry like it; entries past acllog-max-len are dropped */
+        let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let mut entries = self.lock_entries();
+        let similar_idx = entries.iter().position(|entry| {
+            entry.reason == reason && entry.context == context && entry.object == object && entry.username == username
+                && now_ms.saturating_sub(entry.updated_ms) <= LOG_GROUPING_MS
+        });
+        let entry = match similar_idx.and_then(|idx| entries.remove(idx)) {
+            Some(entry) => AclLogEntry { count: entry.count + 1, client_info, updated_ms: now_ms, ..entry },
+            None => AclLogEntry {
+                id: self.next_id.fetch_add(1, Ordering::Relaxed),
+                count: 1,
+                reason,
+                context,
+                object: object.to_string(),
+                username: username.to_string(),
+                client_info,
+                created_ms: now_ms,
+                updated_ms: now_ms,
+            },
+        };
+        entries.push_front(entry);
+        entries.truncate(self.max_len.load(Ordering::Relaxed));
+    }
+
+    pub fn latest(&self, count: usize) -> Vec<AclLogEntry> {
+        self.lock_entries().iter().take(count).cloned().collect()
+    }
+
+    pub fn reset(&self) {
+        self.lock_entries().clear();
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    sha256(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    /* SHA-256 as in FIPS 180-4, which Redis hashes ACL passwords with */
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+        0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+        0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+        0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+        0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+        0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+        0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+    ];
+    let mut state: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+    // Padded with a 1 bit, zeroes, and the length in bits, to a multiple of 64 bytes
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (idx, word) in block.chunks(4).enumerate() {
+            w[idx] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for idx in 16..64 {
+            let s0 = w[idx - 15].rotate_right(7) ^ w[idx - 15].rotate_right(18) ^ (w[idx - 15] >> 3);
+            let s1 = w[idx - 2].rotate_right(17) ^ w[idx - 2].rotate_right(19) ^ (w[idx - 2] >> 10);
+            w[idx] = w[idx - 16].wrapping_add(s0).wrapping_add(w[idx - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for idx in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let temp1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[idx]).wrapping_add(w[idx]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(maj);
+            (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(temp1), c, b, a, temp1.wrapping_add(temp2));
+        }
+        for (word, added) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(added);
+        }
+    }
+    let mut digest = [0u8; 32];
+    for (idx, word) in state.iter().enumerate() {
+        digest[idx * 4..idx * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_acl() -> Acl {
+        let commands = [("get", &["readonly", "fast"][..]), ("set", &["write"][..]), ("flushall", &["write"][..]), ("config", &["admin"][..])];
+        Acl::new(commands.iter().map(|(name, flags)| (*name, command_categories(name, flags))).collect())
+    }
+
+    fn rules(rules: &[&str]) -> Vec<String> {
+        rules.iter().map(|rule| rule.to_string()).collect()
+    }
+
+    #[test]
+    fn passwords_are_hashed_with_sha256() {
+        assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(sha256_hex(&[b'a'; 64]), "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb");
+    }
+
+    #[test]
+    fn rules_apply_in_order_and_all_or_nothing() {
+        let acl = test_acl();
+        assert!(!acl.default_requires_password());
+        assert!(acl.is_allowed(DEFAULT_USER, "config"));
+
+        acl.set_user("alice", &rules(&["on", ">pw", "+@all", "-@dangerous", "+flushall"])).unwrap();
+        let alice = acl.get_user("alice").unwrap();
+        assert_eq!(alice.describe("alice"), format!("user alice on #{} ~* &* +@all -@dangerous +flushall", sha256_hex(b"pw")));
+        assert!(acl.is_allowed("alice", "get") && acl.is_allowed("alice", "flushall") && !acl.is_allowed("alice", "config"));
+        assert!(acl.check_password("alice", b"pw") && !acl.check_password("alice", b"other"));
+
+        // A bad rule leaves the user as it was
+        assert_eq!(
+            acl.set_user("alice", &rules(&["-@all", "+nosuch"])),
+            Err("Error in ACL SETUSER modifier '+nosuch': Unknown command or category name in ACL".to_string())
+        );
+        assert!(acl.is_allowed("alice", "get"));
+        acl.set_user("alice", &rules(&["nocommands", "+@read", "off"])).unwrap();
+        assert_eq!(acl.get_user("alice").unwrap().commands(), "+@read");
+        assert!(!acl.is_allowed("alice", "set") && !acl.check_password("alice", b"pw"));
+
+        acl.set_user("bob", &[]).unwrap();
+        assert_eq!(acl.get_user("bob").unwrap().describe("bob"), "user bob off ~* &* -@all");
+        assert!(acl.set_user("bob", &rules(&["~cache:*"])).is_err());
+
+        acl.set_user(DEFAULT_USER, &rules(&["resetpass", ">secret"])).unwrap();
+        assert!(acl.default_requires_password());
+        assert!(acl.delete_user("bob") && !acl.delete_user("bob"));
+        assert_eq!(acl.usernames(), ["alice", "default"]);
+    }
+
+    #[test]
+    fn aclfiles_load_all_users_or_none() {
+        let acl = test_acl();
+        acl.set_user("alice", &rules(&["on", ">pw", "+@read"])).unwrap();
+        acl.set_user(DEFAULT_USER, &rules(&["off"])).unwrap();
+        let saved = acl.save();
+        assert_eq!(saved.lines().count(), 2);
+
+        let other_acl = test_acl();
+        other_acl.load(&saved).unwrap();
+        assert_eq!(other_acl.describe_users(), acl.describe_users());
+        assert!(other_acl.check_password("alice", b"pw") && other_acl.default_requires_password());
+
+        // A bad line leaves the users as they were, and `default` starts out as usual when the file leaves it out
+        assert_eq!(other_acl.load("user bob on\nuser bob off\n"), Err("line 2: duplicate user 'bob' found".to_string()));
+        assert_eq!(other_acl.load("# users\nuser bob +nosuch\n"), Err("line 2: Unknown command or category name in ACL".to_string()));
+        assert!(other_acl.load("bob on\n").is_err());
+        assert_eq!(other_acl.usernames(), ["alice", "default"]);
+        other_acl.load("user bob on nopass +get\n").unwrap();
+        assert_eq!(other_acl.describe_users(), ["user bob on nopass ~* &* +get", "user default on nopass ~* &* +@all"]);
+    }
+
+    #[test]
+    fn acl_log_groups_similar_denials() {
+        let acl_log = AclLog::new(2);
+        acl_log.record(DenialReason::Auth, "toplevel", "AUTH", "alice", "id=1".to_string());
+        acl_log.record(DenialReason::Command, "toplevel", "get", "alice", "id=1".to_string());
+        acl_log.record(DenialReason::Auth, "toplevel", "AUTH", "alice", "id=2".to_string());
+        let entries = acl_log.latest(10);
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].id, entries[0].count, entries[0].client_info.as_str()), (0, 2, "id=2"));
+        assert_eq!((entries[1].reason, entries[1].object.as_str()), (DenialReason::Command, "get"));
+
+        acl_log.record(DenialReason::Command, "multi", "set", "bob", "id=3".to_string());
+        assert_eq!(acl_log.latest(10).iter().map(|entry| entry.id).collect::<Vec<u64>>(), [2, 0]);
+        acl_log.reset();
+        assert!(acl_log.latest(10).is_empty());
+    }
+}
@@ -0,0 +1,297 @@
+// Connected clients, for CLIENT LIST and friends
+//
+// Every connection registers a ClientInfo when it's accepted and removes it once it's closed. Only the connection
+// itself writes its entry (after each command it runs); other connections just read it, or kill the connection with
+// CLIENT KILL, which the connection's task notices the next time it waits for a read.
+//
+// With `timeout` set, the client-timeout cron job kills clients that have been idle for too long the same way.
+//
+// CLIENT PAUSE holds up every client's commands (or just the writes) server-wide until a deadline or CLIENT UNPAUSE.
+// Connections check for a pause before each command and wait it out without running anything, so their commands stay
+// buffered in the meantime.
+
+use crate::acl::DEFAULT_USER;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+// What a connection was last seen doing, as of the end of its latest command
+pub struct ClientDetails {
+    // Set with CLIENT SETNAME; empty when there's none, like in Redis
+    pub name: Vec<u8>,
+    pub last_interaction: Instant,
+    // Name of the latest command, e.g. `client|list` for one with subcommands; NULL before the first one
+    pub last_cmd: String,
+    pub db: usize,
+    pub sub: usize,
+    pub psub: usize,
+    // Commands queued since MULTI, while in a transaction
+    pub multi: Option<usize>,
+    // Set by MONITOR
+    pub monitor: bool,
+    // Set while a blocking command (e.g. BLPOP) waits
+    pub blocked: bool,
+    // The ACL user the connection is logged in as
+    pub user: String,
+    pub resp: u8,
+}
+
+pub struct ClientInfo {
+    pub id: u64,
+    // The client's address and ours, as ip:port
+    pub addr: String,
+    pub laddr: String,
+    created: Instant,
+    details: Mutex<ClientDetails>,
+    // Notified by CLIENT KILL; a kill while the connection is busy is remembered until it next waits
+    kill: Notify,
+    // Also set by CLIENT KILL, for connections that check instead of waiting (see RedisServer::new_session)
+    killed: AtomicBool,
+}
+
+impl ClientInfo {
+    pub fn new(id: u64, addr: String, laddr: String) -> Self {
+        let created = Instant::now();
+        let details = ClientDetails {
+            name: Vec::new(),
+            last_interaction: created,
+            last_cmd: "NULL".to_string(),
+            db: 0,
+            sub: 0,
+            psub: 0,
+            multi: None,
+            monitor: false,
+            blocked: false,
+            user: DEFAULT_USER.to_string(),
+            resp: 2,
+        };
+        ClientInfo { id, addr, laddr, created, details: Mutex::new(details), kill: Notify::new(), killed: AtomicBool::new(false) }
+    }
+
+    pub fn kill(&self) {
+        self.killed.store(true, Ordering::Relaxed);
+        self.kill.notify_one();
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::Relaxed)
+    }
+
+    pub async fn killed(&self) {
+        /* Wait until the connection is killed with CLIENT KILL */
+        self.kill.notified().await
+    }
+
+    pub fn lock_details(&self) -> MutexGuard<'_, ClientDetails> {
+        self.details.lock().unwrap_or_else(|err| {
+            panic!("Failed to lock client details mutex: {}!", err);
+        })
+    }
+
+    pub fn timed_out(&self, timeout: Duration) -> bool {
+        /* Whether the client has been idle for longer than timeout; like in Redis, subscribers, monitors and blocked clients never are */
+        let details = self.lock_details();
+        !(details.monitor || details.sub + details.psub > 0 || details.blocked) && details.last_interaction.elapsed() > timeout
+    }
+
+    pub fn describe(&self) -> String {
+        /* The client's line in CLIENT LIST (and the reply to CLIENT INFO), with a subset of Redis's fields */
+        let details = self.lock_details();
+        let flags = match (details.monitor, details.sub + details.psub > 0, details.multi.is_some(), details.blocked) {
+            (true, _, _, _) => "O",
+            (false, true, _, _) => "P",
+            (false, false, true, _) => "x",
+            (false, false, false, true) => "b",
+            (false, false, false, false) => "N",
+        };
+        format!(
+            "id={} addr={} laddr={} name={} age={} idle={} flags={} db={} sub={} psub={} multi={} cmd={} user={} resp={}",
+            self.id,
+            self.addr,
+            self.laddr,
+            String::from_utf8_lossy(&details.name),
+            self.created.elapsed().as_secs(),
+            details.last_interaction.elapsed().as_secs(),
+            flags,
+            details.db,
+            details.sub,
+            details.psub,
+            details.multi.map_or(-1, |num_queued| num_queued as i64),
+            details.last_cmd,
+            details.user,
+            details.resp,
+        )
+    }
+}
+
+// The filters of CLIENT KILL's newer form; a client has to match all of them to be killed
+#[derive(Debug, Default, PartialEq)]
+pub struct ClientKillFilter {
+    id: Option<u64>,
+    addr: Option<Vec<u8>>,
+    laddr: Option<Vec<u8>>,
+    // Whether the connection running CLIENT KILL is spared; it is unless SKIPME no is given
+    skip_me: bool,
+}
+
+impl ClientKillFilter {
+    pub fn parse(args: &[impl AsRef<[u8]>]) -> Result<Self, String> {
+        /* Parse `<option> <value>` pairs, e.g. [ID, 3, SKIPME, no] */
+        let mut filter = ClientKillFilter { skip_me: true, ..Default::default() };
+        for pair in args.chunks(2) {
+            let [option, val] = pair else {
+                return Err("ERR syntax error".to_string());
+            };
+            let (option, val) = (option.as_ref().to_ascii_uppercase(), val.as_ref());
+            match option.as_slice() {
+                b"ID" => match crate::parse_arg::<u64>(val) {
+                    Some(id) if id > 0 => filter.id = Some(id),
+                    _ => return Err("ERR client-id should be greater than 0".to_string()),
+                },
+                b"ADDR" => filter.addr = Some(val.to_vec()),
+                b"LADDR" => filter.laddr = Some(val.to_vec()),
+                b"SKIPME" if val.eq_ignore_ascii_case(b"yes") => filter.skip_me = true,
+                b"SKIPME" if val.eq_ignore_ascii_case(b"no") => filter.skip_me = false,
+                _ => return Err("ERR syntax error".to_string()),
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn matches(&self, client: &ClientInfo, my_id: u64) -> bool {
+        !(self.skip_me && client.id == my_id)
+            && self.id.map_or(true, |id| client.id == id)
+            && self.addr.as_ref().map_or(true, |addr| client.addr.as_bytes() == addr.as_slice())
+            && self.laddr.as_ref().map_or(true, |laddr| client.laddr.as_bytes() == laddr.as_slice())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClientPause {
+    pub deadline: Instant,
+    // CLIENT PAUSE WRITE, which lets read-only commands through
+    pub writes_only: bool,
+}
+
+#[derive(Default)]
+pub struct ClientRegistry {
+    // Keyed by client ID, so clients are listed in the order they connected
+    clients: Mutex<BTreeMap<u64, Arc<ClientInfo>>>,
+    pause: Mutex<Option<ClientPause>>,
+    // Notified by CLIENT UNPAUSE, so that paused clients don't wait for the deadline
+    unpaused: Notify,
+}
+
+impl ClientRegistry {
+    fn lock_clients(&self) -> MutexGuard<'_, BTreeMap<u64, Arc<ClientInfo>>> {
+        self.clients.lock().unwrap_or_else(|err| {
+            panic!("Failed to lock client registry mutex: {}!", err);
+        })
+    }
+
+    pub fn register(&self, client: Arc<ClientInfo>) {
+        self.lock_clients().insert(client.id, client);
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.lock_clients().remove(&id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock_clients().len()
+    }
+
+    pub fn list(&self) -> Vec<Arc<ClientInfo>> {
+        self.lock_clients().values().cloned().collect()
+    }
+
+    fn lock_pause(&self) -> MutexGuard<'_, Option<ClientPause>> {
+        self.pause.lock().unwrap_or_else(|err| {
+            panic!("Failed to lock client pause mutex: {}!", err);
+        })
+    }
+
+    pub fn pause(&self, new_pause: ClientPause) {
+        /* Like in Redis, pausing while already paused keeps the later deadline and the stricter mode */
+        let mut pause = self.lock_pause();
+        *pause = Some(match *pause {
+            Some(pause) if pause.deadline > Instant::now() => ClientPause {
+                deadline: pause.deadline.max(new_pause.deadline),
+                writes_only: pause.writes_only && new_pause.writes_only,
+            },
+            _ => new_pause,
+        });
+    }
+
+    pub fn unpause(&self) {
+        *self.lock_pause() = None;
+        self.unpaused.notify_waiters();
+    }
+
+    pub fn paused_until(&self, is_write: bool) -> Option<Instant> {
+        /* When the current pause (if any) ends, if it holds up a command that is or isn't a write */
+        let mut pause = self.lock_pause();
+        match *pause {
+            Some(ClientPause { deadline, .. }) if deadline <= Instant::now() => {
+                *pause = None;
+                None
+            },
+            Some(ClientPause { writes_only: true, .. }) if !is_write => None,
+            Some(ClientPause { deadline, .. }) => Some(deadline),
+            None => None,
+        }
+    }
+
+    pub async fn wait_while_paused(&self, is_write: bool) {
+        /* Wait until there's no pause that holds up the command, which may take more than one pause */
+        loop {
+            // Created before checking, so that an unpause in between still wakes us up
+            let unpaused = self.unpaused.notified();
+            let Some(deadline) = self.paused_until(is_write) else {
+                return;
+            };
+            tokio::select! {
+                _ = unpaused => (),
+                _ = tokio::time::sleep_until(deadline.into()) => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kill_filters_must_all_match() {
+        let client = ClientInfo::new(3, "127.0.0.1:5000".to_string(), "127.0.0.1:6379".to_string());
+        let filter = |args: &[&str]| ClientKillFilter::parse(args);
+
+        assert!(filter(&["ID", "3", "LADDR", "127.0.0.1:6379"]).unwrap().matches(&client, 1));
+        assert!(!filter(&["id", "3", "addr", "127.0.0.1:5001"]).unwrap().matches(&client, 1));
+        // SKIPME is on unless turned off
+        assert!(!filter(&["ADDR", "127.0.0.1:5000"]).unwrap().matches(&client, 3));
+        assert!(filter(&["ADDR", "127.0.0.1:5000", "SKIPME", "no"]).unwrap().matches(&client, 3));
+        assert_eq!(filter(&["ID", "0"]), Err("ERR client-id should be greater than 0".to_string()));
+        assert_eq!(filter(&["SKIPME", "maybe"]), Err("ERR syntax error".to_string()));
+        assert_eq!(filter(&["TYPE", "normal"]), Err("ERR syntax error".to_string()));
+    }
+
+    #[test]
+    fn only_idle_clients_that_arent_waiting_on_anything_time_out() {
+        let client = ClientInfo::new(1, "127.0.0.1:5000".to_string(), "127.0.0.1:6379".to_string());
+        assert!(!client.timed_out(Duration::from_secs(1)));
+        client.lock_details().last_interaction = Instant::now() - Duration::from_secs(2);
+        assert!(client.timed_out(Duration::from_secs(1)));
+        assert!(!client.timed_out(Duration::from_secs(3)));
+
+        client.lock_details().blocked = true;
+        assert!(!client.timed_out(Duration::from_secs(1)));
+        assert!(client.describe().contains(" flags=b "));
+        client.lock_details().blocked = false;
+        client.lock_details().sub = 1;
+        assert!(!client.timed_out(Duration::from_secs(1)));
+    }
+}
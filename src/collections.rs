@@ -0,0 +1,410 @@
+// Lists, hashes and sets, each with a compact encoding for small collections like Redis has:
+// https://redis.io/docs/management/optimization/memory-optimization/#special-encoding-of-small-aggregate-data-types
+// Small collections are kept in flat vectors (Redis's listpack/intset), which have far less per-element overhead
+// than hash tables and are just as fast to scan at these sizes. Once a collection grows past the configured
+// limits it's converted to the general-purpose representation, and like Redis it never converts back.
+use std::collections::{HashMap, HashSet, VecDeque};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EncodingLimits {
+    pub list_max_listpack_size: usize,
+    pub hash_max_listpack_entries: usize,
+    pub hash_max_listpack_value: usize,
+    pub set_max_intset_entries: usize,
+    pub set_max_listpack_entries: usize,
+    pub set_max_listpack_value: usize,
+}
+
+impl Default for EncodingLimits {
+    fn default() -> Self {
+        // Same defaults as redis.conf, except that the list limit counts entries rather than bytes
+        EncodingLimits {
+            list_max_listpack_size: 128,
+            hash_max_listpack_entries: 128,
+            hash_max_listpack_value: 64,
+            set_max_intset_entries: 512,
+            set_max_listpack_entries: 128,
+            set_max_listpack_value: 64,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+#[derive(Clone, Debug)]
+pub enum RedisList {
+    Listpack(Vec<Vec<u8>>),
+    Quicklist(VecDeque<Vec<u8>>),
+}
+
+impl Default for RedisList {
+    fn default() -> Self {
+        RedisList::Listpack(Vec::new())
+    }
+}
+
+impl RedisList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            RedisList::Listpack(elems) => elems.len(),
+            RedisList::Quicklist(elems) => elems.len(),
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            RedisList::Listpack(_) => "listpack",
+            RedisList::Quicklist(_) => "quicklist",
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn DoubleEndedIterator<Item = &Vec<u8>> + '_> {
+        match self {
+            RedisList::Listpack(elems) => Box::new(elems.iter()),
+            RedisList::Quicklist(elems) => Box::new(elems.iter()),
+        }
+    }
+
+    fn convert_if_needed(&mut self, limits: &EncodingLimits) {
+        /* Switch to a quicklist once the listpack would grow past list_max_listpack_size */
+        if let RedisList::Listpack(elems) = self {
+            if elems.len() >= limits.list_max_listpack_size {
+                *self = RedisList::Quicklist(std::mem::take(elems).into());
+            }
+        }
+    }
+
+    pub fn push_front(&mut self, elem: Vec<u8>, limits: &EncodingLimits) {
+        self.convert_if_needed(limits);
+        match self {
+            RedisList::Listpack(elems) => elems.insert(0, elem),
+            RedisList::Quicklist(elems) => elems.push_front(elem),
+        }
+    }
+
+    pub fn push_back(&mut self, elem: Vec<u8>, limits: &EncodingLimits) {
+        self.convert_if_needed(limits);
+        match self {
+            RedisList::Listpack(elems) => elems.push(elem),
+            RedisList::Quicklist(elems) => elems.push_back(elem),
+        }
+    }
+
+    pub fn pop_list(&mut self, end: ListEnd, count: usize) -> (Vec<Vec<u8>>, bool) {
+        /*
+        Pop up to count elements from the given end, in the order they were popped
+        Also returns whether the list is now empty, since callers must then delete its key.
+        */
+        let count = count.min(self.len());
+        let popped = match (&mut *self, end) {
+            (RedisList::Listpack(elems), ListEnd::Left) => elems.drain(..count).collect(),
+            (RedisList::Listpack(elems), ListEnd::Right) => elems.drain(elems.len() - count..).rev().collect(),
+            (RedisList::Quicklist(elems), ListEnd::Left) => elems.drain(..count).collect(),
+            (RedisList::Quicklist(elems), ListEnd::Right) => elems.drain(elems.len() - count..).rev().collect(),
+        };
+        (popped, self.len() == 0)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum HashFields {
+    Listpack(Vec<(Vec<u8>, Vec<u8>)>),
+    Hashtable(HashMap<Vec<u8>, Vec<u8>>),
+}
+
+#[derive(Clone, Debug)]
+pub struct RedisHash {
+    fields: HashFields,
+    // Unix time in ms at which each field with its own TTL (set by HEXPIRE and friends) expires
+    field_expiries: HashMap<Vec<u8>, u128>,
+}
+
+impl Default for RedisHash {
+    fn default() -> Self {
+        RedisHash { fields: HashFields::Listpack(Vec::new()), field_expiries: HashMap::new() }
+    }
+}
+
+impl RedisHash {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.fields {
+            HashFields::Listpack(fields) => fields.len(),
+            HashFields::Hashtable(fields) => fields.len(),
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        // Like Redis 7.4, small hashes with field TTLs report a separate encoding
+        match &self.fields {
+            HashFields::Listpack(_) if !self.field_expiries.is_empty() => "listpackex",
+            HashFields::Listpack(_) => "listpack",
+            HashFields::Hashtable(_) => "hashtable",
+        }
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&Vec<u8>> {
+        match &self.fields {
+            HashFields::Listpack(fields) => fields.iter().find(|(f, _)| f == field).map(|(_, val)| val),
+            HashFields::Hashtable(fields) => fields.get(field),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&Vec<u8>, &Vec<u8>)> + '_> {
+        match &self.fields {
+            HashFields::Listpack(fields) => Box::new(fields.iter().map(|(field, val)| (field, val))),
+            HashFields::Hashtable(fields) => Box::new(fields.iter()),
+        }
+    }
+
+    pub fn insert(&mut self, field: Vec<u8>, val: Vec<u8>, limits: &EncodingLimits) -> bool {
+        /*
+        Set field to val, returning whether the field is new
+        Like in Redis, this clears any TTL the field had. Converts to a hash table if there would be too many fields,
+        or the field or value is too long.
+        */
+        self.field_expiries.remove(&field);
+        if let HashFields::Listpack(fields) = &mut self.fields {
+            let is_new = !fields.iter().any(|(f, _)| *f == field);
+            let too_many = is_new && fields.len() >= limits.hash_max_listpack_entries;
+            let too_long = field.len() > limits.hash_max_listpack_value || val.len() > limits.hash_max_listpack_value;
+            if too_many || too_long {
+                self.fields = HashFields::Hashtable(std::mem::take(fields).into_iter().collect());
+            }
+        }
+        match &mut self.fields {
+            HashFields::Listpack(fields) => match fields.iter_mut().find(|(f, _)| *f == field) {
+                Some((_, curr_val)) => {
+                    *curr_val = val;
+                    false
+                },
+                None => {
+                    fields.push((field, val));
+                    true
+                }
+            },
+            HashFields::Hashtable(fields) => fields.insert(field, val).is_none(),
+        }
+    }
+
+    pub fn remove(&mut self, field: &[u8]) -> bool {
+        self.field_expiries.remove(field);
+        match &mut self.fields {
+            HashFields::Listpack(fields) => match fields.iter().position(|(f, _)| f == field) {
+                Some(idx) => {
+                    fields.remove(idx);
+                    true
+                },
+                None => false,
+            },
+            HashFields::Hashtable(fields) => fields.remove(field).is_some(),
+        }
+    }
+
+    pub fn has_field_expiries(&self) -> bool {
+        !self.field_expiries.is_empty()
+    }
+
+    pub fn field_expiry(&self, field: &[u8]) -> Option<u128> {
+        self.field_expiries.get(field).copied()
+    }
+
+    pub fn set_field_expiry(&mut self, field: &[u8], expiry_ts: u128) {
+        /* Give an existing field a TTL, expiring at the unix time expiry_ts in ms */
+        if self.get(field).is_some() {
+            self.field_expiries.insert(field.to_vec(), expiry_ts);
+        }
+    }
+
+    pub fn num_expired_fields(&self, now_ms: u128) -> usize {
+        /* How many fields have a TTL that has passed, but are yet to be removed */
+        self.field_expiries.values().filter(|expiry_ts| now_ms > **expiry_ts).count()
+    }
+
+    pub fn remove_expired_fields(&mut self, now_ms: u128) -> usize {
+        /* Remove every field whose TTL has passed, returning the number removed */
+        if self.field_expiries.is_empty() {
+            return 0;
+        }
+        let expired = self.field_expiries.iter()
+            .filter(|(_, expiry_ts)| now_ms > **expiry_ts)
+            .map(|(field, _)| field.clone())
+            .collect::<Vec<Vec<u8>>>();
+        for field in &expired {
+            self.remove(field);
+        }
+        expired.len()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum RedisSet {
+    // Sorted, so membership checks can binary search
+    Intset(Vec<i64>),
+    Listpack(Vec<Vec<u8>>),
+    Hashtable(HashSet<Vec<u8>>),
+}
+
+impl Default for RedisSet {
+    fn default() -> Self {
+        RedisSet::Intset(Vec::new())
+    }
+}
+
+fn as_intset_member(member: &[u8]) -> Option<i64> {
+    /* Only members whose canonical decimal form round-trips (e.g. not "007" or "+1") can go in an intset */
+    let num = std::str::from_utf8(member).ok()?.parse::<i64>().ok()?;
+    (num.to_string().as_bytes() == member).then_some(num)
+}
+
+impl RedisSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            RedisSet::Intset(members) => members.len(),
+            RedisSet::Listpack(members) => members.len(),
+            RedisSet::Hashtable(members) => members.len(),
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            RedisSet::Intset(_) => "intset",
+            RedisSet::Listpack(_) => "listpack",
+            RedisSet::Hashtable(_) => "hashtable",
+        }
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        match self {
+            RedisSet::Intset(members) => as_intset_member(member).is_some_and(|num| members.binary_search(&num).is_ok()),
+            RedisSet::Listpack(members) => members.iter().any(|m| m == member),
+            RedisSet::Hashtable(members) => members.contains(member),
+        }
+    }
+
+    pub fn members(&self) -> Vec<Vec<u8>> {
+        match self {
+            RedisSet::Intset(members) => members.iter().map(|num| num.to_string().into_bytes()).collect(),
+            RedisSet::Listpack(members) => members.clone(),
+            RedisSet::Hashtable(members) => members.iter().cloned().collect(),
+        }
+    }
+
+    fn convert_for(&mut self, member: &[u8], limits: &EncodingLimits) {
+        /* Pick the most compact encoding that can still hold the current members plus the new member */
+        let new_len = self.len() + 1;
+        let fits_listpack = |members: &[Vec<u8>]| {
+            new_len <= limits.set_max_listpack_entries
+                && member.len() <= limits.set_max_listpack_value
+                && members.iter().all(|m| m.len() <= limits.set_max_listpack_value)
+        };
+        match self {
+            RedisSet::Intset(members) if as_intset_member(member).is_none() || new_len > limits.set_max_intset_entries => {
+                let members = members.iter().map(|num| num.to_string().into_bytes()).collect::<Vec<Vec<u8>>>();
+                *self = if fits_listpack(&members) {
+                    RedisSet::Listpack(members)
+                } else {
+                    RedisSet::Hashtable(members.into_iter().collect())
+                };
+            },
+            RedisSet::Listpack(members) if !fits_listpack(&[]) => {
+                *self = RedisSet::Hashtable(std::mem::take(members).into_iter().collect());
+            },
+            _ => {},
+        }
+    }
+
+    pub fn insert(&mut self, member: Vec<u8>, limits: &EncodingLimits) -> bool {
+        /* Add member, returning whether it wasn't already in the set */
+        if self.contains(&member) {
+            return false;
+        }
+        self.convert_for(&member, limits);
+        match self {
+            RedisSet::Intset(members) => {
+                // convert_for only keeps an intset if the member is an integer
+                let num = as_intset_member(&member).unwrap();
+                let idx = members.binary_search(&num).unwrap_err();
+                members.insert(idx, num);
+            },
+            RedisSet::Listpack(members) => members.push(member),
+            RedisSet::Hashtable(members) => {
+                members.insert(member);
+            },
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adding_past_the_limit_flips_the_encoding() {
+        let limits = EncodingLimits {
+            list_max_listpack_size: 3,
+            hash_max_listpack_entries: 3,
+            hash_max_listpack_value: 8,
+            set_max_intset_entries: 3,
+            set_max_listpack_entries: 4,
+            set_max_listpack_value: 8,
+        };
+
+        let mut list = RedisList::new();
+        for elem in ["a", "b", "c"] {
+            list.push_back(elem.as_bytes().to_vec(), &limits);
+        }
+        assert_eq!(list.encoding(), "listpack");
+        list.push_front(b"z".to_vec(), &limits);
+        assert_eq!(list.encoding(), "quicklist");
+        assert_eq!(list.iter().map(|elem| elem.as_slice()).collect::<Vec<&[u8]>>(), vec![b"z", b"a", b"b", b"c"]);
+
+        let mut hash = RedisHash::new();
+        for field in ["a", "b", "c"] {
+            hash.insert(field.as_bytes().to_vec(), b"v".to_vec(), &limits);
+        }
+        // Overwriting an existing field doesn't add an entry
+        assert!(!hash.insert(b"a".to_vec(), b"w".to_vec(), &limits));
+        assert_eq!(hash.encoding(), "listpack");
+        assert!(hash.insert(b"d".to_vec(), b"v".to_vec(), &limits));
+        assert_eq!(hash.encoding(), "hashtable");
+        assert_eq!(hash.get(b"a"), Some(&b"w".to_vec()));
+        // A value that's too long converts regardless of the number of fields
+        let mut hash = RedisHash::new();
+        hash.insert(b"f".to_vec(), b"a value that is too long".to_vec(), &limits);
+        assert_eq!(hash.encoding(), "hashtable");
+
+        let mut set = RedisSet::new();
+        for member in ["3", "1", "2"] {
+            set.insert(member.as_bytes().to_vec(), &limits);
+        }
+        assert_eq!(set.encoding(), "intset");
+        assert_eq!(set.members(), vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()]);
+        set.insert(b"4".to_vec(), &limits);
+        assert_eq!(set.encoding(), "listpack");
+        set.insert(b"5".to_vec(), &limits);
+        assert_eq!(set.encoding(), "hashtable");
+        assert!(set.contains(b"1") && set.contains(b"5"));
+        // Non-integers also force an intset out of its encoding
+        let mut set = RedisSet::new();
+        set.insert(b"1".to_vec(), &limits);
+        set.insert(b"01".to_vec(), &limits);
+        assert_eq!(set.encoding(), "listpack");
+    }
+}
@@ -0,0 +1,540 @@
+// Server settings given on the command line, e.g. `./redis-starter-rust --metrics-port 9121`, or in a redis.conf-style
+// file, e.g. `./redis-starter-rust redis.conf --port 6380` (flags after the file override it)
+use anyhow::{anyhow, bail};
+use crate::acl;
+use crate::collections::EncodingLimits;
+use crate::latency;
+use crate::logging;
+use crate::pubsub::{OutputBufferLimit, OverflowPolicy};
+use crate::resp::split_inline_args;
+use crate::slowlog;
+use log::LevelFilter;
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+pub const DEFAULT_BIND: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 6379;
+pub const DEFAULT_DBFILENAME: &str = "dump.rdb";
+// Where a daemonized server writes its pid when no pidfile is configured, like Redis
+pub const DEFAULT_PIDFILE: &str = "/var/run/redis.pid";
+// Number of logical databases, selected with SELECT
+pub const DEFAULT_DATABASES: usize = 16;
+// Maintenance ticks per second; like Redis, values outside MIN_HZ..=MAX_HZ are clamped rather than rejected
+pub const DEFAULT_HZ: u64 = 10;
+pub const MIN_HZ: u64 = 1;
+pub const MAX_HZ: u64 = 500;
+pub const DEFAULT_PIPELINE_BATCH_SIZE: usize = 64;
+// Longest bulk string argument a client may send, like Redis's proto-max-bulk-len
+pub const DEFAULT_PROTO_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+// Connections accepted at once; any more are told so and closed
+pub const DEFAULT_MAXCLIENTS: usize = 10000;
+// Seconds of silence before TCP keepalive probes a client's connection, like Redis's default; 0 turns it off
+pub const DEFAULT_TCP_KEEPALIVE: u64 = 300;
+
+// Same levels as redis.conf's loglevel
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogLevel {
+    // Per-request details such as raw request bytes
+    Debug,
+    // Connection and background activity, e.g. accepted connections and active expiry
+    Verbose,
+    #[default]
+    Notice,
+    Warning,
+    Nothing,
+}
+
+impl LogLevel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Verbose => "verbose",
+            LogLevel::Notice => "notice",
+            LogLevel::Warning => "warning",
+            LogLevel::Nothing => "nothing",
+        }
+    }
+
+    pub fn filter(&self) -> LevelFilter {
+        match self {
+            LogLevel::Debug => LevelFilter::Trace,
+            LogLevel::Verbose => LevelFilter::Debug,
+            LogLevel::Notice => LevelFilter::Info,
+            LogLevel::Warning => LevelFilter::Warn,
+            LogLevel::Nothing => LevelFilter::Off,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    // Addresses and port to listen on, e.g. `--bind "127.0.0.1 -::1" --port 6380`; DEFAULT_BIND when no addresses are
+    // set. A leading `-` marks an address that may be unavailable, e.g. when there's no IPv6
+    pub bind: Vec<String>,
+    pub port: u16,
+    // Path of a Unix socket to also listen on, e.g. `--unixsocket /tmp/redis.sock`; none by default
+    pub unixsocket: Option<PathBuf>,
+    // Address and port of the Prometheus metrics HTTP endpoint, e.g. `--metrics-bind 0.0.0.0 --metrics-port 9121`;
+    // the endpoint is disabled when no port is set, and only listens on DEFAULT_BIND unless told otherwise
+    pub metrics_bind: String,
+    pub metrics_port: Option<u16>,
+    // Thresholds past which small lists, hashes and sets switch to their general-purpose encoding
+    pub encoding_limits: EncodingLimits,
+    // How much output can queue up for a slow Pub/Sub subscriber, and what happens past that
+    pub pubsub_output_limit: OutputBufferLimit,
+    pub loglevel: LogLevel,
+    // File that logs are appended to; they go to stderr when this isn't set (or is set to "")
+    pub logfile: Option<PathBuf>,
+    // The logfile is rotated once it reaches log_rotate_size bytes (0: never) or every log_rotate_interval seconds,
+    // keeping log_rotate_keep old files
+    pub log_rotate_size: u64,
+    pub log_rotate_interval: Option<u64>,
+    pub log_rotate_keep: usize,
+    // Fork into the background at startup
+    pub daemonize: bool,
+    // File the server's pid is written to at startup and removed from on shutdown; see pidfile_path
+    pub pidfile: Option<PathBuf>,
+    // Tell systemd when the server is ready, with `supervised systemd` (or `auto`, since it's the only supervisor known)
+    pub supervised: bool,
+    // Directory and file name of the RDB snapshot
+    pub dir: PathBuf,
+    pub dbfilename: String,
+    pub databases: usize,
+    pub hz: u64,
+    // Pipelined commands a connection runs in a row before letting other connections run
+    pub pipeline_batch_size: usize,
+    pub proto_max_bulk_len: usize,
+    // Memory limit in bytes, 0 meaning no limit; only reported for now, since there's no eviction yet
+    pub maxmemory: usize,
+    pub maxclients: usize,
+    // Seconds a client may stay idle before it's disconnected, 0 meaning forever
+    pub timeout: u64,
+    pub tcp_keepalive: u64,
+    // Snapshot to the RDB file once any of these points is reached; none by default, unlike Redis
+    pub save_points: Vec<SavePoint>,
+    // Refuse writes like a read-only replica would
+    pub replica_read_only: bool,
+    // Only accept clients connecting over the loopback interface unless there's an explicit bind or a password
+    pub protected_mode: bool,
+    // Password that clients must AUTH with before running other commands; none by default
+    pub requirepass: Option<String>,
+    // File the ACL users are loaded from at startup and by ACL LOAD, and saved to by ACL SAVE; none by default
+    pub aclfile: Option<PathBuf>,
+    // How many entries ACL LOG keeps
+    pub acllog_max_len: usize,
+    // Commands running for at least this many microseconds go in the slow log (negative: none do), which keeps the
+    // latest slowlog_max_len of them
+    pub slowlog_log_slower_than: i64,
+    pub slowlog_max_len: usize,
+    // Events taking at least this many milliseconds are recorded for LATENCY (0: none are)
+    pub latency_monitor_threshold: u64,
+    // `rename-command <name> <new name>` pairs, in order; an empty new name removes the command
+    pub renamed_commands: Vec<(String, String)>,
+    // The file the config was loaded from, if any, which CONFIG REWRITE writes back to
+    pub config_file: Option<PathBuf>,
+}
+
+// `save <seconds> <changes>`: snapshot when at least this many writes happened and this many seconds passed since the last one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SavePoint {
+    pub secs: u64,
+    pub changes: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: Vec::new(),
+            port: DEFAULT_PORT,
+            unixsocket: None,
+            metrics_bind: DEFAULT_BIND.to_string(),
+            metrics_port: None,
+            encoding_limits: EncodingLimits::default(),
+            pubsub_output_limit: OutputBufferLimit::default(),
+            loglevel: LogLevel::default(),
+            logfile: None,
+            log_rotate_size: 0,
+            log_rotate_interval: None,
+            log_rotate_keep: logging::DEFAULT_ROTATE_KEEP,
+            daemonize: false,
+            pidfile: None,
+            supervised: false,
+            dir: PathBuf::from("."),
+            dbfilename: DEFAULT_DBFILENAME.to_string(),
+            databases: DEFAULT_DATABASES,
+            hz: DEFAULT_HZ,
+            pipeline_batch_size: DEFAULT_PIPELINE_BATCH_SIZE,
+            proto_max_bulk_len: DEFAULT_PROTO_MAX_BULK_LEN,
+            maxmemory: 0,
+            maxclients: DEFAULT_MAXCLIENTS,
+            timeout: 0,
+            tcp_keepalive: DEFAULT_TCP_KEEPALIVE,
+            save_points: Vec::new(),
+            replica_read_only: false,
+            protected_mode: true,
+            requirepass: None,
+            aclfile: None,
+            acllog_max_len: acl::DEFAULT_LOG_MAX_LEN,
+            slowlog_log_slower_than: slowlog::DEFAULT_LOG_SLOWER_THAN_US,
+            slowlog_max_len: slowlog::DEFAULT_MAX_LEN,
+            latency_monitor_threshold: latency::DEFAULT_THRESHOLD_MS,
+            renamed_commands: Vec::new(),
+            config_file: None,
+        }
+    }
+}
+
+fn parse_limit(name: &str, val: &str) -> anyhow::Result<usize> {
+    val.parse::<usize>().map_err(|_| anyhow!("Invalid {}: {}", name, val))
+}
+
+pub fn parse_loglevel(val: &str) -> Option<LogLevel> {
+    match val.to_lowercase().as_str() {
+        "debug" => Some(LogLevel::Debug),
+        "verbose" => Some(LogLevel::Verbose),
+        "notice" => Some(LogLevel::Notice),
+        "warning" => Some(LogLevel::Warning),
+        "nothing" => Some(LogLevel::Nothing),
+        _ => None,
+    }
+}
+
+pub fn parse_hz(val: &str) -> Option<u64> {
+    val.parse::<u64>().ok().map(|hz| hz.clamp(MIN_HZ, MAX_HZ))
+}
+
+pub fn parse_pipeline_batch_size(val: &str) -> Option<usize> {
+    val.parse::<usize>().ok().filter(|batch_size| *batch_size > 0)
+}
+
+pub fn parse_proto_max_bulk_len(val: &str) -> Option<usize> {
+    parse_memory(val).filter(|max_len| *max_len > 0)
+}
+
+pub fn parse_maxclients(val: &str) -> Option<usize> {
+    val.parse::<usize>().ok().filter(|maxclients| *maxclients > 0)
+}
+
+pub fn parse_maxmemory(val: &str) -> Option<usize> {
+    parse_memory(val)
+}
+
+fn parse_memory(val: &str) -> Option<usize> {
+    /* A byte count with an optional unit, as in redis.conf: k/m/g are powers of 1000 and kb/mb/gb powers of 1024 */
+    let val = val.to_lowercase();
+    let digits_end = val.find(|c: char| !c.is_ascii_digit()).unwrap_or(val.len());
+    let multiplier = match &val[digits_end..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    val[..digits_end].parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+pub fn parse_save_points(val: &str) -> Option<Vec<SavePoint>> {
+    /* `<seconds> <changes> [<seconds> <changes> ...]` */
+    let nums = val.split_whitespace().map(|num| num.parse::<u64>().ok()).collect::<Option<Vec<u64>>>()?;
+    if nums.is_empty() || nums.len() % 2 != 0 {
+        return None;
+    }
+    Some(nums.chunks(2).map(|point| SavePoint { secs: point[0], changes: point[1] }).collect())
+}
+
+pub fn format_save_points(save_points: &[SavePoint]) -> String {
+    save_points.iter().map(|point| format!("{} {}", point.secs, point.changes)).collect::<Vec<String>>().join(" ")
+}
+
+pub fn rewrite(contents: &str, params: &[(&str, String)]) -> String {
+    /*
+    Update a config file's contents with the given parameter values, for CONFIG REWRITE
+    The first line setting a parameter is replaced and any later ones dropped; parameters the file doesn't set yet
+    are appended. Comments and every other line are kept as they are.
+    */
+    let directive_name = |line: &str| line.split_whitespace().next().map(|name| name.to_lowercase());
+    let format_line = |name: &str, val: &str| match val {
+        "" => format!("{} \"\"", name),
+        val => format!("{} {}", name, val),
+    };
+    let mut rewritten = Vec::new();
+    let mut written = HashSet::new();
+    for line in contents.lines() {
+        let trimmed = line.trim();
+        let param = (!trimmed.starts_with('#'))
+            .then(|| directive_name(trimmed))
+            .flatten()
+            .and_then(|name| params.iter().find(|(param, _)| *param == name));
+        match param {
+            Some((name, _)) if written.contains(name) => (),
+            Some((name, val)) => {
+                rewritten.push(format_line(name, val));
+                written.insert(*name);
+            },
+            None => rewritten.push(line.to_string()),
+        }
+    }
+    let missing = params.iter().filter(|(name, _)| !written.contains(name)).collect::<Vec<_>>();
+    if !missing.is_empty() {
+        rewritten.push("# Generated by CONFIG REWRITE".to_string());
+        rewritten.extend(missing.iter().map(|(name, val)| format_line(name, val)));
+    }
+    rewritten.push(String::new());
+    rewritten.join("\n")
+}
+
+fn parse_output_buffer_limit(val: &str) -> anyhow::Result<OutputBufferLimit> {
+    /* `pubsub <hard limit> [disconnect|drop-oldest]`; only the pubsub class is supported */
+    let invalid = || anyhow!("Invalid client-output-buffer-limit: {}", val);
+    let (max_bytes, overflow) = match val.split_whitespace().collect::<Vec<&str>>().as_slice() {
+        ["pubsub", max_bytes] => (*max_bytes, OverflowPolicy::Disconnect),
+        ["pubsub", max_bytes, "disconnect"] => (*max_bytes, OverflowPolicy::Disconnect),
+        ["pubsub", max_bytes, "drop-oldest"] => (*max_bytes, OverflowPolicy::DropOldest),
+        _ => return Err(invalid()),
+    };
+    let max_bytes = parse_memory(max_bytes).ok_or_else(invalid)?;
+    Ok(OutputBufferLimit { max_bytes, overflow })
+}
+
+impl Config {
+    pub fn rdb_path(&self) -> PathBuf {
+        self.dir.join(&self.dbfilename)
+    }
+
+    pub fn pidfile_path(&self) -> Option<PathBuf> {
+        /* The pidfile, if one is configured or the server daemonizes */
+        self.pidfile.clone().or_else(|| self.daemonize.then(|| PathBuf::from(DEFAULT_PIDFILE)))
+    }
+
+    pub fn addrs(&self) -> Vec<String> {
+        /* The bind addresses with the port, in the form RedisServerBuilder::bind takes */
+        let default_bind = [DEFAULT_BIND.to_string()];
+        let bind = if self.bind.is_empty() { &default_bind[..] } else { &self.bind[..] };
+        bind.iter()
+            .map(|addr| {
+                let (optional, addr) = addr.strip_prefix('-').map_or(("", addr.as_str()), |addr| ("-", addr));
+                match addr.contains(':') {
+                    true => format!("{}[{}]:{}", optional, addr, self.port),
+                    false => format!("{}{}:{}", optional, addr, self.port),
+                }
+            })
+            .collect()
+    }
+
+    pub fn from_args(args: impl Iterator<Item = String>) -> anyhow::Result<Config> {
+        /*
+        Parse an optional config file path followed by `--<name> <value>` pairs (the program name must already be skipped)
+        The flags are applied after the file, so they override it.
+        */
+        let mut args = args.peekable();
+        let mut config = match args.next_if(|arg| !arg.starts_with("--")) {
+            Some(path) => Config { config_file: Some(PathBuf::from(&path)), ..Config::from_file(Path::new(&path))? },
+            None => Config::default(),
+        };
+        while let Some(arg) = args.next() {
+            let name = arg.strip_prefix("--").ok_or_else(|| anyhow!("Unexpected argument: {}", arg))?;
+            let val = args.next().ok_or_else(|| anyhow!("Missing value for config option: {}", name))?;
+            config.set(name, val)?;
+        }
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Config> {
+        let contents = fs::read_to_string(path).map_err(|err| anyhow!("Failed to read {}: {}", path.display(), err))?;
+        Config::parse(&contents).map_err(|err| anyhow!("{} in {}", err, path.display()))
+    }
+
+    pub fn parse(contents: &str) -> anyhow::Result<Config> {
+        /*
+        Parse redis.conf's format: a `<name> <value ...>` directive per line, with blank lines and `#` comments skipped
+        Values can be quoted like inline commands' arguments, e.g. `logfile ""`; multi-word values such as
+        `save 900 1 300 10` are passed on as one space-separated value. Later lines override earlier ones.
+        */
+        let mut config = Config::default();
+        for (line_idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let args = split_inline_args(line.as_bytes())
+                .ok_or_else(|| anyhow!("Unbalanced quotes on line {}", line_idx + 1))?
+                .into_iter()
+                .map(|arg| String::from_utf8(arg).map_err(|_| anyhow!("Invalid UTF-8 on line {}", line_idx + 1)))
+                .collect::<anyhow::Result<Vec<String>>>()?;
+            let [name, vals @ ..] = args.as_slice() else {
+                continue;
+            };
+            if vals.is_empty() {
+                bail!("Missing value for config option {} on line {}", name, line_idx + 1);
+            }
+            config.set(&name.to_lowercase(), vals.join(" "))
+                .map_err(|err| anyhow!("{} on line {}", err, line_idx + 1))?;
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, name: &str, val: String) -> anyhow::Result<()> {
+        /* Apply one option, given by its redis.conf name */
+        match name {
+            "bind" => self.bind = val.split_whitespace().map(|addr| addr.to_string()).collect(),
+            "unixsocket" => self.unixsocket = (!val.is_empty()).then(|| PathBuf::from(val)),
+            "port" => self.port = val.parse::<u16>().map_err(|_| anyhow!("Invalid port: {}", val))?,
+            "metrics-bind" => self.metrics_bind = val,
+            "metrics-port" => {
+                let port = val.parse::<u16>().map_err(|_| anyhow!("Invalid metrics-port: {}", val))?;
+                self.metrics_port = Some(port);
+            },
+            "list-max-listpack-size" => self.encoding_limits.list_max_listpack_size = parse_limit(name, &val)?,
+            "hash-max-listpack-entries" => self.encoding_limits.hash_max_listpack_entries = parse_limit(name, &val)?,
+            "hash-max-listpack-value" => self.encoding_limits.hash_max_listpack_value = parse_limit(name, &val)?,
+            "set-max-intset-entries" => self.encoding_limits.set_max_intset_entries = parse_limit(name, &val)?,
+            "set-max-listpack-entries" => self.encoding_limits.set_max_listpack_entries = parse_limit(name, &val)?,
+            "set-max-listpack-value" => self.encoding_limits.set_max_listpack_value = parse_limit(name, &val)?,
+            "loglevel" => self.loglevel = parse_loglevel(&val).ok_or_else(|| anyhow!("Invalid loglevel: {}", val))?,
+            "logfile" => self.logfile = (!val.is_empty()).then(|| PathBuf::from(val)),
+            "log-rotate-size" => {
+                self.log_rotate_size = parse_memory(&val).ok_or_else(|| anyhow!("Invalid log-rotate-size: {}", val))? as u64;
+            },
+            "log-rotate-interval" => {
+                let interval = val.parse::<u64>().map_err(|_| anyhow!("Invalid log-rotate-interval: {}", val))?;
+                self.log_rotate_interval = (interval > 0).then_some(interval);
+            },
+            "log-rotate-keep" => self.log_rotate_keep = parse_limit(name, &val)?,
+            "daemonize" => match val.to_lowercase().as_str() {
+                "yes" => self.daemonize = true,
+                "no" => self.daemonize = false,
+                _ => bail!("Invalid daemonize: {}", val),
+            },
+            "pidfile" => self.pidfile = (!val.is_empty()).then(|| PathBuf::from(val)),
+            "supervised" => match val.to_lowercase().as_str() {
+                "systemd" | "auto" => self.supervised = true,
+                "no" => self.supervised = false,
+                _ => bail!("Invalid supervised: {}", val),
+            },
+            "dir" => self.dir = PathBuf::from(val),
+            "dbfilename" => self.dbfilename = val,
+            "databases" => {
+                self.databases = val.parse::<usize>().ok().filter(|databases| *databases > 0)
+                    .ok_or_else(|| anyhow!("Invalid databases: {}", val))?;
+            },
+            "hz" => self.hz = parse_hz(&val).ok_or_else(|| anyhow!("Invalid hz: {}", val))?,
+            "pipeline-batch-size" => {
+                self.pipeline_batch_size = parse_pipeline_batch_size(&val)
+                    .ok_or_else(|| anyhow!("Invalid pipeline-batch-size: {}", val))?;
+            },
+            "proto-max-bulk-len" => {
+                self.proto_max_bulk_len = parse_proto_max_bulk_len(&val)
+                    .ok_or_else(|| anyhow!("Invalid proto-max-bulk-len: {}", val))?;
+            },
+            "maxmemory" => {
+                self.maxmemory = parse_maxmemory(&val).ok_or_else(|| anyhow!("Invalid maxmemory: {}", val))?;
+            },
+            "maxclients" => {
+                self.maxclients = parse_maxclients(&val).ok_or_else(|| anyhow!("Invalid maxclients: {}", val))?;
+            },
+            "timeout" => self.timeout = val.parse::<u64>().map_err(|_| anyhow!("Invalid timeout: {}", val))?,
+            "tcp-keepalive" => {
+                self.tcp_keepalive = val.parse::<u64>().map_err(|_| anyhow!("Invalid tcp-keepalive: {}", val))?;
+            },
+            "client-output-buffer-limit" => self.pubsub_output_limit = parse_output_buffer_limit(&val)?,
+            // Like in Redis 7, each `save` adds to the save points, and `save ""` removes them all
+            "save" if val.is_empty() => self.save_points.clear(),
+            "save" => self.save_points.extend(parse_save_points(&val).ok_or_else(|| anyhow!("Invalid save: {}", val))?),
+            "replica-read-only" => match val.to_lowercase().as_str() {
+                "yes" => self.replica_read_only = true,
+                "no" => self.replica_read_only = false,
+                _ => bail!("Invalid replica-read-only: {}", val),
+            },
+            "requirepass" => self.requirepass = (!val.is_empty()).then_some(val),
+            "aclfile" => self.aclfile = (!val.is_empty()).then(|| PathBuf::from(val)),
+            "acllog-max-len" => self.acllog_max_len = parse_limit(name, &val)?,
+            "protected-mode" => match val.to_lowercase().as_str() {
+                "yes" => self.protected_mode = true,
+                "no" => self.protected_mode = false,
+                _ => bail!("Invalid protected-mode: {}", val),
+            },
+            "slowlog-log-slower-than" => {
+                self.slowlog_log_slower_than = val.parse::<i64>()
+                    .map_err(|_| anyhow!("Invalid slowlog-log-slower-than: {}", val))?;
+            },
+            "slowlog-max-len" => self.slowlog_max_len = parse_limit(name, &val)?,
+            "latency-monitor-threshold" => {
+                self.latency_monitor_threshold = val.parse::<u64>()
+                    .map_err(|_| anyhow!("Invalid latency-monitor-threshold: {}", val))?;
+            },
+            // Whether the command exists is only checked once the server is built
+            "rename-command" => match val.split_whitespace().collect::<Vec<&str>>().as_slice() {
+                [cmd_name] => self.renamed_commands.push((cmd_name.to_string(), String::new())),
+                [cmd_name, new_name] => self.renamed_commands.push((cmd_name.to_string(), new_name.to_string())),
+                _ => bail!("Invalid rename-command: {}", val),
+            },
+            // There's no AOF, so it can only be turned off
+            "appendonly" => match val.to_lowercase().as_str() {
+                "no" => (),
+                "yes" => bail!("appendonly yes isn't supported, there's no AOF yet"),
+                _ => bail!("Invalid appendonly: {}", val),
+            },
+            _ => bail!("Unsupported config option: {}", name),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_address_logfile_and_maxmemory_come_from_flags() {
+        let args = ["--bind", "0.0.0.0", "--port", "6380", "--logfile", "redis.log", "--maxmemory", "100mb"];
+        let config = Config::from_args(args.iter().map(|arg| arg.to_string())).unwrap();
+        assert_eq!(config.addrs(), ["0.0.0.0:6380"]);
+        assert_eq!(config.logfile, Some(PathBuf::from("redis.log")));
+        assert_eq!(config.maxmemory, 100 * 1024 * 1024);
+        let args = ["--log-rotate-size", "10mb", "--log-rotate-interval", "86400", "--log-rotate-keep", "3"];
+        let config = Config::from_args(args.iter().map(|arg| arg.to_string())).unwrap();
+        assert_eq!((config.log_rotate_size, config.log_rotate_interval, config.log_rotate_keep), (10 * 1024 * 1024, Some(86400), 3));
+        assert_eq!(Config::default().addrs(), ["127.0.0.1:6379"]);
+        let config = Config::parse("bind 127.0.0.1 -::1\nport 6380\n").unwrap();
+        assert_eq!(config.addrs(), ["127.0.0.1:6380", "-[::1]:6380"]);
+        // An empty logfile means stderr, as in redis.conf
+        assert_eq!(Config::from_args(["--logfile", ""].iter().map(|arg| arg.to_string())).unwrap().logfile, None);
+        assert_eq!(Config::parse("daemonize yes\n").unwrap().pidfile_path(), Some(PathBuf::from(DEFAULT_PIDFILE)));
+        assert_eq!(Config::parse("pidfile /tmp/redis.pid\n").unwrap().pidfile_path(), Some(PathBuf::from("/tmp/redis.pid")));
+        assert_eq!(Config::default().pidfile_path(), None);
+        assert!(Config::from_args(["--port", "65536"].iter().map(|arg| arg.to_string())).is_err());
+        let config = Config::from_args(["--metrics-bind", "0.0.0.0", "--metrics-port", "9121"].iter().map(|arg| arg.to_string())).unwrap();
+        assert_eq!((config.metrics_bind.as_str(), config.metrics_port), ("0.0.0.0", Some(9121)));
+        assert_eq!(Config::default().metrics_bind, DEFAULT_BIND);
+    }
+
+    #[test]
+    fn config_files_use_redis_conf_syntax() {
+        let config = Config::parse(concat!(
+            "# Comments and blank lines are skipped\n",
+            "\n",
+            "port 6380\n",
+            "logfile \"\"\n",
+            "save 900 1 300 10\n",
+            "save 60 10000\n",
+            "appendonly no\n",
+            "client-output-buffer-limit pubsub 32mb drop-oldest\n",
+            "rename-command FLUSHALL \"\"\n",
+            "rename-command CONFIG cfg\n",
+            "PORT 6381\n",
+        )).unwrap();
+        assert_eq!(config.port, 6381);
+        assert_eq!(config.logfile, None);
+        assert_eq!(config.save_points, [(900, 1), (300, 10), (60, 10000)].map(|(secs, changes)| SavePoint { secs, changes }));
+        assert_eq!(config.renamed_commands, [("FLUSHALL", ""), ("CONFIG", "cfg")].map(|(name, new_name)| (name.to_string(), new_name.to_string())));
+        assert_eq!(config.pubsub_output_limit, OutputBufferLimit { max_bytes: 32 * 1024 * 1024, overflow: OverflowPolicy::DropOldest });
+        assert!(Config::parse("save 900 1\nsave \"\"\n").unwrap().save_points.is_empty());
+        assert!(Config::parse("port 6380\nbogus 1\n").unwrap_err().to_string().contains("line 2"));
+        assert!(Config::parse("appendonly yes\n").is_err());
+    }
+}
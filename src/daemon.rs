@@ -0,0 +1,111 @@
+// Running as a classic daemon, for init scripts: `daemonize yes` and the pidfile; and under systemd, with
+// `supervised systemd`
+//
+// Like Redis, daemonizing forks: the parent exits right away, and the child carries on in a session of its own, without
+// a controlling terminal, and with stdin, stdout and stderr on /dev/null (so logs are lost without a logfile). Only the
+// forking thread survives a fork, so this has to happen before the tokio runtime starts its threads. fork, setsid and
+// dup2 are called directly since std already links libc (like tcp.rs does for setsockopt).
+//
+// Under systemd (with `Type=notify`), the server tells systemd it's ready the way sd_notify does, by sending `READY=1`
+// to the datagram socket named by $NOTIFY_SOCKET, once it's serving clients, i.e. after the dataset is loaded.
+
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+
+extern "C" {
+    fn fork() -> i32;
+    fn setsid() -> i32;
+    fn dup2(oldfd: i32, newfd: i32) -> i32;
+}
+
+fn check(ret: i32) -> io::Result<i32> {
+    match ret {
+        ret if ret < 0 => Err(io::Error::last_os_error()),
+        ret => Ok(ret),
+    }
+}
+
+pub fn daemonize() -> io::Result<()> {
+    /* Carry on in the background as a child of init, returning in the child only */
+    if check(unsafe { fork() })? > 0 {
+        std::process::exit(0);
+    }
+    check(unsafe { setsid() })?;
+    let dev_null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    for fd in 0..3 {
+        check(unsafe { dup2(dev_null.as_raw_fd(), fd) })?;
+    }
+    Ok(())
+}
+
+pub fn write_pidfile(path: &Path) -> io::Result<()> {
+    fs::write(path, format!("{}\n", std::process::id()))
+}
+
+pub fn notify_systemd(status: &str) -> io::Result<()> {
+    /* Send a status such as `READY=1` to systemd, if it's supervising the server */
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(socket_path) => notify_socket(&socket_path, status),
+        None => Ok(()),
+    }
+}
+
+fn notify_socket(socket_path: &OsStr, status: &str) -> io::Result<()> {
+    /* A path starting with `@` is a socket in Linux's abstract namespace */
+    let socket = UnixDatagram::unbound()?;
+    match socket_path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(status.as_bytes(), &addr)?;
+        },
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(io::Error::new(io::ErrorKind::Unsupported, "abstract sockets are Linux-only")),
+        None => {
+            socket.send_to(status.as_bytes(), socket_path)?;
+        },
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pidfile_holds_the_process_id() {
+        let path = std::env::temp_dir().join(format!("pidfile-test-{}.pid", std::process::id()));
+        write_pidfile(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn systemd_is_notified_over_its_socket() {
+        let path = std::env::temp_dir().join(format!("notify-test-{}.sock", std::process::id()));
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        notify_socket(path.as_os_str(), "READY=1\n").unwrap();
+        let mut buf = [0; 64];
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn systemd_can_listen_on_an_abstract_socket() {
+        use std::os::linux::net::SocketAddrExt;
+        let name = format!("notify-test-{}", std::process::id());
+        let systemd = UnixDatagram::bind_addr(&std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        notify_socket(OsStr::new(&format!("@{}", name)), "READY=1\n").unwrap();
+        let mut buf = [0; 64];
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1\n");
+    }
+}
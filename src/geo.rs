@@ -0,0 +1,383 @@
+// Geohash encoding used by the GEO commands, matching Redis's: https://github.com/redis/redis/blob/unstable/src/geohash.c
+// A longitude/latitude pair is encoded as a 52-bit integer (26 bits each, interleaved) which is stored as the
+// member's score in a sorted set. 52 bits fit exactly in an f64 mantissa, so the score round-trips losslessly.
+use crate::resp::RespValue;
+use crate::sorted_set::SortedSet;
+use crate::{arg_to_string, parse_arg, CommandSpec, Connection, RedisServer, RedisValue, ServerState, WRONGTYPE_ERR};
+use bytes::Bytes;
+use std::io;
+
+const GEO_STEP: u32 = 26;
+pub const GEO_LONG_MIN: f64 = -180.0;
+pub const GEO_LONG_MAX: f64 = 180.0;
+// Web Mercator limits, beyond which latitudes can't be represented
+pub const GEO_LAT_MIN: f64 = -85.051_128_78;
+pub const GEO_LAT_MAX: f64 = 85.051_128_78;
+const EARTH_RADIUS_IN_METERS: f64 = 6_372_797.560_856;
+
+pub fn is_valid_coord(lon: f64, lat: f64) -> bool {
+    (GEO_LONG_MIN..=GEO_LONG_MAX).contains(&lon) && (GEO_LAT_MIN..=GEO_LAT_MAX).contains(&lat)
+}
+
+fn interleave(even_bits: u32, odd_bits: u32) -> u64 {
+    /* Spread the bits of even_bits over the even bit positions and odd_bits over the odd ones */
+    (0..GEO_STEP).fold(0, |acc, bit| {
+        acc | (((even_bits as u64 >> bit) & 1) << (2 * bit)) | (((odd_bits as u64 >> bit) & 1) << (2 * bit + 1))
+    })
+}
+
+fn deinterleave(bits: u64) -> (u32, u32) {
+    (0..GEO_STEP).fold((0, 0), |(even_bits, odd_bits), bit| {
+        (
+            even_bits | (((bits >> (2 * bit)) & 1) as u32) << bit,
+            odd_bits | (((bits >> (2 * bit + 1)) & 1) as u32) << bit,
+        )
+    })
+}
+
+pub fn encode(lon: f64, lat: f64) -> u64 {
+    /* Encode a (valid) coordinate pair as a 52-bit geohash */
+    let cells = (1u64 << GEO_STEP) as f64;
+    let lat_cell = ((lat - GEO_LAT_MIN) / (GEO_LAT_MAX - GEO_LAT_MIN) * cells) as u32;
+    let lon_cell = ((lon - GEO_LONG_MIN) / (GEO_LONG_MAX - GEO_LONG_MIN) * cells) as u32;
+    // Redis puts latitude bits in the even positions and longitude bits in the odd ones
+    interleave(lat_cell, lon_cell)
+}
+
+pub fn decode(hash: u64) -> (f64, f64) {
+    /* Decode a geohash to the (longitude, latitude) at the center of its cell */
+    let cells = (1u64 << GEO_STEP) as f64;
+    let (lat_cell, lon_cell) = deinterleave(hash);
+    let lon = GEO_LONG_MIN + (lon_cell as f64 + 0.5) / cells * (GEO_LONG_MAX - GEO_LONG_MIN);
+    let lat = GEO_LAT_MIN + (lat_cell as f64 + 0.5) / cells * (GEO_LAT_MAX - GEO_LAT_MIN);
+    (lon.clamp(GEO_LONG_MIN, GEO_LONG_MAX), lat.clamp(GEO_LAT_MIN, GEO_LAT_MAX))
+}
+
+pub fn distance(lon1: f64, lat1: f64, lon2: f64, lat2: f64) -> f64 {
+    /* Great-circle distance in meters using the haversine formula */
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let u = ((lat2 - lat1) / 2.0).sin();
+    let v = ((lon2 - lon1).to_radians() / 2.0).sin();
+    2.0 * EARTH_RADIUS_IN_METERS * (u * u + lat1.cos() * lat2.cos() * v * v).sqrt().asin()
+}
+
+pub fn unit_to_meters(unit: &str) -> Option<f64> {
+    match unit.to_lowercase().as_str() {
+        "m" => Some(1.0),
+        "km" => Some(1000.0),
+        "mi" => Some(1609.34),
+        "ft" => Some(0.3048),
+        _ => None,
+    }
+}
+
+// The GEO commands, which command_table() registers along with the other modules' commands
+pub(crate) const COMMANDS: &[CommandSpec] = &[
+    CommandSpec { name: "geoadd", arity: (4, None), flags: &["write", "denyoom"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_geoadd_cmd },
+    CommandSpec { name: "geosearch", arity: (6, None), flags: &["readonly"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_geosearch_cmd },
+    CommandSpec { name: "geodist", arity: (3, Some(4)), flags: &["readonly"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_geodist_cmd },
+    CommandSpec { name: "geopos", arity: (1, None), flags: &["readonly"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_geopos_cmd },
+];
+
+impl RedisServer {
+    fn handle_geoadd_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Add members at the given longitude/latitude to the sorted set at key, using their geohash as the score
+        NX only adds new members and XX only updates existing ones. Replies with the number of members added,
+        or with CH, the number of members added or moved.
+        */
+        let (opts, arg_idx) = Self::parse_add_options(args);
+        let points_args = &args[arg_idx..];
+        if points_args.is_empty() || points_args.len() % 3 != 0 || (opts.nx && opts.xx) || opts.gt || opts.lt {
+            let geoadd_err_response = RespValue::Error("ERR syntax error".to_string());
+            conn.write_reply(&geoadd_err_response)?;
+            return Ok(());
+        }
+        let mut points = Vec::with_capacity(points_args.len() / 3);
+        for point in points_args.chunks(3) {
+            match (parse_arg::<f64>(&point[0]), parse_arg::<f64>(&point[1])) {
+                (Some(lon), Some(lat)) if is_valid_coord(lon, lat) => {
+                    points.push((point[2].to_vec(), encode(lon, lat) as f64));
+                },
+                (Some(_), Some(_)) => {
+                    let geoadd_err_response = RespValue::Error("ERR invalid longitude,latitude pair".to_string());
+                    conn.write_reply(&geoadd_err_response)?;
+                    return Ok(());
+                },
+                _ => {
+                    let geoadd_err_response = RespValue::Error("ERR value is not a valid float".to_string());
+                    conn.write_reply(&geoadd_err_response)?;
+                    return Ok(());
+                }
+            }
+        }
+
+        let geoadd_resp = match Self::add_to_sorted_set(state, conn.db, &args[1], points, opts) {
+            Ok(num_changed) => RespValue::Integer(num_changed as i64),
+            Err(()) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+        };
+        conn.write_reply(&geoadd_resp)?;
+        Ok(())
+    }
+
+    fn handle_geosearch_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Reply with the members of the geo sorted set at key within a radius (BYRADIUS) or box (BYBOX) around
+        a member (FROMMEMBER) or coordinates (FROMLONLAT)
+        Results are sorted nearest first unless DESC is given, and COUNT limits how many are returned.
+        WITHDIST, WITHHASH and WITHCOORD add the distance (in the search unit), geohash and coordinates of each member.
+        */
+        const GEOSEARCH_ORIGIN_ERR: &str = "exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH";
+        const GEOSEARCH_SHAPE_ERR: &str = "exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH";
+        enum Origin {
+            Member(Vec<u8>),
+            LonLat(f64, f64),
+        }
+        enum Shape {
+            Radius(f64),
+            Box(f64, f64),
+        }
+        let mut origin = None;
+        let mut shape = None;
+        let mut unit_meters = 1.0;
+        let mut descending = false;
+        let mut count = None;
+        let (mut with_dist, mut with_hash, mut with_coord) = (false, false, false);
+        let mut syntax_err = false;
+
+        let mut arg_idx = 2;
+        while arg_idx < args.len() && !syntax_err {
+            let remaining = &args[arg_idx + 1..];
+            let num_option_args = match arg_to_string(&args[arg_idx]).to_uppercase().as_str() {
+                // Like Redis, a second origin or shape is rejected as soon as it's seen, whatever follows it
+                "FROMMEMBER" | "FROMLONLAT" if origin.is_some() => {
+                    let geosearch_err_response = RespValue::Error(format!("ERR {}", GEOSEARCH_ORIGIN_ERR));
+                    conn.write_reply(&geosearch_err_response)?;
+                    return Ok(());
+                },
+                "BYRADIUS" | "BYBOX" if shape.is_some() => {
+                    let geosearch_err_response = RespValue::Error(format!("ERR {}", GEOSEARCH_SHAPE_ERR));
+                    conn.write_reply(&geosearch_err_response)?;
+                    return Ok(());
+                },
+                "FROMMEMBER" if !remaining.is_empty() => {
+                    origin = Some(Origin::Member(remaining[0].to_vec()));
+                    1
+                },
+                "FROMLONLAT" if remaining.len() >= 2 => {
+                    match (parse_arg::<f64>(&remaining[0]), parse_arg::<f64>(&remaining[1])) {
+                        (Some(lon), Some(lat)) if is_valid_coord(lon, lat) => origin = Some(Origin::LonLat(lon, lat)),
+                        _ => {
+                            let geosearch_err_response = RespValue::Error("ERR invalid longitude,latitude pair".to_string());
+                            conn.write_reply(&geosearch_err_response)?;
+                            return Ok(());
+                        }
+                    }
+                    2
+                },
+                "BYRADIUS" if remaining.len() >= 2 => {
+                    shape = parse_arg::<f64>(&remaining[0]).filter(|radius| *radius >= 0.0).map(Shape::Radius);
+                    unit_meters = unit_to_meters(&arg_to_string(&remaining[1])).unwrap_or(-1.0);
+                    syntax_err = shape.is_none() || unit_meters < 0.0;
+                    2
+                },
+                "BYBOX" if remaining.len() >= 3 => {
+                    shape = match (parse_arg::<f64>(&remaining[0]), parse_arg::<f64>(&remaining[1])) {
+                        (Some(width), Some(height)) if width >= 0.0 && height >= 0.0 => Some(Shape::Box(width, height)),
+                        _ => None,
+                    };
+                    unit_meters = unit_to_meters(&arg_to_string(&remaining[2])).unwrap_or(-1.0);
+                    syntax_err = shape.is_none() || unit_meters < 0.0;
+                    3
+                },
+                "ASC" => {
+                    descending = false;
+                    0
+                },
+                "DESC" => {
+                    descending = true;
+                    0
+                },
+                "COUNT" if !remaining.is_empty() => {
+                    let count_err = match parse_arg::<i64>(&remaining[0]) {
+                        Some(parsed_count) if parsed_count > 0 => {
+                            count = Some(parsed_count as usize);
+                            None
+                        },
+                        Some(_) => Some("ERR COUNT must be > 0"),
+                        None => Some("ERR value is not an integer or out of range"),
+                    };
+                    if let Some(count_err) = count_err {
+                        let geosearch_err_response = RespValue::Error(count_err.to_string());
+                        conn.write_reply(&geosearch_err_response)?;
+                        return Ok(());
+                    }
+                    // ANY only lets Redis stop searching early; the results are the same here
+                    if remaining.get(1).is_some_and(|arg| arg.eq_ignore_ascii_case(b"ANY")) { 2 } else { 1 }
+                },
+                "WITHDIST" => {
+                    with_dist = true;
+                    0
+                },
+                "WITHHASH" => {
+                    with_hash = true;
+                    0
+                },
+                "WITHCOORD" => {
+                    with_coord = true;
+                    0
+                },
+                _ => {
+                    syntax_err = true;
+                    0
+                }
+            };
+            arg_idx += 1 + num_option_args;
+        }
+        let (origin, shape) = match (syntax_err, origin, shape) {
+            (false, Some(origin), Some(shape)) => (origin, shape),
+            (syntax_err, origin, _) => {
+                let geosearch_err = if syntax_err {
+                    "syntax error"
+                } else if origin.is_none() {
+                    GEOSEARCH_ORIGIN_ERR
+                } else {
+                    GEOSEARCH_SHAPE_ERR
+                };
+                let geosearch_err_response = RespValue::Error(format!("ERR {}", geosearch_err));
+                conn.write_reply(&geosearch_err_response)?;
+                return Ok(());
+            }
+        };
+
+        // (member, distance in meters, geohash, longitude, latitude)
+        let matches = Self::get_key(state, conn.db, &args[1], |val| {
+            let zset = match val {
+                Some(RedisValue::SortedSet(zset)) => zset,
+                Some(_) => return Err(RespValue::Error(WRONGTYPE_ERR.to_string())),
+                None => &SortedSet::new(),
+            };
+            let (origin_lon, origin_lat) = match origin {
+                Origin::LonLat(lon, lat) => (lon, lat),
+                Origin::Member(member) => match zset.score(&member) {
+                    Some(score) => decode(score as u64),
+                    None => return Err(RespValue::Error("ERR could not decode requested zset member".to_string())),
+                },
+            };
+            let mut matches = Vec::new();
+            for (member, score) in zset.iter() {
+                let (lon, lat) = decode(score as u64);
+                let dist = distance(origin_lon, origin_lat, lon, lat);
+                let in_shape = match shape {
+                    Shape::Radius(radius) => dist <= radius * unit_meters,
+                    Shape::Box(width, height) => {
+                        // Measure along each axis from the center, like Redis does
+                        let lon_dist = distance(origin_lon, origin_lat, lon, origin_lat);
+                        let lat_dist = distance(origin_lon, origin_lat, origin_lon, lat);
+                        lon_dist <= width * unit_meters / 2.0 && lat_dist <= height * unit_meters / 2.0
+                    },
+                };
+                if in_shape {
+                    matches.push((member.clone(), dist, score as u64, lon, lat));
+                }
+            }
+            Ok(matches)
+        });
+        let mut matches = match matches {
+            Ok(matches) => matches,
+            Err(geosearch_err_response) => {
+                conn.write_reply(&geosearch_err_response)?;
+                return Ok(());
+            },
+        };
+        matches.sort_by(|a, b| a.1.total_cmp(&b.1));
+        if descending {
+            matches.reverse();
+        }
+        matches.truncate(count.unwrap_or(usize::MAX));
+
+        let num_extras = [with_dist, with_hash, with_coord].iter().filter(|with| **with).count();
+        let geosearch_resp = RespValue::Array(matches.into_iter().map(|(member, dist, hash, lon, lat)| {
+            let mut entry = vec![RespValue::BulkString(member.to_vec())];
+            if with_dist {
+                entry.push(RespValue::BulkString(format!("{:.4}", dist / unit_meters).into_bytes()));
+            }
+            if with_hash {
+                entry.push(RespValue::Integer(hash as i64));
+            }
+            if with_coord {
+                entry.push(RespValue::bulk_strings(&[lon.to_string().into_bytes(), lat.to_string().into_bytes()]));
+            }
+            // Without any WITH options, each match is just its name rather than a one-element array
+            if num_extras > 0 { RespValue::Array(entry) } else { entry.remove(0) }
+        }).collect());
+        conn.write_reply(&geosearch_resp)?;
+        Ok(())
+    }
+
+    fn handle_geodist_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with the distance between two members of the geo sorted set at key, in meters or the given unit */
+        let unit_meters = match args.get(4) {
+            Some(unit) => match unit_to_meters(&arg_to_string(unit)) {
+                Some(unit_meters) => unit_meters,
+                None => {
+                    let geodist_err_response = RespValue::Error("ERR unsupported unit provided. please use M, KM, FT, MI".to_string());
+                    conn.write_reply(&geodist_err_response)?;
+                    return Ok(());
+                }
+            },
+            None => 1.0,
+        };
+        let geodist_resp = Self::get_key(state, conn.db, &args[1], |val| match val {
+            Some(RedisValue::SortedSet(zset)) => match (zset.score(&args[2]), zset.score(&args[3])) {
+                (Some(score1), Some(score2)) => {
+                    let (lon1, lat1) = decode(score1 as u64);
+                    let (lon2, lat2) = decode(score2 as u64);
+                    let dist = distance(lon1, lat1, lon2, lat2) / unit_meters;
+                    RespValue::BulkString(format!("{:.4}", dist).into_bytes())
+                },
+                _ => RespValue::Null,
+            },
+            Some(_) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+            None => RespValue::Null,
+        });
+        conn.write_reply(&geodist_resp)?;
+        Ok(())
+    }
+
+    fn handle_geopos_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with the [longitude, latitude] of each member of the geo sorted set at key, or nil for missing members */
+        let geopos_resp = Self::get_key(state, conn.db, &args[1], |val| {
+            let zset = match val {
+                Some(RedisValue::SortedSet(zset)) => zset,
+                Some(_) => return RespValue::Error(WRONGTYPE_ERR.to_string()),
+                None => &SortedSet::new(),
+            };
+            RespValue::Array(args[2..].iter().map(|member| match zset.score(member) {
+                Some(score) => {
+                    let (lon, lat) = decode(score as u64);
+                    RespValue::bulk_strings(&[lon.to_string().into_bytes(), lat.to_string().into_bytes()])
+                },
+                None => RespValue::NullArray,
+            }).collect())
+        });
+        conn.write_reply(&geopos_resp)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coordinates_round_trip_within_geohash_precision() {
+        for (lon, lat) in [(13.361389, 38.115556), (-122.4194, 37.7749), (0.0, 0.0), (179.999, -85.0)] {
+            let (decoded_lon, decoded_lat) = decode(encode(lon, lat));
+            // A 26-bit cell is 360 / 2^26 degrees wide in longitude, so the center is at most half of that away
+            assert!((decoded_lon - lon).abs() <= 360.0 / (1u64 << 27) as f64, "longitude {} -> {}", lon, decoded_lon);
+            assert!((decoded_lat - lat).abs() <= 180.0 / (1u64 << 27) as f64, "latitude {} -> {}", lat, decoded_lat);
+            assert!(distance(lon, lat, decoded_lon, decoded_lat) < 1.0);
+        }
+    }
+}
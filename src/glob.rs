@@ -0,0 +1,172 @@
+// Glob-style pattern matching, as used by KEYS, SCAN MATCH, PSUBSCRIBE and CONFIG GET. Same syntax as Redis's
+// stringmatchlen: https://github.com/redis/redis/blob/unstable/src/util.c
+//   *       any run of bytes, including none
+//   ?       any single byte
+//   [abc]   one of the listed bytes; [^abc] negates, [a-z] is a range (either way round), and \ escapes inside
+//   \x      the byte x itself, e.g. \* or \[
+// Rather than recursing at every `*` like Redis, this only remembers the last `*` and retries from there, which
+// can't blow up on patterns with many stars.
+
+fn match_byte(pattern: &[u8], pattern_idx: usize, byte: u8) -> Option<usize> {
+    /* Match byte against the pattern element (other than `*`) at pattern_idx, returning the index of the next element */
+    match pattern[pattern_idx] {
+        b'?' => Some(pattern_idx + 1),
+        b'[' => {
+            let mut idx = pattern_idx + 1;
+            let negate = pattern.get(idx) == Some(&b'^');
+            if negate {
+                idx += 1;
+            }
+            let mut matched = false;
+            // Like in Redis, a class missing its closing `]` runs to the end of the pattern
+            while let Some(&class_byte) = pattern.get(idx) {
+                match class_byte {
+                    b'\\' if idx + 1 < pattern.len() => {
+                        matched |= pattern[idx + 1] == byte;
+                        idx += 2;
+                    },
+                    b']' => {
+                        idx += 1;
+                        break;
+                    },
+                    _ if idx + 2 < pattern.len() && pattern[idx + 1] == b'-' => {
+                        let (start, end) = (class_byte.min(pattern[idx + 2]), class_byte.max(pattern[idx + 2]));
+                        matched |= (start..=end).contains(&byte);
+                        idx += 3;
+                    },
+                    _ => {
+                        matched |= class_byte == byte;
+                        idx += 1;
+                    },
+                }
+            }
+            (matched != negate).then_some(idx)
+        },
+        b'\\' if pattern_idx + 1 < pattern.len() => (pattern[pattern_idx + 1] == byte).then_some(pattern_idx + 2),
+        literal => (literal == byte).then_some(pattern_idx + 1),
+    }
+}
+
+pub fn glob_match(pattern: &[u8], subject: &[u8]) -> bool {
+    /* Whether all of subject matches pattern */
+    let (mut pattern_idx, mut subject_idx) = (0, 0);
+    // Where to resume after the last `*` seen: the pattern after it, and the subject position it has matched up to
+    let mut last_star = None;
+    loop {
+        if pattern.get(pattern_idx) == Some(&b'*') {
+            while pattern.get(pattern_idx) == Some(&b'*') {
+                pattern_idx += 1;
+            }
+            last_star = Some((pattern_idx, subject_idx));
+            continue;
+        }
+        let Some(&byte) = subject.get(subject_idx) else {
+            return pattern_idx == pattern.len();
+        };
+        if pattern_idx < pattern.len() {
+            if let Some(next_pattern_idx) = match_byte(pattern, pattern_idx, byte) {
+                pattern_idx = next_pattern_idx;
+                subject_idx += 1;
+                continue;
+            }
+        }
+        // Mismatch: let the last `*` swallow one more byte and try again, or fail if there wasn't one
+        match last_star {
+            Some((star_pattern_idx, star_subject_idx)) => {
+                pattern_idx = star_pattern_idx;
+                subject_idx = star_subject_idx + 1;
+                last_star = Some((star_pattern_idx, subject_idx));
+            },
+            None => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_like_redis_stringmatchlen() {
+        let cases: &[(&str, &str, bool)] = &[
+            // Literals and the empty pattern
+            ("", "", true),
+            ("", "a", false),
+            ("abc", "abc", true),
+            ("abc", "abcd", false),
+            ("abcd", "abc", false),
+            // ?
+            ("?", "a", true),
+            ("?", "", false),
+            ("a?c", "abc", true),
+            ("a?c", "ac", false),
+            // *, including several and nested between literals
+            ("*", "", true),
+            ("*", "anything", true),
+            ("a*", "a", true),
+            ("*a", "bca", true),
+            ("*a", "bcab", false),
+            ("a*b*c", "aXXbYYc", true),
+            ("a*b*c", "aXXbYY", false),
+            ("a**b", "ab", true),
+            ("*a*b*", "xxaxxbxx", true),
+            ("*ab", "aab", true),
+            ("*abc*abc", "abcabcXabc", true),
+            ("h*llo", "hello world", false),
+            ("h*o*d", "hello world", true),
+            ("user:*:name", "user:42:name", true),
+            ("user:*:name", "user:42:email", false),
+            // Character classes, ranges and negation
+            ("h[ae]llo", "hello", true),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-b]llo", "hbllo", true),
+            ("h[a-b]llo", "hcllo", false),
+            ("h[b-a]llo", "hallo", true),
+            ("[a-z0-9]", "7", true),
+            ("[a-z0-9]", "-", false),
+            ("[-a]", "-", true),
+            // Like in Redis, the `]` here ends a range rather than the class
+            ("[a-]", "-", false),
+            ("[a-]", "b", false),
+            ("[a-]", "^", true),
+            // `[]` is an empty class, which never matches
+            ("[]", "]", false),
+            ("[]a", "a", false),
+            ("*[0-9]", "key9", true),
+            ("*[0-9]", "key", false),
+            // Escapes, both outside and inside classes
+            ("\\*", "*", true),
+            ("\\*", "a", false),
+            ("\\?", "?", true),
+            ("a\\[b", "a[b", true),
+            ("[\\]]", "]", true),
+            ("[\\-a]", "-", true),
+            ("\\", "\\", true),
+            // An unterminated class runs to the end of the pattern
+            ("[abc", "b", true),
+            ("[abc", "d", false),
+            ("a[", "a", false),
+        ];
+        for (pattern, subject, expected) in cases {
+            assert_eq!(glob_match(pattern.as_bytes(), subject.as_bytes()), *expected, "{:?} against {:?}", pattern, subject);
+        }
+    }
+
+    #[test]
+    fn many_stars_dont_blow_up() {
+        let pattern = "a*".repeat(64) + "b";
+        let subject = "a".repeat(10_000);
+        assert!(!glob_match(pattern.as_bytes(), subject.as_bytes()));
+        assert!(glob_match(pattern.as_bytes(), (subject + "b").as_bytes()));
+    }
+
+    #[test]
+    fn matches_arbitrary_bytes() {
+        assert!(glob_match(b"\xff*\x00", b"\xff\x01\x02\x00"));
+        assert!(glob_match(b"[\x00-\x10]", b"\x05"));
+        assert!(!glob_match(b"?", b"\xff\xff"));
+    }
+}
@@ -0,0 +1,439 @@
+// The keyspace, split into shards that each have their own lock so that commands on unrelated keys don't wait on each other
+//
+// A key always lives in the shard its hash picks. Commands lock the shards of the keys they touch (lock_keys), or all
+// of them when they need the whole keyspace (lock_all), always in shard order so that two commands can't deadlock.
+// The guard they get back is used like the map itself; touching a key whose shard wasn't locked is a bug and panics.
+//
+// Shards are RwLocks: commands that only read a key can share its shard (read_keys). Readers can't remove the expired
+// keys they come across, so they queue them (queue_expired) for the write side to remove later (take_expired).
+//
+// SCAN goes over the shards in order, and over each shard's keys in order of their hash, which every shard keeps an
+// index of. Unlike the map's own bucket order, that doesn't change when keys are added or removed (or the map is
+// resized), so the cursor, which is the hash of the next key to visit, stays valid between calls.
+
+use bytes::Bytes;
+use std::collections::{BTreeSet, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Instant;
+use tokio::sync::watch;
+use crate::{CacheMap, KeyEntry};
+
+pub const DEFAULT_NUM_SHARDS: usize = 16;
+
+pub struct Keyspace {
+    shards: Vec<RwLock<Shard>>,
+    // Bumped on every write that blocking commands may be waiting for; see wait_for_writes
+    writes: watch::Sender<u64>,
+    // Expired keys found by readers, which only the write side can remove
+    expired_queue: Mutex<HashSet<Bytes>>,
+}
+
+pub struct KeyspaceGuard<'a> {
+    keyspace: &'a Keyspace,
+    // One slot per shard, holding the guards of the shards that were locked
+    shards: Vec<Option<RwLockWriteGuard<'a, Shard>>>,
+}
+
+pub struct KeyspaceReadGuard<'a> {
+    keyspace: &'a Keyspace,
+    shards: Vec<Option<RwLockReadGuard<'a, Shard>>>,
+}
+
+// The keys of one shard, along with the order SCAN visits them in
+#[derive(Default)]
+pub struct Shard {
+    entries: CacheMap,
+    // Every key of entries, by its hash
+    scan_order: BTreeSet<(u64, Bytes)>,
+}
+
+fn key_hash(key: &[u8]) -> u64 {
+    /* Picks the key's shard, and its place in that shard's scan order */
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Keyspace {
+    pub fn new(num_shards: usize) -> Self {
+        assert!(num_shards > 0, "The keyspace needs at least one shard");
+        Keyspace {
+            shards: (0..num_shards).map(|_| RwLock::new(Shard::default())).collect(),
+            writes: watch::channel(0).0,
+            expired_queue: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard_of(&self, key: &[u8]) -> usize {
+        (key_hash(key) % self.shards.len() as u64) as usize
+    }
+
+    pub fn lock_shard(&self, shard_idx: usize) -> RwLockWriteGuard<'_, Shard> {
+        /* Lock a single shard for writing, for jobs that go over the whole keyspace one shard at a time */
+        self.shards[shard_idx].write().unwrap_or_else(|err| {
+            panic!("Failed to lock keyspace shard for writing: {}!", err);
+        })
+    }
+
+    fn wanted_shards<K: AsRef<[u8]>>(&self, keys: impl IntoIterator<Item = K>) -> Vec<bool> {
+        let mut wanted = vec![false; self.shards.len()];
+        for key in keys {
+            wanted[self.shard_of(key.as_ref())] = true;
+        }
+        wanted
+    }
+
+    fn lock_shards(&self, wanted: &[bool]) -> KeyspaceGuard<'_> {
+        // In shard order, so that every command takes the locks it needs in the same order
+        let shards = wanted.iter().enumerate()
+            .map(|(shard_idx, wanted)| wanted.then(|| self.lock_shard(shard_idx)))
+            .collect();
+        KeyspaceGuard { keyspace: self, shards }
+    }
+
+    pub fn lock_all(&self) -> KeyspaceGuard<'_> {
+        self.lock_shards(&vec![true; self.shards.len()])
+    }
+
+    pub fn lock_keys<K: AsRef<[u8]>>(&self, keys: impl IntoIterator<Item = K>) -> KeyspaceGuard<'_> {
+        /* Lock the shards that the keys live in */
+        self.lock_shards(&self.wanted_shards(keys))
+    }
+
+    fn read_shard(&self, shard_idx: usize) -> RwLockReadGuard<'_, Shard> {
+        self.shards[shard_idx].read().unwrap_or_else(|err| {
+            panic!("Failed to lock keyspace shard for reading: {}!", err);
+        })
+    }
+
+    pub fn read_keys<K: AsRef<[u8]>>(&self, keys: impl IntoIterator<Item = K>) -> KeyspaceReadGuard<'_> {
+        /* Lock the shards that the keys live in for reading, which other readers of those shards can do at the same time */
+        let shards = self.wanted_shards(keys).into_iter().enumerate()
+            .map(|(shard_idx, wanted)| wanted.then(|| self.read_shard(shard_idx)))
+            .collect();
+        KeyspaceReadGuard { keyspace: self, shards }
+    }
+
+    pub fn scan(&self, cursor: u64, count: usize) -> (Vec<Bytes>, u64) {
+        /*
+        Visit about count keys from cursor on, returning them and the cursor to carry on from (0 once all were visited)
+        A key's hash picks its shard, so the cursor tells which shard it's in. Once a shard runs out, the next one is
+        started from the smallest hash its keys can have, which is its own index. Only one shard is locked at a time.
+        */
+        let num_shards = self.shards.len() as u64;
+        let (mut shard_idx, mut from) = (cursor % num_shards, cursor);
+        let mut keys = Vec::new();
+        loop {
+            let (shard_keys, next) = self.read_shard(shard_idx as usize).scan(from, count - keys.len());
+            keys.extend(shard_keys);
+            match next {
+                Some(next) => return (keys, next),
+                None if shard_idx + 1 == num_shards => return (keys, 0),
+                None => {
+                    shard_idx += 1;
+                    from = shard_idx;
+                    if keys.len() >= count {
+                        return (keys, from);
+                    }
+                },
+            }
+        }
+    }
+
+    fn lock_expired_queue(&self) -> MutexGuard<'_, HashSet<Bytes>> {
+        self.expired_queue.lock().unwrap_or_else(|err| {
+            panic!("Failed to lock expired key queue mutex: {}!", err);
+        })
+    }
+
+    pub fn queue_expired(&self, key: &[u8]) {
+        /* Have the write side remove an expired key (or a hash's expired fields) that a reader came across */
+        self.lock_expired_queue().insert(Bytes::copy_from_slice(key));
+    }
+
+    pub fn take_expired(&self) -> HashSet<Bytes> {
+        std::mem::take(&mut *self.lock_expired_queue())
+    }
+
+    pub fn notify_writes(&self) {
+        /* Wake up the blocking commands waiting in wait_for_writes, so that they check their keys again */
+        self.writes.send_modify(|write_generation| *write_generation += 1);
+    }
+
+    pub fn write_generation(&self) -> u64 {
+        /* Read by a blocking command while it still holds the shards it found nothing in, and passed to wait_for_writes */
+        *self.writes.borrow()
+    }
+
+    pub async fn wait_for_writes(&self, seen_generation: u64, deadline: Option<Instant>) -> bool {
+        /*
+        Wait until a writer calls notify_writes after seen_generation was read, or until the deadline (if any) passes
+        Used by blocking commands, which check their keys again after every wakeup. Returns whether the deadline passed.
+        Since seen_generation is read before the shards are released, a write made in between still wakes us up.
+        */
+        let mut writes = self.writes.subscribe();
+        let wait = async move {
+            while *writes.borrow_and_update() == seen_generation {
+                // The sender lives as long as the keyspace, so this never fails while we borrow it
+                let _ = writes.changed().await;
+            }
+        };
+        match deadline {
+            None => {
+                wait.await;
+                false
+            },
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), wait).await.is_err(),
+        }
+    }
+}
+
+impl Shard {
+    pub fn get(&self, key: &[u8]) -> Option<&KeyEntry> {
+        self.entries.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut KeyEntry> {
+        self.entries.get_mut(key)
+    }
+
+    pub fn insert(&mut self, key: Bytes, entry: KeyEntry) -> Option<KeyEntry> {
+        let replaced = self.entries.insert(key.clone(), entry);
+        if replaced.is_none() {
+            self.scan_order.insert((key_hash(&key), key));
+        }
+        replaced
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<KeyEntry> {
+        let (key, entry) = self.entries.remove_entry(key)?;
+        self.scan_order.remove(&(key_hash(&key), key));
+        Some(entry)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&Bytes, &mut KeyEntry) -> bool) {
+        let scan_order = &mut self.scan_order;
+        self.entries.retain(|key, entry| {
+            let kept = keep(key, entry);
+            if !kept {
+                scan_order.remove(&(key_hash(key), key.clone()));
+            }
+            kept
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.scan_order.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &KeyEntry)> {
+        self.entries.iter()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Bytes> {
+        self.entries.keys()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.capacity()
+    }
+
+    pub fn shrink_to(&mut self, min_capacity: usize) {
+        self.entries.shrink_to(min_capacity);
+    }
+
+    fn scan(&self, from: u64, count: usize) -> (Vec<Bytes>, Option<u64>) {
+        /*
+        Visit count keys whose hash is from or more, in hash order, returning them and the hash of the next key, if any
+        Keys that share a hash all have to be visited in the same call, since the cursor can't point between them.
+        */
+        let mut keys = Vec::new();
+        let mut last_hash = None;
+        for (hash, key) in self.scan_order.range((from, Bytes::new())..) {
+            if keys.len() >= count && last_hash != Some(*hash) {
+                return (keys, Some(*hash));
+            }
+            keys.push(key.clone());
+            last_hash = Some(*hash);
+        }
+        (keys, None)
+    }
+}
+
+impl KeyspaceReadGuard<'_> {
+    pub fn get(&self, key: &[u8]) -> Option<&KeyEntry> {
+        self.shards[self.keyspace.shard_of(key)].as_deref()
+            .unwrap_or_else(|| panic!("Accessed key {:?} without locking its shard", String::from_utf8_lossy(key)))
+            .get(key)
+    }
+}
+
+impl KeyspaceGuard<'_> {
+    fn shard(&self, key: &[u8]) -> &Shard {
+        self.shards[self.keyspace.shard_of(key)].as_deref()
+            .unwrap_or_else(|| panic!("Accessed key {:?} without locking its shard", String::from_utf8_lossy(key)))
+    }
+
+    fn shard_mut(&mut self, key: &[u8]) -> &mut Shard {
+        self.shards[self.keyspace.shard_of(key)].as_deref_mut()
+            .unwrap_or_else(|| panic!("Accessed key {:?} without locking its shard", String::from_utf8_lossy(key)))
+    }
+
+    fn locked_shards(&self) -> impl Iterator<Item = &Shard> {
+        self.shards.iter().flatten().map(|shard| &**shard)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&KeyEntry> {
+        self.shard(key).get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut KeyEntry> {
+        self.shard_mut(key).get_mut(key)
+    }
+
+    pub fn insert(&mut self, key: Bytes, entry: KeyEntry) -> Option<KeyEntry> {
+        self.shard_mut(&key).insert(key, entry)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<KeyEntry> {
+        self.shard_mut(key).remove(key)
+    }
+
+    // The methods below only see the locked shards, which is the whole keyspace for a guard from lock_all
+
+    pub fn len(&self) -> usize {
+        self.locked_shards().map(|shard| shard.len()).sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &KeyEntry)> {
+        self.locked_shards().flat_map(|shard| shard.iter())
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Bytes> {
+        self.locked_shards().flat_map(|shard| shard.keys())
+    }
+
+    pub fn swap(&mut self, other: &mut KeyspaceGuard<'_>) {
+        /*
+        Swap the contents of two (fully locked) keyspaces, for SWAPDB
+        Every keyspace has the same number of shards, so a key's shard is the same in both and shards can be swapped whole.
+        */
+        assert_eq!(self.shards.len(), other.shards.len(), "Can't swap keyspaces with different numbers of shards");
+        for (shard, other_shard) in self.shards.iter_mut().zip(other.shards.iter_mut()) {
+            match (shard, other_shard) {
+                (Some(shard), Some(other_shard)) => std::mem::swap(&mut **shard, &mut **other_shard),
+                _ => panic!("Swapped keyspaces that weren't fully locked"),
+            }
+        }
+    }
+
+    pub fn take_all(&mut self) -> Vec<Shard> {
+        /* Empty the (fully locked) keyspace, handing back what its shards held so the caller decides where it's freed */
+        self.shards.iter_mut().flatten().map(|shard| std::mem::take(&mut **shard)).collect()
+    }
+
+    pub fn replace_all(&mut self, entries: CacheMap) {
+        /* Swap the contents of the (fully locked) keyspace for entries, e.g. a snapshot that was just loaded */
+        for shard in self.shards.iter_mut().flatten() {
+            shard.clear();
+        }
+        for (key, entry) in entries {
+            self.insert(key, entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RedisValue;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn shards_of_unrelated_keys_are_locked_independently() {
+        let keyspace = Arc::new(Keyspace::new(DEFAULT_NUM_SHARDS));
+        let key = b"key".as_slice();
+        let other_key = (0..).map(|idx| format!("other{}", idx))
+            .find(|other_key| keyspace.shard_of(other_key.as_bytes()) != keyspace.shard_of(key))
+            .unwrap();
+        let mut guard = keyspace.lock_keys([key]);
+        guard.insert(Bytes::from_static(b"key"), KeyEntry::new(RedisValue::String(Bytes::from_static(b"v")), None));
+
+        // Another thread can write a key in another shard while this one is still locked
+        std::thread::spawn({
+            let keyspace = Arc::clone(&keyspace);
+            move || {
+                let mut other_guard = keyspace.lock_keys([other_key.as_bytes()]);
+                other_guard.insert(Bytes::from(other_key), KeyEntry::new(RedisValue::String(Bytes::from_static(b"v")), None));
+            }
+        }).join().unwrap();
+        assert_eq!(guard.len(), 1);
+        drop(guard);
+        assert_eq!(keyspace.lock_all().len(), 2);
+
+        // A blocked reader wakes up for writes made after it released its shards, even on a single-threaded runtime
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+        let guard = keyspace.lock_keys([key]);
+        let seen_generation = keyspace.write_generation();
+        drop(guard);
+        let writer = std::thread::spawn({
+            let keyspace = Arc::clone(&keyspace);
+            move || {
+                keyspace.lock_keys([b"key".as_slice()]).remove(b"key");
+                keyspace.notify_writes();
+            }
+        });
+        let timed_out = runtime.block_on(keyspace.wait_for_writes(seen_generation, Some(Instant::now() + Duration::from_secs(5))));
+        assert!(!timed_out);
+        assert!(keyspace.lock_keys([key]).get(key).is_none());
+        writer.join().unwrap();
+
+        // Without any writes, it gives up at the deadline
+        let seen_generation = keyspace.write_generation();
+        let timed_out = runtime.block_on(keyspace.wait_for_writes(seen_generation, Some(Instant::now() + Duration::from_millis(20))));
+        assert!(timed_out);
+    }
+
+    #[test]
+    fn scan_visits_every_key_that_stays_however_the_keyspace_changes() {
+        let keyspace = Keyspace::new(DEFAULT_NUM_SHARDS);
+        let insert = |key: String| {
+            let key = Bytes::from(key);
+            keyspace.lock_keys([&key]).insert(key, KeyEntry::new(RedisValue::String(Bytes::from_static(b"v")), None));
+        };
+        for idx in 0..1000 {
+            insert(format!("key{}", idx));
+        }
+        let mut visited = HashSet::new();
+        let (mut cursor, mut num_calls) = (0, 0);
+        loop {
+            let (keys, next_cursor) = keyspace.scan(cursor, 10);
+            assert!(keys.len() <= 11, "visited {} keys in one call", keys.len());
+            visited.extend(keys);
+            // Keys come and go between calls, growing and shrinking the shards
+            insert(format!("new{}", num_calls));
+            keyspace.lock_keys([format!("key{}", num_calls * 2)]).remove(format!("key{}", num_calls * 2).as_bytes());
+            num_calls += 1;
+            if next_cursor == 0 {
+                break;
+            }
+            cursor = next_cursor;
+        }
+        assert!(num_calls >= 50, "finished in {} calls", num_calls);
+        for idx in (1..1000).step_by(2) {
+            assert!(visited.contains(format!("key{}", idx).as_bytes()), "key{} wasn't visited", idx);
+        }
+    }
+}
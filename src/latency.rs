@@ -0,0 +1,137 @@
+// The latency monitor: spikes of at least latency-monitor-threshold milliseconds, per event class, for LATENCY LATEST
+//
+// Like Redis's, it keeps the latest HISTORY_LEN samples of each event along with the worst one ever seen, and samples
+// of the same event within the same second are merged into one, keeping the worst. A threshold of 0 turns it off.
+//
+// The events are `command` (the time spent running a command, like the slow log measures it), `expire-cycle` (one run
+// of active expiry) and `save` (writing a snapshot, which stands in for Redis's `fork` since there's no fork here).
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_THRESHOLD_MS: u64 = 0;
+const HISTORY_LEN: usize = 160;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LatencySample {
+    // Unix time in seconds
+    pub time: u64,
+    pub latency_ms: u64,
+}
+
+#[derive(Default)]
+struct LatencyEvent {
+    // Oldest first
+    samples: VecDeque<LatencySample>,
+    max_ms: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LatestSample {
+    pub event: String,
+    pub latest: LatencySample,
+    pub max_ms: u64,
+}
+
+pub struct LatencyMonitor {
+    // Keyed by event name, so LATENCY LATEST lists them in a stable order
+    events: Mutex<BTreeMap<String, LatencyEvent>>,
+    pub threshold_ms: AtomicU64,
+}
+
+impl LatencyMonitor {
+    pub fn new(threshold_ms: u64) -> Self {
+        LatencyMonitor { events: Mutex::new(BTreeMap::new()), threshold_ms: AtomicU64::new(threshold_ms) }
+    }
+
+    fn lock_events(&self) -> MutexGuard<'_, BTreeMap<String, LatencyEvent>> {
+        self.events.lock().unwrap_or_else(|err| {
+            panic!("Failed to lock latency monitor mutex: {}!", err);
+        })
+    }
+
+    pub fn add_sample_if_needed(&self, event: &str, duration: Duration) {
+        /* Record the event's latency if it's a spike, i.e. at least latency-monitor-threshold (when that's on) */
+        let threshold_ms = self.threshold_ms.load(Ordering::Relaxed);
+        let latency_ms = duration.as_millis() as u64;
+        if threshold_ms == 0 || latency_ms < threshold_ms {
+            return;
+        }
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut events = self.lock_events();
+        let latency_event = events.entry(event.to_string()).or_default();
+        latency_event.max_ms = latency_event.max_ms.max(latency_ms);
+        match latency_event.samples.back_mut() {
+            Some(last) if last.time == time => last.latency_ms = last.latency_ms.max(latency_ms),
+            _ => {
+                latency_event.samples.push_back(LatencySample { time, latency_ms });
+                if latency_event.samples.len() > HISTORY_LEN {
+                    latency_event.samples.pop_front();
+                }
+            },
+        }
+    }
+
+    pub fn history(&self, event: &str) -> Vec<LatencySample> {
+        /* The event's samples, oldest first; none for an event that never spiked */
+        self.lock_events().get(event).map_or_else(Vec::new, |latency_event| latency_event.samples.iter().copied().collect())
+    }
+
+    pub fn latest(&self) -> Vec<LatestSample> {
+        self.lock_events().iter()
+            .filter_map(|(event, latency_event)| latency_event.samples.back().map(|latest| LatestSample {
+                event: event.clone(),
+                latest: *latest,
+                max_ms: latency_event.max_ms,
+            }))
+            .collect()
+    }
+
+    pub fn reset(&self, events: &[String]) -> usize {
+        /* Forget the given events, or all of them if none are given, returning how many were forgotten */
+        let mut all_events = self.lock_events();
+        if events.is_empty() {
+            let num_events = all_events.len();
+            all_events.clear();
+            return num_events;
+        }
+        events.iter().filter(|event| all_events.remove(event.as_str()).is_some()).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_spikes_per_event_merging_those_in_the_same_second() {
+        let monitor = LatencyMonitor::new(DEFAULT_THRESHOLD_MS);
+        monitor.add_sample_if_needed("command", Duration::from_secs(1));
+        assert!(monitor.latest().is_empty());
+
+        monitor.threshold_ms.store(10, Ordering::Relaxed);
+        monitor.add_sample_if_needed("command", Duration::from_millis(9));
+        monitor.add_sample_if_needed("command", Duration::from_millis(20));
+        monitor.add_sample_if_needed("command", Duration::from_millis(15));
+        monitor.add_sample_if_needed("save", Duration::from_millis(30));
+        let history = monitor.history("command");
+        // Unless the clock ticked over between the samples, they're merged into one
+        assert!(history.len() <= 2, "{:?}", history);
+        assert_eq!(history.iter().map(|sample| sample.latency_ms).max(), Some(20));
+        assert!(monitor.history("expire-cycle").is_empty());
+        let latest = monitor.latest();
+        assert_eq!(latest.iter().map(|sample| (sample.event.as_str(), sample.max_ms)).collect::<Vec<(&str, u64)>>(), vec![("command", 20), ("save", 30)]);
+
+        for _ in 1..HISTORY_LEN {
+            monitor.lock_events().get_mut("save").unwrap().samples.push_back(LatencySample { time: 0, latency_ms: 10 });
+        }
+        monitor.add_sample_if_needed("save", Duration::from_millis(10));
+        assert_eq!(monitor.history("save").len(), HISTORY_LEN);
+
+        assert_eq!(monitor.reset(&["save".to_string(), "fork".to_string()]), 1);
+        assert_eq!(monitor.reset(&[]), 1);
+        assert!(monitor.latest().is_empty());
+    }
+}
@@ -0,0 +1,95 @@
+// Lazy freeing: values that take a while to free (big collections removed by UNLINK, or every key of a database
+// flushed with FLUSHDB ASYNC) are handed to one long-lived background thread to drop, like Redis's lazyfree bio
+// thread, instead of being dropped by the command that removed them while it holds the keyspace's locks.
+//
+// The thread is started on the first free and stops once the server state (and with it the sending end) is dropped.
+// Objects handed over but not freed yet are counted, and reported as lazyfree_pending_objects in INFO memory.
+
+use log::warn;
+use std::any::Any;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, OnceLock};
+use std::thread;
+
+// Something to drop, along with how many objects it counts for
+type Garbage = (Box<dyn Any + Send>, u64);
+
+pub struct LazyFree {
+    sender: OnceLock<mpsc::Sender<Garbage>>,
+    pending_objects: Arc<AtomicU64>,
+}
+
+impl Default for LazyFree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LazyFree {
+    pub fn new() -> Self {
+        LazyFree { sender: OnceLock::new(), pending_objects: Arc::new(AtomicU64::new(0)) }
+    }
+
+    pub fn free<T: Send + 'static>(&self, garbage: T, num_objects: u64) {
+        /* Have the lazyfree thread drop garbage, counting its objects as pending until it has */
+        self.pending_objects.fetch_add(num_objects, Ordering::Relaxed);
+        let sender = self.sender.get_or_init(|| self.spawn_worker());
+        // Without a thread to send it to (it couldn't be started), the garbage is freed right here instead
+        if let Err(mpsc::SendError((garbage, num_objects))) = sender.send((Box::new(garbage), num_objects)) {
+            drop(garbage);
+            self.pending_objects.fetch_sub(num_objects, Ordering::Relaxed);
+        }
+    }
+
+    fn spawn_worker(&self) -> mpsc::Sender<Garbage> {
+        let (sender, receiver) = mpsc::channel::<Garbage>();
+        let pending_objects = Arc::clone(&self.pending_objects);
+        let spawned = thread::Builder::new().name("lazyfree".to_string()).spawn(move || {
+            for (garbage, num_objects) in receiver {
+                drop(garbage);
+                pending_objects.fetch_sub(num_objects, Ordering::Relaxed);
+            }
+        });
+        if let Err(err) = spawned {
+            warn!("Failed to start the lazyfree thread, freeing in the foreground instead: {}", err);
+        }
+        sender
+    }
+
+    pub fn pending_objects(&self) -> u64 {
+        self.pending_objects.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    // Records which thread dropped it
+    struct DropRecorder(Arc<Mutex<Vec<thread::ThreadId>>>);
+
+    impl Drop for DropRecorder {
+        fn drop(&mut self) {
+            self.0.lock().unwrap().push(thread::current().id());
+        }
+    }
+
+    #[test]
+    fn one_background_thread_frees_everything_and_counts_what_is_pending() {
+        let lazyfree = LazyFree::new();
+        let dropped_by = Arc::new(Mutex::new(Vec::new()));
+        for _ in 0..3 {
+            lazyfree.free(vec![DropRecorder(Arc::clone(&dropped_by)), DropRecorder(Arc::clone(&dropped_by))], 2);
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while lazyfree.pending_objects() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(lazyfree.pending_objects(), 0);
+        let dropped_by = dropped_by.lock().unwrap();
+        assert_eq!(dropped_by.len(), 6);
+        assert!(dropped_by.iter().all(|thread_id| *thread_id == dropped_by[0] && *thread_id != thread::current().id()));
+    }
+}
@@ -0,0 +1,6844 @@
+mod acl;
+mod clients;
+mod collections;
+pub mod daemon;
+pub mod config;
+mod geo;
+mod glob;
+mod hyperloglog;
+mod keyspace;
+mod latency;
+mod lazyfree;
+pub mod logging;
+mod metrics;
+mod pubsub;
+mod rdb;
+mod slowlog;
+pub mod resp;
+mod sorted_set;
+mod streams;
+mod tcp;
+
+use bytes::Bytes;
+use log::{info,debug,error,trace,warn};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::Entry;
+use std::io::{self,ErrorKind,IoSlice};
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::task::Poll;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use acl::{Acl, AclLog, DenialReason};
+use clients::{ClientInfo, ClientKillFilter, ClientPause, ClientRegistry};
+use collections::{EncodingLimits, ListEnd, RedisHash, RedisList, RedisSet};
+use config::{Config, SavePoint};
+use glob::glob_match;
+use hyperloglog::HyperLogLog;
+use keyspace::{Keyspace, KeyspaceGuard, KeyspaceReadGuard};
+use latency::LatencyMonitor;
+use lazyfree::LazyFree;
+use metrics::ServerStats;
+use pubsub::{PubSub, Subscriber};
+use resp::RespValue;
+use slowlog::Slowlog;
+use sorted_set::SortedSet;
+use std::ops::Bound;
+use streams::RedisStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Notify;
+
+
+const CHUNK_SIZE: usize = 1024;
+// Below this many buckets the keyspace map is never shrunk, like Redis's DICT_HT_INITIAL_SIZE
+const MIN_KEYSPACE_CAPACITY: usize = 16;
+// UNLINK frees values made of more than this many allocations on a background thread, like Redis's LAZYFREE_THRESHOLD
+const LAZYFREE_THRESHOLD: usize = 64;
+const PING_FRAME: &[u8] = b"*1\r\n$4\r\nPING\r\n";
+const PONG_REPLY: &[u8] = b"+PONG\r\n";
+// Pending replies are written out once they add up to this much, even in the middle of a pipeline
+const REPLY_BUFFER_LIMIT: usize = 64 * 1024;
+// Replies at least this large (typically big bulk strings) are written from their own buffer with a vectored write
+// rather than copied into the one the smaller replies share
+const VECTORED_REPLY_LEN: usize = 16 * 1024;
+const RESP_DELIMITER: &str = "\r\n";
+// Reported to clients by HELLO, INFO and LOLWUT
+const REDIS_VERSION: &str = env!("CARGO_PKG_VERSION");
+// How many arguments the unknown command error quotes back to the client
+const MAX_UNKNOWN_CMD_ARGS_SHOWN: usize = 3;
+const WRONGTYPE_ERR: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+// Sent to clients that protected mode refuses, before closing their connection; Redis's own wording
+const PROTECTED_MODE_ERR: &str = concat!(
+    "DENIED Redis is running in protected mode because protected mode is enabled and no password is set for the ",
+    "default user. In this mode connections are only accepted from the loopback interface. If you want to connect ",
+    "from external computers to Redis you may adopt one of the following solutions: 1) Just disable protected mode ",
+    "sending the command 'CONFIG SET protected-mode no' from the loopback interface by connecting to Redis from the ",
+    "same host the server is running, however MAKE SURE Redis is not publicly accessible from internet if you do so. ",
+    "Use CONFIG REWRITE to make this change permanent. 2) Alternatively you can just disable the protected mode by ",
+    "editing the Redis configuration file, and setting the protected mode option to 'no', and then restarting the ",
+    "server. 3) If you started the server manually just for testing, restart it with the '--protected-mode no' ",
+    "option. 4) Setup a bind address or an authentication password. NOTE: You only need to do one of the above things ",
+    "in order for the server to start accepting connections from the outside.",
+);
+
+#[derive(Clone, Debug)]
+enum RedisValue {
+    // Strings are stored as raw bytes so that bitmap commands (BITOP, etc.) can produce non-UTF-8 data
+    String(Bytes),
+    HyperLogLog(HyperLogLog),
+    Stream(RedisStream),
+    List(RedisList),
+    Hash(RedisHash),
+    Set(RedisSet),
+    SortedSet(SortedSet),
+}
+
+impl RedisValue {
+    fn type_name(&self) -> &'static str {
+        /* What TYPE reports for the value; HLLs are strings as far as clients can tell */
+        match self {
+            RedisValue::String(_) | RedisValue::HyperLogLog(_) => "string",
+            RedisValue::Stream(_) => "stream",
+            RedisValue::List(_) => "list",
+            RedisValue::Hash(_) => "hash",
+            RedisValue::Set(_) => "set",
+            RedisValue::SortedSet(_) => "zset",
+        }
+    }
+
+    fn as_string(&self) -> Option<Bytes> {
+        /* The value as the string commands (GET, GETRANGE, ...) see it; HLLs read as Redis's dense HLL string */
+        match self {
+            RedisValue::String(val) => Some(val.clone()),
+            RedisValue::HyperLogLog(hll) => Some(Bytes::from(hll.to_dense())),
+            _ => None,
+        }
+    }
+
+    fn encoding(&self) -> &'static str {
+        /* Name of the internal representation, using the same names Redis reports */
+        match self {
+            // Redis keeps strings that look like integers as actual integers, and inlines short strings
+            RedisValue::String(val) if val.len() <= 20 && parse_arg::<i64>(val).is_some() => "int",
+            RedisValue::String(val) if val.len() <= 44 => "embstr",
+            RedisValue::String(_) => "raw",
+            // HLLs are strings in Redis; only the dense representation is implemented here
+            RedisValue::HyperLogLog(_) => "raw",
+            RedisValue::Stream(_) => "stream",
+            RedisValue::List(list) => list.encoding(),
+            RedisValue::Hash(hash) => hash.encoding(),
+            RedisValue::Set(set) => set.encoding(),
+            RedisValue::SortedSet(_) => "skiplist",
+        }
+    }
+
+    fn free_effort(&self) -> usize {
+        /* Roughly how many allocations freeing the value takes: one per element of a collection */
+        match self {
+            RedisValue::String(_) | RedisValue::HyperLogLog(_) => 1,
+            RedisValue::Stream(redis_stream) => redis_stream.len(),
+            RedisValue::List(list) => list.len(),
+            RedisValue::Hash(hash) => hash.len(),
+            RedisValue::Set(set) => set.len(),
+            RedisValue::SortedSet(zset) => zset.len(),
+        }
+    }
+
+    fn serialized_len(&self) -> usize {
+        /* Approximate number of bytes the value would take up when serialized */
+        match self {
+            RedisValue::String(val) => val.len(),
+            RedisValue::HyperLogLog(hll) => hll.serialized_len(),
+            RedisValue::Stream(redis_stream) => redis_stream
+                .range(Bound::Unbounded, Bound::Unbounded, None)
+                .iter()
+                // Each ID is two u64s
+                .map(|(_, fields)| 16 + fields.iter().map(|(field, val)| field.len() + val.len()).sum::<usize>())
+                .sum(),
+            RedisValue::List(list) => list.iter().map(|elem| elem.len()).sum(),
+            RedisValue::Hash(hash) => hash.iter().map(|(field, val)| field.len() + val.len()).sum(),
+            RedisValue::Set(set) => set.members().iter().map(|member| member.len()).sum(),
+            // Each score is an f64
+            RedisValue::SortedSet(zset) => zset.iter().map(|(member, _)| member.len() + 8).sum(),
+        }
+    }
+}
+
+// Walks the options that follow a command's positional arguments, e.g. SET's `NX EX 10`, in whatever order they're given
+struct OptionParser<'a> {
+    args: std::slice::Iter<'a, Bytes>,
+}
+
+impl<'a> OptionParser<'a> {
+    fn new(args: &'a [Bytes]) -> Self {
+        OptionParser { args: args.iter() }
+    }
+
+    fn next_option(&mut self) -> Option<String> {
+        /* The next option's name, uppercased so that matching on it is case-insensitive */
+        self.args.next().map(|arg| arg_to_string(arg).to_uppercase())
+    }
+
+    fn value(&mut self) -> Result<&'a [u8], String> {
+        /* The argument of the option just returned by next_option; a missing one is a syntax error */
+        self.args.next().map(|arg| arg.as_ref()).ok_or_else(|| "ERR syntax error".to_string())
+    }
+
+    fn int_value<T: FromStr>(&mut self) -> Result<T, String> {
+        parse_arg::<T>(self.value()?).ok_or_else(|| "ERR value is not an integer or out of range".to_string())
+    }
+}
+
+// Options given to SET; at most one of EX, PX, EXAT, PXAT and KEEPTTL
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct SetOptions {
+    nx: bool,
+    xx: bool,
+    get: bool,
+    keep_ttl: bool,
+    // Unix time in milliseconds
+    expiry_ts: Option<u128>,
+}
+
+impl SetOptions {
+    fn parse(args: &[Bytes], now_ms: u128) -> Result<SetOptions, String> {
+        /* Parse `[NX|XX] [GET] [EX seconds|PX milliseconds|EXAT unix-time-seconds|PXAT unix-time-milliseconds|KEEPTTL]` */
+        let mut opts = SetOptions::default();
+        let mut options = OptionParser::new(args);
+        while let Some(option) = options.next_option() {
+            let has_expiry = opts.keep_ttl || opts.expiry_ts.is_some();
+            let (unit_ms, is_absolute) = match option.as_str() {
+                "NX" if !opts.xx => {
+                    opts.nx = true;
+                    continue;
+                },
+                "XX" if !opts.nx => {
+                    opts.xx = true;
+                    continue;
+                },
+                "GET" => {
+                    opts.get = true;
+                    continue;
+                },
+                "KEEPTTL" if !has_expiry => {
+                    opts.keep_ttl = true;
+                    continue;
+                },
+                "EX" if !has_expiry => (1000, false),
+                "PX" if !has_expiry => (1, false),
+                "EXAT" if !has_expiry => (1000, true),
+                "PXAT" if !has_expiry => (1, true),
+                _ => return Err("ERR syntax error".to_string()),
+            };
+            let when = options.int_value::<i64>()?;
+            let when_ms = when as i128 * unit_ms + if is_absolute { 0 } else { now_ms as i128 };
+            if when <= 0 || when_ms > i64::MAX as i128 {
+                return Err("ERR invalid expire time in 'set' command".to_string());
+            }
+            opts.expiry_ts = Some(when_ms as u128);
+        }
+        Ok(opts)
+    }
+}
+
+// Conditions given to the EXPIRE family of commands; XX can be combined with GT or LT
+#[derive(Clone, Copy, Debug, Default)]
+struct ExpireOptions {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+}
+
+impl ExpireOptions {
+    fn parse(args: &[Bytes]) -> Result<ExpireOptions, String> {
+        /* Parse the flags that follow the key and time arguments */
+        let mut opts = ExpireOptions::default();
+        let mut options = OptionParser::new(args);
+        while let Some(option) = options.next_option() {
+            match option.as_str() {
+                "NX" => opts.nx = true,
+                "XX" => opts.xx = true,
+                "GT" => opts.gt = true,
+                "LT" => opts.lt = true,
+                other_option => return Err(format!("ERR Unsupported option {}", other_option)),
+            }
+        }
+        if opts.nx && (opts.xx || opts.gt || opts.lt) {
+            return Err("ERR NX and XX, GT or LT options at the same time are not compatible".to_string());
+        }
+        if opts.gt && opts.lt {
+            return Err("ERR GT and LT options at the same time are not compatible".to_string());
+        }
+        Ok(opts)
+    }
+
+    fn allows(&self, curr_expiry_ts: Option<u128>, new_expiry_ts: i128) -> bool {
+        /*
+        Whether the conditions allow replacing the key's current expiry with the new one
+        A key without an expiry has an infinite TTL: GT never applies to it, and LT always does.
+        */
+        match curr_expiry_ts {
+            Some(_) if self.nx => false,
+            None if self.xx || self.gt => false,
+            Some(curr_expiry_ts) if self.gt => new_expiry_ts > curr_expiry_ts as i128,
+            Some(curr_expiry_ts) if self.lt => new_expiry_ts < curr_expiry_ts as i128,
+            _ => true,
+        }
+    }
+}
+
+// Which combination of sets SINTER, SUNION, SDIFF and their STORE variants compute
+#[derive(Clone, Copy, Debug)]
+enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+// A key's value and the metadata kept alongside it
+#[derive(Clone, Debug)]
+struct KeyEntry {
+    value: RedisValue,
+    // Unix time in ms at which the key expires, if it has a TTL
+    expiry_ts: Option<u128>,
+}
+
+impl KeyEntry {
+    fn new(value: RedisValue, expiry_ts: Option<u128>) -> Self {
+        KeyEntry { value, expiry_ts }
+    }
+}
+
+type CacheMap = HashMap<Bytes, KeyEntry>;
+// A maintenance job run on every tick of server_cron_loop
+type CronJob = Box<dyn Fn(&ServerState) + Send + Sync>;
+
+// A key that connections are watching: its version, bumped by every write to it, and how many connections watch it
+struct WatchedKey {
+    version: u64,
+    num_watchers: usize,
+}
+
+// State shared by every connection; handlers get a reference to it from the dispatch layer
+struct ServerState {
+    // The logical databases that SELECT picks from, each its own keyspace. A keyspace is sharded so that commands on
+    // unrelated keys don't contend; blocking commands (e.g. XREAD BLOCK) wait on it for writes, which commands that
+    // add data announce with notify_writes
+    dbs: Vec<Keyspace>,
+    // Set while the snapshot the server started with is loaded, in the background; until then, commands without the
+    // loading flag get -LOADING
+    loading: AtomicBool,
+    // Set with --supervised systemd, to tell systemd once the server is ready; see notify_ready
+    supervised: bool,
+    // Toggled by DEBUG SET-ACTIVE-EXPIRE so tests can observe passive-only expiry deterministically
+    active_expire_enabled: AtomicBool,
+    next_client_id: AtomicU64,
+    // Every open connection, for CLIENT LIST
+    clients: ClientRegistry,
+    // Commands that ran for longer than slowlog-log-slower-than; see run_command
+    slowlog: Slowlog,
+    // Spikes in command, active expiry and snapshot latency, for LATENCY; see latency.rs
+    latency_monitor: LatencyMonitor,
+    // The dispatch table: command_table() with the config's rename-command directives applied
+    commands: CommandRegistry,
+    // Connections that ran MONITOR, by client ID, each fed through its own output queue; see feed_monitors
+    monitors: RwLock<HashMap<u64, Arc<Subscriber>>>,
+    start_time: Instant,
+    encoding_limits: EncodingLimits,
+    pubsub: PubSub,
+    // Where DEBUG RELOAD and the save points save snapshots, and where the dataset is loaded from at startup
+    rdb_path: PathBuf,
+    // Set with --save or CONFIG SET save
+    save_points: Mutex<Vec<SavePoint>>,
+    // Where CONFIG REWRITE saves the parameters, if the config was loaded from a file
+    config_file: Option<PathBuf>,
+    // Counters for INFO and the Prometheus endpoint; each server keeps its own, so embedded servers don't mix theirs
+    stats: ServerStats,
+    // Drops what UNLINK and FLUSHDB ASYNC removed on a background thread
+    lazyfree: LazyFree,
+    // Writes since the last snapshot, and when that was; checked against the save points by the auto-save cron job
+    dirty: AtomicU64,
+    last_save: Mutex<Instant>,
+    // Keys (by database) that at least one connection is watching, with their versions; see mark_modified
+    watched_keys: Mutex<HashMap<(usize, Bytes), WatchedKey>>,
+    // How many keys are in watched_keys, so that writes can skip its lock while nobody is watching anything
+    num_watched_keys: AtomicUsize,
+    next_key_version: AtomicU64,
+    // Set with --replica-read-only or CONFIG SET replica-read-only; there's no replication, so this is what makes the server a read-only replica
+    replica_read_only: AtomicBool,
+    // Set with --protected-mode or CONFIG SET protected-mode; only matters without an explicit bind, see is_protected_from
+    protected_mode: AtomicBool,
+    explicit_bind: bool,
+    // Set with --requirepass or CONFIG SET requirepass, which make it the default user's only password; kept for CONFIG GET
+    requirepass: Mutex<Option<String>>,
+    // Users and the commands they may run; until a connection AUTHs as one, it can only run no_auth commands if the
+    // default user needs a password
+    acl: Acl,
+    // Where ACL LOAD and ACL SAVE read and write the users, if there's an aclfile
+    aclfile: Option<PathBuf>,
+    // Failed AUTHs and commands refused with NOPERM, for ACL LOG
+    acl_log: AclLog,
+    // Commands hold this for reading while they run, and EXEC for writing so that a transaction runs on its own
+    exec_lock: RwLock<()>,
+    // How many times a second server_cron_loop ticks; set with --hz or CONFIG SET hz
+    hz: AtomicU64,
+    // How many pipelined commands a connection runs before yielding to other connections' tasks
+    pipeline_batch_size: AtomicUsize,
+    // Longest bulk string argument accepted from clients; set with --proto-max-bulk-len or CONFIG SET
+    proto_max_bulk_len: AtomicUsize,
+    // Memory limit set with --maxmemory or CONFIG SET; 0 means no limit, and nothing is evicted yet either way
+    maxmemory: AtomicUsize,
+    // Set with --maxclients or CONFIG SET maxclients; connections past it are rejected by the accept loop
+    maxclients: AtomicUsize,
+    // Set with --timeout or CONFIG SET timeout; enforced by the client-timeout cron job
+    timeout: AtomicU64,
+    // Set with --tcp-keepalive or CONFIG SET tcp-keepalive; applied to connections as they're accepted
+    tcp_keepalive: AtomicU64,
+    // Jobs run by every tick, registered by each subsystem with register_cron_job
+    cron_jobs: Mutex<Vec<(&'static str, CronJob)>>,
+    // Set by request_shutdown (for RedisServer::shutdown or SHUTDOWN), which also wakes up the accept loop with
+    // shutdown_requested; server_cron_loop stops once it sees the flag
+    shutting_down: AtomicBool,
+    shutdown_requested: Notify,
+}
+
+impl ServerState {
+    fn new() -> Self {
+        ServerState {
+            dbs: Self::new_dbs(config::DEFAULT_DATABASES),
+            loading: AtomicBool::new(false),
+            supervised: false,
+            active_expire_enabled: AtomicBool::new(true),
+            next_client_id: AtomicU64::new(1),
+            clients: ClientRegistry::default(),
+            monitors: RwLock::new(HashMap::new()),
+            slowlog: Slowlog::new(slowlog::DEFAULT_LOG_SLOWER_THAN_US, slowlog::DEFAULT_MAX_LEN),
+            latency_monitor: LatencyMonitor::new(latency::DEFAULT_THRESHOLD_MS),
+            commands: command_table().clone(),
+            start_time: Instant::now(),
+            encoding_limits: EncodingLimits::default(),
+            pubsub: PubSub::new(Default::default()),
+            rdb_path: PathBuf::from(config::DEFAULT_DBFILENAME),
+            save_points: Mutex::new(Vec::new()),
+            config_file: None,
+            stats: ServerStats::new(command_table().iter().map(|spec| spec.name)),
+            lazyfree: LazyFree::new(),
+            dirty: AtomicU64::new(0),
+            last_save: Mutex::new(Instant::now()),
+            watched_keys: Mutex::new(HashMap::new()),
+            num_watched_keys: AtomicUsize::new(0),
+            next_key_version: AtomicU64::new(1),
+            replica_read_only: AtomicBool::new(false),
+            protected_mode: AtomicBool::new(true),
+            explicit_bind: false,
+            requirepass: Mutex::new(None),
+            acl: Acl::new(command_table().iter().map(|spec| (spec.name, acl::command_categories(spec.name, spec.flags))).collect()),
+            aclfile: None,
+            acl_log: AclLog::new(acl::DEFAULT_LOG_MAX_LEN),
+            exec_lock: RwLock::new(()),
+            hz: AtomicU64::new(config::DEFAULT_HZ),
+            pipeline_batch_size: AtomicUsize::new(config::DEFAULT_PIPELINE_BATCH_SIZE),
+            proto_max_bulk_len: AtomicUsize::new(config::DEFAULT_PROTO_MAX_BULK_LEN),
+            maxmemory: AtomicUsize::new(0),
+            maxclients: AtomicUsize::new(config::DEFAULT_MAXCLIENTS),
+            timeout: AtomicU64::new(0),
+            tcp_keepalive: AtomicU64::new(config::DEFAULT_TCP_KEEPALIVE),
+            cron_jobs: Mutex::new(Vec::new()),
+            shutting_down: AtomicBool::new(false),
+            shutdown_requested: Notify::new(),
+        }
+    }
+
+    fn request_shutdown(&self) {
+        /* Make RedisServer::run return; a request made before it's waiting is remembered, so none are missed */
+        self.shutting_down.store(true, Ordering::Relaxed);
+        self.shutdown_requested.notify_one();
+    }
+
+    fn is_protected_from(&self, peer: SocketAddr) -> bool {
+        /*
+        Whether protected mode refuses a client connecting from this address: one that isn't on the loopback interface,
+        when neither the bind addresses nor a password for the default user were configured.
+        */
+        let is_loopback = match peer.ip() {
+            IpAddr::V4(ip) => ip.is_loopback(),
+            IpAddr::V6(ip) => ip.is_loopback() || ip.to_ipv4_mapped().is_some_and(|ip| ip.is_loopback()),
+        };
+        self.protected_mode.load(Ordering::Relaxed) && !self.explicit_bind && !self.acl.default_requires_password() && !is_loopback
+    }
+
+    fn requires_auth(&self, conn: &Connection) -> bool {
+        !conn.authenticated && self.acl.default_requires_password()
+    }
+
+    fn set_requirepass(&self, requirepass: Option<String>) {
+        /* Make requirepass the default user's only password, or let it in without one if there's none, like Redis */
+        let rules = match &requirepass {
+            Some(password) => vec!["resetpass".to_string(), format!(">{}", password)],
+            None => vec!["nopass".to_string()],
+        };
+        self.acl.set_user(acl::DEFAULT_USER, &rules).unwrap_or_else(|err| panic!("Invalid requirepass: {}", err));
+        *self.lock_requirepass() = requirepass;
+    }
+
+    fn register_cron_job(&self, name: &'static str, job: impl Fn(&ServerState) + Send + Sync + 'static) {
+        self.lock_cron_jobs().push((name, Box::new(job)));
+    }
+
+    fn lock_cron_jobs(&self) -> MutexGuard<'_, Vec<(&'static str, CronJob)>> {
+        self.cron_jobs.lock().unwrap_or_else(|err| {
+            panic!("Failed to lock cron jobs mutex: {}!", err);
+        })
+    }
+
+    fn lock_requirepass(&self) -> MutexGuard<'_, Option<String>> {
+        self.requirepass.lock().unwrap_or_else(|err| {
+            panic!("Failed to lock requirepass mutex: {}!", err);
+        })
+    }
+
+    fn lock_save_points(&self) -> MutexGuard<'_, Vec<SavePoint>> {
+        self.save_points.lock().unwrap_or_else(|err| {
+            panic!("Failed to lock save points mutex: {}!", err);
+        })
+    }
+
+    fn lock_watched_keys(&self) -> MutexGuard<'_, HashMap<(usize, Bytes), WatchedKey>> {
+        self.watched_keys.lock().unwrap_or_else(|err| {
+            panic!("Failed to lock watched keys mutex: {}!", err);
+        })
+    }
+
+    fn mark_modified(&self, db: usize, key: &[u8]) {
+        /*
+        Give key a new version from the server-wide counter if it's being watched, so WATCH can tell that it changed
+        Called by the dispatch layer for the keys of every write command, so handlers only have to for keys outside the
+        connection's database or their key arguments (e.g. MOVE's, or every key FLUSHDB removes); the versions live
+        in their own map rather than next to each value so that handlers can keep replacing values wholesale, and so
+        that a key that's removed and written again still counts as modified. Like Redis's watched_keys, the map only
+        holds keys that are being watched, so unwatched writes don't touch it at all.
+        */
+        if self.num_watched_keys.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        if let Some(watched_key) = self.lock_watched_keys().get_mut(&(db, Bytes::copy_from_slice(key))) {
+            watched_key.version = self.next_key_version.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn watch_key(&self, db: usize, key: &Bytes) -> u64 {
+        /* Start watching key on behalf of one more connection, returning its current version */
+        let mut watched_keys = self.lock_watched_keys();
+        let watched_key = watched_keys.entry((db, key.clone())).or_insert_with(|| {
+            self.num_watched_keys.fetch_add(1, Ordering::SeqCst);
+            WatchedKey { version: 0, num_watchers: 0 }
+        });
+        watched_key.num_watchers += 1;
+        watched_key.version
+    }
+
+    fn key_version(&self, db: usize, key: &[u8]) -> u64 {
+        /* Only asked about keys that the asking connection is watching */
+        self.lock_watched_keys().get(&(db, Bytes::copy_from_slice(key))).map_or(0, |watched_key| watched_key.version)
+    }
+
+    fn unwatch_keys(&self, keys: impl Iterator<Item = (usize, Bytes)>) {
+        /* Stop watching keys on behalf of one connection, forgetting those that nobody watches anymore */
+        let mut watched_keys = self.lock_watched_keys();
+        for db_key in keys {
+            if let Some(watched_key) = watched_keys.get_mut(&db_key) {
+                watched_key.num_watchers -= 1;
+                if watched_key.num_watchers == 0 {
+                    watched_keys.remove(&db_key);
+                    self.num_watched_keys.fetch_sub(1, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+
+    fn read_exec_lock(&self) -> RwLockReadGuard<'_, ()> {
+        self.exec_lock.read().unwrap_or_else(|err| {
+            panic!("Failed to lock exec lock: {}!", err);
+        })
+    }
+
+    fn write_exec_lock(&self) -> RwLockWriteGuard<'_, ()> {
+        self.exec_lock.write().unwrap_or_else(|err| {
+            panic!("Failed to lock exec lock: {}!", err);
+        })
+    }
+
+    fn read_monitors(&self) -> RwLockReadGuard<'_, HashMap<u64, Arc<Subscriber>>> {
+        self.monitors.read().unwrap_or_else(|err| {
+            panic!("Failed to lock monitors for reading: {}!", err);
+        })
+    }
+
+    fn write_monitors(&self) -> RwLockWriteGuard<'_, HashMap<u64, Arc<Subscriber>>> {
+        self.monitors.write().unwrap_or_else(|err| {
+            panic!("Failed to lock monitors for writing: {}!", err);
+        })
+    }
+
+    fn new_dbs(num_dbs: usize) -> Vec<Keyspace> {
+        (0..num_dbs).map(|_| Keyspace::new(keyspace::DEFAULT_NUM_SHARDS)).collect()
+    }
+
+    fn lock_db(&self, db: usize) -> KeyspaceGuard<'_> {
+        /* Lock a whole database, for commands that go over every key */
+        self.dbs[db].lock_all()
+    }
+
+    fn lock_all_dbs(&self) -> Vec<KeyspaceGuard<'_>> {
+        /* Lock every database, in order, e.g. to snapshot them all at once */
+        self.dbs.iter().map(|keyspace| keyspace.lock_all()).collect()
+    }
+
+    fn lock_keys<K: AsRef<[u8]>>(&self, db: usize, keys: impl IntoIterator<Item = K>) -> KeyspaceGuard<'_> {
+        /* Lock just the shards of the given keys, which is all that most commands need */
+        self.dbs[db].lock_keys(keys)
+    }
+
+    fn read_keys<K: AsRef<[u8]>>(&self, db: usize, keys: impl IntoIterator<Item = K>) -> KeyspaceReadGuard<'_> {
+        /* Lock the shards of the given keys for reading only; see get_key */
+        self.dbs[db].read_keys(keys)
+    }
+}
+
+// A client connection along with the per-connection settings that affect how replies are encoded
+// Whether a connection gets replies, as set with CLIENT REPLY
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReplyMode {
+    On,
+    Off,
+    // CLIENT REPLY SKIP was just run, so the next command's replies are dropped
+    SkipNext,
+    // Replies of the command running now are dropped
+    Skip,
+}
+
+// A blocking command that found nothing to reply with, which handle_connection runs again once its database is written to
+struct BlockedCmd {
+    // The command's arguments, with XREAD's `$` IDs replaced by the IDs they stood for when it first ran
+    args: Vec<Bytes>,
+    // When to give up and reply with a null array; taken from the command's timeout the first time it blocks
+    deadline: Option<Instant>,
+    // The database's write generation, read while the command still held the shards of its keys
+    write_generation: u64,
+}
+
+// The halves of a client's TCP or Unix socket connection
+type StreamReader = Box<dyn AsyncRead + Send + Unpin>;
+type StreamWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+struct Connection {
+    reader: StreamReader,
+    // Handed over to the subscriber's writer task on the first SUBSCRIBE
+    writer: Option<StreamWriter>,
+    id: u64,
+    // RESP version negotiated with HELLO; new connections speak RESP2 until they ask for RESP3
+    protocol: u8,
+    // Set on the first SUBSCRIBE; from then on all output is queued for the subscriber's writer task
+    subscriber: Option<Arc<Subscriber>>,
+    channels: HashSet<Vec<u8>>,
+    patterns: HashSet<Vec<u8>>,
+    // Commands queued since MULTI, and whether any of them was rejected (which makes EXEC abort)
+    transaction: Option<Vec<Vec<Bytes>>>,
+    transaction_error: bool,
+    // Keys watched with WATCH (by database), along with their versions at the time
+    watched_keys: HashMap<(usize, Bytes), u64>,
+    // Set while EXEC runs the queued commands; blocking commands don't block inside a transaction
+    in_exec: bool,
+    // Set by a blocking command that has to wait for writes; see wait_while_blocked
+    blocked: Option<BlockedCmd>,
+    // The deadline of the blocked command being run again, which it keeps instead of starting its timeout over
+    resumed_deadline: Option<Option<Instant>>,
+    // The database selected with SELECT, which the connection's commands work on
+    db: usize,
+    // Set by QUIT; the connection is closed once the replies so far are written
+    closing: bool,
+    // What CLIENT LIST shows about the connection; updated after every command
+    info: Arc<ClientInfo>,
+    // Set with CLIENT REPLY; replies are dropped instead of queued while it's Off or Skip
+    reply_mode: ReplyMode,
+    // Set by MONITOR, after which every command the server runs is fed to the connection
+    monitoring: bool,
+    // Set by AUTH (or HELLO's AUTH option), and on connecting while the default user needs no password; see requires_auth
+    authenticated: bool,
+    // The ACL user whose permissions the connection's commands are checked against; `default` until AUTH
+    user: String,
+    // Bytes read but not parsed yet, i.e. the start of a command whose remaining bytes haven't arrived
+    query_buf: Vec<u8>,
+    // Replies not written to the stream yet, so that those of pipelined commands go out together on flush
+    reply_bufs: VecDeque<Vec<u8>>,
+    pending_reply_len: usize,
+}
+
+impl Connection {
+    fn new(stream: tokio::net::TcpStream, id: u64) -> Self {
+        let addr_string = |addr: io::Result<SocketAddr>| addr.map_or_else(|_| "?:0".to_string(), |addr| addr.to_string());
+        let info = ClientInfo::new(id, addr_string(stream.peer_addr()), addr_string(stream.local_addr()));
+        let (reader, writer) = stream.into_split();
+        Self::from_halves(Box::new(reader), Box::new(writer), id, info)
+    }
+
+    fn new_unix(stream: tokio::net::UnixStream, id: u64, path: &Path) -> Self {
+        /* A connection over the unixsocket; like in Redis, both of its addresses are the socket's path with port 0 */
+        let addr = format!("{}:0", path.display());
+        let info = ClientInfo::new(id, addr.clone(), addr);
+        let (reader, writer) = stream.into_split();
+        Self::from_halves(Box::new(reader), Box::new(writer), id, info)
+    }
+
+    fn from_halves(reader: StreamReader, writer: StreamWriter, id: u64, info: ClientInfo) -> Self {
+        Connection {
+            reader,
+            writer: Some(writer),
+            id,
+            protocol: 2,
+            subscriber: None,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            transaction: None,
+            transaction_error: false,
+            watched_keys: HashMap::new(),
+            in_exec: false,
+            blocked: None,
+            resumed_deadline: None,
+            db: 0,
+            closing: false,
+            info: Arc::new(info),
+            reply_mode: ReplyMode::On,
+            monitoring: false,
+            authenticated: false,
+            user: acl::DEFAULT_USER.to_string(),
+            query_buf: Vec::new(),
+            reply_bufs: VecDeque::new(),
+            pending_reply_len: 0,
+        }
+    }
+
+    fn num_subscriptions(&self) -> usize {
+        /* Channels and patterns together, as counted in SUBSCRIBE and PSUBSCRIBE replies */
+        self.channels.len() + self.patterns.len()
+    }
+
+    fn write_reply(&mut self, reply: &RespValue) -> io::Result<()> {
+        /* Send a reply, encoded for the protocol negotiated with HELLO */
+        let encoded = reply.serialize(self.protocol);
+        self.queue_output(Cow::Owned(encoded))
+    }
+
+    fn queue_output(&mut self, output: Cow<'_, [u8]>) -> io::Result<()> {
+        /*
+        Buffer output until the next flush, or hand it to the writer task once the connection has subscribed
+        Output is dropped instead while CLIENT REPLY has turned replies off. Small replies are appended to a shared buffer; large ones keep their own, so they're never copied again.
+        handle_connection flushes once REPLY_BUFFER_LIMIT is reached, between commands.
+        */
+        if matches!(self.reply_mode, ReplyMode::Off | ReplyMode::Skip) {
+            return Ok(());
+        }
+        let Some(subscriber) = &self.subscriber else {
+            let output_len = output.len();
+            match self.reply_bufs.back_mut() {
+                Some(reply_buf) if output_len < VECTORED_REPLY_LEN && reply_buf.len() < VECTORED_REPLY_LEN => {
+                    reply_buf.extend_from_slice(&output);
+                },
+                _ => self.reply_bufs.push_back(output.into_owned()),
+            }
+            self.pending_reply_len += output_len;
+            return Ok(());
+        };
+        match subscriber.send(output.into_owned()) {
+            true => Ok(()),
+            // Disconnected for falling too far behind on its output
+            false => Err(io::Error::from(ErrorKind::BrokenPipe)),
+        }
+    }
+
+    async fn flush_replies(&mut self) -> io::Result<()> {
+        /* Write out the pending replies, all of them in each vectored write that the stream takes whole */
+        let Some(writer) = &mut self.writer else {
+            return Ok(());
+        };
+        let mut written_of_first = 0;
+        while let Some(first_buf) = self.reply_bufs.front() {
+            let slices = std::iter::once(IoSlice::new(&first_buf[written_of_first..]))
+                .chain(self.reply_bufs.iter().skip(1).map(|reply_buf| IoSlice::new(reply_buf)))
+                .collect::<Vec<IoSlice>>();
+            let mut num_written = match writer.write_vectored(&slices).await {
+                Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+                Ok(num_written) => num_written,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            // Drop the buffers written in full, and remember how much of the next one was
+            while let Some(reply_buf) = self.reply_bufs.front() {
+                let remaining = reply_buf.len() - written_of_first;
+                if num_written < remaining {
+                    written_of_first += num_written;
+                    break;
+                }
+                num_written -= remaining;
+                written_of_first = 0;
+                self.reply_bufs.pop_front();
+            }
+        }
+        self.pending_reply_len = 0;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        /* Wait until everything sent so far has been written, by us or by the subscriber's writer task */
+        match &self.subscriber {
+            Some(subscriber) if subscriber.flush().await => Ok(()),
+            Some(_) => Err(io::Error::from(ErrorKind::BrokenPipe)),
+            None => self.flush_replies().await,
+        }
+    }
+}
+
+// TODO: Learn about sync primitives like Arc and try out <Arc<Mutex<RedisServer>>!
+// The reason why you can't pass in self into the async move block in tokio is that:
+// Tokio doesn't allow a single piece of data to be accessible from more than one task concurrently! It must be shared using sync primitives like Arc and Mutex.
+// Learn more about Arc::clone and how it works. Read the Tokio docs as well.
+pub struct RedisServer {
+    // One per bind address
+    listeners: Vec<TcpListener>,
+    // Listening on the unixsocket path, if one is configured
+    unix_listener: Option<(UnixListener, PathBuf)>,
+    state: Arc<ServerState>,
+    // Address and port the Prometheus endpoint listens on, if it's enabled
+    metrics_addr: Option<(String, u16)>,
+    // The pidfile written by build, removed once run returns
+    pidfile: Option<PathBuf>,
+}
+
+// Sets up a RedisServer to embed in another program, e.g. `RedisServerBuilder::new().bind("127.0.0.1:0").build()?`
+pub struct RedisServerBuilder {
+    // 127.0.0.1:6379 if none are given
+    addrs: Vec<String>,
+    config: Config,
+}
+
+impl Default for RedisServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RedisServerBuilder {
+    pub fn new() -> Self {
+        RedisServerBuilder {
+            addrs: Vec::new(),
+            config: Config::default(),
+        }
+    }
+
+    pub fn bind(mut self, addr: &str) -> Self {
+        /*
+        Address to listen on, which can be given several times to listen on each of them
+        Port 0 picks a free port, which RedisServer::local_addr then reports (for the first address). An address with a
+        leading `-`, e.g. `-[::1]:6379`, is skipped if it can't be bound, like in redis.conf.
+        */
+        self.addrs.push(addr.to_string());
+        self
+    }
+
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<RedisServer> {
+        /* Bind the listener and load the RDB snapshot (if there is one), without accepting connections yet */
+        let config = self.config;
+        let mut commands = command_table().clone();
+        for (name, new_name) in &config.renamed_commands {
+            commands.rename(name, new_name)?;
+        }
+        let state = ServerState {
+            dbs: ServerState::new_dbs(config.databases),
+            encoding_limits: config.encoding_limits,
+            pubsub: PubSub::new(config.pubsub_output_limit),
+            rdb_path: config.rdb_path(),
+            save_points: Mutex::new(config.save_points.clone()),
+            config_file: config.config_file.clone(),
+            replica_read_only: AtomicBool::new(config.replica_read_only),
+            protected_mode: AtomicBool::new(config.protected_mode),
+            explicit_bind: !config.bind.is_empty(),
+            hz: AtomicU64::new(config.hz),
+            pipeline_batch_size: AtomicUsize::new(config.pipeline_batch_size),
+            proto_max_bulk_len: AtomicUsize::new(config.proto_max_bulk_len),
+            maxmemory: AtomicUsize::new(config.maxmemory),
+            maxclients: AtomicUsize::new(config.maxclients),
+            timeout: AtomicU64::new(config.timeout),
+            tcp_keepalive: AtomicU64::new(config.tcp_keepalive),
+            slowlog: Slowlog::new(config.slowlog_log_slower_than, config.slowlog_max_len),
+            latency_monitor: LatencyMonitor::new(config.latency_monitor_threshold),
+            loading: AtomicBool::new(config.rdb_path().exists()),
+            supervised: config.supervised,
+            aclfile: config.aclfile.clone(),
+            acl_log: AclLog::new(config.acllog_max_len),
+            commands,
+            ..ServerState::new()
+        };
+        state.set_requirepass(config.requirepass.clone());
+        // Like in Redis, the aclfile's users replace requirepass's, and a file that can't be loaded stops the server
+        if let Some(aclfile) = &state.aclfile {
+            RedisServer::load_acl_file(&state, aclfile).map_err(|err| anyhow::anyhow!("Failed to load ACL users: {}", err))?;
+        }
+        let addrs = if self.addrs.is_empty() { vec![String::from("127.0.0.1:6379")] } else { self.addrs };
+        let mut listeners = Vec::new();
+        for addr in &addrs {
+            let (optional, addr) = addr.strip_prefix('-').map_or((false, addr.as_str()), |addr| (true, addr));
+            let listener = match TcpListener::bind(addr) {
+                Ok(listener) => listener,
+                Err(err) if optional => {
+                    warn!("Skipping optional bind address {}: {}", addr, err);
+                    continue;
+                },
+                Err(err) => anyhow::bail!("Failed to bind {}: {}", addr, err),
+            };
+            // Accepted by the runtime in run, which needs the socket not to block
+            listener.set_nonblocking(true)?;
+            listeners.push(listener);
+        }
+        if listeners.is_empty() {
+            anyhow::bail!("Failed to bind any of {}", addrs.join(" "));
+        }
+        let pidfile = config.pidfile_path();
+        let unix_listener = match config.unixsocket {
+            Some(path) => {
+                // Left behind by a server that didn't shut down cleanly; anything else at the path is kept
+                if path.metadata().is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                    std::fs::remove_file(&path)?;
+                }
+                let unix_listener = UnixListener::bind(&path)
+                    .map_err(|err| anyhow::anyhow!("Failed to bind {}: {}", path.display(), err))?;
+                unix_listener.set_nonblocking(true)?;
+                Some((unix_listener, path))
+            },
+            None => None,
+        };
+        // Like in Redis, a pidfile that can't be written is only worth a warning
+        let pidfile = pidfile.filter(|pidfile| match daemon::write_pidfile(pidfile) {
+            Ok(()) => true,
+            Err(err) => {
+                warn!("Failed to write PID file {}: {}", pidfile.display(), err);
+                false
+            },
+        });
+        let metrics_addr = config.metrics_port.map(|metrics_port| (config.metrics_bind.clone(), metrics_port));
+        Ok(RedisServer { listeners, unix_listener, state: Arc::new(state), metrics_addr, pidfile })
+    }
+}
+
+// A client connection whose socket is read and written by the caller instead of a tokio task, so that the front ends in
+// examples (a thread pool, an epoll event loop, and a single executor thread) serve the same commands as run. What the
+// client sends goes to feed, and what take_output returns goes back to it. Commands that can't go on yet (a blocked
+// BLPOP, or any command during CLIENT PAUSE) wait for poll, as do Pub/Sub messages and MONITOR output, so needs_polling
+// sessions must be polled.
+pub struct Session {
+    conn: Connection,
+    state: Arc<ServerState>,
+    // Set when CLIENT PAUSE held up the next command, which stays in the query buffer until poll finds the pause over
+    paused: bool,
+}
+
+impl Session {
+    pub fn feed(&mut self, input: &[u8]) -> io::Result<()> {
+        /* Run the commands that input completes, up to one that has to wait; the rest stay buffered until it's done */
+        self.conn.query_buf.extend_from_slice(input);
+        self.run_queued()
+    }
+
+    pub fn poll(&mut self) -> io::Result<()> {
+        /* Run the blocked command again if its database was written to or its deadline passed, then the commands after it */
+        if let Some(blocked) = self.conn.blocked.take() {
+            let timed_out = blocked.deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            if !timed_out && self.state.dbs[self.conn.db].write_generation() == blocked.write_generation {
+                self.conn.blocked = Some(blocked);
+                return Ok(());
+            }
+            Self::set_blocked(&self.conn, &self.state, false);
+            RedisServer::resume_blocked_cmd(&mut self.conn, &self.state, blocked, timed_out)?;
+            Self::set_blocked(&self.conn, &self.state, self.conn.blocked.is_some());
+        }
+        self.run_queued()
+    }
+
+    fn run_queued(&mut self) -> io::Result<()> {
+        /* Like handle_connection's read loop, without the waiting, which poll does instead */
+        let (conn, state) = (&mut self.conn, &*self.state);
+        let mut pos = 0;
+        self.paused = false;
+        while pos < conn.query_buf.len() && conn.blocked.is_none() && !conn.closing {
+            let max_bulk_len = state.proto_max_bulk_len.load(Ordering::Relaxed);
+            let (args, cmd_len) = match resp::parse_command(&conn.query_buf[pos..], max_bulk_len) {
+                Ok(Some(parsed)) => parsed,
+                Ok(None) => break,
+                Err(protocol_err) => {
+                    let protocol_err_response = RespValue::Error(format!("ERR Protocol error: {}", protocol_err));
+                    conn.write_reply(&protocol_err_response)?;
+                    conn.closing = true;
+                    break;
+                }
+            };
+            if let Some(cmd_spec) = args.first().and_then(|cmd_name| state.commands.get(arg_to_string(cmd_name).to_lowercase().as_str())) {
+                if state.clients.paused_until(RedisServer::is_write_cmd(cmd_spec, conn, state)).is_some() {
+                    self.paused = true;
+                    break;
+                }
+            }
+            pos += cmd_len;
+            if args.is_empty() {
+                continue;
+            }
+            RedisServer::handle_cmd(&args, conn, state)?;
+            Self::set_blocked(conn, state, conn.blocked.is_some());
+        }
+        conn.query_buf.drain(..pos);
+        Ok(())
+    }
+
+    fn set_blocked(conn: &Connection, state: &ServerState, blocked: bool) {
+        /* Show the connection as blocked (or not) in CLIENT LIST and INFO, which handle_connection does while it waits */
+        let mut details = conn.info.lock_details();
+        match (details.blocked, blocked) {
+            (false, true) => state.stats.blocked_clients.fetch_add(1, Ordering::Relaxed),
+            (true, false) => state.stats.blocked_clients.fetch_sub(1, Ordering::Relaxed),
+            _ => 0,
+        };
+        details.blocked = blocked;
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        /* The replies (and, once subscribed, messages) not taken yet, for the caller to write to the client in this order */
+        self.conn.pending_reply_len = 0;
+        let mut output = self.conn.reply_bufs.drain(..).flatten().collect::<Vec<u8>>();
+        if let Some(subscriber) = &self.conn.subscriber {
+            output.extend(subscriber.take_queued());
+        }
+        output
+    }
+
+    pub fn needs_polling(&self) -> bool {
+        /* Whether the session may have something to do without the client sending anything, so poll should be called */
+        self.conn.blocked.is_some() || self.conn.subscriber.is_some() || self.paused
+    }
+
+    pub fn is_closed(&self) -> bool {
+        /*
+        Whether the connection should be closed, once the output taken so far is written: after QUIT or a protocol error,
+        or once the client was killed with CLIENT KILL (or for being idle too long, or too slow to take its messages)
+        */
+        self.conn.closing || self.conn.info.is_killed() || self.conn.subscriber.as_ref().is_some_and(|subscriber| subscriber.is_killed())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        Self::set_blocked(&self.conn, &self.state, false);
+        RedisServer::close_subscriptions(&mut self.conn, &self.state);
+        RedisServer::unwatch_all(&mut self.conn, &self.state);
+        self.state.clients.unregister(self.conn.id);
+        self.state.stats.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Runs a command; handlers get the full argv of the request (argv[0] is the command name) after arity has been checked
+pub(crate) trait CommandHandler: Send + Sync {
+    fn handle(&self, conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()>;
+}
+
+// Plain functions like RedisServer::handle_get_cmd are handlers, which is what most commands use
+impl<F> CommandHandler for F
+where
+    F: Fn(&mut Connection, &[Bytes], &ServerState) -> io::Result<()> + Send + Sync,
+{
+    fn handle(&self, conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        self(conn, args, state)
+    }
+}
+
+// Finds the keys among the arguments of a movablekeys command, e.g. after LMPOP's numkeys; None if they're malformed
+pub(crate) type KeyFinder = fn(&[Bytes]) -> Option<Vec<&Bytes>>;
+
+fn numkeys_key_args(args: &[Bytes], numkeys_idx: usize) -> Option<Vec<&Bytes>> {
+    /* The keys following a numkeys argument at numkeys_idx, which is how most movablekeys commands take them */
+    let num_keys = parse_arg::<usize>(args.get(numkeys_idx)?)?;
+    args.get(numkeys_idx + 1..numkeys_idx.checked_add(1 + num_keys)?).map(|keys| keys.iter().collect())
+}
+
+pub(crate) struct CommandSpec {
+    pub(crate) name: &'static str,
+    // (min, max) number of arguments, not counting the command name itself; a max of None means variadic
+    pub(crate) arity: (usize, Option<usize>),
+    // Reported by COMMAND INFO; same names as Redis (e.g. readonly, write, fast)
+    pub(crate) flags: &'static [&'static str],
+    // (first, last, step) positions of the key arguments in argv; a negative last counts from the end
+    pub(crate) keys: (i64, i64, i64),
+    // For movablekeys commands, whose keys can only be found by parsing their arguments
+    pub(crate) find_keys: Option<KeyFinder>,
+    pub(crate) handler: &'static dyn CommandHandler,
+}
+
+impl CommandSpec {
+    fn check_arity(&self, num_args: usize) -> Result<(), String> {
+        /* Validate the number of arguments against the arity table before the command's handler runs */
+        let (min_args, max_args) = self.arity;
+        if num_args < min_args || max_args.is_some_and(|max_args| num_args > max_args) {
+            return Err(format!("ERR wrong number of arguments for '{}' command", self.name));
+        }
+        Ok(())
+    }
+
+    fn key_args<'a>(&self, args: &'a [Bytes]) -> Vec<&'a Bytes> {
+        /*
+        The arguments of args that are keys, going by the key spec, or for movablekeys commands their key finder
+        If a movablekeys command's arguments are malformed, every argument after the command name counts, which is
+        safe for WATCH since at worst it sees a change that didn't happen.
+        */
+        match self.find_keys {
+            Some(find_keys) => find_keys(args).unwrap_or_else(|| args[1..].iter().collect()),
+            None => self.fixed_key_args(args),
+        }
+    }
+
+    fn fixed_key_args<'a>(&self, args: &'a [Bytes]) -> Vec<&'a Bytes> {
+        /* The arguments at the positions given by the key spec */
+        let (first_key, last_key, key_step) = self.keys;
+        if first_key == 0 {
+            return Vec::new();
+        }
+        let last_key = if last_key < 0 { args.len() as i64 + last_key } else { last_key };
+        (first_key..=last_key)
+            .step_by(key_step.max(1) as usize)
+            .filter_map(|key_idx| args.get(key_idx as usize))
+            .collect()
+    }
+
+    fn redis_arity(&self) -> i64 {
+        /* Arity the way Redis reports it: counts the command name, and is negative for a minimum rather than exact count */
+        match self.arity {
+            (min_args, Some(max_args)) if min_args == max_args => min_args as i64 + 1,
+            (min_args, _) => -(min_args as i64 + 1),
+        }
+    }
+}
+
+// Adding a command is a matter of registering its handler here, or in the COMMANDS of the module implementing its data type
+const COMMANDS: &[CommandSpec] = &[
+    CommandSpec { name: "hello", arity: (0, None), flags: &["noscript", "loading", "stale", "fast", "no_auth"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_hello_cmd },
+    CommandSpec { name: "auth", arity: (1, Some(2)), flags: &["noscript", "loading", "stale", "fast", "no_auth"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_auth_cmd },
+    CommandSpec { name: "ping", arity: (0, Some(1)), flags: &["fast", "stale"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_ping_cmd },
+    CommandSpec { name: "echo", arity: (1, Some(1)), flags: &["fast"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_echo_cmd },
+    CommandSpec { name: "quit", arity: (0, None), flags: &["noscript", "loading", "stale", "fast", "no_auth"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_quit_cmd },
+    CommandSpec { name: "reset", arity: (0, Some(0)), flags: &["noscript", "loading", "stale", "fast", "no_auth"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_reset_cmd },
+    CommandSpec { name: "get", arity: (1, Some(1)), flags: &["readonly", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_get_cmd },
+    // key val [NX|XX] [GET] [EX|PX|EXAT|PXAT time|KEEPTTL]
+    CommandSpec { name: "set", arity: (2, None), flags: &["write", "denyoom"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_set_cmd },
+    CommandSpec { name: "mset", arity: (2, None), flags: &["write", "denyoom"], keys: (1, -1, 2), find_keys: None, handler: &RedisServer::handle_mset_cmd },
+    CommandSpec { name: "bitop", arity: (3, None), flags: &["write", "denyoom"], keys: (2, -1, 1), find_keys: None, handler: &RedisServer::handle_bitop_cmd },
+    CommandSpec { name: "getrange", arity: (3, Some(3)), flags: &["readonly"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_getrange_cmd },
+    // Deprecated alias of GETRANGE
+    CommandSpec { name: "substr", arity: (3, Some(3)), flags: &["readonly"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_getrange_cmd },
+    CommandSpec { name: "bitpos", arity: (2, Some(4)), flags: &["readonly"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_bitpos_cmd },
+    CommandSpec { name: "incrbyfloat", arity: (2, Some(2)), flags: &["write", "denyoom", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_incrbyfloat_cmd },
+    CommandSpec { name: "command", arity: (0, None), flags: &["loading", "stale"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_command_cmd },
+    CommandSpec { name: "del", arity: (1, None), flags: &["write"], keys: (1, -1, 1), find_keys: None, handler: &RedisServer::handle_del_cmd },
+    CommandSpec { name: "unlink", arity: (1, None), flags: &["write", "fast"], keys: (1, -1, 1), find_keys: None, handler: &RedisServer::handle_unlink_cmd },
+    CommandSpec { name: "exists", arity: (1, None), flags: &["readonly", "fast"], keys: (1, -1, 1), find_keys: None, handler: &RedisServer::handle_exists_cmd },
+    CommandSpec { name: "type", arity: (1, Some(1)), flags: &["readonly", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_type_cmd },
+    CommandSpec { name: "keys", arity: (1, Some(1)), flags: &["readonly"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_keys_cmd },
+    CommandSpec { name: "scan", arity: (1, None), flags: &["readonly"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_scan_cmd },
+    CommandSpec { name: "select", arity: (1, Some(1)), flags: &["loading", "stale", "fast"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_select_cmd },
+    CommandSpec { name: "swapdb", arity: (2, Some(2)), flags: &["write", "fast"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_swapdb_cmd },
+    CommandSpec { name: "move", arity: (2, Some(2)), flags: &["write", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_move_cmd },
+    CommandSpec { name: "flushdb", arity: (0, Some(1)), flags: &["write"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_flushdb_cmd },
+    CommandSpec { name: "flushall", arity: (0, Some(1)), flags: &["write"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_flushall_cmd },
+    CommandSpec { name: "dbsize", arity: (0, Some(0)), flags: &["readonly", "fast"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_dbsize_cmd },
+    CommandSpec { name: "expire", arity: (2, None), flags: &["write", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_expire_cmd },
+    CommandSpec { name: "pexpire", arity: (2, None), flags: &["write", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_pexpire_cmd },
+    CommandSpec { name: "expireat", arity: (2, None), flags: &["write", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_expireat_cmd },
+    CommandSpec { name: "pexpireat", arity: (2, None), flags: &["write", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_pexpireat_cmd },
+    CommandSpec { name: "ttl", arity: (1, Some(1)), flags: &["readonly", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_ttl_cmd },
+    CommandSpec { name: "pttl", arity: (1, Some(1)), flags: &["readonly", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_pttl_cmd },
+    CommandSpec { name: "expiretime", arity: (1, Some(1)), flags: &["readonly", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_expiretime_cmd },
+    CommandSpec { name: "pexpiretime", arity: (1, Some(1)), flags: &["readonly", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_pexpiretime_cmd },
+    CommandSpec { name: "lpush", arity: (2, None), flags: &["write", "denyoom", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_lpush_cmd },
+    CommandSpec { name: "rpush", arity: (2, None), flags: &["write", "denyoom", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_rpush_cmd },
+    CommandSpec { name: "lpop", arity: (1, Some(2)), flags: &["write", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_lpop_cmd },
+    CommandSpec { name: "rpop", arity: (1, Some(2)), flags: &["write", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_rpop_cmd },
+    CommandSpec { name: "lmpop", arity: (3, None), flags: &["write", "movablekeys"], keys: (0, 0, 0), find_keys: Some(RedisServer::lmpop_keys), handler: &RedisServer::handle_lmpop_cmd },
+    CommandSpec { name: "blpop", arity: (2, None), flags: &["write", "blocking"], keys: (1, -2, 1), find_keys: None, handler: &RedisServer::handle_blpop_cmd },
+    CommandSpec { name: "brpop", arity: (2, None), flags: &["write", "blocking"], keys: (1, -2, 1), find_keys: None, handler: &RedisServer::handle_brpop_cmd },
+    CommandSpec { name: "blmpop", arity: (4, None), flags: &["write", "blocking", "movablekeys"], keys: (0, 0, 0), find_keys: Some(RedisServer::blmpop_keys), handler: &RedisServer::handle_blmpop_cmd },
+    CommandSpec { name: "lrange", arity: (3, Some(3)), flags: &["readonly"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_lrange_cmd },
+    CommandSpec { name: "hset", arity: (3, None), flags: &["write", "denyoom", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_hset_cmd },
+    CommandSpec { name: "hget", arity: (2, Some(2)), flags: &["readonly", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_hget_cmd },
+    CommandSpec { name: "hgetall", arity: (1, Some(1)), flags: &["readonly"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_hgetall_cmd },
+    CommandSpec { name: "hexpire", arity: (5, None), flags: &["write", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_hexpire_cmd },
+    CommandSpec { name: "hpexpire", arity: (5, None), flags: &["write", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_hpexpire_cmd },
+    CommandSpec { name: "hexpireat", arity: (5, None), flags: &["write", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_hexpireat_cmd },
+    CommandSpec { name: "hpexpireat", arity: (5, None), flags: &["write", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_hpexpireat_cmd },
+    CommandSpec { name: "httl", arity: (4, None), flags: &["readonly", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_httl_cmd },
+    CommandSpec { name: "hpttl", arity: (4, None), flags: &["readonly", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_hpttl_cmd },
+    CommandSpec { name: "hexpiretime", arity: (4, None), flags: &["readonly", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_hexpiretime_cmd },
+    CommandSpec { name: "hpexpiretime", arity: (4, None), flags: &["readonly", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_hpexpiretime_cmd },
+    CommandSpec { name: "sadd", arity: (2, None), flags: &["write", "denyoom", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_sadd_cmd },
+    CommandSpec { name: "smembers", arity: (1, Some(1)), flags: &["readonly"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_smembers_cmd },
+    CommandSpec { name: "sinter", arity: (1, None), flags: &["readonly"], keys: (1, -1, 1), find_keys: None, handler: &RedisServer::handle_sinter_cmd },
+    CommandSpec { name: "sunion", arity: (1, None), flags: &["readonly"], keys: (1, -1, 1), find_keys: None, handler: &RedisServer::handle_sunion_cmd },
+    CommandSpec { name: "sdiff", arity: (1, None), flags: &["readonly"], keys: (1, -1, 1), find_keys: None, handler: &RedisServer::handle_sdiff_cmd },
+    CommandSpec { name: "sinterstore", arity: (2, None), flags: &["write", "denyoom"], keys: (1, -1, 1), find_keys: None, handler: &RedisServer::handle_sinterstore_cmd },
+    CommandSpec { name: "sunionstore", arity: (2, None), flags: &["write", "denyoom"], keys: (1, -1, 1), find_keys: None, handler: &RedisServer::handle_sunionstore_cmd },
+    CommandSpec { name: "sdiffstore", arity: (2, None), flags: &["write", "denyoom"], keys: (1, -1, 1), find_keys: None, handler: &RedisServer::handle_sdiffstore_cmd },
+    CommandSpec { name: "object", arity: (1, None), flags: &["readonly"], keys: (2, 2, 1), find_keys: None, handler: &RedisServer::handle_object_cmd },
+    CommandSpec { name: "multi", arity: (0, Some(0)), flags: &["noscript", "loading", "stale", "fast"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_multi_cmd },
+    CommandSpec { name: "exec", arity: (0, Some(0)), flags: &["noscript", "loading", "stale"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_exec_cmd },
+    CommandSpec { name: "discard", arity: (0, Some(0)), flags: &["noscript", "loading", "stale", "fast"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_discard_cmd },
+    CommandSpec { name: "watch", arity: (1, None), flags: &["noscript", "loading", "stale", "fast"], keys: (1, -1, 1), find_keys: None, handler: &RedisServer::handle_watch_cmd },
+    CommandSpec { name: "unwatch", arity: (0, Some(0)), flags: &["noscript", "loading", "stale", "fast"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_unwatch_cmd },
+    CommandSpec { name: "subscribe", arity: (1, None), flags: &["pubsub", "noscript", "loading", "stale"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_subscribe_cmd },
+    CommandSpec { name: "unsubscribe", arity: (0, None), flags: &["pubsub", "noscript", "loading", "stale"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_unsubscribe_cmd },
+    CommandSpec { name: "psubscribe", arity: (1, None), flags: &["pubsub", "noscript", "loading", "stale"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_psubscribe_cmd },
+    CommandSpec { name: "punsubscribe", arity: (0, None), flags: &["pubsub", "noscript", "loading", "stale"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_punsubscribe_cmd },
+    CommandSpec { name: "publish", arity: (2, Some(2)), flags: &["pubsub", "loading", "stale", "fast"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_publish_cmd },
+    CommandSpec { name: "waitaof", arity: (3, Some(3)), flags: &["noscript"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_waitaof_cmd },
+    CommandSpec { name: "slowlog", arity: (1, None), flags: &["admin", "loading", "stale"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_slowlog_cmd },
+    CommandSpec { name: "latency", arity: (1, None), flags: &["admin", "noscript", "loading", "stale"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_latency_cmd },
+    CommandSpec { name: "monitor", arity: (0, Some(0)), flags: &["admin", "noscript", "loading", "stale"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_monitor_cmd },
+    CommandSpec { name: "client", arity: (1, None), flags: &["admin", "noscript", "loading", "stale"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_client_cmd },
+    CommandSpec { name: "config", arity: (1, None), flags: &["admin", "noscript", "loading", "stale"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_config_cmd },
+    CommandSpec { name: "acl", arity: (1, None), flags: &["admin", "noscript", "loading", "stale"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_acl_cmd },
+    CommandSpec { name: "info", arity: (0, None), flags: &["loading", "stale"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_info_cmd },
+    CommandSpec { name: "lolwut", arity: (0, None), flags: &["readonly", "fast"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_lolwut_cmd },
+    CommandSpec { name: "shutdown", arity: (0, Some(1)), flags: &["admin", "noscript", "loading", "stale"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_shutdown_cmd },
+    CommandSpec { name: "debug", arity: (1, None), flags: &["admin", "noscript"], keys: (0, 0, 0), find_keys: None, handler: &RedisServer::handle_debug_cmd },
+];
+
+// Help text replied to `<CMD> HELP` for commands with subcommands, one line per element, like Redis's addReplyHelp
+const SUBCOMMAND_HELP: &[(&str, &[&str])] = &[
+    ("object", &[
+        "ENCODING <key>",
+        "    Return the kind of internal representation used in order to store the value",
+        "    associated with a <key>.",
+    ]),
+    ("slowlog", &[
+        "GET [<count>]",
+        "    Return top <count> entries from the slowlog (default: 10, -1 mean all).",
+        "    Entries are made of:",
+        "    id, timestamp, time in microseconds, arguments array, client IP and port,",
+        "    client name",
+        "LEN",
+        "    Return the length of the slowlog.",
+        "RESET",
+        "    Reset the slowlog.",
+    ]),
+    ("latency", &[
+        "HISTORY <event>",
+        "    Return time-latency samples for the <event> class.",
+        "LATEST",
+        "    Return the latest latency samples for all events.",
+        "RESET [<event> ...]",
+        "    Reset latency data of one or more <event> classes.",
+        "    (default: reset all data for all event classes)",
+    ]),
+    ("client", &[
+        "ID",
+        "    Return the ID of the current connection.",
+        "INFO",
+        "    Return information about the current client connection.",
+        "LIST [ID <client-id> [<client-id> ...]]",
+        "    Return information about client connections.",
+        "GETNAME",
+        "    Return the name of the current connection.",
+        "SETNAME <name>",
+        "    Assign the name <name> to the current connection.",
+        "REPLY (ON|OFF|SKIP)",
+        "    Control the replies sent to the current connection.",
+        "PAUSE <timeout> [WRITE|ALL]",
+        "    Suspend all, or just write, clients for <timeout> milliseconds.",
+        "UNPAUSE",
+        "    Stop the current client pause, resuming traffic.",
+        "KILL <ip:port>",
+        "    Kill connection made from <ip:port>.",
+        "KILL <option> <value> [<option> <value> [...]]",
+        "    Kill connections matching all options: ID <client-id>, ADDR <ip:port>,",
+        "    LADDR <ip:port>, SKIPME (YES|NO) (skip the current one; default: yes).",
+    ]),
+    ("config", &[
+        "GET <pattern>",
+        "    Return parameters matching the glob-like <pattern> and their values.",
+        "SET <directive> <value>",
+        "    Set the configuration <directive> to <value>.",
+        "REWRITE",
+        "    Rewrite the configuration file.",
+        "RESETSTAT",
+        "    Reset statistics reported by the INFO command.",
+    ]),
+    ("acl", &[
+        "CAT [<category>]",
+        "    List all commands that belong to <category>, or all command categories",
+        "    when no category is specified.",
+        "DELUSER <username> [<username> ...]",
+        "    Delete a list of users.",
+        "GETUSER <username>",
+        "    Get the user's details.",
+        "LIST",
+        "    Show users details in config file format.",
+        "LOAD",
+        "    Reload users from the ACL file.",
+        "LOG [<count> | RESET]",
+        "    Show the ACL log entries.",
+        "SAVE",
+        "    Save the current config to the ACL file.",
+        "USERS",
+        "    List all the registered usernames.",
+        "SETUSER <username> [<attribs> ...]",
+        "    Create or modify a user with the specified attributes.",
+        "WHOAMI",
+        "    Return the current connection username.",
+    ]),
+    ("debug", &[
+        "OBJECT <key>",
+        "    Show low level info about the <key> and associated value.",
+        "RELOAD",
+        "    Save the RDB on disk and reload it back to memory.",
+        "STRINGMATCH-LEN <pattern> <string>",
+        "    Return 1 if <string> matches the glob-style <pattern>, 0 otherwise.",
+        "SLEEP <seconds>",
+        "    Stop the server for <seconds>. Decimals allowed.",
+        "SET-ACTIVE-EXPIRE <0|1>",
+        "    Setting it to 0 disables expiring keys in background when they are not",
+        "    accessed (otherwise the Redis behavior). Setting it to 1 reenables back the",
+        "    default.",
+    ]),
+    ("command", &[
+        "(no subcommand)",
+        "    Return details about all Redis commands.",
+        "COUNT",
+        "    Return the total number of commands in this Redis server.",
+        "INFO [<command-name> ...]",
+        "    Return details about multiple Redis commands.",
+        "    If no command names are given, documentation details for all",
+        "    commands are returned.",
+        "DOCS [<command-name> ...]",
+        "    Return documentation details about multiple Redis commands.",
+        "GETKEYS <full-command>",
+        "    Return the keys from a full Redis command.",
+        "LIST [FILTERBY PATTERN <pattern>]",
+        "    Return a list of all commands in this Redis server, optionally",
+        "    filtered by a glob-style pattern.",
+    ]),
+];
+
+// Parameters that CONFIG GET and CONFIG SET know about
+const CONFIG_PARAMS: &[&str] = &[
+    "acllog-max-len",
+    "hz",
+    "latency-monitor-threshold",
+    "loglevel",
+    "maxclients",
+    "maxmemory",
+    "pipeline-batch-size",
+    "protected-mode",
+    "proto-max-bulk-len",
+    "replica-read-only",
+    "requirepass",
+    "save",
+    "slowlog-log-slower-than",
+    "slowlog-max-len",
+    "tcp-keepalive",
+    "timeout",
+];
+
+// The commands the dispatch layer knows about, keyed by lowercase name; COMMAND lists them in registration order
+#[derive(Clone, Default)]
+pub(crate) struct CommandRegistry {
+    specs: Vec<&'static CommandSpec>,
+    // Usually each spec's own name, unless rename-command gave it another one
+    by_name: HashMap<String, &'static CommandSpec>,
+}
+
+impl CommandRegistry {
+    pub(crate) fn register(&mut self, spec: &'static CommandSpec) {
+        /* Add a command, e.g. from the module that implements it; names must be lowercase and unique */
+        debug_assert_eq!(spec.name, spec.name.to_lowercase());
+        if self.by_name.insert(spec.name.to_string(), spec).is_some() {
+            panic!("Command registered twice: {}", spec.name);
+        }
+        self.specs.push(spec);
+    }
+
+    pub(crate) fn register_all(&mut self, specs: &'static [CommandSpec]) {
+        for spec in specs {
+            self.register(spec);
+        }
+    }
+
+    fn rename(&mut self, name: &str, new_name: &str) -> anyhow::Result<()> {
+        /*
+        Make a command only callable as new_name, or not at all if new_name is empty, like redis.conf's rename-command
+        A renamed command keeps its own name everywhere else, e.g. in COMMAND INFO and INFO commandstats.
+        */
+        let spec = self.by_name.remove(&name.to_lowercase())
+            .ok_or_else(|| anyhow::anyhow!("No such command in rename-command: {}", name))?;
+        if new_name.is_empty() {
+            self.specs.retain(|other_spec| !std::ptr::eq(*other_spec, spec));
+            return Ok(());
+        }
+        let new_name = new_name.to_lowercase();
+        if self.by_name.contains_key(&new_name) {
+            anyhow::bail!("Target command name already exists in rename-command: {}", new_name);
+        }
+        self.by_name.insert(new_name, spec);
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Option<&'static CommandSpec> {
+        self.by_name.get(name).copied()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &'static CommandSpec> + '_ {
+        self.specs.iter().copied()
+    }
+
+    fn len(&self) -> usize {
+        self.specs.len()
+    }
+}
+
+impl std::ops::Index<&str> for CommandRegistry {
+    type Output = CommandSpec;
+
+    fn index(&self, name: &str) -> &CommandSpec {
+        self.get(name).unwrap_or_else(|| panic!("Unknown command: {}", name))
+    }
+}
+
+fn command_table() -> &'static CommandRegistry {
+    /*
+    Registry of every command, built once from the built-in COMMANDS and those of the data type modules
+    Each server copies it, to apply rename-command.
+    */
+    static COMMAND_TABLE: OnceLock<CommandRegistry> = OnceLock::new();
+    COMMAND_TABLE.get_or_init(|| {
+        let mut registry = CommandRegistry::default();
+        registry.register_all(COMMANDS);
+        registry.register_all(hyperloglog::COMMANDS);
+        registry.register_all(streams::COMMANDS);
+        registry.register_all(sorted_set::COMMANDS);
+        registry.register_all(geo::COMMANDS);
+        registry
+    })
+}
+
+fn arg_to_string(arg: &[u8]) -> String {
+    String::from_utf8_lossy(arg).into_owned()
+}
+
+fn normalize_range(start: i64, end: i64, len: usize) -> Option<(usize, usize)> {
+    /* Resolve an inclusive index range where negative indices count from the end, or None if it's empty */
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let end = if end < 0 { len + end } else { end.min(len - 1) };
+    (start <= end && start < len).then_some((start as usize, end as usize))
+}
+
+fn parse_arg<T: FromStr>(arg: &[u8]) -> Option<T> {
+    std::str::from_utf8(arg).ok()?.parse::<T>().ok()
+}
+
+impl RedisServer {
+    fn handle_hello_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Switch the connection's RESP protocol version (HELLO 2 or HELLO 3) and describe the server
+        Without a version, the connection keeps its current protocol. The reply is a map in RESP3 and a flat array in RESP2.
+        HELLO <version> AUTH <username> <password> authenticates at the same time, like AUTH; without it, a connection
+        that still has to authenticate is refused.
+        */
+        // Options are checked first so that a rejected HELLO leaves the protocol alone
+        let mut credentials = None;
+        let mut options = args.iter().skip(2);
+        while let Some(option_arg) = options.next() {
+            match (option_arg.to_ascii_uppercase().as_slice(), options.next(), options.next()) {
+                (b"AUTH", Some(username), Some(password)) => credentials = Some((username, password)),
+                _ => {
+                    let hello_err_response = RespValue::Error(format!("ERR Syntax error in HELLO option '{}'", arg_to_string(option_arg)));
+                    conn.write_reply(&hello_err_response)?;
+                    return Ok(());
+                },
+            }
+        }
+        let auth_result = match credentials {
+            Some((username, password)) => Self::authenticate(conn, state, username, password),
+            None if state.requires_auth(conn) => Err(RespValue::Error(concat!(
+                "NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH ",
+                "<user> <pass> option can be used to authenticate the client and select the RESP protocol version at the ",
+                "same time",
+            ).to_string())),
+            None => Ok(()),
+        };
+        if let Err(auth_err_response) = auth_result {
+            conn.write_reply(&auth_err_response)?;
+            return Ok(());
+        }
+        if let Some(protover) = args.get(1) {
+            match parse_arg::<u8>(protover) {
+                Some(protocol @ (2 | 3)) => {
+                    conn.protocol = protocol;
+                    if let Some(subscriber) = &conn.subscriber {
+                        subscriber.protocol.store(protocol, Ordering::Relaxed);
+                    }
+                },
+                Some(_) => {
+                    let hello_err_response = RespValue::Error("NOPROTO unsupported protocol version".to_string());
+                    conn.write_reply(&hello_err_response)?;
+                    return Ok(());
+                },
+                None => {
+                    let hello_err_response = RespValue::Error("ERR Protocol version is not an integer or out of range".to_string());
+                    conn.write_reply(&hello_err_response)?;
+                    return Ok(());
+                }
+            }
+        }
+
+        let fields = [
+            ("server", RespValue::SimpleString(b"redis".to_vec())),
+            ("version", RespValue::SimpleString(REDIS_VERSION.as_bytes().to_vec())),
+            ("proto", RespValue::Integer(conn.protocol as i64)),
+            ("id", RespValue::Integer(conn.id as i64)),
+            ("mode", RespValue::SimpleString(b"standalone".to_vec())),
+            ("role", RespValue::SimpleString(b"master".to_vec())),
+            ("modules", RespValue::Array(Vec::new())),
+        ];
+        let hello_resp = RespValue::Map(
+            fields.into_iter().map(|(field, val)| (RespValue::BulkString(field.as_bytes().to_vec()), val)).collect()
+        );
+        conn.write_reply(&hello_resp)?;
+        Ok(())
+    }
+
+    fn authenticate(conn: &mut Connection, state: &ServerState, username: &[u8], password: &[u8]) -> Result<(), RespValue> {
+        /* Log the connection in as the user, if it's on and the password is one of its own (any is, for a nopass user) */
+        let username = arg_to_string(username);
+        if !state.acl.check_password(&username, password) {
+            state.acl_log.record(DenialReason::Auth, Self::acl_log_context(conn), "AUTH", &username, conn.info.describe());
+            return Err(RespValue::Error("WRONGPASS invalid username-password pair or user is disabled.".to_string()));
+        }
+        conn.info.lock_details().user = username.clone();
+        conn.user = username;
+        conn.authenticated = true;
+        Ok(())
+    }
+
+    fn handle_auth_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* AUTH [username] password logs the connection in as the user, `default` if there's no username */
+        let auth_result = match args.len() {
+            2 if state.acl.get_user(acl::DEFAULT_USER).is_some_and(|user| user.nopass) => Err(RespValue::Error(
+                "ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".to_string()
+            )),
+            2 => Self::authenticate(conn, state, b"default", &args[1]),
+            _ => Self::authenticate(conn, state, &args[1], &args[2]),
+        };
+        let auth_resp = auth_result.map_or_else(|auth_err_response| auth_err_response, |()| RespValue::ok());
+        conn.write_reply(&auth_resp)?;
+        Ok(())
+    }
+
+    fn acl_log_context(conn: &Connection) -> &'static str {
+        /* Where a denial happened, for ACL LOG: in a transaction being queued, or not */
+        match conn.transaction {
+            Some(_) => "multi",
+            None => "toplevel",
+        }
+    }
+
+    fn load_acl_file(state: &ServerState, aclfile: &Path) -> Result<(), String> {
+        /* Replace the users with the aclfile's, disconnecting the clients logged in as users that are gone */
+        let contents = std::fs::read_to_string(aclfile).map_err(|err| format!("{}: {}", aclfile.display(), err))?;
+        state.acl.load(&contents).map_err(|err| format!("{}:{}", aclfile.display(), err))?;
+        Self::kill_clients(state, |client| !state.acl.has_user(&client.lock_details().user));
+        Ok(())
+    }
+
+    fn save_acl_file(state: &ServerState, aclfile: &Path) -> io::Result<()> {
+        /* Write the users to the aclfile, via a temporary file so a failure leaves it intact */
+        let tmp_path = aclfile.with_file_name(format!("temp-acl-{}.acl", std::process::id()));
+        std::fs::write(&tmp_path, state.acl.save())?;
+        std::fs::rename(&tmp_path, aclfile)
+    }
+
+    fn handle_acl_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Manage the users: ACL SETUSER username [rule ...] creates or changes one (see acl.rs for the rules), ACL GETUSER
+        username describes one as a map, ACL DELUSER username [username ...] deletes some (disconnecting the clients
+        logged in as them) and replies with how many there were, ACL LIST replies with each user's rules and ACL USERS
+        with their names, and ACL WHOAMI with the connection's own. ACL CAT [category] lists the categories, or the
+        commands in one.
+        ACL LOAD replaces the users with the aclfile's, and ACL SAVE writes them to it.
+        ACL LOG [count] replies with the latest count denials (10 by default) as maps, newest first, and ACL LOG RESET
+        empties the log.
+        */
+        let subcommand = arg_to_string(&args[1]).to_uppercase();
+        let acl_resp = match (subcommand.as_str(), args.len()) {
+            ("SETUSER", num_args) if num_args >= 3 => {
+                let rules = args[3..].iter().map(|rule| arg_to_string(rule)).collect::<Vec<String>>();
+                match state.acl.set_user(&arg_to_string(&args[2]), &rules) {
+                    Ok(()) => RespValue::ok(),
+                    Err(setuser_err) => RespValue::Error(format!("ERR {}", setuser_err)),
+                }
+            },
+            ("GETUSER", 3) => match state.acl.get_user(&arg_to_string(&args[2])) {
+                Some(user) => {
+                    let mut flags = vec![if user.enabled { "on" } else { "off" }.as_bytes().to_vec()];
+                    if user.nopass {
+                        flags.push(b"nopass".to_vec());
+                    }
+                    let passwords = user.passwords.iter().map(|hash| hash.as_bytes().to_vec()).collect::<Vec<Vec<u8>>>();
+                    RespValue::Map(vec![
+                        (RespValue::BulkString(b"flags".to_vec()), RespValue::bulk_strings(&flags)),
+                        (RespValue::BulkString(b"passwords".to_vec()), RespValue::bulk_strings(&passwords)),
+                        (RespValue::BulkString(b"commands".to_vec()), RespValue::BulkString(user.commands().into_bytes())),
+                        (RespValue::BulkString(b"keys".to_vec()), RespValue::BulkString(b"~*".to_vec())),
+                        (RespValue::BulkString(b"channels".to_vec()), RespValue::BulkString(b"&*".to_vec())),
+                    ])
+                },
+                None => RespValue::Null,
+            },
+            ("DELUSER", num_args) if num_args >= 3 => {
+                let usernames = args[2..].iter().map(|username| arg_to_string(username)).collect::<Vec<String>>();
+                match usernames.iter().any(|username| username == acl::DEFAULT_USER) {
+                    true => RespValue::Error("ERR The 'default' user cannot be removed".to_string()),
+                    false => {
+                        let num_deleted = usernames.iter().filter(|username| state.acl.delete_user(username)).count();
+                        Self::kill_clients(state, |client| usernames.contains(&client.lock_details().user));
+                        RespValue::Integer(num_deleted as i64)
+                    },
+                }
+            },
+            ("LIST", 2) => RespValue::bulk_strings(&state.acl.describe_users().into_iter().map(String::into_bytes).collect::<Vec<Vec<u8>>>()),
+            ("USERS", 2) => RespValue::bulk_strings(&state.acl.usernames().into_iter().map(String::into_bytes).collect::<Vec<Vec<u8>>>()),
+            ("WHOAMI", 2) => RespValue::BulkString(conn.user.as_bytes().to_vec()),
+            ("CAT", 2) => RespValue::bulk_strings(&acl::CATEGORIES.iter().map(|category| category.as_bytes().to_vec()).collect::<Vec<Vec<u8>>>()),
+            ("CAT", 3) => {
+                let category = arg_to_string(&args[2]).to_lowercase();
+                match acl::CATEGORIES.contains(&category.as_str()) {
+                    true => RespValue::bulk_strings(
+                        &state.acl.commands_in_category(&category).into_iter().map(|name| name.as_bytes().to_vec()).collect::<Vec<Vec<u8>>>(),
+                    ),
+                    false => RespValue::Error(format!("ERR Unknown category '{}'", category)),
+                }
+            },
+            ("LOAD" | "SAVE", 2) => match (&state.aclfile, subcommand.as_str()) {
+                (Some(aclfile), "LOAD") => match Self::load_acl_file(state, aclfile) {
+                    Ok(()) => RespValue::ok(),
+                    Err(load_err) => RespValue::Error(format!("ERR {}", load_err)),
+                },
+                (Some(aclfile), _) => match Self::save_acl_file(state, aclfile) {
+                    Ok(()) => RespValue::ok(),
+                    Err(err) => {
+                        error!("Error saving ACL users to {}: {}", aclfile.display(), err);
+                        RespValue::Error("ERR There was an error trying to save the ACLs. Please check the server logs for more information".to_string())
+                    },
+                },
+                (None, _) => RespValue::Error(concat!(
+                    "ERR This Redis instance is not configured to use an ACL file. You may want to specify users via the ",
+                    "ACL SETUSER command and then issue a CONFIG REWRITE (assuming you have a Redis configuration file set) ",
+                    "in order to store users in the Redis configuration.",
+                ).to_string()),
+            },
+            ("LOG", 3) if args[2].eq_ignore_ascii_case(b"RESET") => {
+                state.acl_log.reset();
+                RespValue::ok()
+            },
+            ("LOG", 2 | 3) => match args.get(2).map_or(Some(10), |count| parse_arg::<usize>(count)) {
+                Some(count) => {
+                    let now_ms = Self::curr_time_ms();
+                    RespValue::Array(state.acl_log.latest(count).into_iter().map(|entry| {
+                        let field = |name: &str| RespValue::BulkString(name.as_bytes().to_vec());
+                        RespValue::Map(vec![
+                            (field("count"), RespValue::Integer(entry.count as i64)),
+                            (field("reason"), RespValue::BulkString(entry.reason.as_str().as_bytes().to_vec())),
+                            (field("context"), RespValue::BulkString(entry.context.as_bytes().to_vec())),
+                            (field("object"), RespValue::BulkString(entry.object.into_bytes())),
+                            (field("username"), RespValue::BulkString(entry.username.into_bytes())),
+                            (field("age-seconds"), RespValue::Double(now_ms.saturating_sub(entry.created_ms) as f64 / 1000.0)),
+                            (field("client-info"), RespValue::BulkString(entry.client_info.into_bytes())),
+                            (field("entry-id"), RespValue::Integer(entry.id as i64)),
+                            (field("timestamp-created"), RespValue::Integer(entry.created_ms as i64)),
+                            (field("timestamp-last-updated"), RespValue::Integer(entry.updated_ms as i64)),
+                        ])
+                    }).collect())
+                },
+                None => RespValue::Error("ERR value is out of range, must be positive".to_string()),
+            },
+            ("SETUSER" | "GETUSER" | "DELUSER" | "LIST" | "USERS" | "WHOAMI" | "CAT" | "LOAD" | "SAVE" | "LOG", _) => {
+                RespValue::Error(format!("ERR wrong number of arguments for 'acl|{}' command", subcommand.to_lowercase()))
+            },
+            _ => RespValue::Error(format!("ERR unknown subcommand '{}'. Try ACL HELP.", arg_to_string(&args[1]))),
+        };
+        conn.write_reply(&acl_resp)?;
+        Ok(())
+    }
+
+    fn handle_ping_cmd(conn: &mut Connection, _args: &[Bytes], _state: &ServerState) -> io::Result<()> {
+        /* Write to stream the response for PING commands */
+        let ping_resp = RespValue::SimpleString(b"PONG".to_vec());
+        conn.write_reply(&ping_resp)?;
+        Ok(())
+    }
+
+    fn handle_echo_cmd(conn: &mut Connection, args: &[Bytes], _state: &ServerState) -> io::Result<()> {
+        /* Fetch the echo output and write it to stream */
+        let echo_resp = RespValue::BulkString(args[1].to_vec());
+        conn.write_reply(&echo_resp)?;
+        Ok(())
+    }
+
+    fn handle_quit_cmd(conn: &mut Connection, _args: &[Bytes], _state: &ServerState) -> io::Result<()> {
+        /* Reply OK and have handle_connection close the connection, after writing this and any earlier replies */
+        conn.write_reply(&RespValue::ok())?;
+        conn.closing = true;
+        Ok(())
+    }
+
+    fn handle_shutdown_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        SHUTDOWN [NOSAVE|SAVE] stops the server, closing the connection without a reply
+        Like in Redis, a snapshot is saved first if there are save points (or SAVE is given, but not with NOSAVE); if
+        that fails, the server keeps running and the client gets an error instead. Nothing is saved while the dataset is
+        still loading, which would overwrite the snapshot with what little has been loaded.
+        */
+        let save = match args.get(1).map(|mode| arg_to_string(mode).to_uppercase()).as_deref() {
+            None => !state.lock_save_points().is_empty(),
+            Some("SAVE") => true,
+            Some("NOSAVE") => false,
+            Some(_) => {
+                conn.write_reply(&RespValue::Error("ERR syntax error".to_string()))?;
+                return Ok(());
+            },
+        };
+        if save && state.loading.load(Ordering::Relaxed) {
+            warn!("Not saving the RDB snapshot before exiting, since the dataset is still loading.");
+        } else if save {
+            info!("Saving the final RDB snapshot before exiting.");
+            if let Err(err) = rdb::save(&state.rdb_path, state.lock_all_dbs().iter().map(|c| c.iter())) {
+                error!("Error trying to save the DB, can't exit: {}", err);
+                conn.write_reply(&RespValue::Error("ERR Errors trying to SHUTDOWN. Check logs.".to_string()))?;
+                return Ok(());
+            }
+            info!("DB saved on disk");
+        }
+        info!("Redis is now ready to exit, bye bye...");
+        state.request_shutdown();
+        conn.closing = true;
+        Ok(())
+    }
+
+    fn curr_time_ms() -> u128 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+    }
+
+    fn get_live_val<'a>(state: &ServerState, c: &'a mut KeyspaceGuard, key: &[u8]) -> Option<&'a mut RedisValue> {
+        /*
+        Look up a key in an already locked cache, removing it if it has expired.
+        Handlers that touch several keys use this so that they only take the lock once.
+        Hash fields with TTLs of their own are expired here too, and the hash is removed if that empties it.
+        */
+        let curr_time = Self::curr_time_ms();
+        let expired = match c.get_mut(key) {
+            Some(KeyEntry { expiry_ts: Some(expiry), .. }) if curr_time > *expiry => {
+                trace!("Curr time: {} and expiry ts: {}", curr_time, expiry);
+                true
+            },
+            Some(KeyEntry { value: RedisValue::Hash(hash), .. }) => hash.remove_expired_fields(curr_time) > 0 && hash.len() == 0,
+            _ => false,
+        };
+        if expired {
+            c.remove(key);
+            state.stats.expired_keys.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        c.get_mut(key).map(|entry| &mut entry.value)
+    }
+
+    fn get_key<T>(state: &ServerState, db: usize, key: &[u8], read: impl FnOnce(Option<&RedisValue>) -> T) -> T {
+        /* Look up a key for a read command, counting the lookup as a keyspace hit or miss for INFO */
+        Self::lookup_key(state, db, key, |val| {
+            let counter = if val.is_some() { &state.stats.keyspace_hits } else { &state.stats.keyspace_misses };
+            counter.fetch_add(1, Ordering::Relaxed);
+            read(val)
+        })
+    }
+
+    fn lookup_key<T>(state: &ServerState, db: usize, key: &[u8], read: impl FnOnce(Option<&RedisValue>) -> T) -> T {
+        /*
+        Get the data from the given database for the given key, and run read against it (None if it's missing or expired)
+        This method of expiration is PASSIVE; keys are only expired when they're accessed.
+        On its own, this would let the cache fill up with stale keys that are never read again,
+        so active_expire_cycle also removes expired keys periodically: https://redis.io/commands/expire/#how-redis-expires-keys
+        Only the key's shard's read lock is taken, so GETs don't wait on each other, and read runs against the value in
+        place while it's held, so read must not lock any keys itself. Readers can't remove anything, so an expired key
+        (or hash field) is left out and queued for remove_queued_expired to remove instead; a hash that still has
+        expired fields is the one value that's copied, to read it without them.
+        */
+        let curr_time = Self::curr_time_ms();
+        let c = state.read_keys(db, [key]);
+        let Some(entry) = c.get(key) else {
+            return read(None);
+        };
+        if entry.expiry_ts.is_some_and(|expiry_ts| curr_time > expiry_ts) {
+            state.dbs[db].queue_expired(key);
+            return read(None);
+        }
+        match &entry.value {
+            RedisValue::Hash(hash) if hash.num_expired_fields(curr_time) > 0 => {
+                let mut hash = hash.clone();
+                drop(c);
+                state.dbs[db].queue_expired(key);
+                hash.remove_expired_fields(curr_time);
+                match hash.len() {
+                    0 => read(None),
+                    _ => read(Some(&RedisValue::Hash(hash))),
+                }
+            },
+            val => read(Some(val)),
+        }
+    }
+
+    fn key_exists(state: &ServerState, db: usize, key: &[u8]) -> bool {
+        /*
+        Whether key exists and hasn't expired, counted as a keyspace hit or miss like get_key
+        Unlike get_key, this never copies anything, not even a hash with expired fields, whose other fields are just counted.
+        */
+        let curr_time = Self::curr_time_ms();
+        let c = state.read_keys(db, [key]);
+        let (exists, has_expired) = match c.get(key) {
+            Some(entry) if entry.expiry_ts.is_some_and(|expiry_ts| curr_time > expiry_ts) => (false, true),
+            Some(KeyEntry { value: RedisValue::Hash(hash), .. }) => {
+                let num_expired_fields = hash.num_expired_fields(curr_time);
+                (hash.len() > num_expired_fields, num_expired_fields > 0)
+            },
+            entry => (entry.is_some(), false),
+        };
+        drop(c);
+        if has_expired {
+            state.dbs[db].queue_expired(key);
+        }
+        let counter = if exists { &state.stats.keyspace_hits } else { &state.stats.keyspace_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        exists
+    }
+
+    fn remove_queued_expired(state: &ServerState) -> usize {
+        /* Remove the expired keys (and hash fields) that readers have come across since the last call, returning how many */
+        let mut num_queued = 0;
+        for (db, keyspace) in state.dbs.iter().enumerate() {
+            let queued = keyspace.take_expired();
+            num_queued += queued.len();
+            for key in queued {
+                // Looking the key up with the shard locked for writing is what removes it, if it's still expired by now
+                Self::get_live_val(state, &mut state.lock_keys(db, [&key]), &key);
+            }
+        }
+        num_queued
+    }
+
+    fn active_expire_cycle(state: &ServerState) -> usize {
+        /*
+        Remove every expired key (and expired hash field), returning the number of keys removed
+        Unlike Redis, which samples random keys with a TTL, this scans the whole keyspace; that's fine at this scale.
+        It locks one shard (of one database) at a time, so commands on the other shards carry on meanwhile.
+        */
+        let curr_time = Self::curr_time_ms();
+        let mut num_expired = 0;
+        let shards = state.dbs.iter().flat_map(|keyspace| (0..keyspace.num_shards()).map(move |shard_idx| (keyspace, shard_idx)));
+        for (keyspace, shard_idx) in shards {
+            let mut shard = keyspace.lock_shard(shard_idx);
+            let num_keys = shard.len();
+            shard.retain(|_, entry| match &mut entry.value {
+                _ if entry.expiry_ts.is_some_and(|expiry_ts| curr_time > expiry_ts) => false,
+                RedisValue::Hash(hash) => hash.remove_expired_fields(curr_time) == 0 || hash.len() > 0,
+                _ => true,
+            });
+            num_expired += num_keys - shard.len();
+        }
+        state.stats.expired_keys.fetch_add(num_expired as u64, Ordering::Relaxed);
+        num_expired
+    }
+
+    fn shrink_keyspace(state: &ServerState) -> bool {
+        /*
+        Give memory back after mass deletions, returning whether any of the databases' shards was shrunk
+        Like Redis's htNeedsResize, this only kicks in for a shard once less than 10% of its buckets are in use.
+        */
+        let mut shrunk = false;
+        let shards = state.dbs.iter().flat_map(|keyspace| (0..keyspace.num_shards()).map(move |shard_idx| (keyspace, shard_idx)));
+        for (keyspace, shard_idx) in shards {
+            let mut shard = keyspace.lock_shard(shard_idx);
+            if shard.capacity() > MIN_KEYSPACE_CAPACITY && shard.len() * 10 < shard.capacity() {
+                shard.shrink_to(MIN_KEYSPACE_CAPACITY);
+                shrunk = true;
+            }
+        }
+        shrunk
+    }
+
+    fn replace_dbs(dbs: &mut [KeyspaceGuard], mut loaded: HashMap<usize, CacheMap>) -> Result<(), String> {
+        /* Swap the contents of the (fully locked) databases for the ones just loaded from a snapshot, by number */
+        if let Some(db) = loaded.keys().find(|db| **db >= dbs.len()) {
+            return Err(format!("the snapshot has database {}, but there are only {} databases", db, dbs.len()));
+        }
+        for (db, c) in dbs.iter_mut().enumerate() {
+            c.replace_all(loaded.remove(&db).unwrap_or_default());
+        }
+        Ok(())
+    }
+
+    fn load_dataset(state: &ServerState) -> anyhow::Result<()> {
+        /* Load the snapshot the server started with, all at once, and let clients at it */
+        let load_start = Instant::now();
+        let loaded = rdb::load_file(&state.rdb_path, &state.encoding_limits, Self::curr_time_ms())
+            .map_err(|err| anyhow::anyhow!("Failed to load {}: {}", state.rdb_path.display(), err))?;
+        let num_keys = loaded.values().map(|cache| cache.len()).sum::<usize>();
+        Self::replace_dbs(&mut state.lock_all_dbs(), loaded)
+            .map_err(|err| anyhow::anyhow!("Failed to load {}: {}", state.rdb_path.display(), err))?;
+        state.loading.store(false, Ordering::Relaxed);
+        info!("DB loaded from disk: {} keys in {:.3} seconds", num_keys, load_start.elapsed().as_secs_f64());
+        Ok(())
+    }
+
+    fn notify_ready(state: &ServerState) {
+        /* Tell systemd that the server is serving clients, with the dataset loaded, if it's supervising the server */
+        if state.supervised {
+            if let Err(err) = daemon::notify_systemd("STATUS=Ready to accept connections\nREADY=1\n") {
+                warn!("Failed to notify systemd: {}", err);
+            }
+        }
+    }
+
+    fn save_if_due(state: &ServerState) {
+        /*
+        Snapshot the databases once a save point is reached, i.e. enough writes happened in enough time since the last one
+        The snapshot is written while holding every shard's lock, like SAVE rather than BGSAVE. A failed save is logged and
+        retried once a save point is reached again.
+        */
+        let dirty = state.dirty.load(Ordering::Relaxed);
+        let mut last_save = state.last_save.lock().unwrap_or_else(|err| {
+            panic!("Failed to lock last save mutex: {}!", err);
+        });
+        let elapsed_secs = last_save.elapsed().as_secs();
+        if !state.lock_save_points().iter().any(|point| dirty >= point.changes && elapsed_secs >= point.secs) {
+            return;
+        }
+        let save_start = Instant::now();
+        let save_result = rdb::save(&state.rdb_path, state.lock_all_dbs().iter().map(|c| c.iter()));
+        state.latency_monitor.add_sample_if_needed("save", save_start.elapsed());
+        match save_result {
+            Ok(()) => {
+                info!("{} changes in {} seconds. DB saved on disk", dirty, elapsed_secs);
+                // Only the writes seen before saving are subtracted, so any counted since carry over to the next snapshot
+                state.dirty.fetch_sub(dirty, Ordering::Relaxed);
+            },
+            Err(err) => error!("Error saving {}: {}", state.rdb_path.display(), err),
+        }
+        *last_save = Instant::now();
+    }
+
+    fn register_cron_jobs(state: &ServerState) {
+        /* The maintenance jobs every server runs; see server_cron_loop */
+        // Passive expiry for reads, so it runs even while active expiry is turned off. Like in Redis, no key expires
+        // while CLIENT PAUSE holds up writes, so that the dataset doesn't change.
+        state.register_cron_job("expire-queued", |state| {
+            if state.clients.paused_until(true).is_some() {
+                return;
+            }
+            let num_queued = Self::remove_queued_expired(state);
+            if num_queued > 0 {
+                trace!("Removed {} expired keys queued by readers", num_queued);
+            }
+        });
+        state.register_cron_job("active-expire", |state| {
+            if state.active_expire_enabled.load(Ordering::Relaxed) && state.clients.paused_until(true).is_none() {
+                let cycle_start = Instant::now();
+                let num_expired = Self::active_expire_cycle(state);
+                state.latency_monitor.add_sample_if_needed("expire-cycle", cycle_start.elapsed());
+                if num_expired > 0 {
+                    debug!("Actively expired {} keys", num_expired);
+                }
+            }
+        });
+        state.register_cron_job("shrink-keyspace", |state| {
+            if Self::shrink_keyspace(state) {
+                debug!("Shrank the keyspace");
+            }
+        });
+        // Like in Redis, clients are only disconnected for being idle while they wait for a command, never mid-command
+        state.register_cron_job("client-timeout", |state| {
+            let timeout = state.timeout.load(Ordering::Relaxed);
+            if timeout == 0 {
+                return;
+            }
+            for client in state.clients.list() {
+                if client.timed_out(Duration::from_secs(timeout)) {
+                    debug!("Closing idle client {}", client.addr);
+                    client.kill();
+                }
+            }
+        });
+        state.register_cron_job("auto-save", |state| {
+            Self::save_if_due(state);
+        });
+    }
+
+    fn run_cron_jobs(state: &ServerState) {
+        for (name, job) in state.lock_cron_jobs().iter() {
+            trace!("Running cron job {}", name);
+            job(state);
+        }
+    }
+
+    async fn server_cron_loop(state: Arc<ServerState>) {
+        /*
+        Background task that runs every registered cron job hz times a second, like Redis's serverCron
+        Having one timer for all periodic maintenance keeps the cadence in one place, and picks up CONFIG SET hz on the
+        next tick.
+        */
+        let mut hz = state.hz.load(Ordering::Relaxed);
+        let mut interval = tokio::time::interval(Duration::from_millis(1000 / hz));
+        loop {
+            interval.tick().await;
+            if state.shutting_down.load(Ordering::Relaxed) {
+                break;
+            }
+            Self::run_cron_jobs(&state);
+            let new_hz = state.hz.load(Ordering::Relaxed);
+            if new_hz != hz {
+                hz = new_hz;
+                interval = tokio::time::interval(Duration::from_millis(1000 / hz));
+                // The first tick of a new interval completes immediately; skip it so the jobs don't run twice in a row
+                interval.tick().await;
+            }
+        }
+    }
+
+    fn handle_get_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Fetch the data from GET request and return data from cache to user */
+        let get_resp = Self::get_key(state, conn.db, &args[1], |val| match val.map(RedisValue::as_string) {
+            Some(Some(v)) => RespValue::BulkString(v.to_vec()),
+            Some(None) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+            None => RespValue::Null,
+        });
+        conn.write_reply(&get_resp)?;
+        Ok(())
+    }
+
+    fn handle_getrange_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Reply with the substring of the string at key between start and end (inclusive, negative indices count from the end)
+        Also serves SUBSTR, its deprecated alias. A missing key is treated as an empty string.
+        */
+        let (Some(start), Some(end)) = (parse_arg::<i64>(&args[2]), parse_arg::<i64>(&args[3])) else {
+            let getrange_err_response = RespValue::Error("ERR value is not an integer or out of range".to_string());
+            conn.write_reply(&getrange_err_response)?;
+            return Ok(());
+        };
+        let getrange_resp = Self::get_key(state, conn.db, &args[1], |val| match val.map(RedisValue::as_string) {
+            Some(Some(val)) => match normalize_range(start, end, val.len()) {
+                Some((start, end)) => RespValue::BulkString(val[start..=end].to_vec()),
+                None => RespValue::BulkString(b"".to_vec()),
+            },
+            Some(None) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+            None => RespValue::BulkString(b"".to_vec()),
+        });
+        conn.write_reply(&getrange_resp)?;
+        Ok(())
+    }
+
+    fn handle_set_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        SET key value [NX|XX] [GET] [EX seconds|PX milliseconds|EXAT unix-time-seconds|PXAT unix-time-milliseconds|KEEPTTL]
+        Write the value to the server cache, replacing whatever the key held and, unless KEEPTTL is given, its TTL.
+        Replies with OK, or nil if NX/XX kept the value from being set. With GET, replies with the key's old string instead.
+        */
+        let opts = match SetOptions::parse(&args[3..], Self::curr_time_ms()) {
+            Ok(opts) => opts,
+            Err(opts_err) => {
+                let set_err_response = RespValue::Error(opts_err);
+                conn.write_reply(&set_err_response)?;
+                return Ok(());
+            }
+        };
+        let key = args[1].clone();
+        let mut c = state.lock_keys(conn.db, [&key]);
+        let (exists, old_val) = match Self::get_live_val(state, &mut c, &key).map(|val| val.as_string()) {
+            Some(Some(old_val)) => (true, opts.get.then_some(old_val)),
+            Some(None) if opts.get => {
+                let set_err_response = RespValue::Error(WRONGTYPE_ERR.to_string());
+                conn.write_reply(&set_err_response)?;
+                return Ok(());
+            },
+            Some(None) => (true, None),
+            None => (false, None),
+        };
+        // NX only sets a missing key and XX only an existing one
+        let is_set = if exists { !opts.nx } else { !opts.xx };
+        if is_set {
+            let expiry_ts = if opts.keep_ttl { c.get(&key).and_then(|entry| entry.expiry_ts) } else { opts.expiry_ts };
+            trace!("Key: {:?}, val: {:?}, expiry ts: {:?}", key, args[2], expiry_ts);
+            c.insert(key, KeyEntry::new(RedisValue::String(args[2].clone()), expiry_ts));
+        }
+        drop(c);
+        let set_resp = match old_val {
+            Some(old_val) => RespValue::BulkString(old_val.to_vec()),
+            None if opts.get || !is_set => RespValue::Null,
+            None => RespValue::ok(),
+        };
+        conn.write_reply(&set_resp)?;
+        Ok(())
+    }
+
+    fn handle_mset_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* MSET key value [key value ...] sets every key at once, replacing whatever they held along with their TTLs */
+        if args.len() % 2 == 0 {
+            let mset_err_response = RespValue::Error("ERR wrong number of arguments for 'mset' command".to_string());
+            conn.write_reply(&mset_err_response)?;
+            return Ok(());
+        }
+        let mut c = state.lock_keys(conn.db, args[1..].iter().step_by(2));
+        for key_and_val in args[1..].chunks(2) {
+            c.insert(key_and_val[0].clone(), KeyEntry::new(RedisValue::String(key_and_val[1].clone()), None));
+        }
+        drop(c);
+        conn.write_reply(&RespValue::ok())?;
+        Ok(())
+    }
+
+    fn set_expiry(conn: &mut Connection, args: &[Bytes], state: &ServerState, unit_ms: i128, is_absolute: bool) -> io::Result<()> {
+        /*
+        Shared implementation of EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT
+        The time argument is in units of unit_ms, either relative to now or as an absolute unix timestamp.
+        Replies with 1 if the expiry was set (deleting the key if the time is already in the past) and 0 if
+        the key doesn't exist or the NX/XX/GT/LT conditions weren't met.
+        */
+        let cmd_name = arg_to_string(&args[0]).to_lowercase();
+        let expiry_ts = parse_arg::<i64>(&args[2])
+            .and_then(|when| (when as i128).checked_mul(unit_ms))
+            .map(|when_ms| if is_absolute { when_ms } else { when_ms + Self::curr_time_ms() as i128 });
+        let expiry_ts = match expiry_ts {
+            Some(expiry_ts) if expiry_ts <= i64::MAX as i128 => expiry_ts,
+            Some(_) => {
+                let expire_err_response = RespValue::Error(format!("ERR invalid expire time in '{}' command", cmd_name));
+                conn.write_reply(&expire_err_response)?;
+                return Ok(());
+            },
+            None => {
+                let expire_err_response = RespValue::Error("ERR value is not an integer or out of range".to_string());
+                conn.write_reply(&expire_err_response)?;
+                return Ok(());
+            }
+        };
+        let opts = match ExpireOptions::parse(&args[3..]) {
+            Ok(opts) => opts,
+            Err(opts_err) => {
+                let expire_err_response = RespValue::Error(opts_err);
+                conn.write_reply(&expire_err_response)?;
+                return Ok(());
+            }
+        };
+
+        let key = args[1].clone();
+        let mut c = state.lock_keys(conn.db, [&key]);
+        let was_set = match Self::get_live_val(state, &mut c, &key) {
+            Some(_) => {
+                let curr_expiry_ts = &mut c.get_mut(&key).unwrap().expiry_ts;
+                if !opts.allows(*curr_expiry_ts, expiry_ts) {
+                    false
+                } else if expiry_ts <= Self::curr_time_ms() as i128 {
+                    c.remove(&key);
+                    true
+                } else {
+                    *curr_expiry_ts = Some(expiry_ts as u128);
+                    true
+                }
+            },
+            None => false,
+        };
+        let expire_resp = RespValue::Integer(was_set as u8 as i64);
+        conn.write_reply(&expire_resp)?;
+        Ok(())
+    }
+
+    fn handle_expire_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Set a key's time to live in seconds */
+        Self::set_expiry(conn, args, state, 1000, false)
+    }
+
+    fn handle_pexpire_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Set a key's time to live in milliseconds */
+        Self::set_expiry(conn, args, state, 1, false)
+    }
+
+    fn handle_expireat_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Set a key to expire at a unix timestamp in seconds */
+        Self::set_expiry(conn, args, state, 1000, true)
+    }
+
+    fn handle_pexpireat_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Set a key to expire at a unix timestamp in milliseconds */
+        Self::set_expiry(conn, args, state, 1, true)
+    }
+
+    fn get_expiry(conn: &mut Connection, args: &[Bytes], state: &ServerState, unit_ms: u128, is_absolute: bool) -> io::Result<()> {
+        /*
+        Shared implementation of TTL, PTTL, EXPIRETIME and PEXPIRETIME
+        Replies with the key's expiry in units of unit_ms, either as the time left or as the absolute unix timestamp,
+        -1 if the key has no expiry, or -2 if the key doesn't exist.
+        */
+        let key = args[1].clone();
+        let mut c = state.lock_keys(conn.db, [&key]);
+        let expiry = match Self::get_live_val(state, &mut c, &key) {
+            Some(_) => match c.get(&key).and_then(|entry| entry.expiry_ts) {
+                Some(expiry_ts) => {
+                    let expiry_ms = if is_absolute { expiry_ts } else { expiry_ts.saturating_sub(Self::curr_time_ms()) };
+                    // Round to the nearest unit like Redis does
+                    ((expiry_ms + unit_ms / 2) / unit_ms) as i128
+                },
+                None => -1,
+            },
+            None => -2,
+        };
+        let expiry_resp = RespValue::Integer(expiry as i64);
+        conn.write_reply(&expiry_resp)?;
+        Ok(())
+    }
+
+    fn handle_ttl_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with a key's remaining time to live in seconds */
+        Self::get_expiry(conn, args, state, 1000, false)
+    }
+
+    fn handle_pttl_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with a key's remaining time to live in milliseconds */
+        Self::get_expiry(conn, args, state, 1, false)
+    }
+
+    fn handle_expiretime_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with the unix timestamp in seconds at which a key expires; this is the stored expiry as is */
+        Self::get_expiry(conn, args, state, 1000, true)
+    }
+
+    fn handle_pexpiretime_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with the unix timestamp in milliseconds at which a key expires */
+        Self::get_expiry(conn, args, state, 1, true)
+    }
+
+    fn remove_keys(conn: &mut Connection, args: &[Bytes], state: &ServerState, lazy: bool) -> io::Result<()> {
+        /*
+        Shared implementation of DEL and UNLINK, replying with how many of the keys existed and were removed
+        Either way the keys are gone once this returns; lazily (UNLINK), values big enough to take a while to free are
+        handed to the lazyfree thread instead, like FLUSHDB ASYNC does with whole databases.
+        */
+        let mut c = state.lock_keys(conn.db, &args[1..]);
+        let mut num_removed = 0;
+        let mut unlinked = Vec::new();
+        for key in &args[1..] {
+            if Self::get_live_val(state, &mut c, key).is_none() {
+                continue;
+            }
+            if let Some(entry) = c.remove(key) {
+                num_removed += 1;
+                if lazy && entry.value.free_effort() > LAZYFREE_THRESHOLD {
+                    unlinked.push(entry);
+                }
+            }
+        }
+        drop(c);
+        if !unlinked.is_empty() {
+            let num_unlinked = unlinked.len() as u64;
+            state.lazyfree.free(unlinked, num_unlinked);
+        }
+        conn.write_reply(&RespValue::Integer(num_removed))?;
+        Ok(())
+    }
+
+    fn handle_del_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Remove the given keys, replying with how many existed */
+        Self::remove_keys(conn, args, state, false)
+    }
+
+    fn handle_unlink_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Like DEL, but big values are freed in the background so that removing them doesn't hold up other clients */
+        Self::remove_keys(conn, args, state, true)
+    }
+
+    fn handle_exists_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with how many of the keys exist and haven't expired; like in Redis, a key given twice is counted twice */
+        let num_existing = args[1..].iter().filter(|key| Self::key_exists(state, conn.db, key)).count();
+        conn.write_reply(&RespValue::Integer(num_existing as i64))?;
+        Ok(())
+    }
+
+    fn handle_type_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with the type of the value stored at key, or none if it doesn't exist */
+        let mut c = state.lock_keys(conn.db, [&args[1]]);
+        let type_name = Self::get_live_val(state, &mut c, &args[1]).map_or("none", |val| val.type_name());
+        let type_resp = RespValue::SimpleString(type_name.as_bytes().to_vec());
+        conn.write_reply(&type_resp)?;
+        Ok(())
+    }
+
+    fn handle_keys_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with every key matching the glob-style pattern */
+        let mut c = state.lock_db(conn.db);
+        let matching_keys = c.keys().filter(|key| glob_match(&args[1], key)).cloned().collect::<Vec<Bytes>>();
+        let live_keys = matching_keys.into_iter()
+            .filter(|key| Self::get_live_val(state, &mut c, key).is_some())
+            .map(|key| key.to_vec())
+            .collect::<Vec<Vec<u8>>>();
+        let keys_resp = RespValue::bulk_strings(&live_keys);
+        conn.write_reply(&keys_resp)?;
+        Ok(())
+    }
+
+    fn handle_scan_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        SCAN cursor [MATCH pattern] [COUNT count]
+        Iterate over the keyspace a few keys at a time, replying with [next cursor, keys]; a next cursor of 0 means the
+        iteration is done. The cursor is where the keyspace's scan order carries on (see Keyspace::scan), so like in
+        Redis, a key that exists for the whole iteration is returned at least once however the keyspace changes in
+        between. COUNT (10 by default) is how many keys to visit per call, and MATCH filters those after they're visited.
+        */
+        let Some(cursor) = parse_arg::<u64>(&args[1]) else {
+            let scan_err_response = RespValue::Error("ERR invalid cursor".to_string());
+            conn.write_reply(&scan_err_response)?;
+            return Ok(());
+        };
+        let (mut pattern, mut count) = (None, 10);
+        for option in args[2..].chunks(2) {
+            let scan_err = match (arg_to_string(&option[0]).to_uppercase().as_str(), option.get(1)) {
+                ("MATCH", Some(match_pattern)) => {
+                    pattern = Some(match_pattern);
+                    None
+                },
+                ("COUNT", Some(raw_count)) => match parse_arg::<i64>(raw_count) {
+                    Some(parsed_count) if parsed_count >= 1 => {
+                        count = parsed_count as usize;
+                        None
+                    },
+                    Some(_) => Some("ERR syntax error"),
+                    None => Some("ERR value is not an integer or out of range"),
+                },
+                _ => Some("ERR syntax error"),
+            };
+            if let Some(scan_err) = scan_err {
+                let scan_err_response = RespValue::Error(scan_err.to_string());
+                conn.write_reply(&scan_err_response)?;
+                return Ok(());
+            }
+        }
+
+        let (visited_keys, next_cursor) = state.dbs[conn.db].scan(cursor, count);
+        // Keys may have been removed (or have expired) since they were visited, which get_live_val catches
+        let mut c = state.lock_keys(conn.db, &visited_keys);
+        let keys = visited_keys.into_iter()
+            .filter(|key| pattern.map_or(true, |pattern| glob_match(pattern, key)))
+            .filter(|key| Self::get_live_val(state, &mut c, key).is_some())
+            .map(|key| key.to_vec())
+            .collect::<Vec<Vec<u8>>>();
+        let scan_resp = RespValue::Array(vec![
+            RespValue::BulkString(next_cursor.to_string().into_bytes()),
+            RespValue::bulk_strings(&keys),
+        ]);
+        conn.write_reply(&scan_resp)?;
+        Ok(())
+    }
+
+    fn parse_db_index(arg: &[u8], state: &ServerState) -> Result<usize, RespValue> {
+        /* A database number for SELECT, SWAPDB and MOVE */
+        match parse_arg::<i64>(arg) {
+            Some(db) if db >= 0 && (db as usize) < state.dbs.len() => Ok(db as usize),
+            Some(_) => Err(RespValue::Error("ERR DB index is out of range".to_string())),
+            None => Err(RespValue::Error("ERR value is not an integer or out of range".to_string())),
+        }
+    }
+
+    fn handle_select_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Switch the connection to another database; keys are per database, so the same key can exist in each */
+        let select_resp = match Self::parse_db_index(&args[1], state) {
+            Ok(db) => {
+                conn.db = db;
+                RespValue::ok()
+            },
+            Err(select_err_response) => select_err_response,
+        };
+        conn.write_reply(&select_resp)?;
+        Ok(())
+    }
+
+    fn handle_swapdb_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Swap the contents of two databases, so that connections using one see the other's keys from then on
+        Every key of both counts as modified for WATCH, and clients blocked on either database check their keys again.
+        */
+        let (db, other_db) = match (Self::parse_db_index(&args[1], state), Self::parse_db_index(&args[2], state)) {
+            (Ok(db), Ok(other_db)) => (db.min(other_db), db.max(other_db)),
+            (Err(swapdb_err_response), _) | (_, Err(swapdb_err_response)) => {
+                conn.write_reply(&swapdb_err_response)?;
+                return Ok(());
+            },
+        };
+        if db != other_db {
+            // Lower database first, like every command that locks more than one
+            let mut c = state.lock_db(db);
+            let mut other_c = state.lock_db(other_db);
+            for key in c.keys().chain(other_c.keys()) {
+                state.mark_modified(db, key);
+                state.mark_modified(other_db, key);
+            }
+            c.swap(&mut other_c);
+            drop((c, other_c));
+            state.dbs[db].notify_writes();
+            state.dbs[other_db].notify_writes();
+        }
+        conn.write_reply(&RespValue::ok())?;
+        Ok(())
+    }
+
+    fn handle_move_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Move a key (with its TTL) to another database, replying 1, or 0 if it doesn't exist or the other database already has it */
+        let dest_db = match Self::parse_db_index(&args[2], state) {
+            Ok(dest_db) if dest_db != conn.db => dest_db,
+            Ok(_) => {
+                let move_err_response = RespValue::Error("ERR source and destination objects are the same".to_string());
+                conn.write_reply(&move_err_response)?;
+                return Ok(());
+            },
+            Err(move_err_response) => {
+                conn.write_reply(&move_err_response)?;
+                return Ok(());
+            },
+        };
+        let key = &args[1];
+        // Lower database first, like every command that locks more than one
+        let (mut c, mut dest_c) = if conn.db < dest_db {
+            let c = state.lock_keys(conn.db, [key]);
+            (c, state.lock_keys(dest_db, [key]))
+        } else {
+            let dest_c = state.lock_keys(dest_db, [key]);
+            (state.lock_keys(conn.db, [key]), dest_c)
+        };
+        let movable = Self::get_live_val(state, &mut c, key).is_some() && Self::get_live_val(state, &mut dest_c, key).is_none();
+        if movable {
+            if let Some(entry) = c.remove(key) {
+                dest_c.insert(key.clone(), entry);
+            }
+            state.mark_modified(dest_db, key);
+        }
+        drop((c, dest_c));
+        if movable {
+            state.dbs[dest_db].notify_writes();
+        }
+        conn.write_reply(&RespValue::Integer(movable as i64))?;
+        Ok(())
+    }
+
+    fn flush_dbs(state: &ServerState, dbs: &mut [KeyspaceGuard], db_nums: impl Iterator<Item = usize>, lazy: bool) {
+        /*
+        Remove every key of the given (fully locked) databases, counting them all as modified for WATCH
+        Either way the databases are empty once this returns; lazily (ASYNC), what they held is handed to the lazyfree
+        thread instead, so that dropping a huge keyspace doesn't hold up every client waiting on its locks.
+        */
+        let mut flushed = Vec::new();
+        let mut num_flushed = 0;
+        for (c, db) in dbs.iter_mut().zip(db_nums) {
+            for key in c.keys() {
+                state.mark_modified(db, key);
+            }
+            num_flushed += c.len() as u64;
+            flushed.extend(c.take_all());
+        }
+        if lazy {
+            state.lazyfree.free(flushed, num_flushed);
+        }
+    }
+
+    fn parse_flush_mode(mode: Option<&Bytes>) -> Option<bool> {
+        /* Whether FLUSHDB or FLUSHALL frees the keys lazily: ASYNC does, SYNC (the default) doesn't */
+        match mode.map(|mode| arg_to_string(mode).to_uppercase()).as_deref() {
+            None | Some("SYNC") => Some(false),
+            Some("ASYNC") => Some(true),
+            Some(_) => None,
+        }
+    }
+
+    fn handle_flushdb_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* FLUSHDB [ASYNC|SYNC] removes every key of the connection's database */
+        let flushdb_resp = match Self::parse_flush_mode(args.get(1)) {
+            Some(lazy) => {
+                Self::flush_dbs(state, &mut [state.lock_db(conn.db)], [conn.db].into_iter(), lazy);
+                RespValue::ok()
+            },
+            None => RespValue::Error("ERR syntax error".to_string()),
+        };
+        conn.write_reply(&flushdb_resp)?;
+        Ok(())
+    }
+
+    fn handle_flushall_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* FLUSHALL [ASYNC|SYNC] removes every key of every database, all at once */
+        let flushall_resp = match Self::parse_flush_mode(args.get(1)) {
+            Some(lazy) => {
+                Self::flush_dbs(state, &mut state.lock_all_dbs(), 0..state.dbs.len(), lazy);
+                RespValue::ok()
+            },
+            None => RespValue::Error("ERR syntax error".to_string()),
+        };
+        conn.write_reply(&flushall_resp)?;
+        Ok(())
+    }
+
+    fn handle_dbsize_cmd(conn: &mut Connection, _args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with the number of keys in the connection's database, leaving out those that have expired but are yet to be removed */
+        let curr_time = Self::curr_time_ms();
+        let num_keys = state.lock_db(conn.db).iter()
+            .filter(|(_, entry)| !entry.expiry_ts.is_some_and(|expiry_ts| curr_time > expiry_ts))
+            .count();
+        conn.write_reply(&RespValue::Integer(num_keys as i64))?;
+        Ok(())
+    }
+
+    fn handle_bitop_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Perform a bitwise operation (AND, OR, XOR, NOT) over the source keys and store the result in destkey
+        Shorter (or missing) source values are treated as zero-padded up to the longest value.
+        Replies with the length of the stored result in bytes; an empty result deletes destkey.
+        */
+        let op = arg_to_string(&args[1]).to_uppercase();
+        let dest_key = args[2].clone();
+        let src_keys = &args[3..];
+        if op == "NOT" && src_keys.len() != 1 {
+            let bitop_err_response = RespValue::Error("ERR BITOP NOT must be called with a single source key.".to_string());
+            conn.write_reply(&bitop_err_response)?;
+            return Ok(());
+        }
+
+        let mut c = state.lock_keys(conn.db, &args[2..]);
+        let mut src_vals = Vec::with_capacity(src_keys.len());
+        for key in src_keys {
+            match Self::get_live_val(state, &mut c, key).map(|val| val.as_string()) {
+                Some(Some(val)) => src_vals.push(val),
+                Some(None) => {
+                    let bitop_err_response = RespValue::Error(WRONGTYPE_ERR.to_string());
+                    conn.write_reply(&bitop_err_response)?;
+                    return Ok(());
+                },
+                None => src_vals.push(Bytes::new()),
+            }
+        }
+        let result_len = src_vals.iter().map(|val| val.len()).max().unwrap_or(0);
+        let byte_at = |val: &Bytes, idx: usize| val.get(idx).copied().unwrap_or(0);
+        let result = match op.as_str() {
+            "AND" => (0..result_len)
+                .map(|idx| src_vals.iter().fold(0xff, |acc, val| acc & byte_at(val, idx)))
+                .collect::<Vec<u8>>(),
+            "OR" => (0..result_len)
+                .map(|idx| src_vals.iter().fold(0, |acc, val| acc | byte_at(val, idx)))
+                .collect::<Vec<u8>>(),
+            "XOR" => (0..result_len)
+                .map(|idx| src_vals.iter().fold(0, |acc, val| acc ^ byte_at(val, idx)))
+                .collect::<Vec<u8>>(),
+            "NOT" => src_vals[0].iter().map(|byte| !byte).collect::<Vec<u8>>(),
+            _ => {
+                let bitop_err_response = RespValue::Error("ERR syntax error".to_string());
+                conn.write_reply(&bitop_err_response)?;
+                return Ok(());
+            }
+        };
+        trace!("BITOP {} into {:?}: {:?}", op, dest_key, result);
+        if result.is_empty() {
+            c.remove(&dest_key);
+        } else {
+            c.insert(dest_key, KeyEntry::new(RedisValue::String(Bytes::from(result)), None));
+        }
+        let bitop_resp = RespValue::Integer(result_len as i64);
+        conn.write_reply(&bitop_resp)?;
+        Ok(())
+    }
+
+    fn handle_bitpos_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Find the position of the first bit set to 0 or 1 in the value at key, optionally within a byte range
+        Replies with the absolute bit position or -1 if no such bit exists in the range.
+        Like Redis, looking for a clear bit without an explicit end treats the value as padded with zeros,
+        so a value of all set bits returns the position just past the end of the string.
+        */
+        let bit = match args[2].as_ref() {
+            b"0" => 0,
+            b"1" => 1,
+            _ => {
+                let bitpos_err_response = RespValue::Error("ERR The bit argument must be 1 or 0.".to_string());
+                conn.write_reply(&bitpos_err_response)?;
+                return Ok(());
+            }
+        };
+        let range_args = match args[3..].iter().map(|arg| parse_arg::<i64>(arg)).collect::<Option<Vec<i64>>>() {
+            Some(range_args) => range_args,
+            None => {
+                let bitpos_err_response = RespValue::Error("ERR value is not an integer or out of range".to_string());
+                conn.write_reply(&bitpos_err_response)?;
+                return Ok(());
+            }
+        };
+
+        // Strings are Bytes, so taking the value out of the keyspace doesn't copy it
+        let val = Self::get_key(state, conn.db, &args[1], |val| match val {
+            Some(val) => val.as_string(),
+            None => Some(Bytes::new()),
+        });
+        let Some(val) = val else {
+            let bitpos_err_response = RespValue::Error(WRONGTYPE_ERR.to_string());
+            conn.write_reply(&bitpos_err_response)?;
+            return Ok(());
+        };
+        let val_len = val.len() as i64;
+        // A missing key is an empty string: there are no set bits, and the first clear bit is at 0
+        if val.is_empty() {
+            let bitpos_resp = RespValue::Integer(if bit == 1 { -1 } else { 0 } as i64);
+            conn.write_reply(&bitpos_resp)?;
+            return Ok(());
+        }
+        // Normalize the byte range the same way GETRANGE does: negative offsets count from the end
+        let normalize = |idx: i64| if idx < 0 { (val_len + idx).max(0) } else { idx };
+        let start = range_args.first().map_or(0, |start| normalize(*start));
+        let end = range_args.get(1).map_or(val_len - 1, |end| normalize(*end).min(val_len - 1));
+        let end_given = range_args.len() == 2;
+
+        let pos = if start > end {
+            None
+        } else {
+            val[start as usize..=end as usize]
+                .iter()
+                .enumerate()
+                .find_map(|(byte_idx, byte)| {
+                    let byte = if bit == 1 { *byte } else { !*byte };
+                    if byte == 0 {
+                        None
+                    } else {
+                        Some((start as usize + byte_idx) * 8 + byte.leading_zeros() as usize)
+                    }
+                })
+                .map(|pos| pos as i64)
+        };
+        let pos = match pos {
+            Some(pos) => pos,
+            None if bit == 0 && !end_given && start <= end => (end + 1) * 8,
+            None => -1,
+        };
+        let bitpos_resp = RespValue::Integer(pos);
+        conn.write_reply(&bitpos_resp)?;
+        Ok(())
+    }
+
+    fn handle_incrbyfloat_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Increment the float stored at key by the given increment, treating a missing key as 0
+        The result is stored as a string formatted like Redis (no trailing zeros or exponent) and any TTL is kept.
+        */
+        let key = args[1].clone();
+        let parse_float = |raw: &[u8]| parse_arg::<f64>(raw).filter(|num| !num.is_nan());
+
+        let mut c = state.lock_keys(conn.db, [&key]);
+        let curr_val = match Self::get_live_val(state, &mut c, &key).map(|val| val.as_string()) {
+            Some(Some(val)) => parse_float(&val),
+            Some(None) => {
+                let incr_err_response = RespValue::Error(WRONGTYPE_ERR.to_string());
+                conn.write_reply(&incr_err_response)?;
+                return Ok(());
+            },
+            None => Some(0.0),
+        };
+        let (curr_val, incr) = match (curr_val, parse_float(&args[2])) {
+            (Some(curr_val), Some(incr)) => (curr_val, incr),
+            _ => {
+                let incr_err_response = RespValue::Error("ERR value is not a valid float".to_string());
+                conn.write_reply(&incr_err_response)?;
+                return Ok(());
+            }
+        };
+        let new_val = curr_val + incr;
+        if !new_val.is_finite() {
+            let incr_err_response = RespValue::Error("ERR increment would produce NaN or Infinity".to_string());
+            conn.write_reply(&incr_err_response)?;
+            return Ok(());
+        }
+        // f64's Display impl never uses an exponent and already trims trailing zeros
+        let new_val = new_val.to_string();
+        let expiry_ts = c.get(&key).and_then(|entry| entry.expiry_ts);
+        c.insert(key, KeyEntry::new(RedisValue::String(Bytes::from(new_val.clone())), expiry_ts));
+
+        let incr_resp = RespValue::BulkString(new_val.as_bytes().to_vec());
+        conn.write_reply(&incr_resp)?;
+        Ok(())
+    }
+
+    fn push_elems(conn: &mut Connection, args: &[Bytes], state: &ServerState, at_front: bool) -> io::Result<()> {
+        /*
+        Shared implementation of LPUSH and RPUSH: push each element in turn onto the list at key, creating it if needed
+        Replies with the length of the list afterwards.
+        */
+        let key = args[1].clone();
+        let mut c = state.lock_keys(conn.db, [&key]);
+        if Self::get_live_val(state, &mut c, &key).is_none() {
+            c.insert(key.clone(), KeyEntry::new(RedisValue::List(RedisList::new()), None));
+        }
+        let push_resp = match Self::get_live_val(state, &mut c, &key) {
+            Some(RedisValue::List(list)) => {
+                for elem in &args[2..] {
+                    if at_front {
+                        list.push_front(elem.to_vec(), &state.encoding_limits);
+                    } else {
+                        list.push_back(elem.to_vec(), &state.encoding_limits);
+                    }
+                }
+                // Wake up any clients blocked in BLPOP and friends
+                state.dbs[conn.db].notify_writes();
+                RespValue::Integer(list.len() as i64)
+            },
+            _ => RespValue::Error(WRONGTYPE_ERR.to_string()),
+        };
+        conn.write_reply(&push_resp)?;
+        Ok(())
+    }
+
+    fn handle_lpush_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Insert elements at the head of the list */
+        Self::push_elems(conn, args, state, true)
+    }
+
+    fn handle_rpush_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Insert elements at the tail of the list */
+        Self::push_elems(conn, args, state, false)
+    }
+
+    fn pop_from_key(state: &ServerState, c: &mut KeyspaceGuard, key: &[u8], end: ListEnd, count: usize) -> Result<Option<Vec<Vec<u8>>>, ()> {
+        /*
+        Pop up to count elements from one end of the list at key, deleting the key if that empties the list
+        Every pop command goes through here. Returns None if the key doesn't exist and Err if it isn't a list.
+        */
+        match Self::get_live_val(state, c, key) {
+            Some(RedisValue::List(list)) => {
+                let (elems, is_empty) = list.pop_list(end, count);
+                if is_empty {
+                    c.remove(key);
+                }
+                Ok(Some(elems))
+            },
+            Some(_) => Err(()),
+            None => Ok(None),
+        }
+    }
+
+    fn pop_elems(conn: &mut Connection, args: &[Bytes], state: &ServerState, end: ListEnd) -> io::Result<()> {
+        /*
+        Shared implementation of LPOP and RPOP
+        Without a count, replies with the popped element; with one, replies with an array of up to count elements.
+        */
+        let count = match args.get(2) {
+            Some(count) => match parse_arg::<usize>(count) {
+                Some(count) => Some(count),
+                None => {
+                    let pop_err_response = RespValue::Error("ERR value is out of range, must be positive".to_string());
+                    conn.write_reply(&pop_err_response)?;
+                    return Ok(());
+                }
+            },
+            None => None,
+        };
+        let mut c = state.lock_keys(conn.db, [&args[1]]);
+        let pop_resp = match (Self::pop_from_key(state, &mut c, &args[1], end, count.unwrap_or(1)), count) {
+            (Ok(Some(elems)), Some(_)) => RespValue::bulk_strings(&elems),
+            (Ok(Some(elems)), None) => match elems.first() {
+                Some(elem) => RespValue::BulkString(elem.to_vec()),
+                None => RespValue::Null,
+            },
+            (Ok(None), Some(_)) => RespValue::NullArray,
+            (Ok(None), None) => RespValue::Null,
+            (Err(()), _) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+        };
+        conn.write_reply(&pop_resp)?;
+        Ok(())
+    }
+
+    fn handle_lpop_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Remove and return elements from the head of the list */
+        Self::pop_elems(conn, args, state, ListEnd::Left)
+    }
+
+    fn handle_rpop_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Remove and return elements from the tail of the list */
+        Self::pop_elems(conn, args, state, ListEnd::Right)
+    }
+
+    fn parse_mpop_args(args: &[Bytes]) -> Result<(Vec<Bytes>, ListEnd, usize), String> {
+        /* Parse `numkeys key [key ...] LEFT|RIGHT [COUNT count]`, the tail of LMPOP and BLMPOP */
+        let num_keys = match args.first().and_then(|num_keys| parse_arg::<usize>(num_keys)) {
+            Some(num_keys) if num_keys > 0 => num_keys,
+            _ => return Err("ERR numkeys should be greater than 0".to_string()),
+        };
+        let keys = match args.get(1..=num_keys) {
+            Some(keys) => keys.to_vec(),
+            None => return Err("ERR syntax error".to_string()),
+        };
+        let end = match args.get(num_keys + 1).map(|end| arg_to_string(end).to_uppercase()).as_deref() {
+            Some("LEFT") => ListEnd::Left,
+            Some("RIGHT") => ListEnd::Right,
+            _ => return Err("ERR syntax error".to_string()),
+        };
+        let count = match &args[num_keys + 2..] {
+            [] => 1,
+            [option_arg, count] if option_arg.eq_ignore_ascii_case(b"COUNT") => match parse_arg::<usize>(count) {
+                Some(count) if count > 0 => count,
+                _ => return Err("ERR count should be greater than 0".to_string()),
+            },
+            _ => return Err("ERR syntax error".to_string()),
+        };
+        Ok((keys, end, count))
+    }
+
+    fn lmpop_keys(args: &[Bytes]) -> Option<Vec<&Bytes>> {
+        /* LMPOP numkeys key [key ...] LEFT|RIGHT [COUNT count] */
+        numkeys_key_args(args, 1)
+    }
+
+    fn handle_lmpop_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Pop up to count elements from the first non-empty list among the keys, replying with [key, [elements]] */
+        let (keys, end, count) = match Self::parse_mpop_args(&args[1..]) {
+            Ok(mpop_args) => mpop_args,
+            Err(mpop_err) => {
+                let lmpop_err_response = RespValue::Error(mpop_err);
+                conn.write_reply(&lmpop_err_response)?;
+                return Ok(());
+            }
+        };
+        Self::block_until(conn, args, state, &keys, None, |c| Self::pop_first_nonempty(state, c, &keys, end, count, true))
+    }
+
+    fn parse_timeout(conn: &mut Connection, raw_timeout: &[u8]) -> io::Result<Option<f64>> {
+        /* Parse the timeout of a blocking command in seconds, replying with an error if it's invalid */
+        let timeout_err = match parse_arg::<f64>(raw_timeout) {
+            Some(timeout) if timeout >= 0.0 && timeout.is_finite() => return Ok(Some(timeout)),
+            Some(timeout) if timeout < 0.0 => "ERR timeout is negative",
+            _ => "ERR timeout is not a float or out of range",
+        };
+        let timeout_err_response = RespValue::Error(timeout_err.to_string());
+        conn.write_reply(&timeout_err_response)?;
+        Ok(None)
+    }
+
+    fn block_until<F>(conn: &mut Connection, args: &[Bytes], state: &ServerState, keys: &[Bytes], timeout_secs: Option<f64>, try_reply: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut KeyspaceGuard) -> Option<RespValue>,
+    {
+        /*
+        Shared body of the blocking commands: send the reply from try_reply if it has one, and otherwise either give up
+        right away with a null array (timeout_secs is None) or block for up to timeout_secs seconds (forever if 0)
+        Only the shards of keys are locked, so try_reply can't look at others.
+        */
+        let timeout_secs = timeout_secs.filter(|_| !conn.in_exec);
+        let mut c = state.lock_keys(conn.db, keys);
+        if let Some(resp) = try_reply(&mut c) {
+            conn.write_reply(&resp)?;
+            return Ok(());
+        }
+        match timeout_secs {
+            Some(timeout_secs) => {
+                let timeout = Some(Duration::from_secs_f64(timeout_secs)).filter(|timeout| !timeout.is_zero());
+                Self::block_for_writes(conn, args.to_vec(), state, timeout);
+                Ok(())
+            },
+            None => {
+                let timed_out_resp = RespValue::NullArray;
+                conn.write_reply(&timed_out_resp)
+            },
+        }
+    }
+
+    fn block_for_writes(conn: &mut Connection, args: Vec<Bytes>, state: &ServerState, timeout: Option<Duration>) {
+        /*
+        Block the connection on the command in args, which wait_while_blocked runs again after the next write to the
+        database, until it replies or its timeout (if any) passes. Called while the command still holds its keys' shards.
+        A command run again after blocking keeps the deadline it got the first time.
+        */
+        let deadline = conn.resumed_deadline.unwrap_or_else(|| timeout.map(|timeout| Instant::now() + timeout));
+        let write_generation = state.dbs[conn.db].write_generation();
+        conn.blocked = Some(BlockedCmd { args, deadline, write_generation });
+    }
+
+    fn pop_first_nonempty(state: &ServerState, c: &mut KeyspaceGuard, keys: &[Bytes], end: ListEnd, count: usize, is_mpop: bool) -> Option<RespValue> {
+        /*
+        Pop from the first non-empty list among the keys, for the (blocking) pop commands to pass to block_until
+        Replies with [key, element] for BLPOP/BRPOP, [key, [elements]] for the MPOP commands, or None if they're all empty.
+        */
+        keys.iter().find_map(|key| match Self::pop_from_key(state, c, key, end, count) {
+            Ok(Some(elems)) if is_mpop => Some(RespValue::Array(vec![
+                RespValue::BulkString(key.to_vec()),
+                RespValue::bulk_strings(&elems),
+            ])),
+            Ok(Some(elems)) => Some(RespValue::bulk_strings(&[key.to_vec(), elems[0].clone()])),
+            Ok(None) => None,
+            Err(()) => Some(RespValue::Error(WRONGTYPE_ERR.to_string())),
+        })
+    }
+
+    fn handle_blpop_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Blocking LPOP across several keys: BLPOP key [key ...] timeout */
+        let Some(timeout_secs) = Self::parse_timeout(conn, &args[args.len() - 1])? else {
+            return Ok(());
+        };
+        let keys = &args[1..args.len() - 1];
+        Self::block_until(conn, args, state, keys, Some(timeout_secs), |c| Self::pop_first_nonempty(state, c, keys, ListEnd::Left, 1, false))
+    }
+
+    fn handle_brpop_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Blocking RPOP across several keys: BRPOP key [key ...] timeout */
+        let Some(timeout_secs) = Self::parse_timeout(conn, &args[args.len() - 1])? else {
+            return Ok(());
+        };
+        let keys = &args[1..args.len() - 1];
+        Self::block_until(conn, args, state, keys, Some(timeout_secs), |c| Self::pop_first_nonempty(state, c, keys, ListEnd::Right, 1, false))
+    }
+
+    fn blmpop_keys(args: &[Bytes]) -> Option<Vec<&Bytes>> {
+        /* BLMPOP timeout numkeys key [key ...] LEFT|RIGHT [COUNT count] */
+        numkeys_key_args(args, 2)
+    }
+
+    fn handle_blmpop_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Blocking LMPOP: BLMPOP timeout numkeys key [key ...] LEFT|RIGHT [COUNT count] */
+        let Some(timeout_secs) = Self::parse_timeout(conn, &args[1])? else {
+            return Ok(());
+        };
+        let (keys, end, count) = match Self::parse_mpop_args(&args[2..]) {
+            Ok(mpop_args) => mpop_args,
+            Err(mpop_err) => {
+                let blmpop_err_response = RespValue::Error(mpop_err);
+                conn.write_reply(&blmpop_err_response)?;
+                return Ok(());
+            }
+        };
+        Self::block_until(conn, args, state, &keys, Some(timeout_secs), |c| Self::pop_first_nonempty(state, c, &keys, end, count, true))
+    }
+
+    fn handle_lrange_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with the elements of the list between start and stop (inclusive, negative indices count from the end) */
+        let (start, end) = match (parse_arg::<i64>(&args[2]), parse_arg::<i64>(&args[3])) {
+            (Some(start), Some(end)) => (start, end),
+            _ => {
+                let lrange_err_response = RespValue::Error("ERR value is not an integer or out of range".to_string());
+                conn.write_reply(&lrange_err_response)?;
+                return Ok(());
+            }
+        };
+        let lrange_resp = Self::get_key(state, conn.db, &args[1], |val| match val {
+            Some(RedisValue::List(list)) => {
+                let elems = match normalize_range(start, end, list.len()) {
+                    Some((start, end)) => list.iter().skip(start).take(end - start + 1).cloned().collect::<Vec<Vec<u8>>>(),
+                    None => Vec::new(),
+                };
+                RespValue::bulk_strings(&elems)
+            },
+            Some(_) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+            None => RespValue::Array(Vec::new()),
+        });
+        conn.write_reply(&lrange_resp)?;
+        Ok(())
+    }
+
+    fn handle_hset_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Set the given fields of the hash at key, replying with the number of fields that were added */
+        if args.len() % 2 != 0 {
+            let hset_err_response = RespValue::Error("ERR wrong number of arguments for 'hset' command".to_string());
+            conn.write_reply(&hset_err_response)?;
+            return Ok(());
+        }
+        let key = args[1].clone();
+        let mut c = state.lock_keys(conn.db, [&key]);
+        if Self::get_live_val(state, &mut c, &key).is_none() {
+            c.insert(key.clone(), KeyEntry::new(RedisValue::Hash(RedisHash::new()), None));
+        }
+        let hset_resp = match Self::get_live_val(state, &mut c, &key) {
+            Some(RedisValue::Hash(hash)) => {
+                let num_added = args[2..]
+                    .chunks(2)
+                    .filter(|pair| hash.insert(pair[0].to_vec(), pair[1].to_vec(), &state.encoding_limits))
+                    .count();
+                RespValue::Integer(num_added as i64)
+            },
+            _ => RespValue::Error(WRONGTYPE_ERR.to_string()),
+        };
+        conn.write_reply(&hset_resp)?;
+        Ok(())
+    }
+
+    fn handle_hget_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with the value of a field in the hash at key */
+        let hget_resp = Self::get_key(state, conn.db, &args[1], |val| match val {
+            Some(RedisValue::Hash(hash)) => match hash.get(&args[2]) {
+                Some(val) => RespValue::BulkString(val.to_vec()),
+                None => RespValue::Null,
+            },
+            Some(_) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+            None => RespValue::Null,
+        });
+        conn.write_reply(&hget_resp)?;
+        Ok(())
+    }
+
+    fn handle_hgetall_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with every field and value of the hash at key: a map in RESP3, a flat array in RESP2 */
+        let hgetall_resp = Self::get_key(state, conn.db, &args[1], |val| match val {
+            Some(RedisValue::Hash(hash)) => RespValue::Map(
+                hash.iter().map(|(field, val)| (RespValue::BulkString(field.to_vec()), RespValue::BulkString(val.to_vec()))).collect()
+            ),
+            Some(_) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+            None => RespValue::Map(Vec::new()),
+        });
+        conn.write_reply(&hgetall_resp)?;
+        Ok(())
+    }
+
+    fn parse_hash_fields(args: &[Bytes], fields_idx: usize) -> Result<&[Bytes], String> {
+        /* Parse `FIELDS numfields field [field ...]` starting at fields_idx, the tail of the per-field TTL commands */
+        if !args.get(fields_idx).is_some_and(|fields_arg| fields_arg.eq_ignore_ascii_case(b"FIELDS")) {
+            return Err("ERR Mandatory argument FIELDS is missing or not at the right position".to_string());
+        }
+        match args.get(fields_idx + 1).and_then(|num_fields| parse_arg::<i64>(num_fields)) {
+            Some(num_fields) if num_fields <= 0 => Err("ERR Parameter `numFields` should be greater than 0".to_string()),
+            Some(num_fields) if num_fields as usize == args.len() - fields_idx - 2 => Ok(&args[fields_idx + 2..]),
+            Some(_) => Err("ERR The `numfields` parameter must match the number of arguments".to_string()),
+            None => Err("ERR value is not an integer or out of range".to_string()),
+        }
+    }
+
+    fn encode_integers(ints: &[i64]) -> RespValue {
+        RespValue::Array(ints.iter().map(|int| RespValue::Integer(*int)).collect())
+    }
+
+    fn set_field_expiry(conn: &mut Connection, args: &[Bytes], state: &ServerState, unit_ms: i128, is_absolute: bool) -> io::Result<()> {
+        /*
+        Shared implementation of HEXPIRE, HPEXPIRE, HEXPIREAT and HPEXPIREAT:
+        <cmd> key time [NX|XX|GT|LT] FIELDS numfields field [field ...]
+        Like set_expiry but per hash field, replying with an array of codes, one per field: -2 if the field (or key)
+        doesn't exist, 0 if the conditions weren't met, 1 if the TTL was set, and 2 if the field was deleted because
+        the time is already in the past.
+        */
+        let cmd_name = arg_to_string(&args[0]).to_lowercase();
+        let expiry_ts = match parse_arg::<i64>(&args[2]).and_then(|when| (when as i128).checked_mul(unit_ms)) {
+            Some(when_ms) if when_ms < 0 => None,
+            Some(when_ms) => Some(if is_absolute { when_ms } else { when_ms + Self::curr_time_ms() as i128 }),
+            None => {
+                let hexpire_err_response = RespValue::Error("ERR value is not an integer or out of range".to_string());
+                conn.write_reply(&hexpire_err_response)?;
+                return Ok(());
+            }
+        };
+        let Some(expiry_ts) = expiry_ts.filter(|expiry_ts| *expiry_ts <= i64::MAX as i128) else {
+            let hexpire_err_response = RespValue::Error(format!("ERR invalid expire time in '{}' command", cmd_name));
+            conn.write_reply(&hexpire_err_response)?;
+            return Ok(());
+        };
+        let fields_idx = args.iter().skip(3).position(|arg| arg.eq_ignore_ascii_case(b"FIELDS")).map_or(3, |idx| idx + 3);
+        let opts_and_fields = ExpireOptions::parse(&args[3..fields_idx])
+            .and_then(|opts| Self::parse_hash_fields(args, fields_idx).map(|fields| (opts, fields)));
+        let (opts, fields) = match opts_and_fields {
+            Ok(opts_and_fields) => opts_and_fields,
+            Err(hexpire_err) => {
+                let hexpire_err_response = RespValue::Error(hexpire_err);
+                conn.write_reply(&hexpire_err_response)?;
+                return Ok(());
+            }
+        };
+
+        let key = args[1].clone();
+        let mut c = state.lock_keys(conn.db, [&key]);
+        let hexpire_resp = match Self::get_live_val(state, &mut c, &key) {
+            Some(RedisValue::Hash(hash)) => {
+                let codes = fields.iter().map(|field| {
+                    if hash.get(field).is_none() {
+                        -2
+                    } else if !opts.allows(hash.field_expiry(field), expiry_ts) {
+                        0
+                    } else if expiry_ts <= Self::curr_time_ms() as i128 {
+                        hash.remove(field);
+                        2
+                    } else {
+                        hash.set_field_expiry(field, expiry_ts as u128);
+                        1
+                    }
+                }).collect::<Vec<i64>>();
+                if hash.len() == 0 {
+                    c.remove(&key);
+                }
+                Self::encode_integers(&codes)
+            },
+            Some(_) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+            None => Self::encode_integers(&vec![-2; fields.len()]),
+        };
+        conn.write_reply(&hexpire_resp)?;
+        Ok(())
+    }
+
+    fn handle_hexpire_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Set hash fields' time to live in seconds */
+        Self::set_field_expiry(conn, args, state, 1000, false)
+    }
+
+    fn handle_hpexpire_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Set hash fields' time to live in milliseconds */
+        Self::set_field_expiry(conn, args, state, 1, false)
+    }
+
+    fn handle_hexpireat_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Set hash fields to expire at a unix timestamp in seconds */
+        Self::set_field_expiry(conn, args, state, 1000, true)
+    }
+
+    fn handle_hpexpireat_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Set hash fields to expire at a unix timestamp in milliseconds */
+        Self::set_field_expiry(conn, args, state, 1, true)
+    }
+
+    fn get_field_expiry(conn: &mut Connection, args: &[Bytes], state: &ServerState, unit_ms: u128, is_absolute: bool) -> io::Result<()> {
+        /*
+        Shared implementation of HTTL, HPTTL, HEXPIRETIME and HPEXPIRETIME: <cmd> key FIELDS numfields field [field ...]
+        Like get_expiry but per hash field, replying with an array with each field's expiry, -1 if it has no TTL,
+        or -2 if the field (or key) doesn't exist.
+        */
+        let fields = match Self::parse_hash_fields(args, 2) {
+            Ok(fields) => fields,
+            Err(httl_err) => {
+                let httl_err_response = RespValue::Error(httl_err);
+                conn.write_reply(&httl_err_response)?;
+                return Ok(());
+            }
+        };
+        let mut c = state.lock_keys(conn.db, [&args[1]]);
+        let httl_resp = match Self::get_live_val(state, &mut c, &args[1]) {
+            Some(RedisValue::Hash(hash)) => {
+                let expiries = fields.iter().map(|field| match (hash.get(field), hash.field_expiry(field)) {
+                    (None, _) => -2,
+                    (Some(_), None) => -1,
+                    (Some(_), Some(expiry_ts)) => {
+                        let expiry_ms = if is_absolute { expiry_ts } else { expiry_ts.saturating_sub(Self::curr_time_ms()) };
+                        ((expiry_ms + unit_ms / 2) / unit_ms) as i64
+                    },
+                }).collect::<Vec<i64>>();
+                Self::encode_integers(&expiries)
+            },
+            Some(_) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+            None => Self::encode_integers(&vec![-2; fields.len()]),
+        };
+        conn.write_reply(&httl_resp)?;
+        Ok(())
+    }
+
+    fn handle_httl_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with hash fields' remaining time to live in seconds */
+        Self::get_field_expiry(conn, args, state, 1000, false)
+    }
+
+    fn handle_hpttl_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with hash fields' remaining time to live in milliseconds */
+        Self::get_field_expiry(conn, args, state, 1, false)
+    }
+
+    fn handle_hexpiretime_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with the unix timestamps in seconds at which hash fields expire */
+        Self::get_field_expiry(conn, args, state, 1000, true)
+    }
+
+    fn handle_hpexpiretime_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with the unix timestamps in milliseconds at which hash fields expire */
+        Self::get_field_expiry(conn, args, state, 1, true)
+    }
+
+    fn handle_sadd_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Add members to the set at key, replying with the number of members that weren't already in it */
+        let key = args[1].clone();
+        let mut c = state.lock_keys(conn.db, [&key]);
+        if Self::get_live_val(state, &mut c, &key).is_none() {
+            c.insert(key.clone(), KeyEntry::new(RedisValue::Set(RedisSet::new()), None));
+        }
+        let sadd_resp = match Self::get_live_val(state, &mut c, &key) {
+            Some(RedisValue::Set(set)) => {
+                let num_added = args[2..]
+                    .iter()
+                    .filter(|member| set.insert(member.to_vec(), &state.encoding_limits))
+                    .count();
+                RespValue::Integer(num_added as i64)
+            },
+            _ => RespValue::Error(WRONGTYPE_ERR.to_string()),
+        };
+        conn.write_reply(&sadd_resp)?;
+        Ok(())
+    }
+
+    fn handle_smembers_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with every member of the set at key: a set in RESP3, an array in RESP2 */
+        let smembers_resp = Self::get_key(state, conn.db, &args[1], |val| match val {
+            Some(RedisValue::Set(set)) => Self::encode_set(&set.members()),
+            Some(_) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+            None => Self::encode_set(&[]),
+        });
+        conn.write_reply(&smembers_resp)?;
+        Ok(())
+    }
+
+    fn encode_set(members: &[Vec<u8>]) -> RespValue {
+        /* A set in RESP3, an array in RESP2 */
+        RespValue::Set(members.iter().map(|member| RespValue::BulkString(member.clone())).collect())
+    }
+
+    fn combine_sets(state: &ServerState, c: &mut KeyspaceGuard, keys: &[Bytes], op: SetOp) -> Result<Vec<Vec<u8>>, ()> {
+        /*
+        Intersect, union or diff the sets at keys (in an already locked cache), or Err if any of them isn't a set
+        Missing keys count as empty sets. Members keep the order of the first set they appear in.
+        */
+        let mut sets = Vec::with_capacity(keys.len());
+        for key in keys {
+            // Cloned since get_live_val borrows the whole cache mutably
+            match Self::get_live_val(state, c, key) {
+                Some(RedisValue::Set(set)) => sets.push(set.clone()),
+                Some(_) => return Err(()),
+                None => sets.push(RedisSet::new()),
+            }
+        }
+        let first_members = sets[0].members();
+        let members = match op {
+            SetOp::Inter => first_members.into_iter().filter(|member| sets[1..].iter().all(|set| set.contains(member))).collect(),
+            SetOp::Diff => first_members.into_iter().filter(|member| !sets[1..].iter().any(|set| set.contains(member))).collect(),
+            SetOp::Union => {
+                let mut seen = HashSet::new();
+                sets.iter().flat_map(|set| set.members()).filter(|member| seen.insert(member.clone())).collect()
+            },
+        };
+        Ok(members)
+    }
+
+    fn set_algebra(conn: &mut Connection, args: &[Bytes], state: &ServerState, op: SetOp) -> io::Result<()> {
+        /* Shared implementation of SINTER, SUNION and SDIFF: reply with the combination of the sets at the keys */
+        let members = Self::combine_sets(state, &mut state.lock_keys(conn.db, &args[1..]), &args[1..], op);
+        let set_algebra_resp = match members {
+            Ok(members) => Self::encode_set(&members),
+            Err(()) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+        };
+        conn.write_reply(&set_algebra_resp)?;
+        Ok(())
+    }
+
+    fn set_algebra_store(conn: &mut Connection, args: &[Bytes], state: &ServerState, op: SetOp) -> io::Result<()> {
+        /*
+        Shared implementation of SINTERSTORE, SUNIONSTORE and SDIFFSTORE: destination key [key ...]
+        Store the combination of the sets at the keys in destination, overwriting whatever was there (the destination
+        can also be one of the keys), and reply with its size. An empty result deletes destination instead.
+        The sources are read and destination written under one lock, so no other command sees a partial result.
+        */
+        let dest_key = args[1].clone();
+        let mut c = state.lock_keys(conn.db, &args[1..]);
+        let set_algebra_store_resp = match Self::combine_sets(state, &mut c, &args[2..], op) {
+            Ok(members) => {
+                if members.is_empty() {
+                    c.remove(&dest_key);
+                } else {
+                    let mut dest_set = RedisSet::new();
+                    for member in &members {
+                        dest_set.insert(member.to_vec(), &state.encoding_limits);
+                    }
+                    c.insert(dest_key, KeyEntry::new(RedisValue::Set(dest_set), None));
+                }
+                RespValue::Integer(members.len() as i64)
+            },
+            Err(()) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+        };
+        conn.write_reply(&set_algebra_store_resp)?;
+        Ok(())
+    }
+
+    fn handle_sinter_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with the members common to every set at the keys */
+        Self::set_algebra(conn, args, state, SetOp::Inter)
+    }
+
+    fn handle_sunion_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with the members of any of the sets at the keys */
+        Self::set_algebra(conn, args, state, SetOp::Union)
+    }
+
+    fn handle_sdiff_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with the members of the first set that aren't in any of the others */
+        Self::set_algebra(conn, args, state, SetOp::Diff)
+    }
+
+    fn handle_sinterstore_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Store the intersection of the sets at the keys in destination */
+        Self::set_algebra_store(conn, args, state, SetOp::Inter)
+    }
+
+    fn handle_sunionstore_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Store the union of the sets at the keys in destination */
+        Self::set_algebra_store(conn, args, state, SetOp::Union)
+    }
+
+    fn handle_sdiffstore_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Store the difference between the first set at the keys and the rest in destination */
+        Self::set_algebra_store(conn, args, state, SetOp::Diff)
+    }
+
+    fn handle_object_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* OBJECT ENCODING key replies with the name of the internal representation of the value at key */
+        let subcommand = arg_to_string(&args[1]).to_uppercase();
+        let object_resp = match (subcommand.as_str(), args.len()) {
+            ("ENCODING", 3) => Self::get_key(state, conn.db, &args[2], |val| match val {
+                Some(val) => RespValue::BulkString(val.encoding().as_bytes().to_vec()),
+                None => RespValue::Null,
+            }),
+            ("ENCODING", _) => RespValue::Error("ERR wrong number of arguments for 'object|encoding' command".to_string()),
+            _ => RespValue::Error(format!("ERR unknown subcommand '{}'. Try OBJECT HELP.", arg_to_string(&args[1]))),
+        };
+        conn.write_reply(&object_resp)?;
+        Ok(())
+    }
+
+    fn encode_command_info(spec: &CommandSpec) -> RespValue {
+        /* Encode the COMMAND INFO reply for a single command: [name, arity, [flags...], first key, last key, step] */
+        let (first_key, last_key, key_step) = spec.keys;
+        let flags = spec.flags.iter().map(|flag| RespValue::SimpleString(flag.as_bytes().to_vec())).collect();
+        RespValue::Array(vec![
+            RespValue::BulkString(spec.name.as_bytes().to_vec()),
+            RespValue::Integer(spec.redis_arity()),
+            RespValue::Array(flags),
+            RespValue::Integer(first_key),
+            RespValue::Integer(last_key),
+            RespValue::Integer(key_step),
+        ])
+    }
+
+    fn handle_command_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Describe the supported commands, straight from the command table
+        COMMAND returns info for every command, COMMAND COUNT the number of commands, and
+        COMMAND INFO [name ...] info for the given commands (nil for unknown ones) or for all of them,
+        COMMAND GETKEYS command [arg ...] which of the arguments of the given command line are keys, and
+        COMMAND LIST [FILTERBY PATTERN pattern] the names of the commands, optionally only those matching the pattern.
+        Only PATTERN filtering is supported, since there are no modules or ACL categories to filter by.
+        COMMAND DOCS [name ...] maps each known command to its docs, which redis-cli asks for when it connects. There are
+        no docs to give, so each map is empty and redis-cli falls back to its built-in hints.
+        */
+        let subcommand = args.get(1).map(|arg| arg_to_string(arg).to_uppercase());
+        let command_resp = match subcommand.as_deref() {
+            None => RespValue::Array(state.commands.iter().map(Self::encode_command_info).collect()),
+            Some("COUNT") if args.len() == 2 => RespValue::Integer(state.commands.len() as i64),
+            Some("INFO") if args.len() == 2 => RespValue::Array(state.commands.iter().map(Self::encode_command_info).collect()),
+            Some("INFO") => RespValue::Array(
+                args[2..]
+                    .iter()
+                    .map(|name| match state.commands.get(arg_to_string(name).to_lowercase().as_str()) {
+                        Some(spec) => Self::encode_command_info(spec),
+                        None => RespValue::NullArray,
+                    })
+                    .collect(),
+            ),
+            Some("DOCS") => {
+                let names = match args.len() {
+                    2 => state.commands.iter().map(|spec| spec.name.to_string()).collect(),
+                    _ => args[2..].iter().map(|name| arg_to_string(name).to_lowercase()).collect::<Vec<String>>(),
+                };
+                RespValue::Map(
+                    names
+                        .into_iter()
+                        .filter(|name| state.commands.get(name).is_some())
+                        .map(|name| (RespValue::BulkString(name.into_bytes()), RespValue::Map(Vec::new())))
+                        .collect(),
+                )
+            },
+            Some("GETKEYS") if args.len() > 2 => {
+                let cmd_args = &args[2..];
+                let getkeys_result = match state.commands.get(arg_to_string(&cmd_args[0]).to_lowercase().as_str()) {
+                    None => Err("Invalid command specified"),
+                    Some(spec) if spec.check_arity(cmd_args.len() - 1).is_err() => Err("Invalid number of arguments specified for command"),
+                    Some(CommandSpec { find_keys: Some(find_keys), .. }) => {
+                        find_keys(cmd_args).ok_or("Invalid arguments specified for command")
+                    },
+                    Some(spec) => Ok(spec.fixed_key_args(cmd_args)),
+                };
+                match getkeys_result {
+                    Ok(keys) if !keys.is_empty() => RespValue::bulk_strings(&keys.into_iter().map(|key| key.to_vec()).collect::<Vec<Vec<u8>>>()),
+                    Ok(_) => RespValue::Error("ERR The command has no key arguments".to_string()),
+                    Err(getkeys_err) => RespValue::Error(format!("ERR {}", getkeys_err)),
+                }
+            },
+            Some("LIST") => match &args[2..] {
+                [] => RespValue::bulk_strings(&state.commands.iter().map(|spec| spec.name.as_bytes().to_vec()).collect::<Vec<Vec<u8>>>()),
+                [filterby, filter, pattern]
+                    if filterby.eq_ignore_ascii_case(b"FILTERBY") && filter.eq_ignore_ascii_case(b"PATTERN") =>
+                {
+                    RespValue::bulk_strings(
+                        &state.commands.iter()
+                            .filter(|spec| glob_match(&pattern.to_ascii_lowercase(), spec.name.as_bytes()))
+                            .map(|spec| spec.name.as_bytes().to_vec())
+                            .collect::<Vec<Vec<u8>>>(),
+                    )
+                },
+                _ => RespValue::Error("ERR syntax error".to_string()),
+            },
+            Some("COUNT") => RespValue::Error("ERR wrong number of arguments for 'command|count' command".to_string()),
+            Some("GETKEYS") => RespValue::Error("ERR wrong number of arguments for 'command|getkeys' command".to_string()),
+            Some(_) => RespValue::Error(format!("ERR unknown subcommand '{}'. Try COMMAND HELP.", arg_to_string(&args[1]))),
+        };
+        conn.write_reply(&command_resp)?;
+        Ok(())
+    }
+
+    fn info_section(title: &str, fields: &[(&str, String)]) -> String {
+        /* A `# Title` line followed by a `field:value` line for each field */
+        let mut section = format!("# {}{}", title, RESP_DELIMITER);
+        for (field, val) in fields {
+            section.push_str(&format!("{}:{}{}", field, val, RESP_DELIMITER));
+        }
+        section
+    }
+
+    fn bytes_to_human(num_bytes: u64) -> String {
+        /* Like Redis's bytesToHuman, e.g. 1.50M */
+        match num_bytes {
+            num_bytes if num_bytes < 1024 => format!("{}B", num_bytes),
+            num_bytes if num_bytes < 1024 * 1024 => format!("{:.2}K", num_bytes as f64 / 1024.0),
+            num_bytes if num_bytes < 1024 * 1024 * 1024 => format!("{:.2}M", num_bytes as f64 / (1024.0 * 1024.0)),
+            num_bytes => format!("{:.2}G", num_bytes as f64 / (1024.0 * 1024.0 * 1024.0)),
+        }
+    }
+
+    fn resident_memory() -> u64 {
+        /* The process's resident set size in bytes, from /proc on Linux; 0 where that isn't available */
+        let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+        status.lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))
+            .and_then(|rss| rss.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+            .map_or(0, |rss_kb| rss_kb * 1024)
+    }
+
+    fn info_server_section(state: &ServerState) -> String {
+        Self::info_section("Server", &[
+            ("redis_version", REDIS_VERSION.to_string()),
+            ("redis_mode", "standalone".to_string()),
+            ("os", std::env::consts::OS.to_string()),
+            ("arch_bits", (usize::BITS).to_string()),
+            ("process_id", std::process::id().to_string()),
+            ("hz", state.hz.load(Ordering::Relaxed).to_string()),
+            ("uptime_in_seconds", state.start_time.elapsed().as_secs().to_string()),
+            ("uptime_in_days", (state.start_time.elapsed().as_secs() / (24 * 60 * 60)).to_string()),
+        ])
+    }
+
+    fn info_clients_section(state: &ServerState) -> String {
+        Self::info_section("Clients", &[
+            ("connected_clients", state.stats.connected_clients.load(Ordering::Relaxed).to_string()),
+            ("maxclients", state.maxclients.load(Ordering::Relaxed).to_string()),
+            ("blocked_clients", state.stats.blocked_clients.load(Ordering::Relaxed).to_string()),
+        ])
+    }
+
+    fn info_memory_section(state: &ServerState) -> String {
+        /* There's no allocator accounting, so only the process's RSS is reported rather than used_memory */
+        let rss = Self::resident_memory();
+        let maxmemory = state.maxmemory.load(Ordering::Relaxed) as u64;
+        Self::info_section("Memory", &[
+            ("used_memory_rss", rss.to_string()),
+            ("used_memory_rss_human", Self::bytes_to_human(rss)),
+            ("maxmemory", maxmemory.to_string()),
+            ("maxmemory_human", Self::bytes_to_human(maxmemory)),
+            ("maxmemory_policy", "noeviction".to_string()),
+            ("lazyfree_pending_objects", state.lazyfree.pending_objects().to_string()),
+        ])
+    }
+
+    fn info_stats_section(state: &ServerState) -> String {
+        let stat = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+        Self::info_section("Stats", &[
+            ("total_connections_received", stat(&state.stats.total_connections_received)),
+            ("rejected_connections", stat(&state.stats.rejected_connections)),
+            ("total_commands_processed", stat(&state.stats.total_commands_processed)),
+            ("expired_keys", stat(&state.stats.expired_keys)),
+            ("evicted_keys", stat(&state.stats.evicted_keys)),
+            ("keyspace_hits", stat(&state.stats.keyspace_hits)),
+            ("keyspace_misses", stat(&state.stats.keyspace_misses)),
+        ])
+    }
+
+    fn info_replication_section() -> String {
+        /* There's no replication yet, so the server is always a master without replicas */
+        Self::info_section("Replication", &[
+            ("role", "master".to_string()),
+            ("connected_slaves", "0".to_string()),
+        ])
+    }
+
+    fn info_keyspace_section(state: &ServerState) -> String {
+        /* A `dbN:keys=...,expires=...,avg_ttl=...` line for each database that has keys; avg_ttl is in milliseconds */
+        let curr_time = Self::curr_time_ms();
+        let mut fields = Vec::new();
+        for db in 0..state.dbs.len() {
+            let c = state.lock_db(db);
+            if c.len() == 0 {
+                continue;
+            }
+            let ttls = c.iter()
+                .filter_map(|(_, entry)| entry.expiry_ts)
+                .map(|expiry_ts| expiry_ts.saturating_sub(curr_time))
+                .collect::<Vec<u128>>();
+            let avg_ttl = if ttls.is_empty() { 0 } else { ttls.iter().sum::<u128>() / ttls.len() as u128 };
+            fields.push((format!("db{}", db), format!("keys={},expires={},avg_ttl={}", c.len(), ttls.len(), avg_ttl)));
+        }
+        let fields = fields.iter().map(|(field, val)| (field.as_str(), val.clone())).collect::<Vec<(&str, String)>>();
+        Self::info_section("Keyspace", &fields)
+    }
+
+    fn info_commandstats_section(state: &ServerState) -> String {
+        /* Lines of the `commandstats` section of INFO: call count and time spent for each command run so far */
+        let mut section = format!("# Commandstats{}", RESP_DELIMITER);
+        for (cmd_name, cmd_stats) in state.stats.command_stats() {
+            section.push_str(&format!(
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2}{}",
+                cmd_name,
+                cmd_stats.calls,
+                cmd_stats.usec,
+                cmd_stats.usec as f64 / cmd_stats.calls as f64,
+                RESP_DELIMITER
+            ));
+        }
+        section
+    }
+
+    fn handle_info_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Reply with server information as `field:value` lines grouped into sections
+        The default sections (server, clients, memory, stats, replication and keyspace) are returned for no section,
+        `all`, `default` or `everything`; like in Redis, `commandstats` has to be asked for by name (or with `all` or
+        `everything`), and unknown sections are left out.
+        */
+        let sections = args[1..].iter().map(|arg| arg_to_string(arg).to_lowercase()).collect::<Vec<String>>();
+        let included = |name: &str, default: bool| {
+            (default && sections.is_empty())
+                || sections.iter().any(|section| section == name || matches!(section.as_str(), "all" | "everything")
+                    || (default && section == "default"))
+        };
+        let mut included_sections = Vec::new();
+        if included("server", true) {
+            included_sections.push(Self::info_server_section(state));
+        }
+        if included("clients", true) {
+            included_sections.push(Self::info_clients_section(state));
+        }
+        if included("memory", true) {
+            included_sections.push(Self::info_memory_section(state));
+        }
+        if included("stats", true) {
+            included_sections.push(Self::info_stats_section(state));
+        }
+        if included("replication", true) {
+            included_sections.push(Self::info_replication_section());
+        }
+        if included("commandstats", false) {
+            included_sections.push(Self::info_commandstats_section(state));
+        }
+        if included("keyspace", true) {
+            included_sections.push(Self::info_keyspace_section(state));
+        }
+        // Sections are separated by a blank line
+        let info = included_sections.join(RESP_DELIMITER);
+        let info_resp = RespValue::BulkString(info.as_bytes().to_vec());
+        conn.write_reply(&info_resp)?;
+        Ok(())
+    }
+
+    fn encode_subscription_reply(kind: &str, channel: Option<&[u8]>, num_channels: usize) -> RespValue {
+        /* [kind, channel, number of channels the connection is now subscribed to], as a push in RESP3 */
+        let channel = match channel {
+            Some(channel) => RespValue::BulkString(channel.to_vec()),
+            None => RespValue::Null,
+        };
+        RespValue::Push(vec![
+            RespValue::BulkString(kind.as_bytes().to_vec()),
+            channel,
+            RespValue::Integer(num_channels as i64),
+        ])
+    }
+
+    fn subscriber(conn: &mut Connection, state: &ServerState) -> io::Result<Arc<Subscriber>> {
+        /*
+        The connection's output queue, set up on the first SUBSCRIBE (or MONITOR), from which the writer task sends
+        its output from then on, so that publishers (and commands being monitored) never wait on a slow client
+        */
+        if let Some(subscriber) = &conn.subscriber {
+            return Ok(Arc::clone(subscriber));
+        }
+        let subscriber = Arc::new(Subscriber::new(state.pubsub.limit, conn.protocol));
+        // Replies still buffered from earlier commands go out first, ahead of the subscription's own output
+        conn.pending_reply_len = 0;
+        if !conn.reply_bufs.drain(..).all(|reply_buf| subscriber.send(reply_buf)) {
+            return Err(io::Error::from(ErrorKind::BrokenPipe));
+        }
+        if let Some(writer) = conn.writer.take() {
+            pubsub::spawn_writer(Arc::clone(&subscriber), writer);
+        }
+        conn.subscriber = Some(Arc::clone(&subscriber));
+        Ok(subscriber)
+    }
+
+    fn handle_monitor_cmd(conn: &mut Connection, _args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Feed the connection every command the server runs from now on (see feed_monitors), until it disconnects
+        Like in Redis, running MONITOR again changes nothing and isn't replied to.
+        */
+        if conn.monitoring {
+            return Ok(());
+        }
+        let subscriber = Self::subscriber(conn, state)?;
+        conn.write_reply(&RespValue::ok())?;
+        conn.monitoring = true;
+        state.write_monitors().insert(conn.id, subscriber);
+        Ok(())
+    }
+
+    fn feed_monitors(args: &[Bytes], conn: &Connection, state: &ServerState) {
+        /*
+        Send a command to every monitor as `+<unix time> [<db> <client addr>] "arg" "arg" ...`
+        A monitor that falls too far behind is disconnected by its writer task rather than holding up the command.
+        */
+        let monitors = state.read_monitors();
+        if monitors.is_empty() {
+            return;
+        }
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let quoted_args = args.iter().map(|arg| resp::quote_arg(arg)).collect::<Vec<String>>().join(" ");
+        let line = format!("{}.{:06} [{} {}] {}", since_epoch.as_secs(), since_epoch.subsec_micros(), conn.db, conn.info.addr, quoted_args);
+        for monitor in monitors.values() {
+            let monitor_resp = RespValue::SimpleString(line.clone().into_bytes());
+            monitor.send(monitor_resp.serialize(monitor.protocol.load(Ordering::Relaxed)));
+        }
+    }
+
+    fn join_channels(conn: &mut Connection, channels_to_join: &[Bytes], state: &ServerState, pattern: bool) -> io::Result<()> {
+        /*
+        Shared implementation of SUBSCRIBE and PSUBSCRIBE, replying with ["subscribe", channel, number of
+        subscriptions] (or "psubscribe" and the pattern) for each
+        */
+        let subscriber = Self::subscriber(conn, state)?;
+        let kind = if pattern { "psubscribe" } else { "subscribe" };
+        for channel in channels_to_join {
+            // Queue the reply before releasing the lock, so it's sent before any message published to the channel
+            let mut channels = if pattern { state.pubsub.lock_patterns() } else { state.pubsub.lock_channels() };
+            channels.entry(channel.to_vec()).or_default().insert(conn.id, Arc::clone(&subscriber));
+            if pattern { &mut conn.patterns } else { &mut conn.channels }.insert(channel.to_vec());
+            let subscribe_resp = Self::encode_subscription_reply(kind, Some(channel), conn.num_subscriptions());
+            conn.write_reply(&subscribe_resp)?;
+        }
+        Ok(())
+    }
+
+    fn handle_subscribe_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Subscribe to each channel */
+        Self::join_channels(conn, &args[1..], state, false)
+    }
+
+    fn handle_psubscribe_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Subscribe to every channel matching each glob-style pattern */
+        Self::join_channels(conn, &args[1..], state, true)
+    }
+
+    fn leave_channels(conn: &mut Connection, args: &[Bytes], state: &ServerState, pattern: bool) -> io::Result<()> {
+        /*
+        Shared implementation of UNSUBSCRIBE and PUNSUBSCRIBE: leave the given channels (or patterns), or all of them
+        if none are given, replying with ["unsubscribe", channel, number of subscriptions left] for each
+        */
+        let kind = if pattern { "punsubscribe" } else { "unsubscribe" };
+        let channels_to_leave = match args.len() {
+            1 => if pattern { &conn.patterns } else { &conn.channels }.iter().cloned().collect::<Vec<Vec<u8>>>(),
+            _ => args[1..].iter().map(|channel| channel.to_vec()).collect(),
+        };
+        if channels_to_leave.is_empty() {
+            let unsubscribe_resp = Self::encode_subscription_reply(kind, None, conn.num_subscriptions());
+            conn.write_reply(&unsubscribe_resp)?;
+        }
+        for channel in &channels_to_leave {
+            let mut channels = if pattern { state.pubsub.lock_patterns() } else { state.pubsub.lock_channels() };
+            if let Some(subscribers) = channels.get_mut(channel) {
+                subscribers.remove(&conn.id);
+                if subscribers.is_empty() {
+                    channels.remove(channel);
+                }
+            }
+            if pattern { &mut conn.patterns } else { &mut conn.channels }.remove(channel);
+            let unsubscribe_resp = Self::encode_subscription_reply(kind, Some(channel), conn.num_subscriptions());
+            conn.write_reply(&unsubscribe_resp)?;
+        }
+        Ok(())
+    }
+
+    fn handle_unsubscribe_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Unsubscribe from the given channels, or from every channel if none are given */
+        Self::leave_channels(conn, args, state, false)
+    }
+
+    fn handle_punsubscribe_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Unsubscribe from the given patterns, or from every pattern if none are given */
+        Self::leave_channels(conn, args, state, true)
+    }
+
+    fn handle_publish_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Send a message to every subscriber of the channel, replying with how many received it
+        This never waits on subscribers; each one has its own bounded queue (see pubsub.rs).
+        */
+        let num_receivers = state.pubsub.publish(&args[1], &args[2]);
+        let publish_resp = RespValue::Integer(num_receivers as i64);
+        conn.write_reply(&publish_resp)?;
+        Ok(())
+    }
+
+    fn close_subscriptions(conn: &mut Connection, state: &ServerState) {
+        /* Drop the connection's subscriptions (and MONITOR feed) when it closes, stopping its writer thread */
+        Self::leave_all_channels(conn, state);
+        if let Some(subscriber) = conn.subscriber.take() {
+            subscriber.close();
+        }
+    }
+
+    fn leave_all_channels(conn: &mut Connection, state: &ServerState) {
+        /* Stop feeding the connection published messages and monitored commands, without replying */
+        if conn.monitoring {
+            state.write_monitors().remove(&conn.id);
+            conn.monitoring = false;
+        }
+        for (mut channels, conn_channels) in [
+            (state.pubsub.lock_channels(), &mut conn.channels),
+            (state.pubsub.lock_patterns(), &mut conn.patterns),
+        ] {
+            for channel in conn_channels.drain() {
+                if let Some(subscribers) = channels.get_mut(&channel) {
+                    subscribers.remove(&conn.id);
+                    if subscribers.is_empty() {
+                        channels.remove(&channel);
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_reset_cmd(conn: &mut Connection, _args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Put the connection back the way it was when it connected, e.g. for a connection pool handing it out again:
+        discard any transaction, unwatch all keys, unsubscribe from everything, leave MONITOR, select database 0, log out
+        if there's a password, and switch back to RESP2 with replies on. The client name is kept, like in Redis.
+        An output queue set up by SUBSCRIBE or MONITOR stays in place, since it has the connection's writer by now.
+        */
+        conn.transaction = None;
+        conn.transaction_error = false;
+        Self::unwatch_all(conn, state);
+        Self::leave_all_channels(conn, state);
+        conn.db = 0;
+        conn.authenticated = !state.acl.default_requires_password();
+        conn.user = acl::DEFAULT_USER.to_string();
+        conn.info.lock_details().user = acl::DEFAULT_USER.to_string();
+        conn.protocol = 2;
+        if let Some(subscriber) = &conn.subscriber {
+            subscriber.protocol.store(2, Ordering::Relaxed);
+        }
+        conn.reply_mode = ReplyMode::On;
+        conn.write_reply(&RespValue::SimpleString(b"RESET".to_vec()))?;
+        Ok(())
+    }
+
+    fn handle_waitaof_cmd(conn: &mut Connection, args: &[Bytes], _state: &ServerState) -> io::Result<()> {
+        /*
+        WAITAOF numlocal numreplicas timeout replies with how many local and replica AOFs have the connection's writes
+        There's no AOF or replication yet, so that's always [0, 0].
+        */
+        if args[1..].iter().any(|arg| parse_arg::<i64>(arg).is_none()) {
+            let waitaof_err_response = RespValue::Error("ERR value is not an integer or out of range".to_string());
+            conn.write_reply(&waitaof_err_response)?;
+            return Ok(());
+        }
+        let waitaof_resp = RespValue::Array(vec![RespValue::Integer(0), RespValue::Integer(0)]);
+        conn.write_reply(&waitaof_resp)?;
+        Ok(())
+    }
+
+    fn get_config_param(state: &ServerState, param: &str) -> Option<String> {
+        /* Current value of a parameter that can be changed at runtime with CONFIG SET */
+        match param {
+            "replica-read-only" => Some(if state.replica_read_only.load(Ordering::Relaxed) { "yes" } else { "no" }.to_string()),
+            "requirepass" => Some(state.lock_requirepass().clone().unwrap_or_default()),
+            "protected-mode" => Some(if state.protected_mode.load(Ordering::Relaxed) { "yes" } else { "no" }.to_string()),
+            "hz" => Some(state.hz.load(Ordering::Relaxed).to_string()),
+            "pipeline-batch-size" => Some(state.pipeline_batch_size.load(Ordering::Relaxed).to_string()),
+            "proto-max-bulk-len" => Some(state.proto_max_bulk_len.load(Ordering::Relaxed).to_string()),
+            "maxmemory" => Some(state.maxmemory.load(Ordering::Relaxed).to_string()),
+            "maxclients" => Some(state.maxclients.load(Ordering::Relaxed).to_string()),
+            "timeout" => Some(state.timeout.load(Ordering::Relaxed).to_string()),
+            "tcp-keepalive" => Some(state.tcp_keepalive.load(Ordering::Relaxed).to_string()),
+            "save" => Some(config::format_save_points(&state.lock_save_points())),
+            "slowlog-log-slower-than" => Some(state.slowlog.log_slower_than.load(Ordering::Relaxed).to_string()),
+            "slowlog-max-len" => Some(state.slowlog.max_len.load(Ordering::Relaxed).to_string()),
+            "acllog-max-len" => Some(state.acl_log.max_len.load(Ordering::Relaxed).to_string()),
+            "loglevel" => Some(logging::loglevel().name().to_string()),
+            "latency-monitor-threshold" => Some(state.latency_monitor.threshold_ms.load(Ordering::Relaxed).to_string()),
+            _ => None,
+        }
+    }
+
+    fn set_config_param(state: &ServerState, param: &str, val: &str) -> Result<(), String> {
+        /* Change a parameter at runtime, or describe why it can't be */
+        match param {
+            "replica-read-only" => match val.to_lowercase().as_str() {
+                "yes" => state.replica_read_only.store(true, Ordering::Relaxed),
+                "no" => state.replica_read_only.store(false, Ordering::Relaxed),
+                _ => return Err("argument must be 'yes' or 'no'".to_string()),
+            },
+            // Connections that are already authenticated stay that way
+            "requirepass" => state.set_requirepass((!val.is_empty()).then(|| val.to_string())),
+            "protected-mode" => match val.to_lowercase().as_str() {
+                "yes" => state.protected_mode.store(true, Ordering::Relaxed),
+                "no" => state.protected_mode.store(false, Ordering::Relaxed),
+                _ => return Err("argument must be 'yes' or 'no'".to_string()),
+            },
+            "hz" => match config::parse_hz(val) {
+                Some(hz) => state.hz.store(hz, Ordering::Relaxed),
+                None => return Err("argument couldn't be parsed into an integer".to_string()),
+            },
+            "pipeline-batch-size" => match config::parse_pipeline_batch_size(val) {
+                Some(batch_size) => state.pipeline_batch_size.store(batch_size, Ordering::Relaxed),
+                None => return Err("argument must be a positive integer".to_string()),
+            },
+            "proto-max-bulk-len" => match config::parse_proto_max_bulk_len(val) {
+                Some(max_len) => state.proto_max_bulk_len.store(max_len, Ordering::Relaxed),
+                None => return Err("argument must be a memory value".to_string()),
+            },
+            "maxmemory" => match config::parse_maxmemory(val) {
+                Some(maxmemory) => state.maxmemory.store(maxmemory, Ordering::Relaxed),
+                None => return Err("argument must be a memory value".to_string()),
+            },
+            // Like in Redis, lowering it doesn't disconnect anyone; it only stops new connections being accepted
+            "maxclients" => match config::parse_maxclients(val) {
+                Some(maxclients) => state.maxclients.store(maxclients, Ordering::Relaxed),
+                None => return Err("argument must be a positive integer".to_string()),
+            },
+            "timeout" => match val.parse::<u64>() {
+                Ok(timeout) => state.timeout.store(timeout, Ordering::Relaxed),
+                Err(_) => return Err("argument couldn't be parsed into an integer".to_string()),
+            },
+            // Only connections accepted from now on get the new setting
+            "tcp-keepalive" => match val.parse::<u64>() {
+                Ok(tcp_keepalive) => state.tcp_keepalive.store(tcp_keepalive, Ordering::Relaxed),
+                Err(_) => return Err("argument couldn't be parsed into an integer".to_string()),
+            },
+            // Replaces every save point, with "" removing them all
+            "save" if val.is_empty() => state.lock_save_points().clear(),
+            "save" => match config::parse_save_points(val) {
+                Some(save_points) => *state.lock_save_points() = save_points,
+                None => return Err("Invalid save parameters".to_string()),
+            },
+            "slowlog-log-slower-than" => match val.parse::<i64>() {
+                Ok(log_slower_than) => state.slowlog.log_slower_than.store(log_slower_than, Ordering::Relaxed),
+                Err(_) => return Err("argument couldn't be parsed into an integer".to_string()),
+            },
+            // Like in Redis, a shorter max length only trims the log the next time a command is added to it
+            "slowlog-max-len" => match val.parse::<usize>() {
+                Ok(max_len) => state.slowlog.max_len.store(max_len, Ordering::Relaxed),
+                Err(_) => return Err("argument couldn't be parsed into an integer".to_string()),
+            },
+            // The logger is the process's, so this changes the loglevel of every server in it
+            "loglevel" => match config::parse_loglevel(val) {
+                Some(loglevel) => logging::set_loglevel(loglevel),
+                None => return Err("argument(s) must be one of the following: debug, verbose, notice, warning, nothing".to_string()),
+            },
+            "acllog-max-len" => match val.parse::<usize>() {
+                Ok(max_len) => state.acl_log.max_len.store(max_len, Ordering::Relaxed),
+                Err(_) => return Err("argument couldn't be parsed into an integer".to_string()),
+            },
+            "latency-monitor-threshold" => match val.parse::<u64>() {
+                Ok(threshold_ms) => state.latency_monitor.threshold_ms.store(threshold_ms, Ordering::Relaxed),
+                Err(_) => return Err("argument couldn't be parsed into an integer".to_string()),
+            },
+            _ => return Err("Unknown option or number of arguments for CONFIG SET".to_string()),
+        }
+        Ok(())
+    }
+
+    fn rewrite_config_file(config_file: &Path, state: &ServerState) -> io::Result<()> {
+        /* Write the current parameter values into the config file, via a temporary file so a failure leaves it intact */
+        let contents = std::fs::read_to_string(config_file)?;
+        let params = CONFIG_PARAMS.iter()
+            .filter_map(|param| Self::get_config_param(state, param).map(|val| (*param, val)))
+            .collect::<Vec<(&str, String)>>();
+        let tmp_path = config_file.with_file_name(format!("temp-config-{}.conf", std::process::id()));
+        std::fs::write(&tmp_path, config::rewrite(&contents, &params))?;
+        std::fs::rename(&tmp_path, config_file)
+    }
+
+    fn handle_client_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Inspect and name connections
+        CLIENT ID replies with the connection's ID, CLIENT SETNAME and CLIENT GETNAME set and get its name, and
+        CLIENT INFO describes it the way CLIENT LIST [ID id [id ...]] describes every connection (or the given ones),
+        with a line of `field=value` pairs each.
+        CLIENT KILL closes other connections: CLIENT KILL addr closes the one connected from that ip:port, and
+        CLIENT KILL [ID id] [ADDR ip:port] [LADDR ip:port] [SKIPME yes|no] every one that matches all the filters (but,
+        unless SKIPME is no, not this one), replying with how many there were. A client blocked in a blocking command
+        is only closed once the command returns.
+        CLIENT PAUSE timeout [WRITE|ALL] holds up every client's commands (or just the writes) for timeout ms, or until
+        CLIENT UNPAUSE; see handle_connection. Like in Redis, that includes this client's own commands, so only a
+        WRITE pause can be lifted early.
+        CLIENT REPLY ON|OFF|SKIP turns this connection's replies back on, off, or off for just the next command, so
+        that a client can send lots of commands without reading their replies. Only ON is replied to.
+        */
+        let subcommand = arg_to_string(&args[1]).to_uppercase();
+        let client_resp = match (subcommand.as_str(), args.len()) {
+            ("ID", 2) => RespValue::Integer(conn.id as i64),
+            ("SETNAME", 3) => {
+                // Names show up in CLIENT LIST's space separated fields, so they can't contain spaces (or worse)
+                if args[2].iter().any(|byte| !(b'!'..=b'~').contains(byte)) {
+                    RespValue::Error("ERR Client names cannot contain spaces, newlines or special characters.".to_string())
+                } else {
+                    conn.info.lock_details().name = args[2].to_vec();
+                    RespValue::ok()
+                }
+            },
+            ("GETNAME", 2) => match &conn.info.lock_details().name {
+                name if name.is_empty() => RespValue::Null,
+                name => RespValue::BulkString(name.clone()),
+            },
+            ("INFO", 2) => {
+                // Our own entry is brought up to date first, so that it shows this command
+                Self::record_client_command(conn, args, state);
+                RespValue::BulkString(format!("{}\n", conn.info.describe()).into_bytes())
+            },
+            ("LIST", _) => {
+                let ids = match args.get(2) {
+                    None => Ok(None),
+                    Some(filter) if filter.eq_ignore_ascii_case(b"ID") && args.len() > 3 => {
+                        args[3..].iter().map(|id| parse_arg::<u64>(id)).collect::<Option<HashSet<u64>>>()
+                            .map(Some)
+                            .ok_or_else(|| RespValue::Error("ERR Invalid client ID".to_string()))
+                    },
+                    Some(_) => Err(RespValue::Error("ERR syntax error".to_string())),
+                };
+                match ids {
+                    Ok(ids) => {
+                        Self::record_client_command(conn, args, state);
+                        let client_list = state.clients.list().iter()
+                            .filter(|client| ids.as_ref().map_or(true, |ids| ids.contains(&client.id)))
+                            .map(|client| format!("{}\n", client.describe()))
+                            .collect::<String>();
+                        RespValue::BulkString(client_list.into_bytes())
+                    },
+                    Err(list_err_response) => list_err_response,
+                }
+            },
+            ("PAUSE", 3 | 4) => {
+                let writes_only = match args.get(3) {
+                    None => Ok(false),
+                    Some(mode) if mode.eq_ignore_ascii_case(b"ALL") => Ok(false),
+                    Some(mode) if mode.eq_ignore_ascii_case(b"WRITE") => Ok(true),
+                    Some(_) => Err(RespValue::Error("ERR syntax error".to_string())),
+                };
+                match (parse_arg::<u64>(&args[2]), writes_only) {
+                    (None, _) => RespValue::Error("ERR timeout is not an integer or out of range".to_string()),
+                    (Some(_), Err(pause_err_response)) => pause_err_response,
+                    (Some(timeout_ms), Ok(writes_only)) => {
+                        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+                        state.clients.pause(ClientPause { deadline, writes_only });
+                        RespValue::ok()
+                    },
+                }
+            },
+            ("REPLY", 3) => match arg_to_string(&args[2]).to_uppercase().as_str() {
+                "ON" => {
+                    conn.reply_mode = ReplyMode::On;
+                    RespValue::ok()
+                },
+                // Neither of these is replied to
+                "OFF" => {
+                    conn.reply_mode = ReplyMode::Off;
+                    return Ok(());
+                },
+                "SKIP" => {
+                    if conn.reply_mode != ReplyMode::Off {
+                        conn.reply_mode = ReplyMode::SkipNext;
+                    }
+                    return Ok(());
+                },
+                _ => RespValue::Error("ERR syntax error".to_string()),
+            },
+            ("UNPAUSE", 2) => {
+                state.clients.unpause();
+                RespValue::ok()
+            },
+            ("KILL", 3) => match Self::kill_clients(state, |client| client.addr.as_bytes() == args[2].as_ref()) {
+                0 => RespValue::Error("ERR No such client".to_string()),
+                _ => RespValue::ok(),
+            },
+            ("KILL", num_args) if num_args > 3 && num_args % 2 == 0 => match ClientKillFilter::parse(&args[2..]) {
+                Ok(filter) => RespValue::Integer(Self::kill_clients(state, |client| filter.matches(client, conn.id)) as i64),
+                Err(kill_err) => RespValue::Error(kill_err),
+            },
+            ("KILL", _) => RespValue::Error("ERR syntax error".to_string()),
+            ("ID" | "SETNAME" | "GETNAME" | "INFO" | "PAUSE" | "REPLY" | "UNPAUSE", _) => RespValue::Error(format!("ERR wrong number of arguments for 'client|{}' command", subcommand.to_lowercase())),
+            _ => RespValue::Error(format!("ERR unknown subcommand '{}'. Try CLIENT HELP.", arg_to_string(&args[1]))),
+        };
+        conn.write_reply(&client_resp)?;
+        Ok(())
+    }
+
+    fn kill_clients(state: &ServerState, matches: impl Fn(&ClientInfo) -> bool) -> usize {
+        /* Kill the connections that match, returning how many there were */
+        let clients = state.clients.list().into_iter().filter(|client| matches(client)).collect::<Vec<Arc<ClientInfo>>>();
+        for client in &clients {
+            client.kill();
+        }
+        clients.len()
+    }
+
+    fn handle_slowlog_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Read the slow log: SLOWLOG GET [count] replies with the latest count entries (10 by default, all for -1) as
+        [id, unix time, microseconds, [args...], client address, client name], newest first; SLOWLOG LEN with how many
+        entries there are, and SLOWLOG RESET empties it
+        */
+        let subcommand = arg_to_string(&args[1]).to_uppercase();
+        let slowlog_resp = match (subcommand.as_str(), args.len()) {
+            ("GET", 2 | 3) => {
+                let count = match args.get(2).map(|count| parse_arg::<i64>(count)) {
+                    None => Some(10),
+                    Some(Some(-1)) => Some(usize::MAX),
+                    Some(Some(count)) => usize::try_from(count).ok(),
+                    Some(None) => None,
+                };
+                match count {
+                    Some(count) => RespValue::Array(state.slowlog.latest(count).into_iter().map(|entry| RespValue::Array(vec![
+                        RespValue::Integer(entry.id as i64),
+                        RespValue::Integer(entry.timestamp as i64),
+                        RespValue::Integer(entry.duration_us as i64),
+                        RespValue::bulk_strings(&entry.args.iter().map(|arg| arg.to_vec()).collect::<Vec<Vec<u8>>>()),
+                        RespValue::BulkString(entry.client_addr.into_bytes()),
+                        RespValue::BulkString(entry.client_name),
+                    ])).collect()),
+                    None => RespValue::Error("ERR count should be greater than or equal to -1".to_string()),
+                }
+            },
+            ("LEN", 2) => RespValue::Integer(state.slowlog.len() as i64),
+            ("RESET", 2) => {
+                state.slowlog.reset();
+                RespValue::ok()
+            },
+            ("GET" | "LEN" | "RESET", _) => RespValue::Error(format!("ERR wrong number of arguments for 'slowlog|{}' command", subcommand.to_lowercase())),
+            _ => RespValue::Error(format!("ERR unknown subcommand '{}'. Try SLOWLOG HELP.", arg_to_string(&args[1]))),
+        };
+        conn.write_reply(&slowlog_resp)?;
+        Ok(())
+    }
+
+    fn handle_latency_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Read the latency monitor: LATENCY HISTORY event replies with the event's [unix time, milliseconds] samples, oldest
+        first; LATENCY LATEST with [event, unix time, latest milliseconds, max milliseconds] for every event, and LATENCY
+        RESET [event ...] forgets the given events (all of them by default), replying with how many it forgot
+        */
+        let subcommand = arg_to_string(&args[1]).to_uppercase();
+        let latency_resp = match (subcommand.as_str(), args.len()) {
+            ("HISTORY", 3) => RespValue::Array(state.latency_monitor.history(&arg_to_string(&args[2])).into_iter().map(|sample| RespValue::Array(vec![
+                RespValue::Integer(sample.time as i64),
+                RespValue::Integer(sample.latency_ms as i64),
+            ])).collect()),
+            ("LATEST", 2) => RespValue::Array(state.latency_monitor.latest().into_iter().map(|sample| RespValue::Array(vec![
+                RespValue::BulkString(sample.event.into_bytes()),
+                RespValue::Integer(sample.latest.time as i64),
+                RespValue::Integer(sample.latest.latency_ms as i64),
+                RespValue::Integer(sample.max_ms as i64),
+            ])).collect()),
+            ("RESET", _) => {
+                let events = args[2..].iter().map(|event| arg_to_string(event)).collect::<Vec<String>>();
+                RespValue::Integer(state.latency_monitor.reset(&events) as i64)
+            },
+            ("HISTORY" | "LATEST", _) => RespValue::Error(format!("ERR wrong number of arguments for 'latency|{}' command", subcommand.to_lowercase())),
+            _ => RespValue::Error(format!("ERR unknown subcommand '{}'. Try LATENCY HELP.", arg_to_string(&args[1]))),
+        };
+        conn.write_reply(&latency_resp)?;
+        Ok(())
+    }
+
+    fn handle_config_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        CONFIG GET pattern [pattern ...] and CONFIG SET parameter value [parameter value ...] read and change the
+        parameters that can be changed at runtime (see CONFIG_PARAMS); CONFIG GET replies with every parameter matching
+        any of the glob-style patterns. Each parameter is its own atomic (or mutex), so a change applies to the commands
+        that start after it.
+        CONFIG REWRITE saves the current values to the config file the server was started with.
+        CONFIG RESETSTAT zeroes the statistics reported by INFO stats and commandstats.
+        */
+        let subcommand = arg_to_string(&args[1]).to_uppercase();
+        let config_resp = match (subcommand.as_str(), args.len()) {
+            ("GET", num_args) if num_args >= 3 => {
+                let patterns = args[2..].iter().map(|pattern| arg_to_string(pattern).to_lowercase()).collect::<Vec<String>>();
+                let params = CONFIG_PARAMS.iter()
+                    .filter(|param| patterns.iter().any(|pattern| glob_match(pattern.as_bytes(), param.as_bytes())))
+                    .filter_map(|param| Self::get_config_param(state, param).map(|val| (param.to_string(), val)))
+                    .map(|(param, val)| (RespValue::BulkString(param.into_bytes()), RespValue::BulkString(val.into_bytes())))
+                    .collect();
+                RespValue::Map(params)
+            },
+            ("SET", num_args) if num_args >= 4 && num_args % 2 == 0 => {
+                let set_result = args[2..].chunks(2).try_for_each(|param_and_val| {
+                    let param = arg_to_string(&param_and_val[0]).to_lowercase();
+                    Self::set_config_param(state, &param, &arg_to_string(&param_and_val[1]))
+                        .map_err(|err| format!("ERR CONFIG SET failed (possibly related to argument '{}') - {}", param, err))
+                });
+                match set_result {
+                    Ok(()) => RespValue::ok(),
+                    Err(config_set_err) => RespValue::Error(config_set_err),
+                }
+            },
+            ("REWRITE", 2) => match &state.config_file {
+                Some(config_file) => match Self::rewrite_config_file(config_file, state) {
+                    Ok(()) => RespValue::ok(),
+                    Err(err) => {
+                        error!("Error rewriting {}: {}", config_file.display(), err);
+                        RespValue::Error(format!("ERR Rewriting config file: {}", err))
+                    },
+                },
+                None => RespValue::Error("ERR The server is running without a config file".to_string()),
+            },
+            ("RESETSTAT", 2) => {
+                state.stats.reset();
+                RespValue::ok()
+            },
+            ("GET" | "SET" | "REWRITE" | "RESETSTAT", _) => RespValue::Error(format!("ERR wrong number of arguments for 'config|{}' command", subcommand.to_lowercase())),
+            _ => RespValue::Error(format!("ERR unknown subcommand '{}'. Try CONFIG HELP.", arg_to_string(&args[1]))),
+        };
+        conn.write_reply(&config_resp)?;
+        Ok(())
+    }
+
+    fn handle_lolwut_cmd(conn: &mut Connection, _args: &[Bytes], _state: &ServerState) -> io::Result<()> {
+        /* Redis replies with generative art followed by its version; we only have the version */
+        let lolwut = format!("Redis ver. {}\n", REDIS_VERSION);
+        let lolwut_resp = RespValue::BulkString(lolwut.as_bytes().to_vec());
+        conn.write_reply(&lolwut_resp)?;
+        Ok(())
+    }
+
+    fn handle_debug_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Introspection helpers for testing, e.g. DEBUG OBJECT key
+        DEBUG OBJECT reports the internal representation of the value at key. It doesn't expire the key,
+        so `expired:1` shows a key that is logically expired but hasn't been removed yet.
+        DEBUG SET-ACTIVE-EXPIRE 0|1 turns the background active expiration cycle off or on.
+        DEBUG RELOAD saves the dataset to the RDB file and loads it back, to test the snapshot round-trip.
+        DEBUG STRINGMATCH-LEN pattern string replies with 1 if string matches the glob-style pattern, and 0 if not.
+        DEBUG SLEEP seconds (decimals allowed) takes that long to reply OK, e.g. to test the slow log or timeouts. Unlike
+        in Redis, only this connection is held up (along with EXEC, which waits for running commands).
+        */
+        let subcommand = arg_to_string(&args[1]).to_uppercase();
+        let debug_resp = match (subcommand.as_str(), args.len()) {
+            ("OBJECT", 3) => {
+                let c = state.lock_keys(conn.db, [&args[2]]);
+                match c.get(&args[2]) {
+                    Some(KeyEntry { value: val, expiry_ts }) => {
+                        // Quicklists are linked lists of listpack nodes, each holding up to list_max_listpack_size elements
+                        let ql_nodes = match val {
+                            RedisValue::List(RedisList::Quicklist(elems)) => {
+                                let node_size = state.encoding_limits.list_max_listpack_size.max(1);
+                                format!(" ql_nodes:{}", (elems.len() + node_size - 1) / node_size)
+                            },
+                            RedisValue::List(RedisList::Listpack(_)) => " ql_nodes:1".to_string(),
+                            _ => String::new(),
+                        };
+                        RespValue::SimpleString(format!(
+                            "Value at:{:p} refcount:1 encoding:{} serializedlength:{} expired:{}{}",
+                            val,
+                            val.encoding(),
+                            val.serialized_len(),
+                            expiry_ts.is_some_and(|expiry_ts| Self::curr_time_ms() > expiry_ts) as u8,
+                            ql_nodes
+                        ).into_bytes())
+                    },
+                    None => RespValue::Error("ERR no such key".to_string()),
+                }
+            },
+            ("SET-ACTIVE-EXPIRE", 3) => match args[2].as_ref() {
+                b"0" | b"1" => {
+                    state.active_expire_enabled.store(args[2][..] == b"1"[..], Ordering::Relaxed);
+                    RespValue::ok()
+                },
+                _ => RespValue::Error("ERR value is out of range, must be 0 or 1".to_string()),
+            },
+            ("RELOAD", 2) => {
+                // Hold the locks throughout so no writes land between the save and the load
+                let mut dbs = state.lock_all_dbs();
+                match rdb::save(&state.rdb_path, dbs.iter().map(|c| c.iter())) {
+                    Ok(()) => match rdb::load_file(&state.rdb_path, &state.encoding_limits, Self::curr_time_ms()) {
+                        Ok(loaded) => match Self::replace_dbs(&mut dbs, loaded) {
+                            Ok(()) => RespValue::ok(),
+                            Err(err) => {
+                                error!("Error loading {}: {}", state.rdb_path.display(), err);
+                                RespValue::Error("ERR Error trying to load the RDB dump, check server logs.".to_string())
+                            },
+                        },
+                        Err(err) => {
+                            error!("Error loading {}: {}", state.rdb_path.display(), err);
+                            RespValue::Error("ERR Error trying to load the RDB dump, check server logs.".to_string())
+                        },
+                    },
+                    Err(err) => {
+                        error!("Error saving {}: {}", state.rdb_path.display(), err);
+                        RespValue::Error("ERR Error trying to save the DB, check server logs.".to_string())
+                    },
+                }
+            },
+            ("STRINGMATCH-LEN", 4) => RespValue::Integer(glob_match(&args[2], &args[3]) as i64),
+            ("SLEEP", 3) => match parse_arg::<f64>(&args[2]).and_then(|secs| Duration::try_from_secs_f64(secs).ok()) {
+                Some(duration) => {
+                    std::thread::sleep(duration);
+                    RespValue::ok()
+                },
+                None => RespValue::Error("ERR value is not a valid float".to_string()),
+            },
+            ("OBJECT" | "SET-ACTIVE-EXPIRE" | "RELOAD" | "STRINGMATCH-LEN" | "SLEEP", _) => RespValue::Error(format!(
+                "ERR wrong number of arguments for 'debug|{}' command",
+                subcommand.to_lowercase()
+            )),
+            _ => RespValue::Error(format!("ERR unknown subcommand '{}'. Try DEBUG HELP.", arg_to_string(&args[1]))),
+        };
+        conn.write_reply(&debug_resp)?;
+        Ok(())
+    }
+
+    fn encode_help(cmd_name: &str, help_lines: &[&str]) -> RespValue {
+        /* Reply to `<CMD> HELP` with a line per simple string, framed by a header and the HELP subcommand's own entry */
+        let header = format!("{} <subcommand> [<arg> [value] [opt] ...]. Subcommands are:", cmd_name.to_uppercase());
+        let lines = std::iter::once(header.as_str())
+            .chain(help_lines.iter().copied())
+            .chain(["HELP", "    Print this help."])
+            .map(|line| RespValue::SimpleString(line.as_bytes().to_vec()))
+            .collect();
+        RespValue::Array(lines)
+    }
+
+    fn unknown_command_err(args: &[Bytes]) -> String {
+        /* Same wording as Redis, which quotes the first few arguments to help spot typos in what was sent */
+        let args_preview = args[1..].iter()
+            .take(MAX_UNKNOWN_CMD_ARGS_SHOWN)
+            .map(|arg| format!("'{}' ", arg_to_string(arg)))
+            .collect::<String>();
+        format!("ERR unknown command '{}', with args beginning with: {}", arg_to_string(&args[0]), args_preview)
+    }
+
+    fn handle_cmd(args: &[Bytes], conn: &mut Connection, state: &ServerState) -> io::Result<()> {
+        /* Run a command, then record it (and what it changed about the connection) for CLIENT LIST */
+        let cmd_result = Self::dispatch_cmd(args, conn, state);
+        Self::record_client_command(conn, args, state);
+        // CLIENT REPLY SKIP only drops the replies of the command after it
+        conn.reply_mode = match conn.reply_mode {
+            ReplyMode::SkipNext => ReplyMode::Skip,
+            ReplyMode::Skip => ReplyMode::On,
+            reply_mode => reply_mode,
+        };
+        cmd_result
+    }
+
+    fn record_client_command(conn: &Connection, args: &[Bytes], state: &ServerState) {
+        /* Update the connection's CLIENT LIST entry; commands with subcommands are recorded like `client|list` */
+        let cmd_name = arg_to_string(&args[0]).to_lowercase();
+        let last_cmd = match state.commands.get(cmd_name.as_str()) {
+            Some(cmd_spec) => match args.get(1) {
+                Some(subcommand) if SUBCOMMAND_HELP.iter().any(|(name, _)| *name == cmd_spec.name) => {
+                    format!("{}|{}", cmd_spec.name, arg_to_string(subcommand).to_lowercase())
+                },
+                _ => cmd_spec.name.to_string(),
+            },
+            None => "NULL".to_string(),
+        };
+        let mut details = conn.info.lock_details();
+        details.last_interaction = Instant::now();
+        details.last_cmd = last_cmd;
+        details.db = conn.db;
+        details.sub = conn.channels.len();
+        details.psub = conn.patterns.len();
+        details.multi = conn.transaction.as_ref().map(Vec::len);
+        details.monitor = conn.monitoring;
+        details.blocked = false;
+        details.resp = conn.protocol;
+    }
+
+    fn dispatch_cmd(args: &[Bytes], conn: &mut Connection, state: &ServerState) -> io::Result<()> {
+        /* Look up the command in the dispatch table, validate its arity, and run its handler */
+        let cmd_name = arg_to_string(&args[0]).to_lowercase();
+        let Some(cmd_spec) = state.commands.get(cmd_name.as_str()) else {
+            conn.transaction_error |= conn.transaction.is_some();
+            let unknown_err_response = RespValue::Error(Self::unknown_command_err(args));
+            conn.write_reply(&unknown_err_response)?;
+            return Ok(());
+        };
+        if let Err(arity_err) = cmd_spec.check_arity(args.len() - 1) {
+            // A command that can't be queued makes the whole transaction fail
+            conn.transaction_error |= conn.transaction.is_some();
+            let arity_err_response = RespValue::Error(arity_err);
+            conn.write_reply(&arity_err_response)?;
+            return Ok(());
+        }
+        if state.requires_auth(conn) && !cmd_spec.flags.contains(&"no_auth") {
+            conn.transaction_error |= conn.transaction.is_some();
+            conn.write_reply(&RespValue::Error("NOAUTH Authentication required.".to_string()))?;
+            return Ok(());
+        }
+        // Like in Redis, commands that don't need authentication don't need permission either, so AUTH always works
+        if !cmd_spec.flags.contains(&"no_auth") && !state.acl.is_allowed(&conn.user, cmd_spec.name) {
+            state.acl_log.record(DenialReason::Command, Self::acl_log_context(conn), cmd_spec.name, &conn.user, conn.info.describe());
+            conn.transaction_error |= conn.transaction.is_some();
+            let noperm_err_response = RespValue::Error(format!("NOPERM User {} has no permissions to run the '{}' command", conn.user, cmd_spec.name));
+            conn.write_reply(&noperm_err_response)?;
+            return Ok(());
+        }
+        if state.loading.load(Ordering::Relaxed) && !cmd_spec.flags.contains(&"loading") {
+            conn.transaction_error |= conn.transaction.is_some();
+            conn.write_reply(&RespValue::Error("LOADING Redis is loading the dataset in memory".to_string()))?;
+            return Ok(());
+        }
+        // RESP2 can't tell replies apart from published messages, so a subscribed connection can only manage its subscriptions
+        let is_subscription_cmd = matches!(cmd_spec.name, "subscribe" | "unsubscribe" | "psubscribe" | "punsubscribe" | "ping" | "quit" | "reset");
+        if conn.protocol == 2 && conn.num_subscriptions() > 0 && !is_subscription_cmd {
+            let subscribed_err_response = RespValue::Error(format!("ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                cmd_spec.name));
+            conn.write_reply(&subscribed_err_response)?;
+            return Ok(());
+        }
+        // Like a replica with replica-read-only set, only clients of our own master (of which there are none) can write
+        if cmd_spec.flags.contains(&"write") && state.replica_read_only.load(Ordering::Relaxed) {
+            conn.transaction_error |= conn.transaction.is_some();
+            let readonly_err_response = RespValue::Error("READONLY You can't write against a read only replica.".to_string());
+            conn.write_reply(&readonly_err_response)?;
+            return Ok(());
+        }
+        if let Some(transaction) = &mut conn.transaction {
+            if !matches!(cmd_spec.name, "multi" | "exec" | "discard" | "watch" | "quit" | "reset") {
+                transaction.push(args.to_vec());
+                conn.write_reply(&RespValue::SimpleString(b"QUEUED".to_vec()))?;
+                return Ok(());
+            }
+        }
+        // EXEC takes the lock for writing itself, and blocking commands would hold up EXEC for as long as they wait
+        let _exec_guard = (cmd_spec.name != "exec" && !cmd_spec.flags.contains(&"blocking")).then(|| state.read_exec_lock());
+        Self::run_command(cmd_spec, args, conn, state)
+    }
+
+    fn run_command(cmd_spec: &CommandSpec, args: &[Bytes], conn: &mut Connection, state: &ServerState) -> io::Result<()> {
+        /*
+        Run the command's handler (or reply to `<CMD> HELP`), recording its stats
+        Write commands bump the versions of their keys afterwards, whether or not they actually changed anything.
+        Monitors are fed the command before it runs; like in Redis, admin commands (e.g. CONFIG) are left out.
+        Slow commands go in the slow log (and spikes in the latency monitor), except for EXEC (whose commands are logged
+        on their own) and blocking commands, whose time spent waiting would count as running.
+        */
+        let logged_args = Self::redact_args(cmd_spec, args);
+        if !cmd_spec.flags.contains(&"admin") {
+            Self::feed_monitors(&logged_args, conn, state);
+        }
+        let help_lines = SUBCOMMAND_HELP.iter().find(|(name, _)| *name == cmd_spec.name).map(|(_, lines)| *lines);
+        let cmd_start = Instant::now();
+        let cmd_result = match help_lines {
+            Some(help_lines) if args.len() == 2 && args[1].eq_ignore_ascii_case(b"HELP") => {
+                conn.write_reply(&Self::encode_help(cmd_spec.name, help_lines))
+            },
+            _ => cmd_spec.handler.handle(conn, args, state),
+        };
+        Self::mark_written(cmd_spec, args, conn, state);
+        let cmd_duration = cmd_start.elapsed();
+        state.stats.record_command(cmd_spec.name, cmd_duration);
+        if cmd_spec.name != "exec" && !cmd_spec.flags.contains(&"blocking") {
+            if state.slowlog.is_slow(cmd_duration) {
+                let client_name = conn.info.lock_details().name.clone();
+                state.slowlog.record(&logged_args, cmd_duration, &conn.info.addr, &client_name);
+            }
+            state.latency_monitor.add_sample_if_needed("command", cmd_duration);
+        }
+        cmd_result
+    }
+
+    fn mark_written(cmd_spec: &CommandSpec, args: &[Bytes], conn: &Connection, state: &ServerState) {
+        /* After a write command runs, bump the versions of its keys for WATCH and count it as a change since the last save */
+        if cmd_spec.flags.contains(&"write") {
+            for key in cmd_spec.key_args(args) {
+                state.mark_modified(conn.db, key);
+            }
+            state.dirty.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn redact_args<'a>(cmd_spec: &CommandSpec, args: &'a [Bytes]) -> Cow<'a, [Bytes]> {
+        /*
+        The command's arguments with passwords (AUTH's, HELLO's AUTH option's, or the rules of ACL SETUSER, which may set
+        some) hidden, for MONITOR and the slow log
+        */
+        let redact_from = match cmd_spec.name {
+            "auth" => Some(1),
+            "acl" if args[1].eq_ignore_ascii_case(b"SETUSER") => Some(3),
+            "hello" => args.iter().position(|arg| arg.eq_ignore_ascii_case(b"AUTH")).map(|auth_pos| auth_pos + 1),
+            _ => None,
+        };
+        match redact_from {
+            Some(redact_from) => Cow::Owned(
+                args.iter().enumerate()
+                    .map(|(idx, arg)| if idx < redact_from { arg.clone() } else { Bytes::from_static(b"(redacted)") })
+                    .collect(),
+            ),
+            None => Cow::Borrowed(args),
+        }
+    }
+
+    fn handle_multi_cmd(conn: &mut Connection, _args: &[Bytes], _state: &ServerState) -> io::Result<()> {
+        /* Start a transaction: the following commands are queued until EXEC runs them all at once */
+        let multi_resp = match conn.transaction {
+            Some(_) => RespValue::Error("ERR MULTI calls can not be nested".to_string()),
+            None => {
+                conn.transaction = Some(Vec::new());
+                RespValue::ok()
+            },
+        };
+        conn.write_reply(&multi_resp)?;
+        Ok(())
+    }
+
+    fn handle_exec_cmd(conn: &mut Connection, _args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Run the commands queued since MULTI, replying with an array of their replies
+        No other command runs in the meantime. The transaction is aborted with a null reply instead if any of the
+        watched keys was written since WATCH, or with an error if any command was rejected while being queued.
+        */
+        let Some(transaction) = conn.transaction.take() else {
+            let exec_err_response = RespValue::Error("ERR EXEC without MULTI".to_string());
+            conn.write_reply(&exec_err_response)?;
+            return Ok(());
+        };
+        let _exec_guard = state.write_exec_lock();
+        let watched_key_modified = conn.watched_keys.iter().any(|((db, key), version)| state.key_version(*db, key) != *version);
+        Self::unwatch_all(conn, state);
+        if std::mem::take(&mut conn.transaction_error) {
+            let exec_err_response = RespValue::Error("EXECABORT Transaction discarded because of previous errors.".to_string());
+            conn.write_reply(&exec_err_response)?;
+            return Ok(());
+        }
+        if watched_key_modified {
+            let exec_resp = RespValue::NullArray;
+            conn.write_reply(&exec_resp)?;
+            return Ok(());
+        }
+        // The elements are the queued commands' own replies, which they write as they run, so only the header is ours
+        conn.queue_output(Cow::Owned(format!("*{}{}", transaction.len(), RESP_DELIMITER).into_bytes()))?;
+        conn.in_exec = true;
+        let exec_result = transaction.iter().try_for_each(|args| {
+            // Every queued command passed the lookup and arity check in handle_cmd
+            let cmd_spec = &state.commands[arg_to_string(&args[0]).to_lowercase().as_str()];
+            Self::run_command(cmd_spec, args, conn, state)
+        });
+        conn.in_exec = false;
+        exec_result
+    }
+
+    fn handle_discard_cmd(conn: &mut Connection, _args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Throw away the commands queued since MULTI, and unwatch all keys */
+        let discard_resp = match conn.transaction.take() {
+            Some(_) => {
+                conn.transaction_error = false;
+                Self::unwatch_all(conn, state);
+                RespValue::ok()
+            },
+            None => RespValue::Error("ERR DISCARD without MULTI".to_string()),
+        };
+        conn.write_reply(&discard_resp)?;
+        Ok(())
+    }
+
+    fn handle_watch_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Make the next EXEC abort if any of the keys is written before it runs */
+        if conn.transaction.is_some() {
+            let watch_err_response = RespValue::Error("ERR WATCH inside MULTI is not allowed".to_string());
+            conn.write_reply(&watch_err_response)?;
+            return Ok(());
+        }
+        for key in &args[1..] {
+            // Watching a key again keeps the version from the first WATCH
+            let db = conn.db;
+            if let Entry::Vacant(entry) = conn.watched_keys.entry((db, key.clone())) {
+                entry.insert(state.watch_key(db, key));
+            }
+        }
+        conn.write_reply(&RespValue::ok())?;
+        Ok(())
+    }
+
+    fn handle_unwatch_cmd(conn: &mut Connection, _args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Forget all watched keys */
+        Self::unwatch_all(conn, state);
+        conn.write_reply(&RespValue::ok())?;
+        Ok(())
+    }
+
+    fn unwatch_all(conn: &mut Connection, state: &ServerState) {
+        /* Stop watching the connection's keys, e.g. once EXEC has checked them or when the connection closes */
+        if !conn.watched_keys.is_empty() {
+            state.unwatch_keys(conn.watched_keys.drain().map(|(db_key, _)| db_key));
+        }
+    }
+
+    fn is_write_cmd(cmd_spec: &CommandSpec, conn: &Connection, state: &ServerState) -> bool {
+        /* Whether CLIENT PAUSE WRITE holds up the command: a write command, or an EXEC with one queued */
+        let is_queued_write = |queued_args: &Vec<Bytes>| {
+            state.commands.get(arg_to_string(&queued_args[0]).to_lowercase().as_str())
+                .is_some_and(|queued_spec| queued_spec.flags.contains(&"write"))
+        };
+        cmd_spec.flags.contains(&"write")
+            || (cmd_spec.name == "exec" && conn.transaction.as_ref().is_some_and(|transaction| transaction.iter().any(is_queued_write)))
+    }
+
+    fn is_disconnect(err: &io::Error) -> bool {
+        /* Errors that just mean the client went away, which isn't a problem on our end */
+        matches!(
+            err.kind(),
+            ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::BrokenPipe
+                | ErrorKind::NotConnected
+                | ErrorKind::UnexpectedEof
+        )
+    }
+
+    fn is_idle_for_ping(conn: &Connection, state: &ServerState) -> bool {
+        /*
+        Whether a PING from the connection can skip dispatch_cmd: nothing that dispatch_cmd checks would change its
+        reply (authentication, ACL permissions, MULTI, the loading state, RESP2's subscribed mode), CLIENT PAUSE wouldn't
+        hold it up, CLIENT REPLY hasn't changed what gets replied to, and there are no monitors that need to see it
+        */
+        !state.requires_auth(conn)
+            && state.acl.is_allowed(&conn.user, "ping")
+            && conn.transaction.is_none()
+            && !state.loading.load(Ordering::Relaxed)
+            && conn.num_subscriptions() == 0
+            && state.clients.paused_until(false).is_none()
+            && conn.reply_mode == ReplyMode::On
+            && state.read_monitors().is_empty()
+    }
+
+    async fn wait_while_blocked(conn: &mut Connection, state: &ServerState) -> io::Result<()> {
+        /*
+        Run the command the connection is blocked on again after every write to its database, until it replies, or reply
+        with a null array once its deadline passes. The replies of the commands before it are sent before waiting.
+        A write command's keys are marked modified once more after the run that pops, like after any other command.
+        */
+        while let Some(blocked) = conn.blocked.take() {
+            conn.flush().await?;
+            conn.info.lock_details().blocked = true;
+            state.stats.blocked_clients.fetch_add(1, Ordering::Relaxed);
+            let timed_out = tokio::select! {
+                biased;
+                _ = conn.info.killed() => None,
+                timed_out = state.dbs[conn.db].wait_for_writes(blocked.write_generation, blocked.deadline) => Some(timed_out),
+            };
+            state.stats.blocked_clients.fetch_sub(1, Ordering::Relaxed);
+            conn.info.lock_details().blocked = false;
+            match timed_out {
+                Some(timed_out) => Self::resume_blocked_cmd(conn, state, blocked, timed_out)?,
+                None => {
+                    debug!("Client {} was killed with CLIENT KILL while blocked", conn.id);
+                    return Err(io::Error::from(ErrorKind::ConnectionAborted));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn resume_blocked_cmd(conn: &mut Connection, state: &ServerState, blocked: BlockedCmd, timed_out: bool) -> io::Result<()> {
+        /* Reply with a null array if the blocked command's deadline passed, and otherwise run it again, maybe blocking again */
+        if timed_out {
+            let timed_out_resp = RespValue::NullArray;
+            return conn.write_reply(&timed_out_resp);
+        }
+        // It blocked the first time it ran, so it's a known command
+        let cmd_spec = &state.commands[arg_to_string(&blocked.args[0]).to_lowercase().as_str()];
+        conn.resumed_deadline = Some(blocked.deadline);
+        let cmd_result = cmd_spec.handler.handle(conn, &blocked.args, state);
+        conn.resumed_deadline = None;
+        cmd_result?;
+        if conn.blocked.is_none() {
+            Self::mark_written(cmd_spec, &blocked.args, conn, state);
+        }
+        Ok(())
+    }
+
+    async fn handle_connection(conn: &mut Connection, state: &ServerState) -> io::Result<()> {
+        /*
+        Handle a given stream/connection/request in an async task
+        A 0-byte read means the client closed (or half-closed) its write side, possibly right after its last
+        command; its reply is flushed and our write side shut down before returning so the client sees it all.
+        A read can hold several pipelined commands; after every pipeline-batch-size of them the task yields so that one
+        client pipelining thousands of commands can't keep the other connections from getting a turn. Reads are
+        appended to the connection's query buffer, so a command split across several reads runs once all of it is in.
+        Replies are buffered and flushed once per read (and per batch), so a pipeline's replies share a few writes.
+        Handlers themselves never wait, on the socket or for writes: replies are flushed here, and a blocking command that
+        has nothing to reply with yet leaves it to wait_while_blocked to run it again.
+        */
+        let mut read_buffer = [0; CHUNK_SIZE];
+        'read: loop {
+            let subscriber = conn.subscriber.clone();
+            let subscriber_killed = async move {
+                match subscriber {
+                    Some(subscriber) => subscriber.killed().await,
+                    None => std::future::pending().await,
+                }
+            };
+            let num_bytes_read = tokio::select! {
+                // Checked first, so that a killed client's pending commands aren't run
+                biased;
+                _ = conn.info.killed() => {
+                    debug!("Client {} was killed with CLIENT KILL", conn.id);
+                    return Err(io::Error::from(ErrorKind::ConnectionAborted));
+                },
+                // The writer task gives up on a subscriber that can't keep up, and then so does the read loop
+                _ = subscriber_killed => return Err(io::Error::from(ErrorKind::BrokenPipe)),
+                num_bytes_read = conn.reader.read(&mut read_buffer) => num_bytes_read?,
+            };
+            trace!("Num bytes read: {}", num_bytes_read);
+            if num_bytes_read == 0 {
+                break;
+            }
+
+            let request = &read_buffer[..num_bytes_read];
+            // Health checks send a lot of bare PINGs, so answer them without going through the parser when dispatch_cmd
+            // would just reply PONG too
+            if conn.query_buf.is_empty() && request.eq_ignore_ascii_case(PING_FRAME) && Self::is_idle_for_ping(conn, state) {
+                let ping_start = Instant::now();
+                conn.queue_output(Cow::Borrowed(PONG_REPLY))?;
+                state.stats.record_command("ping", ping_start.elapsed());
+                conn.flush().await?;
+                continue;
+            }
+            trace!("Stream input: {:?}", String::from_utf8_lossy(request));
+            conn.query_buf.extend_from_slice(request);
+            let (mut pos, mut num_cmds_in_batch) = (0, 0);
+            while pos < conn.query_buf.len() {
+                if num_cmds_in_batch >= state.pipeline_batch_size.load(Ordering::Relaxed) {
+                    conn.flush().await?;
+                    tokio::task::yield_now().await;
+                    num_cmds_in_batch = 0;
+                }
+                let max_bulk_len = state.proto_max_bulk_len.load(Ordering::Relaxed);
+                let args = match resp::parse_command(&conn.query_buf[pos..], max_bulk_len) {
+                    Ok(Some((args, cmd_len))) => {
+                        pos += cmd_len;
+                        args
+                    },
+                    // The rest of the command hasn't arrived yet; it's parsed again once the next read adds to it
+                    Ok(None) => break,
+                    Err(protocol_err) => {
+                        let protocol_err_response = RespValue::Error(format!("ERR Protocol error: {}", protocol_err));
+                        conn.write_reply(&protocol_err_response)?;
+                        break 'read;
+                    }
+                };
+                // Like Redis, an empty array is not a command and is silently ignored
+                if args.is_empty() {
+                    continue;
+                }
+                let cmd_spec = state.commands.get(arg_to_string(&args[0]).to_lowercase().as_str());
+                // Held up by CLIENT PAUSE before running, once the replies of the commands before it are sent
+                let is_write = cmd_spec.is_some_and(|cmd_spec| Self::is_write_cmd(cmd_spec, conn, state));
+                if state.clients.paused_until(is_write).is_some() {
+                    conn.flush().await?;
+                    state.stats.blocked_clients.fetch_add(1, Ordering::Relaxed);
+                    let killed = tokio::select! {
+                        _ = state.clients.wait_while_paused(is_write) => false,
+                        _ = conn.info.killed() => true,
+                    };
+                    state.stats.blocked_clients.fetch_sub(1, Ordering::Relaxed);
+                    if killed {
+                        return Err(io::Error::from(ErrorKind::ConnectionAborted));
+                    }
+                }
+                Self::handle_cmd(&args, conn, state)?;
+                Self::wait_while_blocked(conn, state).await?;
+                num_cmds_in_batch += 1;
+                // Anything pipelined after QUIT is dropped
+                if conn.closing {
+                    break 'read;
+                }
+                if conn.pending_reply_len >= REPLY_BUFFER_LIMIT {
+                    conn.flush().await?;
+                }
+            }
+            conn.query_buf.drain(..pos);
+            conn.flush().await?;
+        }
+
+        conn.flush().await?;
+        match &mut conn.writer {
+            Some(writer) => writer.shutdown().await,
+            None => Ok(()),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(|listener| listener.local_addr()).collect()
+    }
+
+    pub fn listeners(&self) -> &[TcpListener] {
+        /* The bound (non-blocking) listeners, for the front ends in examples that accept connections themselves */
+        &self.listeners
+    }
+
+    pub fn prepare(&self) -> anyhow::Result<()> {
+        /*
+        What run does before serving connections, for the front ends in examples that serve them themselves
+        The snapshot is loaded before returning rather than in the background. The cron jobs are only registered; they
+        run once start_background_jobs starts them, or every time run_cron is called.
+        */
+        if self.state.loading.load(Ordering::Relaxed) {
+            Self::load_dataset(&self.state)?;
+        }
+        Self::register_cron_jobs(&self.state);
+        Self::notify_ready(&self.state);
+        Ok(())
+    }
+
+    pub fn start_background_jobs(&self) -> anyhow::Result<()> {
+        /* Prepare the server, then run the cron jobs hz times a second on a thread of their own until shutdown is requested */
+        self.prepare()?;
+        let cron_runtime = tokio::runtime::Builder::new_current_thread().enable_time().build()?;
+        let state = Arc::clone(&self.state);
+        std::thread::Builder::new()
+            .name("server-cron".to_string())
+            .spawn(move || cron_runtime.block_on(Self::server_cron_loop(state)))?;
+        Ok(())
+    }
+
+    pub fn run_cron(&self) {
+        /* Run every cron job once, for front ends that schedule them themselves, which should do so hz times a second */
+        Self::run_cron_jobs(&self.state);
+    }
+
+    pub fn hz(&self) -> u64 {
+        /* The configured hz, which CONFIG SET can change while the server runs */
+        self.state.hz.load(Ordering::Relaxed)
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        /* Whether SHUTDOWN (or shutdown) was called, after which the front ends in examples stop serving too */
+        self.state.shutting_down.load(Ordering::Relaxed)
+    }
+
+    pub fn new_session(&self, peer: SocketAddr, local: SocketAddr) -> Result<Session, String> {
+        /*
+        Register a client that connected to one of the front ends in examples; it's unregistered once the session is dropped
+        Like run, refuses it past maxclients or in protected mode, with the error to send the client before disconnecting.
+        */
+        if self.state.clients.len() >= self.state.maxclients.load(Ordering::Relaxed) {
+            self.state.stats.rejected_connections.fetch_add(1, Ordering::Relaxed);
+            return Err("ERR max number of clients reached".to_string());
+        }
+        if self.state.is_protected_from(peer) {
+            warn!("Refused connection from {} in protected mode", peer);
+            return Err(PROTECTED_MODE_ERR.to_string());
+        }
+        let id = self.state.next_client_id.fetch_add(1, Ordering::Relaxed);
+        let info = ClientInfo::new(id, peer.to_string(), local.to_string());
+        let mut conn = Connection::from_halves(Box::new(tokio::io::empty()), Box::new(tokio::io::sink()), id, info);
+        // The caller writes the output itself, and a subscriber without a writer keeps its output queued for take_output
+        conn.writer = None;
+        conn.authenticated = !self.state.acl.default_requires_password();
+        self.state.clients.register(Arc::clone(&conn.info));
+        self.state.stats.connected_clients.fetch_add(1, Ordering::Relaxed);
+        self.state.stats.total_connections_received.fetch_add(1, Ordering::Relaxed);
+        Ok(Session { conn, state: Arc::clone(&self.state), paused: false })
+    }
+
+    async fn accept_any(listeners: &[tokio::net::TcpListener]) -> io::Result<(tokio::net::TcpStream, SocketAddr)> {
+        /* Accept the next connection on whichever listener gets one first */
+        std::future::poll_fn(|cx| {
+            listeners.iter()
+                .find_map(|listener| match listener.poll_accept(cx) {
+                    Poll::Ready(accepted) => Some(accepted),
+                    Poll::Pending => None,
+                })
+                .map_or(Poll::Pending, Poll::Ready)
+        }).await
+    }
+
+    async fn accept_unix(unix_listener: Option<&tokio::net::UnixListener>) -> io::Result<tokio::net::UnixStream> {
+        /* Accept the next connection on the unixsocket, or wait forever if there isn't one */
+        match unix_listener {
+            Some(unix_listener) => unix_listener.accept().await.map(|(stream, _)| stream),
+            None => std::future::pending().await,
+        }
+    }
+
+    fn reject_past_maxclients(state: &ServerState, stream: impl AsyncWrite + Unpin + Send + 'static) {
+        debug!("Rejected new connection: max number of clients reached");
+        state.stats.rejected_connections.fetch_add(1, Ordering::Relaxed);
+        Self::refuse_connection(stream, "ERR max number of clients reached");
+    }
+
+    fn refuse_connection(mut stream: impl AsyncWrite + Unpin + Send + 'static, err: &str) {
+        /* Tell a client that isn't let in why, then disconnect it */
+        let err_response = format!("-{}{}", err, RESP_DELIMITER);
+        // A fresh socket's send buffer has room for the error, so these tasks finish right away and can't pile up
+        tokio::spawn(async move {
+            let _ = stream.write_all(err_response.as_bytes()).await;
+        });
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        /*
+        Stop accepting connections, making run return and the cron jobs stop
+        Connections that are already open are served until their clients close them (or the runtime is dropped).
+        */
+        self.state.request_shutdown();
+        Ok(())
+    }
+
+    pub async fn run(&self) -> anyhow::Result<()> {
+        /*
+        Listen for incoming connections until shutdown is called,
+        and spawn an async task to handle the stream/connection/request
+        Idle connections are just tasks waiting on a read, so thousands of them don't tie up the runtime's workers.
+        */
+        let metrics_task = match &self.metrics_addr {
+            Some((metrics_bind, metrics_port)) => {
+                let metrics_listener = tokio::net::TcpListener::bind((metrics_bind.as_str(), *metrics_port)).await?;
+                info!("Serving metrics on {}", metrics_listener.local_addr()?);
+                let state = Arc::clone(&self.state);
+                let render = move || state.stats.render_prometheus(state.lock_all_dbs().iter().map(|c| c.len()).sum());
+                Some(tokio::spawn(metrics::serve(metrics_listener, render)))
+            },
+            None => None,
+        };
+        Self::register_cron_jobs(&self.state);
+        tokio::spawn(Self::server_cron_loop(Arc::clone(&self.state)));
+        let local_addrs = self.local_addrs()?;
+        info!("Ready to accept connections on {}", local_addrs.iter().map(|addr| addr.to_string()).collect::<Vec<String>>().join(", "));
+        let listeners = self.listeners.iter()
+            .map(|listener| listener.try_clone().and_then(tokio::net::TcpListener::from_std))
+            .collect::<io::Result<Vec<tokio::net::TcpListener>>>()?;
+        let unix_listener = match &self.unix_listener {
+            Some((unix_listener, path)) => {
+                info!("Ready to accept connections on {}", path.display());
+                Some((tokio::net::UnixListener::from_std(unix_listener.try_clone()?)?, path))
+            },
+            None => None,
+        };
+        // The snapshot is loaded in the background, so that clients can already connect (and get -LOADING) meanwhile
+        let load_task = self.state.loading.load(Ordering::Relaxed).then(|| {
+            let state = Arc::clone(&self.state);
+            tokio::task::spawn_blocking(move || {
+                let load_result = Self::load_dataset(&state);
+                match &load_result {
+                    Ok(()) => Self::notify_ready(&state),
+                    // Like in Redis, a snapshot that can't be loaded stops the server
+                    Err(_) => state.request_shutdown(),
+                }
+                load_result
+            })
+        });
+        if load_task.is_none() {
+            Self::notify_ready(&self.state);
+        }
+        loop {
+            // Past maxclients, the client is told why and disconnected, without being registered or given an ID
+            let is_full = || self.state.clients.len() >= self.state.maxclients.load(Ordering::Relaxed);
+            let next_client_id = || self.state.next_client_id.fetch_add(1, Ordering::Relaxed);
+            let conn = tokio::select! {
+                stream = Self::accept_any(&listeners) => match stream {
+                    Ok((stream, _)) if is_full() => {
+                        Self::reject_past_maxclients(&self.state, stream);
+                        continue;
+                    },
+                    Ok((stream, peer)) if self.state.is_protected_from(peer) => {
+                        warn!("Refused connection from {} in protected mode", peer);
+                        Self::refuse_connection(stream, PROTECTED_MODE_ERR);
+                        continue;
+                    },
+                    Ok((stream, _)) => {
+                        let tcp_keepalive = self.state.tcp_keepalive.load(Ordering::Relaxed);
+                        if tcp_keepalive > 0 {
+                            if let Err(err) = tcp::set_keepalive(&stream, tcp_keepalive) {
+                                debug!("Failed to set TCP keepalive: {}", err);
+                            }
+                        }
+                        Connection::new(stream, next_client_id())
+                    },
+                    // A failed accept only affects that one client, so keep serving everyone else
+                    Err(e) => {
+                        error!("Error in accepting TCP connection: {}", e);
+                        continue;
+                    },
+                },
+                stream = Self::accept_unix(unix_listener.as_ref().map(|(unix_listener, _)| unix_listener)) => match stream {
+                    Ok(stream) if is_full() => {
+                        Self::reject_past_maxclients(&self.state, stream);
+                        continue;
+                    },
+                    Ok(stream) => {
+                        let path = unix_listener.as_ref().map_or(Path::new(""), |(_, path)| path.as_path());
+                        Connection::new_unix(stream, next_client_id(), path)
+                    },
+                    Err(e) => {
+                        error!("Error in accepting Unix socket connection: {}", e);
+                        continue;
+                    },
+                },
+                _ = self.state.shutdown_requested.notified() => break,
+            };
+            debug!("Accepted new connection");
+            /* tokio::spawn creates an async task that runs the future (I/O function) passed as argument
+            Returns a Result<JoinHandle> (i.e. spawned async task) */
+            tokio::spawn({
+                // Reference for why Arc::clone is necessary: https://stackoverflow.com/questions/69955340/how-to-deal-with-tokiospawn-closure-required-to-be-static-and-self
+                let state = Arc::clone(&self.state);
+                let mut conn = conn;
+                // Like in Redis, a client that connected while there was no password stays logged in if one is set later
+                conn.authenticated = !state.acl.default_requires_password();
+                // Registered before the task runs, so the next accept already counts it against maxclients
+                state.clients.register(Arc::clone(&conn.info));
+                async move {
+                    state.stats.connected_clients.fetch_add(1, Ordering::Relaxed);
+                    state.stats.total_connections_received.fetch_add(1, Ordering::Relaxed);
+                    // Within same connection, accept multiple commands in loop; if # bytes read is 0, exit connection
+                    match Self::handle_connection(&mut conn, &state).await {
+                        Ok(()) => debug!("Client closed the connection"),
+                        Err(err) if Self::is_disconnect(&err) => debug!("Client disconnected: {}", err),
+                        Err(err) => error!("Something went wrong while handling connection: {}", err),
+                    }
+                    Self::close_subscriptions(&mut conn, &state);
+                    Self::unwatch_all(&mut conn, &state);
+                    state.clients.unregister(conn.id);
+                    state.stats.connected_clients.fetch_sub(1, Ordering::Relaxed);
+                }
+            });
+        }
+        if let Some(metrics_task) = metrics_task {
+            metrics_task.abort();
+        }
+        // Like Redis, the socket file is removed on the way out; clients still connected through it are unaffected
+        if let Some((_, path)) = &self.unix_listener {
+            let _ = std::fs::remove_file(path);
+        }
+        if let Some(pidfile) = &self.pidfile {
+            let _ = std::fs::remove_file(pidfile);
+        }
+        if let Some(load_task) = load_task {
+            load_task.await??;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpStream};
+
+    #[test]
+    fn arity_rejects_under_and_over_supplied_args() {
+        let get_spec = &command_table()["get"];
+        assert_eq!(get_spec.check_arity(0), Err("ERR wrong number of arguments for 'get' command".to_string()));
+        assert_eq!(get_spec.check_arity(1), Ok(()));
+        assert_eq!(get_spec.check_arity(2), Err("ERR wrong number of arguments for 'get' command".to_string()));
+
+        let set_spec = &command_table()["set"];
+        assert_eq!(set_spec.check_arity(1), Err("ERR wrong number of arguments for 'set' command".to_string()));
+        assert_eq!(set_spec.check_arity(2), Ok(()));
+        assert_eq!(set_spec.check_arity(6), Ok(()));
+
+        let echo_spec = &command_table()["echo"];
+        assert_eq!(echo_spec.check_arity(0), Err("ERR wrong number of arguments for 'echo' command".to_string()));
+        assert_eq!(echo_spec.check_arity(1), Ok(()));
+        assert_eq!(echo_spec.check_arity(2), Err("ERR wrong number of arguments for 'echo' command".to_string()));
+    }
+    #[test]
+    fn every_handler_copes_with_the_fewest_args_its_arity_allows() {
+        // Handlers index into args up to the minimum arity without checking, relying on dispatch to have done so.
+        // SHUTDOWN closes the connection without replying, so it has a test of its own.
+        for spec in command_table().iter().filter(|spec| spec.name != "shutdown") {
+            let (min_args, _) = spec.arity;
+            let (mut client, mut conn) = connection_pair();
+            let state = ServerState::new();
+            let mut args = vec![spec.name; min_args + 1];
+            args[1..].fill("x");
+            assert!(!run_cmd(&mut client, &mut conn, &state, &args).is_empty(), "{} sent no reply", spec.name);
+            if min_args > 0 {
+                let arity_err = format!("-ERR wrong number of arguments for '{}' command\r\n", spec.name);
+                assert_eq!(run_cmd(&mut client, &mut conn, &state, &args[..min_args]), arity_err);
+            }
+        }
+    }
+
+    #[test]
+    fn expire_options_follow_redis_semantics() {
+        let opts = |flags: &[&str]| {
+            ExpireOptions::parse(&flags.iter().map(|flag| Bytes::copy_from_slice(flag.as_bytes())).collect::<Vec<Bytes>>()).unwrap()
+        };
+        // No flags: always set
+        assert!(opts(&[]).allows(None, 100));
+        assert!(opts(&[]).allows(Some(200), 100));
+        // NX: only when there's no expiry yet
+        assert!(opts(&["NX"]).allows(None, 100));
+        assert!(!opts(&["nx"]).allows(Some(200), 100));
+        // XX: only when there's already an expiry
+        assert!(!opts(&["XX"]).allows(None, 100));
+        assert!(opts(&["XX"]).allows(Some(200), 100));
+        // GT: a key without an expiry has an infinite TTL, so nothing is greater
+        assert!(!opts(&["GT"]).allows(None, 100));
+        assert!(opts(&["GT"]).allows(Some(50), 100));
+        assert!(!opts(&["GT"]).allows(Some(100), 100));
+        // LT: anything is less than an infinite TTL
+        assert!(opts(&["LT"]).allows(None, 100));
+        assert!(opts(&["LT"]).allows(Some(200), 100));
+        assert!(!opts(&["LT"]).allows(Some(100), 100));
+        // XX combined with GT/LT requires an existing expiry
+        assert!(!opts(&["XX", "LT"]).allows(None, 100));
+        assert!(opts(&["XX", "LT"]).allows(Some(200), 100));
+
+        let parse_err = |flags: &[&str]| {
+            ExpireOptions::parse(&flags.iter().map(|flag| Bytes::copy_from_slice(flag.as_bytes())).collect::<Vec<Bytes>>()).unwrap_err()
+        };
+        assert_eq!(parse_err(&["NX", "XX"]), "ERR NX and XX, GT or LT options at the same time are not compatible");
+        assert_eq!(parse_err(&["GT", "LT"]), "ERR GT and LT options at the same time are not compatible");
+    }
+    #[test]
+    fn set_options_are_parsed_in_any_order() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        assert_eq!(run_cmd(&["SET", "key", "v1", "XX"]), "$-1\r\n");
+        assert_eq!(run_cmd(&["SET", "key", "v1", "ex", "100", "NX"]), "+OK\r\n");
+        assert_eq!(run_cmd(&["SET", "key", "v2", "NX"]), "$-1\r\n");
+        assert_eq!(run_cmd(&["SET", "key", "v2", "KEEPTTL", "GET", "XX"]), "$2\r\nv1\r\n");
+        assert_eq!(run_cmd(&["TTL", "key"]), ":100\r\n");
+        // Without KEEPTTL, SET clears the TTL
+        assert_eq!(run_cmd(&["SET", "key", "v3", "GET"]), "$2\r\nv2\r\n");
+        assert_eq!(run_cmd(&["TTL", "key"]), ":-1\r\n");
+        assert_eq!(run_cmd(&["SET", "key", "v4", "PXAT", "99999999999999"]), "+OK\r\n");
+        assert_eq!(run_cmd(&["PEXPIRETIME", "key"]), ":99999999999999\r\n");
+
+        assert_eq!(run_cmd(&["SET", "key", "v", "NX", "XX"]), "-ERR syntax error\r\n");
+        assert_eq!(run_cmd(&["SET", "key", "v", "EX", "10", "PX", "10"]), "-ERR syntax error\r\n");
+        assert_eq!(run_cmd(&["SET", "key", "v", "PX"]), "-ERR syntax error\r\n");
+        assert_eq!(run_cmd(&["SET", "key", "v", "EX", "x"]), "-ERR value is not an integer or out of range\r\n");
+        assert_eq!(run_cmd(&["SET", "key", "v", "EX", "0"]), "-ERR invalid expire time in 'set' command\r\n");
+        run_cmd(&["LPUSH", "list", "a"]);
+        assert_eq!(run_cmd(&["SET", "list", "v", "GET"]), format!("-{}\r\n", WRONGTYPE_ERR));
+    }
+
+    #[test]
+    fn bitop_zero_pads_shorter_sources_and_deletes_empty_results() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["SET", "long", "abc"]);
+        run_cmd(&["SET", "short", "a"]);
+        assert_eq!(run_cmd(&["BITOP", "AND", "dest", "long", "short"]), ":3\r\n");
+        assert_eq!(run_cmd(&["GET", "dest"]), "$3\r\na\0\0\r\n");
+        assert_eq!(run_cmd(&["BITOP", "OR", "dest", "long", "short"]), ":3\r\n");
+        assert_eq!(run_cmd(&["GET", "dest"]), "$3\r\nabc\r\n");
+        assert_eq!(run_cmd(&["BITOP", "XOR", "dest", "long", "short"]), ":3\r\n");
+        assert_eq!(run_cmd(&["GET", "dest"]), "$3\r\n\0bc\r\n");
+        // A missing key is all zeros, as long as the longest source
+        assert_eq!(run_cmd(&["BITOP", "AND", "dest", "long", "missing"]), ":3\r\n");
+        assert_eq!(run_cmd(&["GET", "dest"]), "$3\r\n\0\0\0\r\n");
+
+        assert_eq!(run_cmd(&["BITOP", "NOT", "dest", "long", "short"]), "-ERR BITOP NOT must be called with a single source key.\r\n");
+        assert_eq!(run_cmd(&["BITOP", "NOT", "dest", "short"]), ":1\r\n");
+        assert_eq!(run_cmd(&["GETRANGE", "dest", "0", "-1"]), format!("$1\r\n{}\r\n", String::from_utf8_lossy(&[!b'a'])));
+
+        // Only missing sources make an empty result, which deletes destkey
+        assert_eq!(run_cmd(&["BITOP", "OR", "dest", "missing", "other_missing"]), ":0\r\n");
+        assert_eq!(run_cmd(&["EXISTS", "dest"]), ":0\r\n");
+    }
+
+    #[test]
+    fn bitpos_pads_clear_bit_searches_only_without_an_explicit_end() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["SET", "zeros", "\0\0"]);
+        run_cmd(&["BITOP", "NOT", "ones", "zeros"]);
+        assert_eq!(run_cmd(&["BITPOS", "ones", "1"]), ":0\r\n");
+        // Past the end of the value without an end, which pads it with clear bits, but not within an explicit range
+        assert_eq!(run_cmd(&["BITPOS", "ones", "0"]), ":16\r\n");
+        assert_eq!(run_cmd(&["BITPOS", "ones", "0", "1"]), ":16\r\n");
+        assert_eq!(run_cmd(&["BITPOS", "ones", "0", "0", "-1"]), ":-1\r\n");
+
+        assert_eq!(run_cmd(&["BITPOS", "missing", "1"]), ":-1\r\n");
+        assert_eq!(run_cmd(&["BITPOS", "missing", "0"]), ":0\r\n");
+
+        // 'a' is 0b01100001
+        run_cmd(&["SET", "key", "\0a"]);
+        assert_eq!(run_cmd(&["BITPOS", "key", "1"]), ":9\r\n");
+        assert_eq!(run_cmd(&["BITPOS", "key", "1", "-1"]), ":9\r\n");
+        assert_eq!(run_cmd(&["BITPOS", "key", "1", "-2", "-2"]), ":-1\r\n");
+        assert_eq!(run_cmd(&["BITPOS", "key", "0", "-1", "-1"]), ":8\r\n");
+        // Negative offsets past the start clamp to it
+        assert_eq!(run_cmd(&["BITPOS", "key", "1", "-100"]), ":9\r\n");
+        assert_eq!(run_cmd(&["BITPOS", "key", "1", "0", "-100"]), ":-1\r\n");
+        assert_eq!(run_cmd(&["BITPOS", "key", "2"]), "-ERR The bit argument must be 1 or 0.\r\n");
+    }
+
+    #[test]
+    fn incrbyfloat_keeps_the_ttl_and_formats_like_redis() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["SET", "f", "1", "EX", "100"]);
+        assert_eq!(run_cmd(&["INCRBYFLOAT", "f", "1.5"]), "$3\r\n2.5\r\n");
+        assert_eq!(run_cmd(&["TTL", "f"]), ":100\r\n");
+
+        // No trailing zeros and no exponent, whichever way the operands were written
+        assert_eq!(run_cmd(&["INCRBYFLOAT", "missing", "10.50"]), "$4\r\n10.5\r\n");
+        assert_eq!(run_cmd(&["INCRBYFLOAT", "missing", "-0.5"]), "$2\r\n10\r\n");
+        assert_eq!(run_cmd(&["INCRBYFLOAT", "missing", "5.0e3"]), "$4\r\n5010\r\n");
+        assert_eq!(run_cmd(&["INCRBYFLOAT", "big", "1e20"]), "$21\r\n100000000000000000000\r\n");
+
+        run_cmd(&["SET", "text", "abc"]);
+        assert_eq!(run_cmd(&["INCRBYFLOAT", "text", "1"]), "-ERR value is not a valid float\r\n");
+        assert_eq!(run_cmd(&["GET", "text"]), "$3\r\nabc\r\n");
+        assert_eq!(run_cmd(&["INCRBYFLOAT", "f", "abc"]), "-ERR value is not a valid float\r\n");
+    }
+
+    #[test]
+    fn replies_follow_the_protocol_negotiated_with_hello() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        assert_eq!(run_cmd(&["GET", "missing"]), "$-1\r\n");
+        assert!(run_cmd(&["HELLO", "3"]).starts_with("%7\r\n"));
+        assert_eq!(run_cmd(&["GET", "missing"]), "_\r\n");
+        // A HELLO rejected for its options doesn't switch protocols
+        assert_eq!(run_cmd(&["HELLO", "2", "AUTH"]), "-ERR Syntax error in HELLO option 'AUTH'\r\n");
+        assert_eq!(run_cmd(&["GET", "missing"]), "_\r\n");
+        // Scores are RESP3 doubles, and bulk strings in RESP2
+        run_cmd(&["ZADD", "zset", "1.5", "a"]);
+        assert_eq!(run_cmd(&["ZRANGE", "zset", "0", "-1", "WITHSCORES"]), "*2\r\n$1\r\na\r\n,1.5\r\n");
+        assert!(run_cmd(&["HELLO", "2"]).starts_with("*14\r\n"));
+        assert_eq!(run_cmd(&["ZRANGE", "zset", "0", "-1", "WITHSCORES"]), "*2\r\n$1\r\na\r\n$3\r\n1.5\r\n");
+        assert_eq!(run_cmd(&["GET", "missing"]), "$-1\r\n");
+    }
+
+    #[test]
+    fn list_pops_delete_the_key_when_emptied() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        let pops: [(&[&str], &str); 6] = [
+            (&["LPOP", "list"], "$1\r\na\r\n"),
+            (&["RPOP", "list", "5"], "*1\r\n$1\r\na\r\n"),
+            (&["LMPOP", "2", "missing", "list", "RIGHT", "COUNT", "5"], "*2\r\n$4\r\nlist\r\n*1\r\n$1\r\na\r\n"),
+            (&["BLPOP", "missing", "list", "0"], "*2\r\n$4\r\nlist\r\n$1\r\na\r\n"),
+            (&["BRPOP", "list", "0"], "*2\r\n$4\r\nlist\r\n$1\r\na\r\n"),
+            (&["BLMPOP", "0", "1", "list", "LEFT"], "*2\r\n$4\r\nlist\r\n*1\r\n$1\r\na\r\n"),
+        ];
+        for (pop, expected_resp) in pops {
+            assert_eq!(run_cmd(&["RPUSH", "list", "a"]), ":1\r\n");
+            assert_eq!(run_cmd(pop), expected_resp, "{:?}", pop);
+            assert_eq!(run_cmd(&["LRANGE", "list", "0", "-1"]), "*0\r\n", "{:?}", pop);
+            assert!(state.lock_db(0).get(b"list".as_slice()).is_none(), "{:?} left an empty list behind", pop);
+        }
+        assert_eq!(run_cmd(&["LPOP", "list"]), "$-1\r\n");
+        assert_eq!(run_cmd(&["LPOP", "list", "2"]), "*-1\r\n");
+    }
+
+    #[test]
+    fn concurrent_sets_and_gets_read_committed_values() {
+        // SET only replies once its insert is committed under the cache lock, so a GET sent after that reply
+        // must see it (or a later write), never a partial value
+        const NUM_CLIENTS: usize = 8;
+        const NUM_ROUNDS: usize = 200;
+        let state = Arc::new(ServerState::new());
+        let clients = (0..NUM_CLIENTS).map(|client_id| {
+            let state = Arc::clone(&state);
+            std::thread::spawn(move || {
+                let (mut client, mut conn) = connection_pair();
+                let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+                let own_key = format!("key-{}", client_id);
+                for round in 0..NUM_ROUNDS {
+                    // Long values so that a torn read would show up as a mix of two writers' values
+                    let val = format!("{}-{}", client_id, round).repeat(50);
+                    assert_eq!(run_cmd(&["SET", "shared", &val]), "+OK\r\n");
+                    assert_eq!(run_cmd(&["SET", &own_key, &val]), "+OK\r\n");
+                    assert_eq!(run_cmd(&["GET", &own_key]), format!("${}\r\n{}\r\n", val.len(), val));
+
+                    let shared_resp = run_cmd(&["GET", "shared"]);
+                    let shared_val = shared_resp.split("\r\n").nth(1).unwrap();
+                    let chunk = &shared_val[..shared_val.len() / 50];
+                    assert_eq!(shared_val, chunk.repeat(50), "torn read of shared key");
+                }
+            })
+        }).collect::<Vec<_>>();
+        for client in clients {
+            client.join().unwrap();
+        }
+        assert_eq!(state.lock_db(0).len(), NUM_CLIENTS + 1);
+    }
+
+    #[test]
+    fn debug_sleep_holds_up_the_reply_and_shows_in_the_slow_log() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["CONFIG", "SET", "slowlog-log-slower-than", "5000"]);
+        let sleep_start = Instant::now();
+        assert_eq!(run_cmd(&["DEBUG", "SLEEP", "0.02"]), "+OK\r\n");
+        assert!(sleep_start.elapsed() >= Duration::from_millis(20));
+        let latest = run_cmd(&["SLOWLOG", "GET", "1"]);
+        assert!(latest.contains("\r\n*3\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n$4\r\n0.02\r\n"), "{}", latest);
+        assert_eq!(run_cmd(&["DEBUG", "SLEEP", "-1"]), "-ERR value is not a valid float\r\n");
+        assert_eq!(run_cmd(&["DEBUG", "SLEEP", "0"]), "+OK\r\n");
+    }
+
+    #[test]
+    fn debug_reload_preserves_every_value_type_and_ttl() {
+        let (mut client, mut conn) = connection_pair();
+        let rdb_path = std::env::temp_dir().join(format!("reload-test-{}.rdb", std::process::id()));
+        let state = ServerState { rdb_path: rdb_path.clone(), ..ServerState::new() };
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["SET", "string", "val", "PX", "100000"]);
+        run_cmd(&["PFADD", "hll", "a", "b", "c"]);
+        run_cmd(&["XADD", "stream", "1-1", "field", "val"]);
+        run_cmd(&["XADD", "stream", "2-1", "field", "val"]);
+        run_cmd(&["XDEL", "stream", "2-1"]);
+        run_cmd(&["RPUSH", "list", "a", "b", "c"]);
+        run_cmd(&["HSET", "hash", "field", "val"]);
+        run_cmd(&["SADD", "intset", "3", "1", "2"]);
+        run_cmd(&["SADD", "set", "b", "a"]);
+        run_cmd(&["GEOADD", "geo", "13.361389", "38.115556", "Palermo"]);
+        let reads: [&[&str]; 10] = [
+            &["GET", "string"],
+            &["PFCOUNT", "hll"],
+            &["XRANGE", "stream", "-", "+"],
+            &["LRANGE", "list", "0", "-1"],
+            &["HGETALL", "hash"],
+            &["SMEMBERS", "intset"],
+            &["OBJECT", "ENCODING", "intset"],
+            &["SMEMBERS", "set"],
+            &["GEOPOS", "geo", "Palermo"],
+            &["EXPIRETIME", "string"],
+        ];
+        let before = reads.iter().map(|read| run_cmd(read)).collect::<Vec<String>>();
+
+        assert_eq!(run_cmd(&["DEBUG", "RELOAD"]), "+OK\r\n");
+        let after = reads.iter().map(|read| run_cmd(read)).collect::<Vec<String>>();
+        assert_eq!(before, after);
+        // XADD must keep refusing IDs at or below the deleted entry's
+        assert!(run_cmd(&["XADD", "stream", "2-1", "field", "val"]).starts_with("-ERR"));
+        std::fs::remove_file(rdb_path).unwrap();
+    }
+
+    #[test]
+    fn save_points_snapshot_after_enough_writes() {
+        let (mut client, mut conn) = connection_pair();
+        let rdb_path = std::env::temp_dir().join(format!("save-points-test-{}.rdb", std::process::id()));
+        let save_points = vec![SavePoint { secs: 0, changes: 2 }];
+        let state = ServerState { rdb_path: rdb_path.clone(), save_points: Mutex::new(save_points), ..ServerState::new() };
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["SET", "a", "1"]);
+        run_cmd(&["GET", "a"]);
+        RedisServer::save_if_due(&state);
+        assert!(!rdb_path.exists());
+        run_cmd(&["SET", "b", "2"]);
+        RedisServer::save_if_due(&state);
+        let saved = rdb::load_file(&rdb_path, &EncodingLimits::default(), RedisServer::curr_time_ms()).unwrap();
+        assert_eq!(saved[&0].len(), 2);
+        assert_eq!(state.dirty.load(Ordering::Relaxed), 0);
+        std::fs::remove_file(rdb_path).unwrap();
+    }
+
+    #[test]
+    fn commands_get_loading_errors_until_the_dataset_is_loaded() {
+        let (mut client, mut conn) = connection_pair();
+        let rdb_path = std::env::temp_dir().join(format!("loading-test-{}.rdb", std::process::id()));
+        let saved = CacheMap::from([(Bytes::from("a"), KeyEntry::new(RedisValue::String(Bytes::from("1")), None))]);
+        rdb::save(&rdb_path, [saved.iter()]).unwrap();
+        let state = ServerState { rdb_path: rdb_path.clone(), loading: AtomicBool::new(true), ..ServerState::new() };
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        assert_eq!(run_cmd(&["GET", "a"]), "-LOADING Redis is loading the dataset in memory\r\n");
+        assert_eq!(run_cmd(&["PING"]), "-LOADING Redis is loading the dataset in memory\r\n");
+        assert!(run_cmd(&["INFO", "server"]).starts_with('$'));
+        run_cmd(&["MULTI"]);
+        run_cmd(&["SET", "b", "2"]);
+        assert!(run_cmd(&["EXEC"]).starts_with("-EXECABORT"));
+
+        RedisServer::load_dataset(&state).unwrap();
+        assert_eq!(run_cmd(&["GET", "a"]), "$1\r\n1\r\n");
+        std::fs::remove_file(rdb_path).unwrap();
+    }
+
+    #[test]
+    fn expired_hash_fields_disappear_while_others_persist() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["HSET", "hash", "a", "1", "b", "2"]);
+        assert_eq!(run_cmd(&["HPEXPIRE", "hash", "1", "FIELDS", "1", "a"]), "*1\r\n:1\r\n");
+        assert_eq!(run_cmd(&["OBJECT", "ENCODING", "hash"]), "$10\r\nlistpackex\r\n");
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(run_cmd(&["HGETALL", "hash"]), "*2\r\n$1\r\nb\r\n$1\r\n2\r\n");
+        assert_eq!(run_cmd(&["HTTL", "hash", "FIELDS", "2", "a", "b"]), "*2\r\n:-2\r\n:-1\r\n");
+
+        // Active expiry removes the hash once its last field expires
+        run_cmd(&["HPEXPIRE", "hash", "1", "FIELDS", "1", "b"]);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(RedisServer::active_expire_cycle(&state), 1);
+        assert_eq!(state.lock_db(0).len(), 0);
+    }
+
+    #[test]
+    fn container_commands_reply_to_help() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        for (cmd_name, _) in SUBCOMMAND_HELP {
+            let help = run_cmd(&mut client, &mut conn, &state, &[cmd_name, "help"]);
+            assert!(help.starts_with('*') && help.ends_with("+    Print this help.\r\n"), "{}", help);
+        }
+    }
+
+    #[test]
+    fn set_stores_handle_destination_as_a_source_and_empty_results() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["SADD", "a", "1", "2", "3"]);
+        run_cmd(&["SADD", "b", "2", "3", "4"]);
+        assert_eq!(run_cmd(&["SINTERSTORE", "a", "a", "b"]), ":2\r\n");
+        assert_eq!(run_cmd(&["SMEMBERS", "a"]), "*2\r\n$1\r\n2\r\n$1\r\n3\r\n");
+        assert_eq!(run_cmd(&["SUNIONSTORE", "u", "a", "b", "missing"]), ":3\r\n");
+        run_cmd(&["SET", "dest", "string"]);
+        assert_eq!(run_cmd(&["SDIFFSTORE", "dest", "a", "b"]), ":0\r\n");
+        assert!(state.lock_db(0).get(b"dest".as_slice()).is_none());
+    }
+
+    #[test]
+    fn zunionstore_weights_sources_and_treats_sets_as_score_one() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["ZADD", "zset", "1", "a", "2", "b"]);
+        run_cmd(&["SADD", "set", "b", "c"]);
+        assert_eq!(run_cmd(&["ZUNIONSTORE", "union", "2", "zset", "set", "WEIGHTS", "3", "5"]), ":3\r\n");
+        assert_eq!(
+            run_cmd(&["ZRANGE", "union", "0", "-1", "WITHSCORES"]),
+            "*6\r\n$1\r\na\r\n$1\r\n3\r\n$1\r\nc\r\n$1\r\n5\r\n$1\r\nb\r\n$2\r\n11\r\n"
+        );
+        assert_eq!(run_cmd(&["ZINTERSTORE", "inter", "2", "zset", "set", "AGGREGATE", "MIN"]), ":1\r\n");
+        assert_eq!(run_cmd(&["ZRANGESTORE", "top", "union", "+inf", "5", "BYSCORE", "REV"]), ":2\r\n");
+        assert_eq!(run_cmd(&["ZRANGE", "top", "0", "-1"]), "*2\r\n$1\r\nc\r\n$1\r\nb\r\n");
+    }
+
+    #[test]
+    fn zadd_flags_follow_redis_semantics() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        assert_eq!(run_cmd(&["ZADD", "zset", "5", "a"]), ":1\r\n");
+        // GT only raises scores, but still adds new members; CH counts both
+        assert_eq!(run_cmd(&["ZADD", "zset", "GT", "CH", "3", "a", "1", "b"]), ":1\r\n");
+        assert_eq!(run_cmd(&["ZRANGE", "zset", "0", "-1", "WITHSCORES"]), "*4\r\n$1\r\nb\r\n$1\r\n1\r\n$1\r\na\r\n$1\r\n5\r\n");
+        assert_eq!(run_cmd(&["ZADD", "zset", "GT", "CH", "7", "a"]), ":1\r\n");
+        // XX can be combined with GT or LT: existing members only, and only lowered (or raised)
+        assert_eq!(run_cmd(&["ZADD", "zset", "XX", "LT", "CH", "2", "a", "9", "b", "0", "c"]), ":1\r\n");
+        assert_eq!(run_cmd(&["ZADD", "zset", "XX", "GT", "4", "b"]), ":0\r\n");
+        assert_eq!(run_cmd(&["ZRANGE", "zset", "0", "-1", "WITHSCORES"]), "*4\r\n$1\r\na\r\n$1\r\n2\r\n$1\r\nb\r\n$1\r\n4\r\n");
+
+        let incompatible = "-ERR GT, LT, and/or NX options at the same time are not compatible\r\n";
+        assert_eq!(run_cmd(&["ZADD", "zset", "NX", "GT", "1", "a"]), incompatible);
+        assert_eq!(run_cmd(&["ZADD", "zset", "GT", "LT", "1", "a"]), incompatible);
+        assert_eq!(run_cmd(&["ZADD", "zset", "NX", "XX", "1", "a"]), "-ERR syntax error\r\n");
+        assert_eq!(run_cmd(&["GEOADD", "geo", "GT", "13.36", "38.11", "palermo"]), "-ERR syntax error\r\n");
+    }
+
+    #[test]
+    fn geosearch_count_must_be_positive() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["GEOADD", "sicily", "13.361389", "38.115556", "Palermo", "15.087269", "37.502669", "Catania"]);
+        let search = ["GEOSEARCH", "sicily", "FROMLONLAT", "15", "37", "BYRADIUS", "200", "km", "ASC", "COUNT"];
+        assert_eq!(run_cmd(&[&search[..], &["1"]].concat()), "*1\r\n$7\r\nCatania\r\n");
+        assert_eq!(run_cmd(&[&search[..], &["0"]].concat()), "-ERR COUNT must be > 0\r\n");
+        assert_eq!(run_cmd(&[&search[..], &["-1"]].concat()), "-ERR COUNT must be > 0\r\n");
+        assert_eq!(run_cmd(&[&search[..], &["many"]].concat()), "-ERR value is not an integer or out of range\r\n");
+    }
+
+    #[test]
+    fn geosearch_takes_exactly_one_origin_and_one_shape() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["GEOADD", "sicily", "13.361389", "38.115556", "Palermo", "15.087269", "37.502669", "Catania"]);
+        let origin_err = "-ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH\r\n";
+        let shape_err = "-ERR exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH\r\n";
+        assert_eq!(run_cmd(&["GEOSEARCH", "sicily", "FROMMEMBER", "Palermo", "FROMMEMBER", "Catania", "BYRADIUS", "1", "km"]), origin_err);
+        assert_eq!(run_cmd(&["GEOSEARCH", "sicily", "FROMLONLAT", "15", "37", "FROMMEMBER", "Palermo", "BYRADIUS", "1", "km"]), origin_err);
+        assert_eq!(run_cmd(&["GEOSEARCH", "sicily", "BYRADIUS", "1", "km", "ASC", "WITHDIST"]), origin_err);
+        assert_eq!(run_cmd(&["GEOSEARCH", "sicily", "FROMMEMBER", "Palermo", "BYRADIUS", "1", "km", "BYRADIUS", "2", "km"]), shape_err);
+        assert_eq!(run_cmd(&["GEOSEARCH", "sicily", "FROMMEMBER", "Palermo", "BYBOX", "1", "1", "km", "BYRADIUS", "1", "km"]), shape_err);
+        assert_eq!(run_cmd(&["GEOSEARCH", "sicily", "FROMMEMBER", "Palermo", "ASC", "WITHDIST", "WITHCOORD"]), shape_err);
+        assert_eq!(run_cmd(&["GEOSEARCH", "sicily", "FROMMEMBER", "Palermo", "BYRADIUS", "1", "km"]), "*1\r\n$7\r\nPalermo\r\n");
+    }
+
+    #[test]
+    fn geodist_and_geopos_read_back_added_members() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["GEOADD", "sicily", "13.361389", "38.115556", "Palermo", "15.087269", "37.502669", "Catania"]);
+        assert_eq!(run_cmd(&["GEODIST", "sicily", "Palermo", "Catania"]), "$11\r\n166274.1516\r\n");
+        assert_eq!(run_cmd(&["GEODIST", "sicily", "Palermo", "Catania", "km"]), "$8\r\n166.2742\r\n");
+        assert_eq!(run_cmd(&["GEODIST", "sicily", "Palermo", "Catania", "MI"]), "$8\r\n103.3182\r\n");
+        assert_eq!(run_cmd(&["GEODIST", "sicily", "Palermo", "Agrigento"]), "$-1\r\n");
+        assert_eq!(run_cmd(&["GEODIST", "missing", "Palermo", "Catania"]), "$-1\r\n");
+        assert_eq!(run_cmd(&["GEODIST", "sicily", "Palermo", "Catania", "parsecs"]), "-ERR unsupported unit provided. please use M, KM, FT, MI\r\n");
+
+        // The coordinates come back as stored in the geohash score, which is precise to well under a meter
+        let geopos = run_cmd(&["GEOPOS", "sicily", "Palermo", "Agrigento", "Catania"]);
+        let lines = geopos.split("\r\n").collect::<Vec<&str>>();
+        assert_eq!((lines[0], lines[1], lines[6], lines[7]), ("*3", "*2", "*-1", "*2"), "{}", geopos);
+        for (lon_lat, expected) in [(&lines[3..6], (13.361389, 38.115556)), (&lines[9..12], (15.087269, 37.502669))] {
+            let (lon, lat) = (lon_lat[0].parse::<f64>().unwrap(), lon_lat[2].parse::<f64>().unwrap());
+            assert!((lon - expected.0).abs() < 1e-5 && (lat - expected.1).abs() < 1e-5, "{}", geopos);
+        }
+        assert_eq!(run_cmd(&["GEOPOS", "missing", "Palermo"]), "*1\r\n*-1\r\n");
+    }
+
+    #[test]
+    fn databases_are_selected_swapped_and_flushed_independently() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["SET", "k", "db0"]);
+        assert_eq!(run_cmd(&["SELECT", "1"]), "+OK\r\n");
+        assert_eq!(run_cmd(&["GET", "k"]), "$-1\r\n");
+        run_cmd(&["SET", "k", "db1"]);
+        run_cmd(&["SET", "only1", "v", "PX", "100000"]);
+        assert_eq!(run_cmd(&["SELECT", "16"]), "-ERR DB index is out of range\r\n");
+
+        assert_eq!(run_cmd(&["MOVE", "k", "0"]), ":0\r\n");
+        assert_eq!(run_cmd(&["MOVE", "only1", "0"]), ":1\r\n");
+        assert_eq!(run_cmd(&["MOVE", "only1", "1"]), "-ERR source and destination objects are the same\r\n");
+        assert_eq!(run_cmd(&["GET", "only1"]), "$-1\r\n");
+
+        // WATCH follows the key's database, wherever the connection is when EXEC runs
+        run_cmd(&["WATCH", "k"]);
+        assert_eq!(run_cmd(&["SWAPDB", "0", "1"]), "+OK\r\n");
+        assert_eq!(run_cmd(&["GET", "k"]), "$3\r\ndb0\r\n");
+        run_cmd(&["MULTI"]);
+        run_cmd(&["GET", "k"]);
+        assert_eq!(run_cmd(&["EXEC"]), "*-1\r\n");
+        run_cmd(&["SELECT", "0"]);
+        assert_eq!(run_cmd(&["GET", "k"]), "$3\r\ndb1\r\n");
+        assert_eq!(run_cmd(&["GET", "only1"]), "$-1\r\n");
+
+        assert_eq!(run_cmd(&["FLUSHDB"]), "+OK\r\n");
+        assert_eq!(state.lock_db(0).len(), 0);
+        // MOVE kept the TTL
+        assert_eq!(state.lock_db(1).len(), 2);
+        assert!(state.lock_db(1).get(b"only1".as_slice()).is_some_and(|entry| entry.expiry_ts.is_some()));
+    }
+
+    #[test]
+    fn flushall_empties_every_database_and_dbsize_skips_expired_keys() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["SET", "k", "v"]);
+        run_cmd(&["SET", "short-lived", "v", "PX", "1"]);
+        std::thread::sleep(Duration::from_millis(5));
+        // The expired key is still there until something removes it, but it isn't counted
+        assert_eq!(state.lock_db(0).len(), 2);
+        assert_eq!(run_cmd(&["DBSIZE"]), ":1\r\n");
+        run_cmd(&["SELECT", "1"]);
+        run_cmd(&["SET", "k", "v"]);
+        assert_eq!(run_cmd(&["FLUSHDB", "ASYNC"]), "+OK\r\n");
+        assert_eq!(run_cmd(&["DBSIZE"]), ":0\r\n");
+        run_cmd(&["SET", "k", "v"]);
+
+        assert_eq!(run_cmd(&["FLUSHALL", "NOW"]), "-ERR syntax error\r\n");
+        assert_eq!(run_cmd(&["FLUSHALL", "ASYNC"]), "+OK\r\n");
+        assert_eq!(run_cmd(&["DBSIZE"]), ":0\r\n");
+        assert_eq!(state.lock_db(0).len(), 0);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while state.lazyfree.pending_objects() > 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(run_cmd(&["INFO", "memory"]).contains("lazyfree_pending_objects:0\r\n"));
+        run_cmd(&["SET", "k", "v"]);
+        assert_eq!(run_cmd(&["FLUSHALL"]), "+OK\r\n");
+        assert_eq!(run_cmd(&["DBSIZE"]), ":0\r\n");
+    }
+
+    #[test]
+    fn client_list_describes_every_registered_connection() {
+        let (mut client, mut conn) = connection_pair();
+        let (mut other_client, mut other_conn) = connection_pair();
+        let state = ServerState::new();
+        other_conn.id = 2;
+        other_conn.info = Arc::new(ClientInfo::new(2, "127.0.0.1:2".to_string(), "127.0.0.1:6379".to_string()));
+        // The accept loop registers connections; these didn't come through it
+        state.clients.register(Arc::clone(&conn.info));
+        state.clients.register(Arc::clone(&other_conn.info));
+
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["CLIENT", "ID"]), ":1\r\n");
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["CLIENT", "GETNAME"]), "$-1\r\n");
+        assert_eq!(
+            run_cmd(&mut client, &mut conn, &state, &["CLIENT", "SETNAME", "my name"]),
+            "-ERR Client names cannot contain spaces, newlines or special characters.\r\n"
+        );
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["CLIENT", "SETNAME", "worker"]), "+OK\r\n");
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["CLIENT", "GETNAME"]), "$6\r\nworker\r\n");
+        run_cmd(&mut client, &mut conn, &state, &["SELECT", "3"]);
+        run_cmd(&mut other_client, &mut other_conn, &state, &["SUBSCRIBE", "chan"]);
+
+        let info = run_cmd(&mut client, &mut conn, &state, &["CLIENT", "INFO"]);
+        assert!(info.contains("id=1 addr=127.0.0.1:"), "{}", info);
+        assert!(info.contains(" name=worker age=0 idle=0 flags=N db=3 sub=0 psub=0 multi=-1 cmd=client|info user=default resp=2\n"), "{}", info);
+        let list = run_cmd(&mut client, &mut conn, &state, &["CLIENT", "LIST"]);
+        assert_eq!(list.matches(" addr=").count(), 2, "{}", list);
+        assert!(list.contains("\nid=2 addr=127.0.0.1:2 laddr=127.0.0.1:6379 name= age=0 idle=0 flags=P db=0 sub=1 psub=0 multi=-1 cmd=subscribe user=default resp=2\n"), "{}", list);
+        assert!(list.contains("cmd=client|list"), "{}", list);
+        let filtered = run_cmd(&mut client, &mut conn, &state, &["CLIENT", "LIST", "ID", "2", "99"]);
+        assert!(filtered.contains("\r\nid=2 ") && filtered.matches(" addr=").count() == 1, "{}", filtered);
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["CLIENT", "LIST", "ID", "x"]), "-ERR Invalid client ID\r\n");
+    }
+
+    #[test]
+    fn client_reply_off_and_skip_drop_replies() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        // Commands whose replies are dropped can't go through run_cmd, which waits for a reply
+        let run_unreplied = |conn: &mut Connection, args: &[&str]| {
+            let args = args.iter().map(|arg| Bytes::copy_from_slice(arg.as_bytes())).collect::<Vec<Bytes>>();
+            RedisServer::handle_cmd(&args, conn, &state).unwrap();
+        };
+
+        run_unreplied(&mut conn, &["CLIENT", "REPLY", "OFF"]);
+        run_unreplied(&mut conn, &["SET", "k", "v"]);
+        run_unreplied(&mut conn, &["GET", "nosuchcmd"]);
+        // SKIP doesn't turn replies back on
+        run_unreplied(&mut conn, &["CLIENT", "REPLY", "SKIP"]);
+        run_unreplied(&mut conn, &["RPUSH", "list", "a"]);
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["CLIENT", "REPLY", "ON"]), "+OK\r\n");
+
+        run_unreplied(&mut conn, &["CLIENT", "REPLY", "SKIP"]);
+        run_unreplied(&mut conn, &["RPUSH", "list", "a"]);
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["GET", "k"]), "$1\r\nv\r\n");
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["LRANGE", "list", "0", "-1"]), "*2\r\n$1\r\na\r\n$1\r\na\r\n");
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["CLIENT", "REPLY", "MAYBE"]), "-ERR syntax error\r\n");
+    }
+
+    #[test]
+    fn monitor_is_fed_every_command_but_admin_ones() {
+        let (mut client, mut conn) = connection_pair();
+        let (mut other_client, mut other_conn) = connection_pair();
+        let state = ServerState::new();
+        other_conn.id = 2;
+
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["MONITOR"]), "+OK\r\n");
+        run_cmd(&mut other_client, &mut other_conn, &state, &["SELECT", "1"]);
+        run_cmd(&mut other_client, &mut other_conn, &state, &["CONFIG", "GET", "hz"]);
+        run_cmd(&mut other_client, &mut other_conn, &state, &["SET", "k", "two words\n"]);
+
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut feed = String::new();
+        while feed.matches("\r\n").count() < 2 {
+            let mut read_buffer = [0; CHUNK_SIZE];
+            let num_bytes_read = client.read(&mut read_buffer).unwrap();
+            feed.push_str(&String::from_utf8_lossy(&read_buffer[..num_bytes_read]));
+        }
+        let lines = feed.lines().map(|line| line.split_once(' ').unwrap()).collect::<Vec<(&str, &str)>>();
+        let other_addr = other_client.local_addr().unwrap();
+        assert_eq!(lines[0].1, format!("[0 {}] \"SELECT\" \"1\"", other_addr));
+        assert_eq!(lines[1].1, format!("[1 {}] \"SET\" \"k\" \"two words\\n\"", other_addr));
+        assert!(lines.iter().all(|(timestamp, _)| timestamp.starts_with('+') && timestamp[1..].parse::<f64>().is_ok()));
+        // Closing the monitor's connection stops the feed
+        RedisServer::close_subscriptions(&mut conn, &state);
+        assert!(state.read_monitors().is_empty());
+    }
+
+    #[test]
+    fn slowlog_records_commands_over_the_threshold() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["SET", "fast", "v"]);
+        assert_eq!(run_cmd(&["SLOWLOG", "LEN"]), ":0\r\n");
+        // Everything takes at least 0 microseconds, including the CONFIG SET and the SLOWLOG commands themselves
+        run_cmd(&["CONFIG", "SET", "slowlog-log-slower-than", "0", "slowlog-max-len", "3"]);
+        run_cmd(&["CLIENT", "SETNAME", "logger"]);
+        run_cmd(&["SET", "k", "v"]);
+        let latest = run_cmd(&["SLOWLOG", "GET", "1"]);
+        assert!(latest.starts_with("*1\r\n*6\r\n:2\r\n:"), "{}", latest);
+        assert!(latest.contains("\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n"), "{}", latest);
+        assert!(latest.ends_with("\r\n$6\r\nlogger\r\n"), "{}", latest);
+        assert_eq!(run_cmd(&["SLOWLOG", "LEN"]), ":3\r\n");
+        assert_eq!(run_cmd(&["SLOWLOG", "GET", "-2"]), "-ERR count should be greater than or equal to -1\r\n");
+        run_cmd(&["SLOWLOG", "RESET"]);
+        assert_eq!(run_cmd(&["SLOWLOG", "LEN"]), ":1\r\n");
+        assert_eq!(run_cmd(&["CONFIG", "GET", "slowlog-*"]), "*4\r\n$23\r\nslowlog-log-slower-than\r\n$1\r\n0\r\n$15\r\nslowlog-max-len\r\n$1\r\n3\r\n");
+    }
+
+    #[test]
+    fn latency_reports_spikes_over_the_threshold() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+
+        // The monitor is off until a threshold is set; this one is high enough that no command in the test reaches it
+        state.latency_monitor.add_sample_if_needed("expire-cycle", Duration::from_secs(20));
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["LATENCY", "LATEST"]), "*0\r\n");
+        run_cmd(&mut client, &mut conn, &state, &["CONFIG", "SET", "latency-monitor-threshold", "10000"]);
+        state.latency_monitor.add_sample_if_needed("expire-cycle", Duration::from_secs(5));
+        state.latency_monitor.add_sample_if_needed("expire-cycle", Duration::from_secs(20));
+        let latest = run_cmd(&mut client, &mut conn, &state, &["LATENCY", "LATEST"]);
+        assert!(latest.starts_with("*1\r\n*4\r\n$12\r\nexpire-cycle\r\n:"), "{}", latest);
+        assert!(latest.ends_with("\r\n:20000\r\n:20000\r\n"), "{}", latest);
+        let history = run_cmd(&mut client, &mut conn, &state, &["LATENCY", "HISTORY", "expire-cycle"]);
+        assert!(history.starts_with("*1\r\n*2\r\n:") && history.ends_with("\r\n:20000\r\n"), "{}", history);
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["LATENCY", "HISTORY", "command"]), "*0\r\n");
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["LATENCY", "HISTORY"]), "-ERR wrong number of arguments for 'latency|history' command\r\n");
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["LATENCY", "RESET", "command", "expire-cycle"]), ":1\r\n");
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["LATENCY", "RESET"]), ":0\r\n");
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["CONFIG", "GET", "latency-*"]), "*2\r\n$25\r\nlatency-monitor-threshold\r\n$5\r\n10000\r\n");
+    }
+
+    #[test]
+    fn info_reports_the_requested_sections() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["SET", "k", "v"]);
+        run_cmd(&["SET", "temp", "v", "EX", "1000"]);
+        run_cmd(&["SELECT", "2"]);
+        run_cmd(&["SET", "k", "v"]);
+
+        let info = run_cmd(&["INFO"]);
+        for section in ["# Server", "# Clients", "# Memory", "# Stats", "# Replication", "# Keyspace"] {
+            assert!(info.contains(section), "{} missing from {}", section, info);
+        }
+        assert!(!info.contains("# Commandstats"));
+        assert!(info.contains("\r\ndb0:keys=2,expires=1,avg_ttl="));
+        assert!(info.contains("\r\ndb2:keys=1,expires=0,avg_ttl=0\r\n"));
+        assert!(!info.contains("db1:"));
+
+        // Each server keeps its own counters, so these only count this test's commands
+        run_cmd(&["GET", "k"]);
+        run_cmd(&["GET", "missing"]);
+        let stats = run_cmd(&["INFO", "stats"]);
+        assert!(stats.contains("# Stats\r\ntotal_connections_received:0\r\n"), "{}", stats);
+        assert!(stats.contains("\r\ntotal_commands_processed:7\r\n"));
+        assert!(stats.contains("\r\nkeyspace_hits:1\r\nkeyspace_misses:1\r\n"));
+        assert_eq!(ServerState::new().stats.total_commands_processed.load(Ordering::Relaxed), 0);
+        assert!(!stats.contains("# Server"));
+        let replication = run_cmd(&["INFO", "REPLICATION", "keyspace"]);
+        assert!(replication.contains("role:master\r\n"));
+        assert!(replication.contains("# Keyspace\r\n"));
+        assert!(run_cmd(&["INFO", "all"]).contains("# Commandstats"));
+    }
+
+    #[test]
+    fn watch_detects_writes_by_every_write_command() {
+        let (mut client, mut conn) = connection_pair();
+        let (mut other_client, mut other_conn) = connection_pair();
+        let state = ServerState::new();
+
+        // The dispatch layer marks the keys of write commands, so this covers new commands without any changes
+        for spec in command_table().iter().filter(|spec| spec.flags.contains(&"write")) {
+            let mut args = vec!["x"; spec.arity.0 + 1];
+            args[0] = spec.name;
+            // Database-wide commands have no key arguments, and mark the keys they touch themselves, which have to exist
+            match spec.name {
+                "swapdb" => args = vec!["swapdb", "0", "1"],
+                "flushdb" | "flushall" => (),
+                _ => args[spec.keys.0.max(1) as usize] = "watched",
+            }
+            if matches!(spec.name, "swapdb" | "flushdb" | "flushall") {
+                run_cmd(&mut other_client, &mut other_conn, &state, &["SET", "watched", "val"]);
+            }
+            run_cmd(&mut client, &mut conn, &state, &["WATCH", "watched"]);
+            run_cmd(&mut client, &mut conn, &state, &["MULTI"]);
+            assert_eq!(run_cmd(&mut client, &mut conn, &state, &["PING"]), "+QUEUED\r\n");
+            run_cmd(&mut other_client, &mut other_conn, &state, &args);
+            assert_eq!(run_cmd(&mut client, &mut conn, &state, &["EXEC"]), "*-1\r\n", "{} wasn't detected", spec.name);
+        }
+
+        run_cmd(&mut client, &mut conn, &state, &["WATCH", "watched"]);
+        run_cmd(&mut other_client, &mut other_conn, &state, &["SET", "unwatched", "val"]);
+        run_cmd(&mut client, &mut conn, &state, &["MULTI"]);
+        run_cmd(&mut client, &mut conn, &state, &["SET", "watched", "val"]);
+        assert!(run_cmd(&mut client, &mut conn, &state, &["EXEC"]).starts_with("*1\r\n"));
+        assert!(matches!(state.lock_db(0).get(b"watched".as_slice()), Some(KeyEntry { value: RedisValue::String(val), .. }) if val == "val"));
+    }
+
+    #[test]
+    fn watched_keys_are_forgotten_once_nobody_watches_them() {
+        let (mut client, mut conn) = connection_pair();
+        let (mut other_client, mut other_conn) = connection_pair();
+        let state = ServerState::new();
+
+        // Writes to keys nobody watches don't leave anything behind
+        run_cmd(&mut client, &mut conn, &state, &["SET", "a", "1"]);
+        run_cmd(&mut client, &mut conn, &state, &["FLUSHALL"]);
+        assert_eq!(state.lock_watched_keys().len(), 0);
+
+        run_cmd(&mut client, &mut conn, &state, &["WATCH", "a", "b"]);
+        run_cmd(&mut other_client, &mut other_conn, &state, &["WATCH", "b"]);
+        assert_eq!(state.lock_watched_keys().len(), 2);
+        run_cmd(&mut client, &mut conn, &state, &["MULTI"]);
+        run_cmd(&mut client, &mut conn, &state, &["EXEC"]);
+        assert_eq!(state.lock_watched_keys().keys().map(|(_, key)| key.clone()).collect::<Vec<Bytes>>(), vec![Bytes::from("b")]);
+        // A key that's removed and written again while watched still counts as modified
+        run_cmd(&mut client, &mut conn, &state, &["SET", "b", "1"]);
+        run_cmd(&mut client, &mut conn, &state, &["DEL", "b"]);
+        run_cmd(&mut other_client, &mut other_conn, &state, &["MULTI"]);
+        assert_eq!(run_cmd(&mut other_client, &mut other_conn, &state, &["EXEC"]), "*-1\r\n");
+        assert_eq!(state.lock_watched_keys().len(), 0);
+
+        for unwatch in [&["UNWATCH"][..], &["RESET"], &["MULTI"]] {
+            run_cmd(&mut client, &mut conn, &state, &["WATCH", "a"]);
+            run_cmd(&mut client, &mut conn, &state, unwatch);
+            if unwatch == ["MULTI"] {
+                run_cmd(&mut client, &mut conn, &state, &["DISCARD"]);
+            }
+            assert_eq!(state.lock_watched_keys().len(), 0, "{:?}", unwatch);
+        }
+        run_cmd(&mut client, &mut conn, &state, &["WATCH", "a"]);
+        RedisServer::unwatch_all(&mut conn, &state);
+        assert_eq!((state.lock_watched_keys().len(), state.num_watched_keys.load(Ordering::SeqCst)), (0, 0));
+    }
+
+    #[test]
+    fn read_only_replica_rejects_write_commands() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["SET", "key", "val"]);
+        assert_eq!(run_cmd(&["CONFIG", "SET", "replica-read-only", "yes"]), "+OK\r\n");
+        assert_eq!(run_cmd(&["SET", "key", "other"]), "-READONLY You can't write against a read only replica.\r\n");
+        assert_eq!(run_cmd(&["RPUSH", "list", "a"]), "-READONLY You can't write against a read only replica.\r\n");
+        assert_eq!(run_cmd(&["GET", "key"]), "$3\r\nval\r\n");
+        assert_eq!(run_cmd(&["CONFIG", "GET", "replica-read-only"]), "*2\r\n$17\r\nreplica-read-only\r\n$3\r\nyes\r\n");
+        assert_eq!(run_cmd(&["CONFIG", "SET", "replica-read-only", "no"]), "+OK\r\n");
+        assert_eq!(run_cmd(&["SET", "key", "other"]), "+OK\r\n");
+    }
+
+    #[test]
+    fn command_getkeys_finds_fixed_and_movable_keys() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        assert_eq!(run_cmd(&["COMMAND", "GETKEYS", "SET", "foo", "bar"]), "*1\r\n$3\r\nfoo\r\n");
+        assert_eq!(run_cmd(&["COMMAND", "GETKEYS", "MSET", "a", "1", "b", "2"]), "*2\r\n$1\r\na\r\n$1\r\nb\r\n");
+        assert_eq!(run_cmd(&["COMMAND", "GETKEYS", "SINTERSTORE", "dst", "a", "b"]), "*3\r\n$3\r\ndst\r\n$1\r\na\r\n$1\r\nb\r\n");
+        assert_eq!(
+            run_cmd(&["COMMAND", "GETKEYS", "ZUNIONSTORE", "dst", "2", "a", "b", "WEIGHTS", "1", "2"]),
+            "*3\r\n$3\r\ndst\r\n$1\r\na\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(run_cmd(&["COMMAND", "GETKEYS", "XREAD", "COUNT", "1", "STREAMS", "s1", "s2", "0", "0"]), "*2\r\n$2\r\ns1\r\n$2\r\ns2\r\n");
+        assert_eq!(run_cmd(&["COMMAND", "GETKEYS", "LMPOP", "5", "a", "LEFT"]), "-ERR Invalid arguments specified for command\r\n");
+        assert_eq!(run_cmd(&["COMMAND", "GETKEYS", "PING"]), "-ERR The command has no key arguments\r\n");
+        assert_eq!(run_cmd(&["COMMAND", "GETKEYS", "GET"]), "-ERR Invalid number of arguments specified for command\r\n");
+        assert_eq!(run_cmd(&["COMMAND", "GETKEYS", "NOSUCHCMD", "a"]), "-ERR Invalid command specified\r\n");
+        // Every movablekeys command comes with its key finder, and nothing else has one
+        for spec in command_table().iter() {
+            assert_eq!(spec.flags.contains(&"movablekeys"), spec.find_keys.is_some(), "{}", spec.name);
+        }
+    }
+
+    #[test]
+    fn reads_leave_expired_keys_for_the_write_side_to_remove() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        RedisServer::register_cron_jobs(&state);
+        state.active_expire_enabled.store(false, Ordering::Relaxed);
+        state.lock_db(0).insert(Bytes::from_static(b"stale"), KeyEntry::new(RedisValue::String(Bytes::from_static(b"val")), Some(1)));
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        // GET only takes a read lock, so it can't remove the key itself
+        assert_eq!(run_cmd(&["GET", "stale"]), "$-1\r\n");
+        assert!(state.lock_db(0).get(b"stale".as_slice()).is_some());
+        RedisServer::run_cron_jobs(&state);
+        assert!(state.lock_db(0).get(b"stale".as_slice()).is_none());
+    }
+
+    #[test]
+    fn cron_jobs_expire_keys_and_shrink_the_keyspace() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        RedisServer::register_cron_jobs(&state);
+        {
+            let mut c = state.lock_db(0);
+            for idx in 0..1000 {
+                c.insert(Bytes::from(format!("key{}", idx)), KeyEntry::new(RedisValue::String(Bytes::from_static(b"val")), Some(1)));
+            }
+            c.insert(Bytes::from_static(b"live"), KeyEntry::new(RedisValue::String(Bytes::from_static(b"val")), None));
+        }
+        RedisServer::run_cron_jobs(&state);
+        assert_eq!(state.lock_db(0).len(), 1);
+        // Every shard is shrunk on its own, down to about MIN_KEYSPACE_CAPACITY
+        let num_shards = state.dbs[0].num_shards();
+        let capacity = (0..num_shards).map(|shard_idx| state.dbs[0].lock_shard(shard_idx).capacity()).sum::<usize>();
+        assert!(capacity <= num_shards * 2 * MIN_KEYSPACE_CAPACITY, "capacity {} wasn't shrunk", capacity);
+
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+        assert_eq!(run_cmd(&["CONFIG", "SET", "hz", "1000"]), "+OK\r\n");
+        assert_eq!(run_cmd(&["CONFIG", "GET", "hz"]), "*2\r\n$2\r\nhz\r\n$3\r\n500\r\n");
+    }
+
+    #[test]
+    fn config_rewrite_saves_parameters_to_the_config_file() {
+        let (mut client, mut conn) = connection_pair();
+        let config_file = std::env::temp_dir().join(format!("config-rewrite-test-{}.conf", std::process::id()));
+        std::fs::write(&config_file, "# Kept as is\nhz 20\nport 6380\nhz 30\n").unwrap();
+        let state = ServerState { config_file: Some(config_file.clone()), ..ServerState::new() };
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        assert_eq!(run_cmd(&["CONFIG", "SET", "hz", "15", "save", "900 1 300 10"]), "+OK\r\n");
+        assert_eq!(
+            run_cmd(&["CONFIG", "GET", "maxm*", "sav?"]),
+            "*4\r\n$9\r\nmaxmemory\r\n$1\r\n0\r\n$4\r\nsave\r\n$12\r\n900 1 300 10\r\n"
+        );
+        assert_eq!(run_cmd(&["CONFIG", "REWRITE"]), "+OK\r\n");
+        let rewritten = std::fs::read_to_string(&config_file).unwrap();
+        assert!(rewritten.starts_with("# Kept as is\nhz 15\nport 6380\n# Generated by CONFIG REWRITE\n"));
+        assert!(rewritten.contains("\nsave 900 1 300 10\n"));
+        // The rewritten file loads back into the same values
+        let config = Config::from_file(&config_file).unwrap();
+        assert_eq!((config.hz, config.port, config.save_points.len()), (15, 6380, 2));
+        std::fs::remove_file(config_file).unwrap();
+    }
+
+    #[test]
+    fn config_set_loglevel_changes_the_log_verbosity() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        assert_eq!(run_cmd(&["CONFIG", "SET", "loglevel", "VERBOSE"]), "+OK\r\n");
+        assert_eq!(log::max_level(), log::LevelFilter::Debug);
+        assert_eq!(run_cmd(&["CONFIG", "GET", "loglevel"]), "*2\r\n$8\r\nloglevel\r\n$7\r\nverbose\r\n");
+        assert!(run_cmd(&["CONFIG", "SET", "loglevel", "loud"]).starts_with("-ERR CONFIG SET failed (possibly related to argument 'loglevel')"));
+        assert_eq!(run_cmd(&["CONFIG", "SET", "loglevel", "nothing"]), "+OK\r\n");
+        assert_eq!(log::max_level(), log::LevelFilter::Off);
+    }
+
+    #[test]
+    fn heavy_pipeline_yields_to_interactive_connection() {
+        let state = Arc::new(ServerState::new());
+        state.pipeline_batch_size.store(10, Ordering::Relaxed);
+        // One thread means the tasks take turns, in the order they were scheduled
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let _runtime = runtime.enter();
+        let (mut heavy_client, heavy_stream) = socket_pair();
+        let (mut interactive_client, interactive_stream) = socket_pair();
+        // Both requests are waiting (and the runtime knows it) before either task starts
+        heavy_client.write_all(&b"*3\r\n$5\r\nRPUSH\r\n$4\r\nlist\r\n$1\r\nx\r\n".repeat(30)).unwrap();
+        heavy_client.shutdown(Shutdown::Write).unwrap();
+        interactive_client.write_all(b"*4\r\n$6\r\nLRANGE\r\n$4\r\nlist\r\n$1\r\n0\r\n$2\r\n-1\r\n").unwrap();
+        interactive_client.shutdown(Shutdown::Write).unwrap();
+
+        runtime.block_on(async {
+            heavy_stream.readable().await.unwrap();
+            interactive_stream.readable().await.unwrap();
+            let (mut heavy_conn, mut interactive_conn) = (Connection::new(heavy_stream, 1), Connection::new(interactive_stream, 1));
+            let heavy_state = Arc::clone(&state);
+            let heavy = tokio::spawn(async move { RedisServer::handle_connection(&mut heavy_conn, &heavy_state).await });
+            let interactive_state = Arc::clone(&state);
+            let interactive = tokio::spawn(async move { RedisServer::handle_connection(&mut interactive_conn, &interactive_state).await });
+            heavy.await.unwrap().unwrap();
+            interactive.await.unwrap().unwrap();
+        });
+
+        // LRANGE ran after the first batch rather than after the whole pipeline
+        let mut interactive_resp = String::new();
+        interactive_client.read_to_string(&mut interactive_resp).unwrap();
+        assert!(interactive_resp.starts_with("*10\r\n"), "{:?}", interactive_resp);
+        let mut heavy_resp = String::new();
+        heavy_client.read_to_string(&mut heavy_resp).unwrap();
+        assert_eq!(heavy_resp, (1..=30).map(|len| format!(":{}\r\n", len)).collect::<String>());
+    }
+
+    fn test_runtime() -> &'static tokio::runtime::Runtime {
+        /*
+        Runtime that all the tests' connections are registered with, since a socket can only be driven by its own
+        One worker means the tasks spawned on it take turns.
+        */
+        static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+        RUNTIME.get_or_init(|| tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap())
+    }
+
+    fn socket_pair() -> (TcpStream, tokio::net::TcpStream) {
+        /* A client socket connected to a server-side one */
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        // Registered with the runtime the caller has entered, if any
+        let _runtime = tokio::runtime::Handle::try_current().is_err().then(|| test_runtime().enter());
+        (client, tokio::net::TcpStream::from_std(stream).unwrap())
+    }
+
+    fn connection_pair() -> (TcpStream, Connection) {
+        /* A client socket connected to a server-side Connection, for driving handlers directly */
+        let (client, stream) = socket_pair();
+        (client, Connection::new(stream, 1))
+    }
+
+    fn run_cmd(client: &mut TcpStream, conn: &mut Connection, state: &ServerState, args: &[&str]) -> String {
+        let args = args.iter().map(|arg| Bytes::copy_from_slice(arg.as_bytes())).collect::<Vec<Bytes>>();
+        // SUBSCRIBE spawns the subscriber's writer task
+        let runtime = test_runtime().enter();
+        RedisServer::handle_cmd(&args, conn, state).unwrap();
+        drop(runtime);
+        test_runtime().block_on(conn.flush()).unwrap();
+        let mut read_buffer = [0; CHUNK_SIZE];
+        let num_bytes_read = client.read(&mut read_buffer).unwrap();
+        String::from_utf8_lossy(&read_buffer[..num_bytes_read]).into_owned()
+    }
+
+    #[test]
+    fn embedded_server_serves_clients_until_shutdown() {
+        let config = Config { dbfilename: "embedded-server-test-missing.rdb".to_string(), ..Config::default() };
+        let server = Arc::new(RedisServerBuilder::new().bind("127.0.0.1:0").config(config).build().unwrap());
+        let addr = server.local_addr().unwrap();
+
+        let client = std::thread::spawn({
+            let server = Arc::clone(&server);
+            move || {
+                let mut client = TcpStream::connect(addr).unwrap();
+                client.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n").unwrap();
+                client.shutdown(Shutdown::Write).unwrap();
+                let mut resp = String::new();
+                client.read_to_string(&mut resp).unwrap();
+                server.shutdown().unwrap();
+                resp
+            }
+        });
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        runtime.block_on(server.run()).unwrap();
+        assert_eq!(client.join().unwrap(), "+OK\r\n$1\r\nv\r\n");
+        drop(server);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn shutdown_saves_and_stops_the_server() {
+        let dir = std::env::temp_dir();
+        let dbfilename = format!("shutdown-test-{}.rdb", std::process::id());
+        let config = Config { dir: dir.clone(), dbfilename: dbfilename.clone(), ..Config::default() };
+        let server = Arc::new(RedisServerBuilder::new().bind("127.0.0.1:0").config(config).build().unwrap());
+        let addr = server.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut send = |cmd: &[&str]| {
+                client.write_all(&RespValue::bulk_strings(&cmd.iter().map(|arg| arg.as_bytes().to_vec()).collect::<Vec<Vec<u8>>>()).serialize(2)).unwrap();
+                let mut read_buffer = [0; CHUNK_SIZE];
+                let num_bytes_read = client.read(&mut read_buffer).unwrap();
+                String::from_utf8_lossy(&read_buffer[..num_bytes_read]).into_owned()
+            };
+            let set = send(&["SET", "k", "v"]);
+            let bad_mode = send(&["SHUTDOWN", "LATER"]);
+            // The connection is closed without a reply
+            let shutdown = send(&["SHUTDOWN", "SAVE"]);
+            (set, bad_mode, shutdown)
+        });
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        runtime.block_on(server.run()).unwrap();
+        assert_eq!(client.join().unwrap(), ("+OK\r\n".to_string(), "-ERR syntax error\r\n".to_string(), String::new()));
+        let rdb_path = dir.join(dbfilename);
+        let saved = rdb::load_file(&rdb_path, &EncodingLimits::default(), RedisServer::curr_time_ms()).unwrap();
+        std::fs::remove_file(&rdb_path).unwrap();
+        assert!(saved[&0].contains_key(b"k".as_slice()));
+    }
+
+    #[test]
+    fn connections_past_maxclients_are_rejected() {
+        let config = Config { dbfilename: "maxclients-test-missing.rdb".to_string(), maxclients: 1, ..Config::default() };
+        let server = Arc::new(RedisServerBuilder::new().bind("127.0.0.1:0").config(config).build().unwrap());
+        let addr = server.local_addr().unwrap();
+
+        let client = std::thread::spawn({
+            let server = Arc::clone(&server);
+            move || {
+                let read_reply = |client: &mut TcpStream| {
+                    let mut read_buffer = [0; CHUNK_SIZE];
+                    let num_bytes_read = client.read(&mut read_buffer).unwrap();
+                    String::from_utf8_lossy(&read_buffer[..num_bytes_read]).into_owned()
+                };
+                let mut first = TcpStream::connect(addr).unwrap();
+                first.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                first.write_all(b"PING\r\n").unwrap();
+                let first_ping = read_reply(&mut first);
+                let mut rejected = TcpStream::connect(addr).unwrap();
+                rejected.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                let rejection = read_reply(&mut rejected);
+                let rejected_eof = read_reply(&mut rejected);
+                // Once the first client leaves, there's room again
+                drop(first);
+                let mut replies = Vec::new();
+                for _ in 0..50 {
+                    let mut next = TcpStream::connect(addr).unwrap();
+                    next.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                    next.write_all(b"PING\r\n").unwrap();
+                    replies.push(read_reply(&mut next));
+                    if replies.last().is_some_and(|reply| reply == "+PONG\r\n") {
+                        break;
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                server.shutdown().unwrap();
+                (first_ping, rejection, rejected_eof, replies.pop())
+            }
+        });
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        runtime.block_on(server.run()).unwrap();
+        let (first_ping, rejection, rejected_eof, last_reply) = client.join().unwrap();
+        assert_eq!(first_ping, "+PONG\r\n");
+        assert_eq!((rejection.as_str(), rejected_eof.as_str()), ("-ERR max number of clients reached\r\n", ""));
+        assert_eq!(last_reply.as_deref(), Some("+PONG\r\n"));
+        assert!(server.state.stats.rejected_connections.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn the_server_listens_on_every_bind_address() {
+        let config = Config { dbfilename: "bind-test-missing.rdb".to_string(), ..Config::default() };
+        let server = RedisServerBuilder::new().bind("127.0.0.1:0").bind("-256.0.0.1:0").bind("127.0.0.1:0").config(config);
+        let server = Arc::new(server.build().unwrap());
+        let addrs = server.local_addrs().unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert!(RedisServerBuilder::new().bind("256.0.0.1:0").build().is_err());
+
+        let client = std::thread::spawn({
+            let server = Arc::clone(&server);
+            move || {
+                let replies = addrs.iter()
+                    .map(|addr| {
+                        let mut client = TcpStream::connect(addr).unwrap();
+                        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                        client.write_all(b"PING\r\n").unwrap();
+                        let mut read_buffer = [0; CHUNK_SIZE];
+                        let num_bytes_read = client.read(&mut read_buffer).map_err(|err| err.kind());
+                        num_bytes_read.map(|num_bytes_read| String::from_utf8_lossy(&read_buffer[..num_bytes_read]).into_owned())
+                    })
+                    .collect::<Vec<Result<String, ErrorKind>>>();
+                server.shutdown().unwrap();
+                replies
+            }
+        });
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        runtime.block_on(server.run()).unwrap();
+        assert_eq!(client.join().unwrap(), [Ok("+PONG\r\n".to_string()), Ok("+PONG\r\n".to_string())]);
+    }
+
+    #[test]
+    fn unix_socket_clients_are_served_alongside_tcp_ones() {
+        let path = std::env::temp_dir().join(format!("redis-test-{}.sock", std::process::id()));
+        // A socket file left behind by an earlier server is replaced
+        drop(UnixListener::bind(&path).unwrap());
+        let config = Config { dbfilename: "unixsocket-test-missing.rdb".to_string(), unixsocket: Some(path.clone()), ..Config::default() };
+        let server = Arc::new(RedisServerBuilder::new().bind("127.0.0.1:0").config(config).build().unwrap());
+        let addr = server.local_addr().unwrap();
+
+        let client = std::thread::spawn({
+            let (server, path) = (Arc::clone(&server), path.clone());
+            move || {
+                let mut unix_client = std::os::unix::net::UnixStream::connect(&path).unwrap();
+                unix_client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                unix_client.write_all(b"SET via unix\r\nCLIENT INFO\r\n").unwrap();
+                let mut unix_reply = Vec::new();
+                let mut read_buffer = [0; CHUNK_SIZE];
+                while !unix_reply.ends_with(b"\n\r\n") {
+                    match unix_client.read(&mut read_buffer) {
+                        Ok(num_bytes_read) if num_bytes_read > 0 => unix_reply.extend_from_slice(&read_buffer[..num_bytes_read]),
+                        _ => break,
+                    }
+                }
+                let mut tcp_client = TcpStream::connect(addr).unwrap();
+                tcp_client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                tcp_client.write_all(b"GET via\r\n").unwrap();
+                let tcp_reply = tcp_client.read(&mut read_buffer).map_err(|err| err.kind())
+                    .map(|num_bytes_read| String::from_utf8_lossy(&read_buffer[..num_bytes_read]).into_owned());
+                server.shutdown().unwrap();
+                (String::from_utf8_lossy(&unix_reply).into_owned(), tcp_reply)
+            }
+        });
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        runtime.block_on(server.run()).unwrap();
+        let (unix_reply, tcp_reply) = client.join().unwrap();
+        assert!(unix_reply.starts_with("+OK\r\n$"), "{}", unix_reply);
+        assert!(unix_reply.contains(&format!(" addr={}:0 ", path.display())), "{}", unix_reply);
+        assert_eq!(tcp_reply, Ok("$4\r\nunix\r\n".to_string()));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn protected_mode_only_refuses_remote_clients_without_an_explicit_bind() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let remote = SocketAddr::from(([10, 0, 0, 1], 50000));
+        assert!(state.is_protected_from(remote));
+        for local in ["127.0.0.1:50000", "[::1]:50000", "[::ffff:127.0.0.1]:50000"] {
+            assert!(!state.is_protected_from(local.parse().unwrap()), "{}", local);
+        }
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["CONFIG", "SET", "protected-mode", "no"]), "+OK\r\n");
+        assert!(!state.is_protected_from(remote));
+
+        let config = Config { bind: vec!["0.0.0.0".to_string()], ..Config::default() };
+        let server = RedisServerBuilder::new().bind("127.0.0.1:0").config(config).build().unwrap();
+        assert!(!server.state.is_protected_from(remote));
+    }
+
+    #[test]
+    fn idle_clients_are_disconnected_after_the_timeout() {
+        let config = Config { dbfilename: "timeout-test-missing.rdb".to_string(), timeout: 1, hz: 100, ..Config::default() };
+        let server = Arc::new(RedisServerBuilder::new().bind("127.0.0.1:0").config(config).build().unwrap());
+        let addr = server.local_addr().unwrap();
+
+        let client = std::thread::spawn({
+            let server = Arc::clone(&server);
+            move || {
+                let mut idle = TcpStream::connect(addr).unwrap();
+                let mut blocked = TcpStream::connect(addr).unwrap();
+                for client in [&idle, &blocked] {
+                    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                }
+                // Blocked clients wait as long as their command does, however long that is
+                blocked.write_all(b"BLPOP list 2\r\n").unwrap();
+                let idle_start = Instant::now();
+                let idle_eof = idle.read(&mut [0; CHUNK_SIZE]).map_err(|err| err.kind());
+                let idle_for = idle_start.elapsed();
+                let mut read_buffer = [0; CHUNK_SIZE];
+                let blocked_reply = blocked.read(&mut read_buffer).map(|num_bytes_read| read_buffer[..num_bytes_read].to_vec());
+                server.shutdown().unwrap();
+                (idle_eof, idle_for, blocked_reply.map_err(|err| err.kind()))
+            }
+        });
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        runtime.block_on(server.run()).unwrap();
+        let (idle_eof, idle_for, blocked_reply) = client.join().unwrap();
+        assert_eq!(idle_eof, Ok(0));
+        assert!(idle_for >= Duration::from_millis(900) && idle_for < Duration::from_secs(3), "{:?}", idle_for);
+        assert_eq!(blocked_reply, Ok(b"*-1\r\n".to_vec()));
+    }
+
+    #[test]
+    fn blocking_commands_wait_without_blocking_a_single_threaded_runtime() {
+        let config = Config { dbfilename: "blocking-test-missing.rdb".to_string(), ..Config::default() };
+        let server = Arc::new(RedisServerBuilder::new().bind("127.0.0.1:0").config(config).build().unwrap());
+        let addr = server.local_addr().unwrap();
+
+        let client = std::thread::spawn({
+            let server = Arc::clone(&server);
+            move || {
+                let read_reply = |client: &mut TcpStream| {
+                    let mut read_buffer = [0; CHUNK_SIZE];
+                    let num_bytes_read = client.read(&mut read_buffer).unwrap();
+                    String::from_utf8_lossy(&read_buffer[..num_bytes_read]).to_string()
+                };
+                let mut clients = (0..4).map(|_| TcpStream::connect(addr).unwrap()).collect::<Vec<TcpStream>>();
+                for client in &clients {
+                    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                }
+                // The reply of the command pipelined before BLPOP is sent before it starts waiting
+                clients[0].write_all(b"PING\r\nBLPOP list 0\r\n").unwrap();
+                let ping_reply = read_reply(&mut clients[0]);
+                clients[1].write_all(b"XREAD BLOCK 0 STREAMS stream $\r\n").unwrap();
+                clients[2].write_all(b"BZPOPMIN zset 0.1\r\n").unwrap();
+                let bzpopmin_reply = read_reply(&mut clients[2]);
+                std::thread::sleep(Duration::from_millis(50));
+                clients[3].write_all(b"RPUSH list a\r\n").unwrap();
+                let rpush_reply = read_reply(&mut clients[3]);
+                clients[3].write_all(b"XADD stream 1-1 f v\r\n").unwrap();
+                let xadd_reply = read_reply(&mut clients[3]);
+                let blpop_reply = read_reply(&mut clients[0]);
+                let xread_reply = read_reply(&mut clients[1]);
+                server.shutdown().unwrap();
+                (ping_reply, bzpopmin_reply, rpush_reply, xadd_reply, blpop_reply, xread_reply)
+            }
+        });
+        // The default runtime of #[tokio::test], where blocking a worker would hold up every other connection
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(server.run()).unwrap();
+        let (ping_reply, bzpopmin_reply, rpush_reply, xadd_reply, blpop_reply, xread_reply) = client.join().unwrap();
+        assert_eq!(ping_reply, "+PONG\r\n");
+        assert_eq!(bzpopmin_reply, "*-1\r\n");
+        assert_eq!(rpush_reply, ":1\r\n");
+        assert_eq!(xadd_reply, "$3\r\n1-1\r\n");
+        assert_eq!(blpop_reply, "*2\r\n$4\r\nlist\r\n$1\r\na\r\n");
+        assert_eq!(xread_reply, "*1\r\n*2\r\n$6\r\nstream\r\n*1\r\n*2\r\n$3\r\n1-1\r\n*2\r\n$1\r\nf\r\n$1\r\nv\r\n");
+        assert_eq!(server.state.dbs[0].lock_all().len(), 1);
+    }
+
+    #[test]
+    fn sessions_run_commands_for_front_ends_that_do_their_own_io() {
+        let config = Config { dbfilename: "session-test-missing.rdb".to_string(), ..Config::default() };
+        let server = RedisServerBuilder::new().bind("127.0.0.1:0").config(config).build().unwrap();
+        let addr = server.local_addr().unwrap();
+        let mut session = server.new_session(addr, addr).unwrap();
+        let mut other = server.new_session(addr, addr).unwrap();
+        assert_eq!(server.state.clients.len(), 2);
+
+        // A command split across feeds runs once all of it is in, and pipelined commands run together
+        session.feed(b"*3\r\n$3\r\nSET\r\n$1\r\nk").unwrap();
+        assert_eq!(session.take_output(), b"");
+        session.feed(b"\r\n$1\r\nv\r\nGET k\r\n").unwrap();
+        assert_eq!(session.take_output(), b"+OK\r\n$1\r\nv\r\n");
+        assert!(!session.needs_polling());
+
+        // A blocked command holds up the commands after it until poll finds its keys written to
+        session.feed(b"BLPOP list 0\r\nPING\r\n").unwrap();
+        assert_eq!(session.take_output(), b"");
+        assert!(session.needs_polling());
+        session.poll().unwrap();
+        assert_eq!(session.take_output(), b"");
+        other.feed(b"RPUSH list a\r\n").unwrap();
+        assert_eq!(other.take_output(), b":1\r\n");
+        session.poll().unwrap();
+        assert_eq!(session.take_output(), b"*2\r\n$4\r\nlist\r\n$1\r\na\r\n+PONG\r\n");
+        assert!(!session.needs_polling());
+
+        // Messages come out of take_output too
+        other.feed(b"SUBSCRIBE news\r\n").unwrap();
+        assert_eq!(other.take_output(), b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n");
+        assert!(other.needs_polling());
+        session.feed(b"PUBLISH news hi\r\n").unwrap();
+        assert_eq!(session.take_output(), b":1\r\n");
+        assert_eq!(other.take_output(), b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n");
+
+        session.feed(b"QUIT\r\n").unwrap();
+        assert_eq!(session.take_output(), b"+OK\r\n");
+        assert!(session.is_closed());
+        drop(session);
+        drop(other);
+        assert_eq!(server.state.clients.len(), 0);
+        assert_eq!(server.state.pubsub.lock_channels().len(), 0);
+    }
+
+    #[test]
+    fn client_kill_closes_the_matching_connections() {
+        let config = Config { dbfilename: "client-kill-test-missing.rdb".to_string(), ..Config::default() };
+        let server = Arc::new(RedisServerBuilder::new().bind("127.0.0.1:0").config(config).build().unwrap());
+        let addr = server.local_addr().unwrap();
+
+        let client = std::thread::spawn({
+            let server = Arc::clone(&server);
+            move || {
+                let send = |client: &mut TcpStream, cmd: &str| {
+                    let args = cmd.split(' ').collect::<Vec<&str>>();
+                    client.write_all(&RespValue::bulk_strings(&args.iter().map(|arg| arg.as_bytes().to_vec()).collect::<Vec<Vec<u8>>>()).serialize(2)).unwrap();
+                    let mut read_buffer = [0; CHUNK_SIZE];
+                    let num_bytes_read = client.read(&mut read_buffer).unwrap();
+                    String::from_utf8_lossy(&read_buffer[..num_bytes_read]).into_owned()
+                };
+                let mut admin = TcpStream::connect(addr).unwrap();
+                let mut victim = TcpStream::connect(addr).unwrap();
+                let mut other = TcpStream::connect(addr).unwrap();
+                for client in [&admin, &victim, &other] {
+                    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                }
+                let victim_id = send(&mut victim, "CLIENT ID").trim_start_matches(':').trim_end().to_string();
+                // Make sure the server has accepted (and registered) the other connection before killing it by address
+                send(&mut other, "PING");
+                let other_addr = other.local_addr().unwrap().to_string();
+
+                let killed_by_id = send(&mut admin, &format!("CLIENT KILL ID {} SKIPME yes", victim_id));
+                let victim_eof = victim.read(&mut [0; CHUNK_SIZE]).unwrap();
+                let killed_by_addr = send(&mut admin, &format!("CLIENT KILL {}", other_addr));
+                let other_eof = other.read(&mut [0; CHUNK_SIZE]).unwrap();
+                let no_such_client = send(&mut admin, &format!("CLIENT KILL {}", other_addr));
+                // SKIPME (on by default) spares the connection running CLIENT KILL
+                let admin_addr = admin.local_addr().unwrap().to_string();
+                let skipped_me = send(&mut admin, &format!("CLIENT KILL ADDR {}", admin_addr));
+                server.shutdown().unwrap();
+                (killed_by_id, victim_eof, killed_by_addr, other_eof, no_such_client, skipped_me)
+            }
+        });
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        runtime.block_on(server.run()).unwrap();
+        assert_eq!(client.join().unwrap(), (":1\r\n".to_string(), 0, "+OK\r\n".to_string(), 0, "-ERR No such client\r\n".to_string(), ":0\r\n".to_string()));
+    }
+
+    #[test]
+    fn client_pause_holds_up_commands_until_unpaused() {
+        let config = Config { dbfilename: "client-pause-test-missing.rdb".to_string(), ..Config::default() };
+        let server = Arc::new(RedisServerBuilder::new().bind("127.0.0.1:0").config(config).build().unwrap());
+        let addr = server.local_addr().unwrap();
+
+        let client = std::thread::spawn({
+            let server = Arc::clone(&server);
+            move || {
+                let request = |args: &[&str]| RespValue::bulk_strings(&args.iter().map(|arg| arg.as_bytes().to_vec()).collect::<Vec<Vec<u8>>>()).serialize(2);
+                let read_reply = |client: &mut TcpStream| {
+                    let mut read_buffer = [0; CHUNK_SIZE];
+                    let num_bytes_read = client.read(&mut read_buffer).unwrap();
+                    String::from_utf8_lossy(&read_buffer[..num_bytes_read]).into_owned()
+                };
+                let mut admin = TcpStream::connect(addr).unwrap();
+                let mut other = TcpStream::connect(addr).unwrap();
+                admin.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                other.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+
+                admin.write_all(&request(&["CLIENT", "PAUSE", "10000", "WRITE"])).unwrap();
+                let paused = read_reply(&mut admin);
+                // Reads go through, but the SET waits, and so does the GET pipelined after it
+                other.write_all(&request(&["GET", "k"])).unwrap();
+                let read_during_pause = read_reply(&mut other);
+                other.write_all(&[request(&["SET", "k", "v"]), request(&["GET", "k"])].concat()).unwrap();
+                let write_during_pause = other.read(&mut [0; CHUNK_SIZE]).map_err(|err| err.kind());
+                admin.write_all(&request(&["CLIENT", "UNPAUSE"])).unwrap();
+                let unpaused = read_reply(&mut admin);
+                other.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                let mut after_unpause = read_reply(&mut other);
+                if after_unpause.len() < "+OK\r\n+v\r\n".len() {
+                    after_unpause += &read_reply(&mut other);
+                }
+
+                // A pause that runs out lets everything through again by itself
+                let pause_start = Instant::now();
+                admin.write_all(&request(&["CLIENT", "PAUSE", "100"])).unwrap();
+                read_reply(&mut admin);
+                other.write_all(&request(&["PING"])).unwrap();
+                let ping_after_pause = read_reply(&mut other);
+                let paused_for = pause_start.elapsed();
+                server.shutdown().unwrap();
+                (paused, read_during_pause, write_during_pause, unpaused, after_unpause, ping_after_pause, paused_for)
+            }
+        });
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        runtime.block_on(server.run()).unwrap();
+        let (paused, read_during_pause, write_during_pause, unpaused, after_unpause, ping_after_pause, paused_for) = client.join().unwrap();
+        assert_eq!((paused.as_str(), read_during_pause.as_str(), unpaused.as_str()), ("+OK\r\n", "$-1\r\n", "+OK\r\n"));
+        assert!(matches!(write_during_pause, Err(ErrorKind::WouldBlock | ErrorKind::TimedOut)), "{:?}", write_during_pause);
+        assert_eq!(after_unpause, "+OK\r\n$1\r\nv\r\n");
+        assert_eq!(ping_after_pause, "+PONG\r\n");
+        assert!(paused_for >= Duration::from_millis(100), "{:?}", paused_for);
+    }
+
+    #[test]
+    fn idle_connections_dont_hold_up_active_ones() {
+        let config = Config { dbfilename: "idle-connections-test-missing.rdb".to_string(), ..Config::default() };
+        let server = Arc::new(RedisServerBuilder::new().bind("127.0.0.1:0").config(config).build().unwrap());
+        let addr = server.local_addr().unwrap();
+
+        let client = std::thread::spawn({
+            let server = Arc::clone(&server);
+            move || {
+                // Far more connections than workers, none of which ever sends a command
+                let idle_clients = (0..200).map(|_| TcpStream::connect(addr).unwrap()).collect::<Vec<TcpStream>>();
+                let mut client = TcpStream::connect(addr).unwrap();
+                client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+                client.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+                let mut read_buffer = [0; CHUNK_SIZE];
+                let num_bytes_read = client.read(&mut read_buffer).unwrap();
+                server.shutdown().unwrap();
+                drop(idle_clients);
+                read_buffer[..num_bytes_read].to_vec()
+            }
+        });
+        let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap();
+        runtime.block_on(server.run()).unwrap();
+        assert_eq!(client.join().unwrap(), b"+PONG\r\n");
+    }
+
+    #[test]
+    fn registry_dispatches_to_registered_handlers() {
+        struct Greeting(&'static str);
+        impl CommandHandler for Greeting {
+            fn handle(&self, conn: &mut Connection, _args: &[Bytes], _state: &ServerState) -> io::Result<()> {
+                conn.write_reply(&RespValue::SimpleString(self.0.as_bytes().to_vec()))
+            }
+        }
+        static HI: CommandSpec = CommandSpec { name: "hi", arity: (0, Some(0)), flags: &["fast"], keys: (0, 0, 0), find_keys: None, handler: &Greeting("hello") };
+
+        let mut registry = CommandRegistry::default();
+        registry.register_all(COMMANDS);
+        // Modules register their own commands alongside the built-in ones
+        registry.register_all(streams::COMMANDS);
+        registry.register(&HI);
+        assert_eq!(registry.len(), COMMANDS.len() + streams::COMMANDS.len() + 1);
+        assert_eq!(registry.iter().last().map(|spec| spec.name), Some("hi"));
+        assert!(registry.get("xadd").is_some());
+        assert!(registry.get("nosuchcommand").is_none());
+
+        let (mut client, mut conn) = connection_pair();
+        registry["hi"].handler.handle(&mut conn, &[Bytes::from_static(b"hi")], &ServerState::new()).unwrap();
+        test_runtime().block_on(conn.flush()).unwrap();
+        let mut read_buffer = [0; CHUNK_SIZE];
+        let num_bytes_read = client.read(&mut read_buffer).unwrap();
+        assert_eq!(&read_buffer[..num_bytes_read], b"+hello\r\n");
+    }
+
+    #[test]
+    fn type_reports_the_stored_value_and_other_types_are_rejected() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        for (setup, key, type_name) in [
+            (&["SET", "str", "v"][..], "str", "string"),
+            (&["PFADD", "hll", "a"][..], "hll", "string"),
+            (&["RPUSH", "list", "a"][..], "list", "list"),
+            (&["HSET", "hash", "f", "v"][..], "hash", "hash"),
+            (&["SADD", "set", "a"][..], "set", "set"),
+            (&["ZADD", "zset", "1", "a"][..], "zset", "zset"),
+            (&["XADD", "stream", "1-1", "f", "v"][..], "stream", "stream"),
+        ] {
+            run_cmd(&mut client, &mut conn, &state, setup);
+            assert_eq!(run_cmd(&mut client, &mut conn, &state, &["TYPE", key]), format!("+{}\r\n", type_name));
+        }
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["TYPE", "missing"]), "+none\r\n");
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["GET", "list"]), format!("-{}\r\n", WRONGTYPE_ERR));
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["LRANGE", "str", "0", "-1"]), format!("-{}\r\n", WRONGTYPE_ERR));
+        // HLLs are strings, so the string commands read their dense encoding
+        let hll_len = HyperLogLog::new().serialized_len();
+        // The whole reply doesn't fit in a single read, so it goes to a connection of its own that is then dropped
+        let (mut hll_client, mut hll_conn) = connection_pair();
+        assert!(run_cmd(&mut hll_client, &mut hll_conn, &state, &["GET", "hll"]).starts_with(&format!("${}\r\nHYLL", hll_len)));
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["GETRANGE", "hll", "0", "3"]), "$4\r\nHYLL\r\n");
+        assert_eq!(run_cmd(&mut client, &mut conn, &state, &["INCRBYFLOAT", "hll", "1"]), "-ERR value is not a valid float\r\n");
+    }
+
+    #[test]
+    fn exists_del_and_unlink_count_live_keys() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["SET", "a", "1"]);
+        run_cmd(&["SET", "expired", "1", "PX", "1"]);
+        run_cmd(&["RPUSH", "list", "a", "b"]);
+        // Hashes only exist as long as they have fields that haven't expired
+        run_cmd(&["HSET", "hash", "f", "v", "g", "v"]);
+        run_cmd(&["HPEXPIRE", "hash", "1", "FIELDS", "1", "f"]);
+        run_cmd(&["HSET", "expired-hash", "f", "v"]);
+        run_cmd(&["HPEXPIRE", "expired-hash", "1", "FIELDS", "1", "f"]);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(run_cmd(&["EXISTS", "a", "a", "expired", "list", "missing", "hash", "expired-hash"]), ":4\r\n");
+        assert_eq!(RedisServer::remove_queued_expired(&state), 3);
+        assert_eq!(run_cmd(&["DEL", "a", "a", "expired", "missing", "hash"]), ":2\r\n");
+        assert_eq!(run_cmd(&["GET", "a"]), "$-1\r\n");
+
+        // Big enough to be freed in the background, but gone right away all the same
+        let members = (0..=LAZYFREE_THRESHOLD).map(|idx| idx.to_string()).collect::<Vec<String>>();
+        run_cmd(&[&["SADD", "set"], &members.iter().map(String::as_str).collect::<Vec<&str>>()[..]].concat());
+        assert_eq!(run_cmd(&["UNLINK", "set", "list"]), ":2\r\n");
+        assert_eq!(run_cmd(&["EXISTS", "set", "list"]), ":0\r\n");
+        assert_eq!(state.lock_db(0).len(), 0);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while state.lazyfree.pending_objects() > 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(run_cmd(&["INFO", "memory"]).contains("lazyfree_pending_objects:0\r\n"));
+    }
+
+    #[test]
+    fn command_docs_covers_known_commands_only() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        assert_eq!(run_cmd(&["COMMAND", "DOCS", "GET", "nosuch"]), "*2\r\n$3\r\nget\r\n*0\r\n");
+        assert!(run_cmd(&["HELLO", "3"]).starts_with("%7\r\n"));
+        assert!(run_cmd(&["COMMAND", "DOCS"]).starts_with(&format!("%{}\r\n", command_table().len())));
+    }
+
+    #[test]
+    fn renamed_commands_are_only_callable_by_their_new_name() {
+        let config = Config {
+            renamed_commands: vec![("FLUSHALL".to_string(), String::new()), ("config".to_string(), "cfg".to_string())],
+            ..Config::default()
+        };
+        let server = RedisServerBuilder::new().bind("127.0.0.1:0").config(config).build().unwrap();
+        let (mut client, mut conn) = connection_pair();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &server.state, args);
+
+        assert!(run_cmd(&["FLUSHALL"]).starts_with("-ERR unknown command 'FLUSHALL'"));
+        assert!(run_cmd(&["CONFIG", "GET", "hz"]).starts_with("-ERR unknown command 'CONFIG'"));
+        assert_eq!(run_cmd(&["CFG", "GET", "hz"]), "*2\r\n$2\r\nhz\r\n$2\r\n10\r\n");
+        assert_eq!(run_cmd(&["COMMAND", "COUNT"]), format!(":{}\r\n", command_table().len() - 1));
+        assert_eq!(run_cmd(&["COMMAND", "INFO", "flushall"]), "*1\r\n*-1\r\n");
+
+        for renamed_commands in [vec![("nosuch", "")], vec![("get", "set")]] {
+            let config = Config {
+                renamed_commands: renamed_commands.iter().map(|(name, new_name)| (name.to_string(), new_name.to_string())).collect(),
+                ..Config::default()
+            };
+            assert!(RedisServerBuilder::new().bind("127.0.0.1:0").config(config).build().is_err());
+        }
+    }
+
+    #[test]
+    fn command_info_and_list_come_from_the_command_table() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        assert_eq!(
+            run_cmd(&["COMMAND", "INFO", "get", "nosuch"]),
+            "*2\r\n*6\r\n$3\r\nget\r\n:2\r\n*2\r\n+readonly\r\n+fast\r\n:1\r\n:1\r\n:1\r\n*-1\r\n"
+        );
+        assert_eq!(run_cmd(&["COMMAND", "LIST", "FILTERBY", "PATTERN", "GETR*"]), "*1\r\n$8\r\ngetrange\r\n");
+        assert_eq!(run_cmd(&["COMMAND", "LIST", "FILTERBY", "ACLCAT", "string"]), "-ERR syntax error\r\n");
+        assert!(run_cmd(&["COMMAND", "INFO"]).starts_with(&format!("*{}\r\n", command_table().len())));
+    }
+
+    #[test]
+    fn unknown_commands_get_an_error_and_the_connection_carries_on() {
+        let state = ServerState::new();
+        let (mut client, mut conn) = connection_pair();
+        client.write_all(b"*3\r\n$10\r\nFROBNICATE\r\n$1\r\na\r\n$1\r\nb\r\n*1\r\n$5\r\nMULTI\r\n*1\r\n$4\r\nNOPE\r\n*1\r\n$4\r\nEXEC\r\n*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        test_runtime().block_on(RedisServer::handle_connection(&mut conn, &state)).unwrap();
+
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).unwrap();
+        assert_eq!(resp, [
+            "-ERR unknown command 'FROBNICATE', with args beginning with: 'a' 'b' \r\n",
+            "+OK\r\n",
+            "-ERR unknown command 'NOPE', with args beginning with: \r\n",
+            "-EXECABORT Transaction discarded because of previous errors.\r\n",
+            "$2\r\nhi\r\n",
+        ].concat());
+    }
+
+    #[test]
+    fn commands_need_auth_once_requirepass_is_set() {
+        let config = Config { requirepass: Some("secret".to_string()), ..Config::default() };
+        let server = RedisServerBuilder::new().bind("127.0.0.1:0").config(config).build().unwrap();
+        let (mut client, mut conn) = connection_pair();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &server.state, args);
+
+        assert_eq!(run_cmd(&["GET", "key"]), "-NOAUTH Authentication required.\r\n");
+        assert!(run_cmd(&["HELLO", "3"]).starts_with("-NOAUTH HELLO must be called with the client already authenticated"));
+        assert_eq!(run_cmd(&["AUTH", "wrong"]), "-WRONGPASS invalid username-password pair or user is disabled.\r\n");
+        assert_eq!(run_cmd(&["AUTH", "someone", "secret"]), "-WRONGPASS invalid username-password pair or user is disabled.\r\n");
+        assert_eq!(run_cmd(&["AUTH", "secret"]), "+OK\r\n");
+        assert_eq!(run_cmd(&["GET", "key"]), "$-1\r\n");
+
+        // RESET logs the connection out again, and HELLO can log it back in
+        assert_eq!(run_cmd(&["RESET"]), "+RESET\r\n");
+        assert_eq!(run_cmd(&["GET", "key"]), "-NOAUTH Authentication required.\r\n");
+        assert!(run_cmd(&["HELLO", "3", "AUTH", "default", "secret"]).starts_with("%7\r\n"));
+        assert_eq!(run_cmd(&["CONFIG", "GET", "requirepass"]), "%1\r\n$11\r\nrequirepass\r\n$6\r\nsecret\r\n");
+
+        // Passwords are kept out of the slow log
+        run_cmd(&["CONFIG", "SET", "slowlog-log-slower-than", "0"]);
+        run_cmd(&["AUTH", "default", "secret"]);
+        let slowlog = run_cmd(&["SLOWLOG", "GET", "1"]);
+        assert!(slowlog.contains("(redacted)") && !slowlog.contains("secret"), "{}", slowlog);
+
+        assert_eq!(run_cmd(&["CONFIG", "SET", "requirepass", ""]), "+OK\r\n");
+        assert!(run_cmd(&["AUTH", "secret"]).starts_with("-ERR AUTH <password> called without any password configured"));
+    }
+
+    #[test]
+    fn acl_users_only_run_the_commands_they_are_allowed() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        assert_eq!(run_cmd(&["ACL", "SETUSER", "reader", "on", ">pw", "+@read", "-@dangerous", "+@transaction"]), "+OK\r\n");
+        assert_eq!(
+            run_cmd(&["ACL", "SETUSER", "reader", "+@nosuch"]),
+            "-ERR Error in ACL SETUSER modifier '+@nosuch': Unknown command or category name in ACL\r\n"
+        );
+        assert_eq!(
+            run_cmd(&["ACL", "GETUSER", "reader"]),
+            format!(
+                "*10\r\n$5\r\nflags\r\n*1\r\n$2\r\non\r\n$9\r\npasswords\r\n*1\r\n$64\r\n{}\r\n$8\r\ncommands\r\n$32\r\n+@read -@dangerous +@transaction\r\n{}",
+                "30c952fab122c3f9759f02a6d95c3758b246b4fee239957b2d4fee46e26170c4",
+                "$4\r\nkeys\r\n$2\r\n~*\r\n$8\r\nchannels\r\n$2\r\n&*\r\n",
+            )
+        );
+        assert_eq!(run_cmd(&["ACL", "USERS"]), "*2\r\n$7\r\ndefault\r\n$6\r\nreader\r\n");
+        assert_eq!(run_cmd(&["ACL", "CAT", "nosuch"]), "-ERR Unknown category 'nosuch'\r\n");
+
+        assert_eq!(run_cmd(&["AUTH", "reader", "nope"]), "-WRONGPASS invalid username-password pair or user is disabled.\r\n");
+        assert_eq!(run_cmd(&["AUTH", "reader", "pw"]), "+OK\r\n");
+        assert_eq!(run_cmd(&["ACL", "WHOAMI"]), "-NOPERM User reader has no permissions to run the 'acl' command\r\n");
+        assert_eq!(run_cmd(&["GET", "key"]), "$-1\r\n");
+        assert_eq!(run_cmd(&["SET", "key", "val"]), "-NOPERM User reader has no permissions to run the 'set' command\r\n");
+        assert_eq!(run_cmd(&["KEYS", "*"]), "-NOPERM User reader has no permissions to run the 'keys' command\r\n");
+        // A rejected command fails the transaction it would have been queued in
+        run_cmd(&["MULTI"]);
+        run_cmd(&["SET", "key", "val"]);
+        assert!(run_cmd(&["EXEC"]).starts_with("-EXECABORT"));
+
+        // RESET logs back in as the default user
+        assert_eq!(run_cmd(&["RESET"]), "+RESET\r\n");
+        assert_eq!(run_cmd(&["ACL", "WHOAMI"]), "$7\r\ndefault\r\n");
+        assert_eq!(run_cmd(&["ACL", "DELUSER", "default"]), "-ERR The 'default' user cannot be removed\r\n");
+        assert_eq!(run_cmd(&["ACL", "DELUSER", "reader", "nosuch"]), ":1\r\n");
+        assert_eq!(run_cmd(&["ACL", "LIST"]), "*1\r\n$34\r\nuser default on nopass ~* &* +@all\r\n");
+    }
+
+    #[test]
+    fn acl_users_persist_in_the_aclfile_and_denials_are_logged() {
+        let (mut client, mut conn) = connection_pair();
+        let aclfile = std::env::temp_dir().join(format!("acl-save-test-{}.acl", std::process::id()));
+        let state = ServerState { aclfile: Some(aclfile.clone()), ..ServerState::new() };
+        let mut run_cmd = |args: &[&str]| run_cmd(&mut client, &mut conn, &state, args);
+
+        run_cmd(&["ACL", "SETUSER", "reader", "on", "nopass", "+get"]);
+        assert_eq!(run_cmd(&["ACL", "SAVE"]), "+OK\r\n");
+        assert_eq!(
+            std::fs::read_to_string(&aclfile).unwrap(),
+            "user default on nopass ~* &* +@all\nuser reader on nopass ~* &* +get\n"
+        );
+        run_cmd(&["ACL", "DELUSER", "reader"]);
+        assert_eq!(run_cmd(&["ACL", "LOAD"]), "+OK\r\n");
+        assert_eq!(run_cmd(&["ACL", "USERS"]), "*2\r\n$7\r\ndefault\r\n$6\r\nreader\r\n");
+        std::fs::write(&aclfile, "user reader on nopass +nosuch\n").unwrap();
+        assert!(run_cmd(&["ACL", "LOAD"]).ends_with(":line 1: Unknown command or category name in ACL\r\n"));
+        std::fs::remove_file(&aclfile).unwrap();
+
+        run_cmd(&["AUTH", "nobody", "pw"]);
+        run_cmd(&["AUTH", "reader", "pw"]);
+        run_cmd(&["SET", "key", "val"]);
+        run_cmd(&["SET", "key", "val"]);
+        assert_eq!(run_cmd(&["RESET"]), "+RESET\r\n");
+        let log = run_cmd(&["ACL", "LOG", "1"]);
+        assert!(log.starts_with("*1\r\n*20\r\n$5\r\ncount\r\n:2\r\n$6\r\nreason\r\n$7\r\ncommand\r\n$7\r\ncontext\r\n$8\r\ntoplevel\r\n$6\r\nobject\r\n$3\r\nset\r\n$8\r\nusername\r\n$6\r\nreader\r\n"), "{}", log);
+        assert!(log.contains(" user=reader "), "{}", log);
+        let log = run_cmd(&["ACL", "LOG"]);
+        assert!(log.starts_with("*2\r\n") && log.contains("$4\r\nauth\r\n") && log.contains("$6\r\nnobody\r\n"), "{}", log);
+        assert_eq!(run_cmd(&["ACL", "LOG", "RESET"]), "+OK\r\n");
+        assert_eq!(run_cmd(&["ACL", "LOG"]), "*0\r\n");
+    }
+
+    #[test]
+    fn reset_returns_the_connection_to_its_initial_state() {
+        let (mut client, mut conn) = connection_pair();
+        let state = ServerState::new();
+        let mut run_cmd = |conn: &mut Connection, args: &[&str]| run_cmd(&mut client, conn, &state, args);
+
+        run_cmd(&mut conn, &["CLIENT", "SETNAME", "pooled"]);
+        run_cmd(&mut conn, &["HELLO", "3"]);
+        run_cmd(&mut conn, &["SELECT", "2"]);
+        run_cmd(&mut conn, &["WATCH", "k"]);
+        run_cmd(&mut conn, &["SUBSCRIBE", "ch"]);
+        run_cmd(&mut conn, &["MULTI"]);
+        assert_eq!(run_cmd(&mut conn, &["SET", "k", "v"]), "+QUEUED\r\n");
+        assert_eq!(run_cmd(&mut conn, &["RESET"]), "+RESET\r\n");
+
+        assert_eq!((conn.db, conn.protocol, conn.num_subscriptions()), (0, 2, 0));
+        assert!(conn.transaction.is_none() && conn.watched_keys.is_empty());
+        assert!(state.pubsub.lock_channels().is_empty());
+        // Commands run right away again, with RESP2 replies, and the client keeps its name
+        assert_eq!(run_cmd(&mut conn, &["PUBLISH", "ch", "m"]), ":0\r\n");
+        assert_eq!(run_cmd(&mut conn, &["HSET", "h", "f", "v"]), ":1\r\n");
+        assert_eq!(run_cmd(&mut conn, &["HGETALL", "h"]), "*2\r\n$1\r\nf\r\n$1\r\nv\r\n");
+        assert_eq!(run_cmd(&mut conn, &["CLIENT", "GETNAME"]), "$6\r\npooled\r\n");
+        assert!(state.lock_db(0).get(b"h".as_slice()).is_some());
+
+        // It takes a MONITOR connection out of monitor mode too
+        run_cmd(&mut conn, &["MONITOR"]);
+        run_cmd(&mut conn, &["RESET"]);
+        assert!(!conn.monitoring && state.read_monitors().is_empty());
+    }
+
+    #[test]
+    fn quit_closes_the_connection_after_replying() {
+        let state = ServerState::new();
+        let (mut client, mut conn) = connection_pair();
+        // The client doesn't close its side, so only QUIT ends the connection; the PING after it is dropped
+        client.write_all(b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nQUIT\r\n*1\r\n$4\r\nPING\r\n").unwrap();
+        test_runtime().block_on(RedisServer::handle_connection(&mut conn, &state)).unwrap();
+
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).unwrap();
+        assert_eq!(resp, "+PONG\r\n+OK\r\n");
+    }
+
+    #[test]
+    fn keys_and_values_are_binary_safe() {
+        let state = ServerState::new();
+        let (mut client, mut conn) = connection_pair();
+        // Keys that aren't valid UTF-8 must not collapse into the same key
+        client.write_all(b"*3\r\n$3\r\nSET\r\n$1\r\n\xff\r\n$2\r\n\x00\xfe\r\n*3\r\n$3\r\nSET\r\n$1\r\n\xfe\r\n$1\r\nb\r\n").unwrap();
+        client.write_all(b"*2\r\n$3\r\nGET\r\n$1\r\n\xff\r\n*2\r\n$4\r\nKEYS\r\n$1\r\n\xfe\r\n").unwrap();
+        // Values with CRLFs in them come back whole rather than splitting the reply in two
+        client.write_all(b"*2\r\n$4\r\nECHO\r\n$4\r\na\r\nb\r\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        test_runtime().block_on(RedisServer::handle_connection(&mut conn, &state)).unwrap();
+
+        let mut resp = Vec::new();
+        client.read_to_end(&mut resp).unwrap();
+        assert_eq!(resp, b"+OK\r\n+OK\r\n$2\r\n\x00\xfe\r\n*1\r\n$1\r\n\xfe\r\n$4\r\na\r\nb\r\n");
+        assert_eq!(state.lock_db(0).len(), 2);
+    }
+
+    #[test]
+    fn replies_are_buffered_until_flushed() {
+        let (mut client, mut conn) = connection_pair();
+        let big_val = vec![b'x'; VECTORED_REPLY_LEN * 2];
+        conn.write_reply(&RespValue::ok()).unwrap();
+        conn.write_reply(&RespValue::BulkString(big_val.clone())).unwrap();
+        conn.write_reply(&RespValue::Integer(1)).unwrap();
+        client.set_nonblocking(true).unwrap();
+        assert_eq!(client.read(&mut [0; CHUNK_SIZE]).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        test_runtime().block_on(async {
+            conn.flush().await.unwrap();
+            conn.writer.as_mut().unwrap().shutdown().await.unwrap();
+        });
+        client.set_nonblocking(false).unwrap();
+        let mut resp = Vec::new();
+        client.read_to_end(&mut resp).unwrap();
+        let expected = [b"+OK\r\n".to_vec(), RespValue::BulkString(big_val).serialize(2), b":1\r\n".to_vec()].concat();
+        assert_eq!(resp, expected);
+    }
+
+    #[test]
+    fn commands_split_across_reads_are_reassembled() {
+        let state = ServerState::new();
+        let (mut client, mut conn) = connection_pair();
+        client.set_nodelay(true).unwrap();
+        let writer = std::thread::spawn(move || {
+            // Split mid-header, mid-payload and right after a complete command that's followed by a partial one
+            for piece in [&b"*3\r\n$3\r\nSE"[..], b"T\r\n$1\r\nk\r\n$5\r\nhel", b"lo\r\n*2\r\n$3", b"\r\nGET\r\n$1\r\nk\r\n"] {
+                client.write_all(piece).unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            client.shutdown(Shutdown::Write).unwrap();
+            client
+        });
+        test_runtime().block_on(RedisServer::handle_connection(&mut conn, &state)).unwrap();
+
+        let mut resp = String::new();
+        writer.join().unwrap().read_to_string(&mut resp).unwrap();
+        assert_eq!(resp, "+OK\r\n$5\r\nhello\r\n");
+    }
+
+    #[test]
+    fn bare_pings_are_only_answered_early_when_dispatch_would_reply_pong() {
+        let state = ServerState::new();
+        state.set_requirepass(Some("pw".to_string()));
+        let (mut client, mut conn) = connection_pair();
+        client.set_nodelay(true).unwrap();
+        let writer = std::thread::spawn(move || {
+            // Each PING arrives in a read of its own, which is what the fast path looks for
+            for piece in [PING_FRAME, b"AUTH pw\r\n", PING_FRAME, b"MULTI\r\n", PING_FRAME, b"EXEC\r\n", PING_FRAME] {
+                client.write_all(piece).unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            client.shutdown(Shutdown::Write).unwrap();
+            client
+        });
+        test_runtime().block_on(RedisServer::handle_connection(&mut conn, &state)).unwrap();
+
+        let mut resp = String::new();
+        writer.join().unwrap().read_to_string(&mut resp).unwrap();
+        assert_eq!(resp, concat!(
+            "-NOAUTH Authentication required.\r\n+OK\r\n+PONG\r\n",
+            "+OK\r\n+QUEUED\r\n*1\r\n+PONG\r\n+PONG\r\n",
+        ));
+    }
+
+    #[test]
+    fn pipelined_commands_reply_in_order() {
+        let state = ServerState::new();
+        let (mut client, mut conn) = connection_pair();
+        // Inline and multibulk commands can be mixed in the same segment
+        client.write_all(b"SET a 1\r\nGET a\r\n*2\r\n$4\r\nECHO\r\n$2\r\nhi\r\nINCRBYFLOAT a 1.5\r\nGET a\r\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        test_runtime().block_on(RedisServer::handle_connection(&mut conn, &state)).unwrap();
+
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).unwrap();
+        assert_eq!(resp, "+OK\r\n$1\r\n1\r\n$2\r\nhi\r\n$3\r\n2.5\r\n$3\r\n2.5\r\n");
+    }
+
+    #[test]
+    fn requests_larger_than_a_read_are_accepted_up_to_proto_max_bulk_len() {
+        let state = ServerState::new();
+        let big_val = "v".repeat(100 * CHUNK_SIZE);
+        let (mut client, mut conn) = connection_pair();
+        let writer = std::thread::spawn(move || {
+            let set = format!("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n${}\r\n{}\r\n", big_val.len(), big_val);
+            client.write_all(set.as_bytes()).unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+            client
+        });
+        test_runtime().block_on(RedisServer::handle_connection(&mut conn, &state)).unwrap();
+        let mut resp = String::new();
+        writer.join().unwrap().read_to_string(&mut resp).unwrap();
+        assert_eq!(resp, "+OK\r\n");
+        assert!(matches!(state.lock_db(0).get(b"k".as_slice()), Some(KeyEntry { value: RedisValue::String(val), .. }) if val.len() == 100 * CHUNK_SIZE));
+
+        // Past the limit the request is refused from its header alone
+        state.proto_max_bulk_len.store(CHUNK_SIZE, Ordering::Relaxed);
+        let (mut client, mut conn) = connection_pair();
+        client.write_all(format!("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n${}\r\n", CHUNK_SIZE + 1).as_bytes()).unwrap();
+        test_runtime().block_on(RedisServer::handle_connection(&mut conn, &state)).unwrap();
+        let mut resp = String::new();
+        client.read_to_string(&mut resp).unwrap();
+        assert_eq!(resp, "-ERR Protocol error: invalid bulk length\r\n");
+    }
+}
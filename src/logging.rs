@@ -0,0 +1,199 @@
+// Log output: Redis-style log lines written to stderr or to the logfile, which can be rotated by size or age
+//
+// Each line looks like Redis's, `<pid>:M <day> <month> <year> <time> <level> <message>`, e.g.
+// `4242:M 16 Oct 2026 04:31:14.123 * Ready to accept connections`, with the time in UTC and the level as one of
+// `.` (debug), `-` (verbose), `*` (notice) or `#` (warning).
+//
+// With log-rotate-size or log-rotate-interval set, the logfile is rotated like logrotate does: `redis.log` becomes
+// `redis.log.1`, the previous `redis.log.1` becomes `redis.log.2`, and so on, keeping log-rotate-keep old files.
+//
+// The logger itself lets every record through, and the loglevel is enforced with log's global max level instead, so
+// that CONFIG SET loglevel can change it while the server runs.
+
+use crate::config::{Config, LogLevel};
+use env_logger::Target;
+use log::{Level, LevelFilter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_ROTATE_KEEP: usize = 5;
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+pub fn init(config: &Config) -> anyhow::Result<()> {
+    /* Install the logger for the configured logfile (stderr without one) and loglevel; RUST_LOG, if set, still wins */
+    let mut logger = env_logger::Builder::new();
+    logger.filter_level(LevelFilter::Trace).parse_default_env();
+    logger.format(|buf, record| writeln!(buf, "{}", format_line(record.level(), &record.args().to_string(), SystemTime::now())));
+    if let Some(logfile) = &config.logfile {
+        let file = RotatingFile::open(logfile, config.log_rotate_size, config.log_rotate_interval.map(Duration::from_secs), config.log_rotate_keep)
+            .map_err(|err| anyhow::anyhow!("Failed to open logfile {}: {}", logfile.display(), err))?;
+        logger.target(Target::Pipe(Box::new(file)));
+    }
+    let logger = logger.build();
+    let max_level = match std::env::var_os("RUST_LOG") {
+        Some(_) => logger.filter(),
+        None => config.loglevel.filter(),
+    };
+    log::set_boxed_logger(Box::new(logger))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+pub fn set_loglevel(loglevel: LogLevel) {
+    /* Change which records get logged from now on, e.g. for CONFIG SET loglevel */
+    log::set_max_level(loglevel.filter());
+}
+
+pub fn loglevel() -> LogLevel {
+    /* The loglevel records are logged at, which RUST_LOG may have made something in between redis.conf's levels */
+    match log::max_level() {
+        LevelFilter::Trace => LogLevel::Debug,
+        LevelFilter::Debug => LogLevel::Verbose,
+        LevelFilter::Info => LogLevel::Notice,
+        LevelFilter::Warn | LevelFilter::Error => LogLevel::Warning,
+        LevelFilter::Off => LogLevel::Nothing,
+    }
+}
+
+fn format_line(level: Level, message: &str, time: SystemTime) -> String {
+    let level_char = match level {
+        Level::Trace => '.',
+        Level::Debug => '-',
+        Level::Info => '*',
+        Level::Warn | Level::Error => '#',
+    };
+    format!("{}:M {} {} {}", std::process::id(), format_timestamp(time), level_char, message)
+}
+
+fn format_timestamp(time: SystemTime) -> String {
+    /* E.g. `16 Oct 2026 04:31:14.123`, in UTC */
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let secs_of_day = secs % 86_400;
+    format!(
+        "{} {} {} {:02}:{:02}:{:02}.{:03}",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    /* The (year, month, day) of a day counted from 1970-01-01, from Howard Hinnant's chrono-compatible algorithms */
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// The logfile, appended to until it's due for rotation
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    // Bytes in the current file, including those it had when it was opened
+    len: u64,
+    opened: Instant,
+    // Rotate once the file reaches this many bytes (0: never) or has been open for this long
+    max_size: u64,
+    interval: Option<Duration>,
+    // How many rotated files to keep, e.g. `redis.log.1` to `redis.log.5`
+    keep: usize,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, interval: Option<Duration>, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let len = file.metadata()?.len();
+        Ok(RotatingFile { path: path.to_path_buf(), file, len, opened: Instant::now(), max_size, interval, keep })
+    }
+
+    fn rotated_path(&self, idx: usize) -> PathBuf {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", idx));
+        PathBuf::from(rotated)
+    }
+
+    fn is_due(&self) -> bool {
+        (self.max_size > 0 && self.len >= self.max_size) || self.interval.is_some_and(|interval| self.opened.elapsed() >= interval)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        /* Shift the rotated files up by one, dropping the oldest, and start a new logfile */
+        for idx in (1..self.keep).rev() {
+            let rotated = self.rotated_path(idx);
+            if rotated.exists() {
+                fs::rename(&rotated, self.rotated_path(idx + 1))?;
+            }
+        }
+        match self.keep {
+            0 => fs::remove_file(&self.path)?,
+            _ => fs::rename(&self.path, self.rotated_path(1))?,
+        }
+        *self = RotatingFile::open(&self.path, self.max_size, self.interval, self.keep)?;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A line that can't go in a rotated file still goes in the current one rather than being lost, and rotating is
+        // tried again once it's due again
+        if self.is_due() {
+            if let Err(err) = self.rotate() {
+                (self.len, self.opened) = (0, Instant::now());
+                eprintln!("Failed to rotate logfile {}: {}", self.path.display(), err);
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_lines_look_like_redis_ones() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_792_125_074_123);
+        assert_eq!(format_timestamp(time), "16 Oct 2026 04:31:14.123");
+        assert_eq!(format_timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400)), "29 Feb 2000 00:00:00.000");
+        assert_eq!(format_line(Level::Info, "Ready", time), format!("{}:M 16 Oct 2026 04:31:14.123 * Ready", std::process::id()));
+        assert!(format_line(Level::Error, "Failed", time).ends_with(" # Failed"));
+    }
+
+    #[test]
+    fn logfile_rotates_by_size_keeping_the_latest_files() {
+        let path = std::env::temp_dir().join(format!("rotate-test-{}.log", std::process::id()));
+        let mut file = RotatingFile::open(&path, 10, None, 2).unwrap();
+        for line in ["first line\n", "second line\n", "third line\n", "fourth line\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth line\n");
+        assert_eq!(fs::read_to_string(file.rotated_path(1)).unwrap(), "third line\n");
+        assert_eq!(fs::read_to_string(file.rotated_path(2)).unwrap(), "second line\n");
+        assert!(!file.rotated_path(3).exists());
+        for idx in 1..=2 {
+            fs::remove_file(file.rotated_path(idx)).unwrap();
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
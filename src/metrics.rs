@@ -0,0 +1,198 @@
+// Server-wide counters, shared by INFO-style introspection and the Prometheus scrape endpoint.
+// Each server's state holds its own, so that servers embedded in the same process don't mix their counters.
+use log::{debug, error};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// How long a scraper gets to send its request and read the response before its connection is dropped
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CommandStats {
+    pub calls: u64,
+    // Total time spent running the command, in microseconds
+    pub usec: u64,
+}
+
+// Counters of one command, bumped without any locking by whichever connection runs it
+#[derive(Default)]
+struct CommandCounters {
+    calls: AtomicU64,
+    usec: AtomicU64,
+}
+
+pub struct ServerStats {
+    // Keyed by lowercase command name; every command gets its counters up front, so recording a call is lock-free
+    commands_processed: HashMap<&'static str, CommandCounters>,
+    pub total_commands_processed: AtomicU64,
+    pub connected_clients: AtomicU64,
+    pub total_connections_received: AtomicU64,
+    // Connections closed right away because maxclients was reached
+    pub rejected_connections: AtomicU64,
+    // Clients waiting in a blocking command (e.g. BLPOP) right now
+    pub blocked_clients: AtomicU64,
+    pub expired_keys: AtomicU64,
+    pub evicted_keys: AtomicU64,
+    // Key lookups by read commands that found the key, and those that didn't
+    pub keyspace_hits: AtomicU64,
+    pub keyspace_misses: AtomicU64,
+}
+
+impl ServerStats {
+    pub fn new(cmd_names: impl IntoIterator<Item = &'static str>) -> Self {
+        ServerStats {
+            commands_processed: cmd_names.into_iter().map(|cmd_name| (cmd_name, CommandCounters::default())).collect(),
+            total_commands_processed: AtomicU64::new(0),
+            connected_clients: AtomicU64::new(0),
+            total_connections_received: AtomicU64::new(0),
+            rejected_connections: AtomicU64::new(0),
+            blocked_clients: AtomicU64::new(0),
+            expired_keys: AtomicU64::new(0),
+            evicted_keys: AtomicU64::new(0),
+            keyspace_hits: AtomicU64::new(0),
+            keyspace_misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_command(&self, cmd_name: &'static str, duration: Duration) {
+        /* Count a call of a command given to new; the calls of any other are only counted in the total */
+        if let Some(counters) = self.commands_processed.get(cmd_name) {
+            counters.calls.fetch_add(1, Ordering::Relaxed);
+            counters.usec.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        }
+        self.total_commands_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn command_stats(&self) -> BTreeMap<&'static str, CommandStats> {
+        /* The counters of every command that was called, like INFO commandstats lists them */
+        self.commands_processed.iter()
+            .map(|(cmd_name, counters)| {
+                (*cmd_name, CommandStats { calls: counters.calls.load(Ordering::Relaxed), usec: counters.usec.load(Ordering::Relaxed) })
+            })
+            .filter(|(_, cmd_stats)| cmd_stats.calls > 0)
+            .collect()
+    }
+
+    pub fn reset(&self) {
+        /* Zero the counters, for CONFIG RESETSTAT; gauges such as connected_clients are left as they are */
+        for counters in self.commands_processed.values() {
+            counters.calls.store(0, Ordering::Relaxed);
+            counters.usec.store(0, Ordering::Relaxed);
+        }
+        for counter in [
+            &self.total_commands_processed,
+            &self.total_connections_received,
+            &self.rejected_connections,
+            &self.expired_keys,
+            &self.evicted_keys,
+            &self.keyspace_hits,
+            &self.keyspace_misses,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn render_prometheus(&self, num_keys: usize) -> String {
+        /* Render the counters in the Prometheus text exposition format: https://prometheus.io/docs/instrumenting/exposition_formats/ */
+        let mut body = String::new();
+        body.push_str("# HELP redis_commands_processed_total Number of commands processed, by command.\n");
+        body.push_str("# TYPE redis_commands_processed_total counter\n");
+        for (cmd_name, cmd_stats) in self.command_stats() {
+            // writeln! into a String can't fail
+            writeln!(body, "redis_commands_processed_total{{cmd=\"{}\"}} {}", cmd_name, cmd_stats.calls).unwrap();
+        }
+        let metrics = [
+            ("redis_connected_clients", "gauge", "Number of client connections.", self.connected_clients.load(Ordering::Relaxed)),
+            ("redis_keyspace_keys", "gauge", "Number of keys in the keyspace.", num_keys as u64),
+            ("redis_evicted_keys_total", "counter", "Number of keys evicted due to the memory limit.", self.evicted_keys.load(Ordering::Relaxed)),
+            ("redis_expired_keys_total", "counter", "Number of keys removed because their TTL elapsed.", self.expired_keys.load(Ordering::Relaxed)),
+        ];
+        for (name, metric_type, help, val) in metrics {
+            writeln!(body, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, metric_type, name, val).unwrap();
+        }
+        body
+    }
+}
+
+async fn handle_scrape(stream: &mut TcpStream, render: &(dyn Fn() -> String + Send + Sync)) -> std::io::Result<()> {
+    /* Reply to a single HTTP request; only GET /metrics is served */
+    let mut read_buffer = [0; 1024];
+    let num_bytes_read = stream.read(&mut read_buffer).await?;
+    let request = String::from_utf8_lossy(&read_buffer[..num_bytes_read]);
+    debug!("Metrics request: {:?}", request);
+    let response = if request.starts_with("GET /metrics ") {
+        let body = render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await
+}
+
+pub async fn serve(listener: TcpListener, render: impl Fn() -> String + Send + Sync + 'static) {
+    /*
+    Answer Prometheus scrapes on the listener, rendering the metrics afresh for each one, until the task is aborted
+    It's separate from the Redis port so scraping never interferes with the RESP protocol. Each scrape is served by
+    its own task, within SCRAPE_TIMEOUT, so a slow or stuck scraper doesn't hold up the others.
+    */
+    let render = Arc::new(render);
+    loop {
+        match listener.accept().await {
+            Ok((mut stream, _)) => {
+                let render = Arc::clone(&render);
+                tokio::spawn(async move {
+                    match tokio::time::timeout(SCRAPE_TIMEOUT, handle_scrape(&mut stream, &*render)).await {
+                        Ok(Ok(())) => {},
+                        Ok(Err(err)) => error!("Error while serving metrics: {}", err),
+                        Err(_) => debug!("Metrics scrape timed out"),
+                    }
+                });
+            },
+            Err(err) => error!("Error in accepting metrics connection: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn a_stuck_scraper_does_not_hold_up_the_others() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let stats = Arc::new(ServerStats::new(["get", "set"]));
+            stats.record_command("get", Duration::from_micros(3));
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let serving = tokio::spawn(serve(listener, {
+                let stats = Arc::clone(&stats);
+                move || stats.render_prometheus(7)
+            }));
+            // Connects, but never sends its request
+            let _stuck = TcpStream::connect(addr).await.unwrap();
+            let start = Instant::now();
+            let mut scraper = TcpStream::connect(addr).await.unwrap();
+            scraper.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").await.unwrap();
+            let mut response = String::new();
+            scraper.read_to_string(&mut response).await.unwrap();
+            assert!(start.elapsed() < SCRAPE_TIMEOUT);
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.contains("redis_commands_processed_total{cmd=\"get\"} 1\n"));
+            // Commands that were never called aren't listed
+            assert!(!response.contains("cmd=\"set\""));
+            assert!(response.contains("redis_keyspace_keys 7\n"));
+            serving.abort();
+        });
+    }
+}
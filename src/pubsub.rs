@@ -0,0 +1,279 @@
+// Pub/Sub: PUBLISH fans a message out to every connection subscribed to the channel.
+// Once a connection subscribes, all of its output goes through a bounded queue drained by its own writer task,
+// so replies and messages reach the client in the order they were queued and a slow subscriber never blocks
+// PUBLISH. Like Redis's `client-output-buffer-limit pubsub`, a queue that would grow past its limit either
+// disconnects the subscriber or (unlike Redis) drops its oldest pending output.
+// PSUBSCRIBE subscribes to every channel matching a glob-style pattern; those subscribers get `pmessage`s instead.
+use crate::glob::glob_match;
+use crate::resp::RespValue;
+use log::{debug, warn};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU8, Ordering};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::Notify;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    Disconnect,
+    DropOldest,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutputBufferLimit {
+    // Most bytes of output that can be pending for a subscriber
+    pub max_bytes: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for OutputBufferLimit {
+    fn default() -> Self {
+        // Same hard limit as redis.conf
+        OutputBufferLimit { max_bytes: 32 * 1024 * 1024, overflow: OverflowPolicy::Disconnect }
+    }
+}
+
+#[derive(Default)]
+struct PendingOutput {
+    queue: VecDeque<Vec<u8>>,
+    num_bytes: usize,
+    // Whether the writer task is in the middle of writing output it has taken off the queue
+    writing: bool,
+    closed: bool,
+    // Set when the subscriber was disconnected for being too slow, or its socket failed
+    killed: bool,
+}
+
+pub struct Subscriber {
+    pending: Mutex<PendingOutput>,
+    // Notified whenever output is queued or written, or the subscriber is closed or killed
+    changed: Notify,
+    limit: OutputBufferLimit,
+    // RESP version of the subscriber's connection, which decides how messages are encoded; HELLO can change it
+    pub protocol: AtomicU8,
+}
+
+impl Subscriber {
+    pub fn new(limit: OutputBufferLimit, protocol: u8) -> Self {
+        Subscriber { pending: Mutex::new(PendingOutput::default()), changed: Notify::new(), limit, protocol: AtomicU8::new(protocol) }
+    }
+
+    fn lock_pending(&self) -> MutexGuard<'_, PendingOutput> {
+        self.pending.lock().unwrap_or_else(|err| {
+            panic!("Failed to lock subscriber output mutex: {}!", err);
+        })
+    }
+
+    fn kill(&self, pending: &mut PendingOutput) {
+        // Wakes up the writer task even mid-write, and the connection's own read loop (see killed) so it can clean up
+        pending.queue.clear();
+        pending.num_bytes = 0;
+        pending.closed = true;
+        pending.killed = true;
+        self.changed.notify_waiters();
+    }
+
+    async fn wait_until<T>(&self, mut ready: impl FnMut(&mut PendingOutput) -> Option<T>) -> T {
+        /* Wait for ready to return something, checking it again every time the pending output changes */
+        loop {
+            // Created before checking so that a change made in between still wakes it up
+            let changed = self.changed.notified();
+            if let Some(result) = ready(&mut self.lock_pending()) {
+                return result;
+            }
+            changed.await;
+        }
+    }
+
+    pub async fn killed(&self) {
+        /* Wait until the subscriber is disconnected, for being too slow or because its socket failed */
+        self.wait_until(|pending| pending.killed.then_some(())).await
+    }
+
+    pub fn send(&self, output: Vec<u8>) -> bool {
+        /*
+        Queue output for the writer thread without waiting for it to be written
+        If that would take the queue past its limit, apply the overflow policy. Returns false once the subscriber
+        has been disconnected.
+        */
+        let mut pending = self.lock_pending();
+        if pending.closed {
+            return false;
+        }
+        if pending.num_bytes + output.len() > self.limit.max_bytes {
+            match self.limit.overflow {
+                OverflowPolicy::Disconnect => {
+                    warn!("Disconnecting subscriber over its output buffer limit of {} bytes", self.limit.max_bytes);
+                    self.kill(&mut pending);
+                    return false;
+                },
+                OverflowPolicy::DropOldest => {
+                    while pending.num_bytes + output.len() > self.limit.max_bytes {
+                        match pending.queue.pop_front() {
+                            Some(oldest) => pending.num_bytes -= oldest.len(),
+                            None => break,
+                        }
+                    }
+                },
+            }
+        }
+        pending.num_bytes += output.len();
+        pending.queue.push_back(output);
+        self.changed.notify_waiters();
+        true
+    }
+
+    pub fn take_queued(&self) -> Vec<u8> {
+        /* Take all the queued output at once, for a connection that writes it itself instead of having a writer task */
+        let mut pending = self.lock_pending();
+        pending.num_bytes = 0;
+        let output = pending.queue.drain(..).flatten().collect();
+        self.changed.notify_waiters();
+        output
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.lock_pending().killed
+    }
+
+    pub async fn flush(&self) -> bool {
+        /* Wait until all queued output has been written, returning false if the subscriber was disconnected */
+        self.wait_until(|pending| (pending.queue.is_empty() && !pending.writing || pending.closed).then_some(())).await;
+        !self.lock_pending().killed
+    }
+
+    pub fn close(&self) {
+        /* Stop accepting output; the writer task exits once it has written what's already queued */
+        let mut pending = self.lock_pending();
+        pending.closed = true;
+        self.changed.notify_waiters();
+    }
+
+    async fn next_output(&self) -> Option<Vec<u8>> {
+        /* Wait until there's output to write (marking the previous output as written), or None once closed and drained */
+        {
+            let mut pending = self.lock_pending();
+            pending.writing = false;
+            self.changed.notify_waiters();
+        }
+        self.wait_until(|pending| match pending.queue.pop_front() {
+            Some(output) => {
+                pending.num_bytes -= output.len();
+                pending.writing = true;
+                Some(Some(output))
+            },
+            None => pending.closed.then_some(None),
+        })
+        .await
+    }
+}
+
+pub fn spawn_writer(subscriber: Arc<Subscriber>, mut stream: impl AsyncWrite + Unpin + Send + 'static) {
+    /*
+    Write the subscriber's queued output to its socket in order, in a task of its own
+    A write to a client that has stopped reading is abandoned as soon as the subscriber is killed.
+    */
+    tokio::spawn(async move {
+        while let Some(output) = subscriber.next_output().await {
+            tokio::select! {
+                written = stream.write_all(&output) => if let Err(err) = written {
+                    debug!("Error writing to subscriber: {}", err);
+                    subscriber.kill(&mut subscriber.lock_pending());
+                },
+                _ = subscriber.killed() => break,
+            }
+        }
+    });
+}
+
+fn encode_message(protocol: u8, kind: &str, parts: &[&[u8]]) -> Vec<u8> {
+    /* ["message", channel, message] or ["pmessage", pattern, channel, message], as a push in RESP3 and an array in RESP2 */
+    let elems = std::iter::once(kind.as_bytes())
+        .chain(parts.iter().copied())
+        .map(|part| RespValue::BulkString(part.to_vec()))
+        .collect();
+    RespValue::Push(elems).serialize(protocol)
+}
+
+// Channel (or pattern) -> subscribers, keyed by client ID
+pub type Channels = HashMap<Vec<u8>, HashMap<u64, Arc<Subscriber>>>;
+
+pub struct PubSub {
+    channels: Mutex<Channels>,
+    // Always locked after channels when both are needed
+    patterns: Mutex<Channels>,
+    pub limit: OutputBufferLimit,
+}
+
+impl PubSub {
+    pub fn new(limit: OutputBufferLimit) -> Self {
+        PubSub { channels: Mutex::new(HashMap::new()), patterns: Mutex::new(HashMap::new()), limit }
+    }
+
+    pub fn lock_channels(&self) -> MutexGuard<'_, Channels> {
+        self.channels.lock().unwrap_or_else(|err| {
+            panic!("Failed to lock pubsub channels mutex: {}!", err);
+        })
+    }
+
+    pub fn lock_patterns(&self) -> MutexGuard<'_, Channels> {
+        self.patterns.lock().unwrap_or_else(|err| {
+            panic!("Failed to lock pubsub patterns mutex: {}!", err);
+        })
+    }
+
+    pub fn publish(&self, channel: &[u8], message: &[u8]) -> usize {
+        /*
+        Queue message for every subscriber of channel and every subscriber of a pattern matching it, returning how
+        many received it (a client subscribed to both the channel and a pattern counts twice, like in Redis)
+        */
+        let mut channels = self.lock_channels();
+        let mut num_receivers = 0;
+        if let Some(subscribers) = channels.get_mut(channel) {
+            // Disconnected subscribers are dropped here rather than waiting for their connection to clean up
+            subscribers.retain(|_, subscriber| {
+                subscriber.send(encode_message(subscriber.protocol.load(Ordering::Relaxed), "message", &[channel, message]))
+            });
+            num_receivers += subscribers.len();
+            if subscribers.is_empty() {
+                channels.remove(channel);
+            }
+        }
+        let mut patterns = self.lock_patterns();
+        patterns.retain(|pattern, subscribers| {
+            if glob_match(pattern, channel) {
+                subscribers.retain(|_, subscriber| {
+                    subscriber.send(encode_message(subscriber.protocol.load(Ordering::Relaxed), "pmessage", &[pattern, channel, message]))
+                });
+                num_receivers += subscribers.len();
+            }
+            !subscribers.is_empty()
+        });
+        num_receivers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_queue_drops_oldest_output_or_disconnects() {
+        let drop_oldest = Subscriber::new(OutputBufferLimit { max_bytes: 4, overflow: OverflowPolicy::DropOldest }, 2);
+        for output in [b"ab", b"cd", b"ef"] {
+            assert!(drop_oldest.send(output.to_vec()));
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        assert_eq!(runtime.block_on(drop_oldest.next_output()), Some(b"cd".to_vec()));
+        assert_eq!(runtime.block_on(drop_oldest.next_output()), Some(b"ef".to_vec()));
+
+        let disconnect = Subscriber::new(OutputBufferLimit { max_bytes: 4, overflow: OverflowPolicy::Disconnect }, 2);
+        assert!(disconnect.send(b"ab".to_vec()));
+        assert!(disconnect.send(b"cd".to_vec()));
+        assert!(!disconnect.send(b"ef".to_vec()));
+        assert!(!disconnect.send(b"gh".to_vec()));
+        assert_eq!(runtime.block_on(disconnect.next_output()), None);
+        assert!(!runtime.block_on(disconnect.flush()));
+        runtime.block_on(disconnect.killed());
+    }
+}
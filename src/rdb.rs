@@ -0,0 +1,333 @@
+// Snapshots of the keyspace in Redis's RDB format: https://rdb.fnordig.de/file_format.html
+// Strings, lists, sets, hashes and sorted sets are saved with the plain RDB value types; the compact
+// listpack/intset encodings aren't saved, since they're rebuilt from the encoding limits when loading.
+// HyperLogLogs are saved as strings in Redis's dense HLL format, like Redis does. Streams use a value type of our
+// own rather than Redis's listpack-based one, so snapshots with streams can only be loaded by this server. The same
+// goes for hashes with field TTLs, which have their own type that saves each field's expiry after its value.
+use crate::collections::{EncodingLimits, RedisHash, RedisList, RedisSet};
+use crate::hyperloglog::HyperLogLog;
+use crate::sorted_set::SortedSet;
+use crate::streams::{RedisStream, StreamFields, StreamId};
+use crate::{CacheMap, KeyEntry, RedisValue};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Bound;
+use std::path::Path;
+use thiserror::Error;
+
+const RDB_MAGIC: &[u8] = b"REDIS0011";
+const RDB_OPCODE_AUX: u8 = 0xFA;
+const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const RDB_OPCODE_EXPIRETIME: u8 = 0xFD;
+const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+const RDB_OPCODE_EOF: u8 = 0xFF;
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_ZSET_2: u8 = 5;
+// Not a Redis type (see above)
+const RDB_TYPE_STREAM: u8 = 0xF0;
+const RDB_TYPE_HASH_WITH_TTLS: u8 = 0xF1;
+
+#[derive(Debug, Error)]
+pub enum RdbError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("Not an RDB file")]
+    BadMagic,
+    #[error("Unexpected end of file")]
+    UnexpectedEof,
+    #[error("Unsupported value type or opcode: {0}")]
+    UnsupportedType(u8),
+    #[error("Unsupported string encoding: {0}")]
+    UnsupportedEncoding(u8),
+}
+
+fn write_len(buf: &mut Vec<u8>, len: u64) {
+    /* RDB length encoding: the top 2 bits of the first byte say whether it takes 6 bits, 14 bits, 32 bits or 64 bits */
+    if len < 1 << 6 {
+        buf.push(len as u8);
+    } else if len < 1 << 14 {
+        buf.extend_from_slice(&[0x40 | (len >> 8) as u8, len as u8]);
+    } else if len <= u32::MAX as u64 {
+        buf.push(0x80);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        buf.push(0x81);
+        buf.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, string: &[u8]) {
+    write_len(buf, string.len() as u64);
+    buf.extend_from_slice(string);
+}
+
+fn write_value(buf: &mut Vec<u8>, key: &[u8], val: &RedisValue) {
+    /* Write the value type, key and value */
+    let value_type = match val {
+        RedisValue::String(_) | RedisValue::HyperLogLog(_) => RDB_TYPE_STRING,
+        RedisValue::List(_) => RDB_TYPE_LIST,
+        RedisValue::Set(_) => RDB_TYPE_SET,
+        RedisValue::Hash(hash) if hash.has_field_expiries() => RDB_TYPE_HASH_WITH_TTLS,
+        RedisValue::Hash(_) => RDB_TYPE_HASH,
+        RedisValue::SortedSet(_) => RDB_TYPE_ZSET_2,
+        RedisValue::Stream(_) => RDB_TYPE_STREAM,
+    };
+    buf.push(value_type);
+    write_string(buf, key);
+    match val {
+        RedisValue::String(string) => write_string(buf, string),
+        RedisValue::HyperLogLog(hll) => write_string(buf, &hll.to_dense()),
+        RedisValue::List(list) => {
+            write_len(buf, list.len() as u64);
+            list.iter().for_each(|elem| write_string(buf, elem));
+        },
+        RedisValue::Set(set) => {
+            write_len(buf, set.len() as u64);
+            set.members().iter().for_each(|member| write_string(buf, member));
+        },
+        RedisValue::Hash(hash) => {
+            write_len(buf, hash.len() as u64);
+            for (field, val) in hash.iter() {
+                write_string(buf, field);
+                write_string(buf, val);
+                if hash.has_field_expiries() {
+                    // 0 for fields without a TTL
+                    write_len(buf, hash.field_expiry(field).unwrap_or(0) as u64);
+                }
+            }
+        },
+        RedisValue::SortedSet(zset) => {
+            write_len(buf, zset.len() as u64);
+            for (member, score) in zset.iter() {
+                write_string(buf, member);
+                buf.extend_from_slice(&score.to_le_bytes());
+            }
+        },
+        RedisValue::Stream(stream) => {
+            let last_id = stream.last_id();
+            write_len(buf, last_id.ms);
+            write_len(buf, last_id.seq);
+            let entries = stream.range(Bound::Unbounded, Bound::Unbounded, None);
+            write_len(buf, entries.len() as u64);
+            for (id, fields) in entries {
+                write_len(buf, id.ms);
+                write_len(buf, id.seq);
+                write_len(buf, fields.len() as u64);
+                for (field, val) in fields {
+                    write_string(buf, field);
+                    write_string(buf, val);
+                }
+            }
+        },
+    }
+}
+
+pub fn dump<'a, D>(dbs: impl IntoIterator<Item = D>) -> Vec<u8>
+where
+    D: IntoIterator<Item = (&'a Bytes, &'a KeyEntry)>,
+{
+    /*
+    Serialize every database (numbered in order), including keys that have expired but haven't been removed yet
+    Like Redis, empty databases are left out rather than given a SELECTDB of their own.
+    */
+    let mut buf = RDB_MAGIC.to_vec();
+    for (db, cache) in dbs.into_iter().enumerate() {
+        let mut cache = cache.into_iter().peekable();
+        if cache.peek().is_none() {
+            continue;
+        }
+        buf.push(RDB_OPCODE_SELECTDB);
+        write_len(&mut buf, db as u64);
+        for (key, entry) in cache {
+            if let Some(expiry_ts) = entry.expiry_ts {
+                buf.push(RDB_OPCODE_EXPIRETIME_MS);
+                buf.extend_from_slice(&(expiry_ts as u64).to_le_bytes());
+            }
+            write_value(&mut buf, key, &entry.value);
+        }
+    }
+    buf.push(RDB_OPCODE_EOF);
+    // A zero checksum tells readers (including Redis) that checksums are disabled
+    buf.extend_from_slice(&[0; 8]);
+    buf
+}
+
+struct RdbReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> RdbReader<'a> {
+    fn read_bytes(&mut self, num_bytes: usize) -> Result<&'a [u8], RdbError> {
+        let end = self.pos.checked_add(num_bytes).ok_or(RdbError::UnexpectedEof)?;
+        let bytes = self.bytes.get(self.pos..end).ok_or(RdbError::UnexpectedEof)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, RdbError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        // read_bytes returns exactly N bytes, so the conversion can't fail
+        Ok(self.read_bytes(N)?.try_into().unwrap())
+    }
+
+    fn read_len_or_encoding(&mut self) -> Result<(u64, bool), RdbError> {
+        /* Read a length, or for the special string encodings (top bits 11), the encoding type and true */
+        let first = self.read_u8()?;
+        match first >> 6 {
+            0 => Ok(((first & 0x3F) as u64, false)),
+            1 => Ok(((((first & 0x3F) as u64) << 8) | self.read_u8()? as u64, false)),
+            2 if first == 0x80 => Ok((u32::from_be_bytes(self.read_array()?) as u64, false)),
+            2 if first == 0x81 => Ok((u64::from_be_bytes(self.read_array()?), false)),
+            3 => Ok(((first & 0x3F) as u64, true)),
+            _ => Err(RdbError::UnsupportedEncoding(first)),
+        }
+    }
+
+    fn read_len(&mut self) -> Result<u64, RdbError> {
+        match self.read_len_or_encoding()? {
+            (len, false) => Ok(len),
+            (encoding, true) => Err(RdbError::UnsupportedEncoding(encoding as u8)),
+        }
+    }
+
+    fn read_string(&mut self) -> Result<Vec<u8>, RdbError> {
+        /* Read a length-prefixed string; Redis also saves integer-like strings as integers, which are supported too */
+        match self.read_len_or_encoding()? {
+            (len, false) => Ok(self.read_bytes(len as usize)?.to_vec()),
+            (0, true) => Ok((self.read_u8()? as i8).to_string().into_bytes()),
+            (1, true) => Ok(i16::from_le_bytes(self.read_array()?).to_string().into_bytes()),
+            (2, true) => Ok(i32::from_le_bytes(self.read_array()?).to_string().into_bytes()),
+            // LZF-compressed strings
+            (encoding, true) => Err(RdbError::UnsupportedEncoding(encoding as u8)),
+        }
+    }
+
+    fn read_value(&mut self, value_type: u8, limits: &EncodingLimits) -> Result<RedisValue, RdbError> {
+        let val = match value_type {
+            RDB_TYPE_STRING => {
+                let string = self.read_string()?;
+                match HyperLogLog::from_dense(&string) {
+                    Some(hll) => RedisValue::HyperLogLog(hll),
+                    None => RedisValue::String(Bytes::from(string)),
+                }
+            },
+            RDB_TYPE_LIST => {
+                let mut list = RedisList::new();
+                for _ in 0..self.read_len()? {
+                    list.push_back(self.read_string()?, limits);
+                }
+                RedisValue::List(list)
+            },
+            RDB_TYPE_SET => {
+                let mut set = RedisSet::new();
+                for _ in 0..self.read_len()? {
+                    set.insert(self.read_string()?, limits);
+                }
+                RedisValue::Set(set)
+            },
+            RDB_TYPE_HASH => {
+                let mut hash = RedisHash::new();
+                for _ in 0..self.read_len()? {
+                    hash.insert(self.read_string()?, self.read_string()?, limits);
+                }
+                RedisValue::Hash(hash)
+            },
+            RDB_TYPE_HASH_WITH_TTLS => {
+                // Fields that have since expired are loaded anyway and then expired like any other field
+                let mut hash = RedisHash::new();
+                for _ in 0..self.read_len()? {
+                    let field = self.read_string()?;
+                    hash.insert(field.clone(), self.read_string()?, limits);
+                    match self.read_len()? {
+                        0 => {},
+                        expiry_ts => hash.set_field_expiry(&field, expiry_ts as u128),
+                    }
+                }
+                RedisValue::Hash(hash)
+            },
+            RDB_TYPE_ZSET_2 => {
+                let mut zset = SortedSet::new();
+                for _ in 0..self.read_len()? {
+                    zset.insert(self.read_string()?, f64::from_le_bytes(self.read_array()?));
+                }
+                RedisValue::SortedSet(zset)
+            },
+            RDB_TYPE_STREAM => {
+                let last_id = StreamId { ms: self.read_len()?, seq: self.read_len()? };
+                let mut entries = Vec::new();
+                for _ in 0..self.read_len()? {
+                    let id = StreamId { ms: self.read_len()?, seq: self.read_len()? };
+                    let mut fields: StreamFields = Vec::new();
+                    for _ in 0..self.read_len()? {
+                        fields.push((self.read_string()?, self.read_string()?));
+                    }
+                    entries.push((id, fields));
+                }
+                RedisValue::Stream(RedisStream::from_entries(entries, last_id))
+            },
+            other => return Err(RdbError::UnsupportedType(other)),
+        };
+        Ok(val)
+    }
+}
+
+pub fn load(bytes: &[u8], limits: &EncodingLimits, now_ms: u128) -> Result<HashMap<usize, CacheMap>, RdbError> {
+    /* Deserialize a snapshot into its databases by number, leaving out keys that expired before now_ms */
+    let mut reader = RdbReader { bytes, pos: 0 };
+    if !reader.read_bytes(RDB_MAGIC.len())?.starts_with(b"REDIS") {
+        return Err(RdbError::BadMagic);
+    }
+    let mut dbs = HashMap::new();
+    // Keys before any SELECTDB belong to database 0
+    let mut db = 0;
+    let mut expiry_ts = None;
+    loop {
+        match reader.read_u8()? {
+            RDB_OPCODE_EOF => break,
+            RDB_OPCODE_SELECTDB => db = reader.read_len()? as usize,
+            RDB_OPCODE_RESIZEDB => {
+                reader.read_len()?;
+                reader.read_len()?;
+            },
+            RDB_OPCODE_AUX => {
+                reader.read_string()?;
+                reader.read_string()?;
+            },
+            RDB_OPCODE_EXPIRETIME_MS => expiry_ts = Some(u64::from_le_bytes(reader.read_array()?) as u128),
+            RDB_OPCODE_EXPIRETIME => expiry_ts = Some(u32::from_le_bytes(reader.read_array()?) as u128 * 1000),
+            value_type => {
+                let key = Bytes::from(reader.read_string()?);
+                let val = reader.read_value(value_type, limits)?;
+                if !expiry_ts.is_some_and(|expiry_ts| expiry_ts <= now_ms) {
+                    dbs.entry(db).or_insert_with(CacheMap::new).insert(key, KeyEntry::new(val, expiry_ts));
+                }
+                expiry_ts = None;
+            },
+        }
+    }
+    Ok(dbs)
+}
+
+pub fn save<'a, D>(path: &Path, dbs: impl IntoIterator<Item = D>) -> Result<(), RdbError>
+where
+    D: IntoIterator<Item = (&'a Bytes, &'a KeyEntry)>,
+{
+    /* Write a snapshot to a temporary file and rename it over path, so a crash never leaves a partial snapshot */
+    let tmp_path = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+    fs::write(&tmp_path, dump(dbs))?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+pub fn load_file(path: &Path, limits: &EncodingLimits, now_ms: u128) -> Result<HashMap<usize, CacheMap>, RdbError> {
+    load(&fs::read(path)?, limits, now_ms)
+}
@@ -0,0 +1,502 @@
+// Incremental parser for the RESP protocol: https://redis.io/docs/reference/protocol-spec
+// Everything is parsed as bytes, and bulk strings are read by their declared length, so they can contain any bytes
+// (including \r\n). A buffer that ends partway through a frame isn't an error: the parsers return None, and the
+// caller can try again once more bytes have arrived.
+use bytes::Bytes;
+use thiserror::Error;
+
+const DELIMITER: &[u8] = b"\r\n";
+// Same limits as Redis: an inline command (or a multibulk header) must fit in 64KB, and a command can have at most
+// 1024*1024 arguments
+const INLINE_MAX_SIZE: usize = 64 * 1024;
+const MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+
+// What was parsed and how many bytes it took up, or None if the buffer ends before it does
+type Parsed<T> = Option<(T, usize)>;
+
+// Malformed client input; the connection is closed after the error is sent, since we can't resync with the client
+#[derive(Debug, Error, PartialEq)]
+pub enum ProtocolError {
+    #[error("invalid multibulk length")]
+    InvalidMultibulkLength,
+    #[error("expected '$', got '{0}'")]
+    ExpectedBulkString(char),
+    #[error("invalid bulk length")]
+    InvalidBulkLength,
+    #[error("bulk string payload does not match its declared length")]
+    BulkLengthMismatch,
+    #[error("invalid integer")]
+    InvalidInteger,
+    #[error("unknown frame type '{0}'")]
+    UnknownFrameType(char),
+    #[error("too big inline request")]
+    InlineTooBig,
+    #[error("unbalanced quotes in request")]
+    UnbalancedQuotes,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    SimpleString(Vec<u8>),
+    Error(Vec<u8>),
+    Integer(i64),
+    BulkString(Vec<u8>),
+    Array(Vec<Frame>),
+    // RESP2's null bulk string ($-1) and null array (*-1)
+    Null,
+}
+
+// A reply to a client. serialize picks the encoding for the connection's protocol, so the RESP3-only types
+// (maps, sets, pushes, doubles, booleans and the single null) fall back to their RESP2 equivalents.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RespValue {
+    SimpleString(Vec<u8>),
+    // Without the leading `-`, e.g. "ERR syntax error"
+    Error(String),
+    Integer(i64),
+    BulkString(Vec<u8>),
+    Array(Vec<RespValue>),
+    // A missing value, e.g. GET of a missing key: $-1 in RESP2
+    Null,
+    // A missing array, e.g. a blocking pop that timed out: *-1 in RESP2
+    NullArray,
+    // A flat array of alternating keys and values in RESP2
+    Map(Vec<(RespValue, RespValue)>),
+    Set(Vec<RespValue>),
+    // Out-of-band data such as Pub/Sub messages
+    Push(Vec<RespValue>),
+    // A bulk string of the number in RESP2, e.g. sorted set scores
+    Double(f64),
+    // :1 or :0 in RESP2
+    Boolean(bool),
+}
+
+impl RespValue {
+    pub fn ok() -> Self {
+        RespValue::SimpleString(b"OK".to_vec())
+    }
+
+    pub fn bulk_strings(elems: &[Vec<u8>]) -> Self {
+        /* An array of bulk strings, the most common kind of multi-value reply */
+        RespValue::Array(elems.iter().map(|elem| RespValue::BulkString(elem.clone())).collect())
+    }
+
+    pub fn serialize(&self, protocol: u8) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.serialize_into(protocol, &mut out);
+        out
+    }
+
+    fn serialize_into(&self, protocol: u8, out: &mut Vec<u8>) {
+        let resp3 = protocol >= 3;
+        let mut write_header = |type_byte: u8, len: usize| {
+            out.push(type_byte);
+            out.extend(len.to_string().as_bytes());
+            out.extend(DELIMITER);
+        };
+        match self {
+            RespValue::SimpleString(val) => {
+                out.push(b'+');
+                out.extend(val);
+                out.extend(DELIMITER);
+            },
+            RespValue::Error(msg) => {
+                out.push(b'-');
+                out.extend(msg.as_bytes());
+                out.extend(DELIMITER);
+            },
+            RespValue::Integer(int) => {
+                out.push(b':');
+                out.extend(int.to_string().as_bytes());
+                out.extend(DELIMITER);
+            },
+            RespValue::BulkString(val) => {
+                write_header(b'$', val.len());
+                out.extend(val);
+                out.extend(DELIMITER);
+            },
+            RespValue::Double(double) => {
+                let repr = match double {
+                    double if double.is_nan() => "nan".to_string(),
+                    double => double.to_string(),
+                };
+                if resp3 {
+                    out.push(b',');
+                    out.extend(repr.as_bytes());
+                    out.extend(DELIMITER);
+                } else {
+                    RespValue::BulkString(repr.into_bytes()).serialize_into(protocol, out);
+                }
+            },
+            RespValue::Boolean(boolean) if resp3 => out.extend(if *boolean { b"#t\r\n" } else { b"#f\r\n" }),
+            RespValue::Boolean(boolean) => RespValue::Integer(*boolean as i64).serialize_into(protocol, out),
+            RespValue::Null | RespValue::NullArray if resp3 => out.extend(b"_\r\n"),
+            RespValue::Null => out.extend(b"$-1\r\n"),
+            RespValue::NullArray => out.extend(b"*-1\r\n"),
+            RespValue::Map(pairs) => {
+                if resp3 {
+                    write_header(b'%', pairs.len());
+                } else {
+                    write_header(b'*', pairs.len() * 2);
+                }
+                for (key, val) in pairs {
+                    key.serialize_into(protocol, out);
+                    val.serialize_into(protocol, out);
+                }
+            },
+            RespValue::Array(elems) | RespValue::Set(elems) | RespValue::Push(elems) => {
+                let type_byte = match self {
+                    RespValue::Set(_) if resp3 => b'~',
+                    RespValue::Push(_) if resp3 => b'>',
+                    _ => b'*',
+                };
+                write_header(type_byte, elems.len());
+                for elem in elems {
+                    elem.serialize_into(protocol, out);
+                }
+            },
+        }
+    }
+}
+
+fn read_line(buf: &[u8]) -> Option<(&[u8], usize)> {
+    /* The line at the start of buf without its CRLF, along with its length including the CRLF */
+    let line_len = buf.windows(DELIMITER.len()).position(|window| window == DELIMITER)?;
+    Some((&buf[..line_len], line_len + DELIMITER.len()))
+}
+
+fn parse_len(line: &[u8], invalid: ProtocolError) -> Result<Option<usize>, ProtocolError> {
+    /* The length in an array or bulk string header; None for -1, which means null */
+    match std::str::from_utf8(line).ok().and_then(|len| len.parse::<i64>().ok()) {
+        Some(-1) => Ok(None),
+        Some(len) if len >= 0 => Ok(Some(len as usize)),
+        _ => Err(invalid),
+    }
+}
+
+fn parse_bulk_payload(buf: &[u8], header: &[u8], header_len: usize, max_len: usize) -> Result<Parsed<Option<Vec<u8>>>, ProtocolError> {
+    /* The payload of the bulk string at the start of buf, whose `$<len>` header has already been read; None if null */
+    let Some(bulk_len) = parse_len(header, ProtocolError::InvalidBulkLength)? else {
+        return Ok(Some((None, header_len)));
+    };
+    if bulk_len > max_len {
+        return Err(ProtocolError::InvalidBulkLength);
+    }
+    // The payload must be exactly bulk_len bytes, immediately followed by CRLF
+    let payload_end = header_len + bulk_len;
+    if buf.len() < payload_end + DELIMITER.len() {
+        return Ok(None);
+    }
+    if &buf[payload_end..payload_end + DELIMITER.len()] != DELIMITER {
+        return Err(ProtocolError::BulkLengthMismatch);
+    }
+    Ok(Some((Some(buf[header_len..payload_end].to_vec()), payload_end + DELIMITER.len())))
+}
+
+pub fn parse_frame(buf: &[u8]) -> Result<Parsed<Frame>, ProtocolError> {
+    /* Parse the frame at the start of buf, returning it along with the number of bytes it took up */
+    let Some((line, header_len)) = read_line(buf) else {
+        return Ok(None);
+    };
+    let (type_byte, line) = (line.first().copied().unwrap_or(b'\r'), line.get(1..).unwrap_or_default());
+    let frame = match type_byte {
+        b'+' => Frame::SimpleString(line.to_vec()),
+        b'-' => Frame::Error(line.to_vec()),
+        b':' => {
+            let int = std::str::from_utf8(line).ok().and_then(|int| int.parse::<i64>().ok());
+            Frame::Integer(int.ok_or(ProtocolError::InvalidInteger)?)
+        },
+        b'$' => {
+            return Ok(parse_bulk_payload(buf, line, header_len, usize::MAX)?.map(|(bulk, bulk_len)| {
+                (bulk.map_or(Frame::Null, Frame::BulkString), bulk_len)
+            }));
+        },
+        b'*' => {
+            let Some(num_elems) = parse_len(line, ProtocolError::InvalidMultibulkLength)? else {
+                return Ok(Some((Frame::Null, header_len)));
+            };
+            let mut pos = header_len;
+            // Don't trust the declared length for the allocation; the elements may never arrive
+            let mut elems = Vec::with_capacity(num_elems.min(1024));
+            for _ in 0..num_elems {
+                let Some((elem, elem_len)) = parse_frame(&buf[pos..])? else {
+                    return Ok(None);
+                };
+                elems.push(elem);
+                pos += elem_len;
+            }
+            return Ok(Some((Frame::Array(elems), pos)));
+        },
+        other => return Err(ProtocolError::UnknownFrameType(other as char)),
+    };
+    Ok(Some((frame, header_len)))
+}
+
+pub(crate) fn split_inline_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    /*
+    Split an inline command line into arguments like Redis's sdssplitargs, or None if its quotes are unbalanced
+    Arguments are separated by whitespace, and can be "double quoted" (with \n, \r, \t, \b, \a, \\, \" and \xHH
+    escapes) or 'single quoted' (where only \' is an escape). A closing quote must end the argument.
+    */
+    let mut args = Vec::new();
+    let mut pos = 0;
+    loop {
+        while line.get(pos).is_some_and(|byte| byte.is_ascii_whitespace()) {
+            pos += 1;
+        }
+        let Some(&first) = line.get(pos) else {
+            return Some(args);
+        };
+        let mut arg = Vec::new();
+        match first {
+            b'"' => {
+                pos += 1;
+                loop {
+                    match *line.get(pos)? {
+                        b'\\' if line.get(pos + 1) == Some(&b'x') => {
+                            let hex = line.get(pos + 2..pos + 4).and_then(|hex| std::str::from_utf8(hex).ok());
+                            match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                                Some(byte) => {
+                                    arg.push(byte);
+                                    pos += 4;
+                                },
+                                None => {
+                                    arg.push(b'\\');
+                                    pos += 1;
+                                },
+                            }
+                        },
+                        b'\\' if pos + 1 < line.len() => {
+                            arg.push(match line[pos + 1] {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'b' => 0x08,
+                                b'a' => 0x07,
+                                other => other,
+                            });
+                            pos += 2;
+                        },
+                        b'"' => {
+                            pos += 1;
+                            break;
+                        },
+                        byte => {
+                            arg.push(byte);
+                            pos += 1;
+                        },
+                    }
+                }
+            },
+            b'\'' => {
+                pos += 1;
+                loop {
+                    match *line.get(pos)? {
+                        b'\\' if line.get(pos + 1) == Some(&b'\'') => {
+                            arg.push(b'\'');
+                            pos += 2;
+                        },
+                        b'\'' => {
+                            pos += 1;
+                            break;
+                        },
+                        byte => {
+                            arg.push(byte);
+                            pos += 1;
+                        },
+                    }
+                }
+            },
+            _ => {
+                while let Some(&byte) = line.get(pos).filter(|byte| !byte.is_ascii_whitespace()) {
+                    arg.push(byte);
+                    pos += 1;
+                }
+            },
+        }
+        // `"foo"bar` is as malformed as an unterminated quote
+        if matches!(first, b'"' | b'\'') && line.get(pos).is_some_and(|byte| !byte.is_ascii_whitespace()) {
+            return None;
+        }
+        args.push(arg);
+    }
+}
+
+pub(crate) fn quote_arg(arg: &[u8]) -> String {
+    /* Double quote an argument like Redis's sdscatrepr (e.g. for MONITOR), escaping it so split_inline_args gets it back */
+    let mut quoted = String::from("\"");
+    for &byte in arg {
+        match byte {
+            b'\\' | b'"' => {
+                quoted.push('\\');
+                quoted.push(byte as char);
+            },
+            b'\n' => quoted.push_str("\\n"),
+            b'\r' => quoted.push_str("\\r"),
+            b'\t' => quoted.push_str("\\t"),
+            0x07 => quoted.push_str("\\a"),
+            0x08 => quoted.push_str("\\b"),
+            byte if byte.is_ascii_graphic() || byte == b' ' => quoted.push(byte as char),
+            byte => quoted.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn parse_inline_command(buf: &[u8]) -> Result<Parsed<Vec<Bytes>>, ProtocolError> {
+    /*
+    Parse an inline command: a plain whitespace-separated line like `PING\r\n` that isn't wrapped in a RESP array
+    This is what clients such as telnet or nc send. Both `\r\n` and a bare `\n` end the line, and arguments can be
+    quoted as in redis-cli, e.g. `SET greeting "hello world"`.
+    */
+    let Some(line_len) = buf.iter().position(|byte| *byte == b'\n') else {
+        return if buf.len() > INLINE_MAX_SIZE { Err(ProtocolError::InlineTooBig) } else { Ok(None) };
+    };
+    let args = split_inline_args(&buf[..line_len]).ok_or(ProtocolError::UnbalancedQuotes)?;
+    Ok(Some((args.into_iter().map(Bytes::from).collect(), line_len + 1)))
+}
+
+pub fn parse_command(buf: &[u8], max_bulk_len: usize) -> Result<Parsed<Vec<Bytes>>, ProtocolError> {
+    /*
+    Parse the command at the start of buf into its argv, along with the number of bytes it took up
+    Each argument is copied out of buf into a Bytes of its own, so handlers can keep it (e.g. as a key or value) without
+    copying it again, and without pinning the rest of the read buffer.
+    Commands are arrays of bulk strings, e.g. `*2\r\n$3\r\nGET\r\n$5\r\nmykey\r\n` for GET mykey; anything that
+    doesn't start with `*` is treated as an inline command instead. An empty argv is not a command, and like Redis,
+    the caller should ignore it. Arguments longer than max_bulk_len are rejected as soon as their header arrives, so a
+    client can't make us buffer an arbitrarily large one.
+    */
+    if buf.first() != Some(&b'*') {
+        return parse_inline_command(buf);
+    }
+    // Check each element's type before parsing it, so a client can't make us parse arbitrarily nested arrays
+    let Some((line, header_len)) = read_line(buf) else {
+        return if buf.len() > INLINE_MAX_SIZE { Err(ProtocolError::InvalidMultibulkLength) } else { Ok(None) };
+    };
+    let num_elems = match parse_len(&line[1..], ProtocolError::InvalidMultibulkLength)? {
+        Some(num_elems) if num_elems > MAX_MULTIBULK_LEN => return Err(ProtocolError::InvalidMultibulkLength),
+        num_elems => num_elems.unwrap_or(0),
+    };
+    let mut pos = header_len;
+    let mut args = Vec::with_capacity(num_elems.min(1024));
+    for _ in 0..num_elems {
+        match buf.get(pos) {
+            None => return Ok(None),
+            Some(b'$') => {},
+            Some(other) => return Err(ProtocolError::ExpectedBulkString(*other as char)),
+        }
+        let Some((line, arg_header_len)) = read_line(&buf[pos..]) else {
+            return Ok(None);
+        };
+        match parse_bulk_payload(&buf[pos..], &line[1..], arg_header_len, max_bulk_len)? {
+            None => return Ok(None),
+            Some((Some(arg), arg_len)) => {
+                args.push(Bytes::from(arg));
+                pos += arg_len;
+            },
+            Some((None, _)) => return Err(ProtocolError::InvalidBulkLength),
+        }
+    }
+    Ok(Some((args, pos)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_typed_frames_and_waits_for_partial_ones() {
+        let frame = b"*5\r\n+OK\r\n-ERR bad\r\n:-42\r\n$4\r\na\r\nb\r\n*2\r\n$-1\r\n*-1\r\n";
+        let expected = Frame::Array(vec![
+            Frame::SimpleString(b"OK".to_vec()),
+            Frame::Error(b"ERR bad".to_vec()),
+            Frame::Integer(-42),
+            Frame::BulkString(b"a\r\nb".to_vec()),
+            Frame::Array(vec![Frame::Null, Frame::Null]),
+        ]);
+        assert_eq!(parse_frame(frame), Ok(Some((expected, frame.len()))));
+        // Every prefix is incomplete rather than malformed
+        for prefix_len in 0..frame.len() {
+            assert_eq!(parse_frame(&frame[..prefix_len]), Ok(None), "prefix of length {}", prefix_len);
+        }
+        assert_eq!(parse_frame(b"$3\r\nabcd\r\n"), Err(ProtocolError::BulkLengthMismatch));
+        assert_eq!(parse_frame(b"$-2\r\n"), Err(ProtocolError::InvalidBulkLength));
+        assert_eq!(parse_frame(b"?\r\n"), Err(ProtocolError::UnknownFrameType('?')));
+    }
+
+    #[test]
+    fn serializes_for_the_negotiated_protocol() {
+        let reply = RespValue::Array(vec![
+            RespValue::ok(),
+            RespValue::Error("ERR bad".to_string()),
+            RespValue::Integer(-1),
+            RespValue::BulkString(b"a\r\nb".to_vec()),
+            RespValue::Null,
+            RespValue::NullArray,
+            RespValue::Map(vec![(RespValue::BulkString(b"k".to_vec()), RespValue::Integer(1))]),
+            RespValue::Set(vec![RespValue::Integer(2)]),
+            RespValue::Push(vec![]),
+            RespValue::Double(1.5),
+            RespValue::Double(f64::NEG_INFINITY),
+            RespValue::Boolean(true),
+        ]);
+        assert_eq!(
+            reply.serialize(2),
+            b"*12\r\n+OK\r\n-ERR bad\r\n:-1\r\n$4\r\na\r\nb\r\n$-1\r\n*-1\r\n*2\r\n$1\r\nk\r\n:1\r\n*1\r\n:2\r\n*0\r\n$3\r\n1.5\r\n$4\r\n-inf\r\n:1\r\n".to_vec()
+        );
+        assert_eq!(
+            reply.serialize(3),
+            b"*12\r\n+OK\r\n-ERR bad\r\n:-1\r\n$4\r\na\r\nb\r\n_\r\n_\r\n%1\r\n$1\r\nk\r\n:1\r\n~1\r\n:2\r\n>0\r\n,1.5\r\n,-inf\r\n#t\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn parses_binary_safe_and_inline_commands() {
+        let set = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\n\xff\r\n\x00\r\n";
+        assert_eq!(
+            parse_command(set, usize::MAX),
+            Ok(Some((vec![Bytes::from_static(b"SET"), Bytes::from_static(b"k"), Bytes::from_static(b"\xff\r\n\x00")], set.len())))
+        );
+        assert_eq!(parse_command(b"*1\r\n:1\r\n", usize::MAX), Err(ProtocolError::ExpectedBulkString(':')));
+        assert_eq!(parse_command(b"*x\r\n", usize::MAX), Err(ProtocolError::InvalidMultibulkLength));
+        assert_eq!(parse_command(b"*2\r\n$3\r\nGET\r\n", usize::MAX), Ok(None));
+
+        assert_eq!(parse_command(b"PING\r\n", usize::MAX), Ok(Some((vec![Bytes::from_static(b"PING")], 6))));
+        assert_eq!(
+            parse_command(b"SET  mykey myval\nGET mykey\n", usize::MAX),
+            Ok(Some((vec![Bytes::from_static(b"SET"), Bytes::from_static(b"mykey"), Bytes::from_static(b"myval")], 17)))
+        );
+        assert_eq!(parse_command(b"\r\n", usize::MAX), Ok(Some((vec![], 2))));
+        assert_eq!(parse_command(b"PING", usize::MAX), Ok(None));
+    }
+
+    #[test]
+    fn rejects_oversized_arguments_before_they_arrive() {
+        assert_eq!(parse_command(b"*2\r\n$3\r\nGET\r\n$5\r\n", 4), Err(ProtocolError::InvalidBulkLength));
+        assert_eq!(parse_command(b"*2\r\n$3\r\nGET\r\n$4\r\n", 4), Ok(None));
+        assert_eq!(parse_command(b"*2000000\r\n", usize::MAX), Err(ProtocolError::InvalidMultibulkLength));
+        assert_eq!(parse_command(&vec![b'a'; INLINE_MAX_SIZE + 1], usize::MAX), Err(ProtocolError::InlineTooBig));
+        assert_eq!(parse_command(&vec![b'a'; INLINE_MAX_SIZE], usize::MAX), Ok(None));
+    }
+
+    #[test]
+    fn splits_quoted_inline_arguments_like_redis_cli() {
+        assert_eq!(
+            parse_command(b"SET k \"hello world\"\r\n", usize::MAX),
+            Ok(Some((vec![Bytes::from_static(b"SET"), Bytes::from_static(b"k"), Bytes::from_static(b"hello world")], 21)))
+        );
+        let (args, _) = parse_command(b"ECHO \"a\\tb\\x41\\\"\" 'it\\'s' \"\"\n", usize::MAX).unwrap().unwrap();
+        assert_eq!(args, vec![b"ECHO".to_vec(), b"a\tbA\"".to_vec(), b"it's".to_vec(), b"".to_vec()]);
+        assert_eq!(parse_command(b"ECHO \"unterminated\r\n", usize::MAX), Err(ProtocolError::UnbalancedQuotes));
+        assert_eq!(parse_command(b"ECHO \"a\"b\r\n", usize::MAX), Err(ProtocolError::UnbalancedQuotes));
+    }
+
+    #[test]
+    fn quoted_arguments_split_back_into_the_originals() {
+        assert_eq!(quote_arg(b"say \"hi\"\n\x00\xff"), "\"say \\\"hi\\\"\\n\\x00\\xff\"");
+        let args = [b"plain".to_vec(), b"back\\slash\t\x07\x08\r".to_vec(), Vec::new(), (0..=255).collect::<Vec<u8>>()];
+        let line = args.iter().map(|arg| quote_arg(arg)).collect::<Vec<String>>().join(" ");
+        assert_eq!(split_inline_args(line.as_bytes()), Some(args.to_vec()));
+    }
+}
@@ -0,0 +1,125 @@
+// The slow log: the latest commands that ran for longer than slowlog-log-slower-than, for SLOWLOG GET
+//
+// Like Redis's, it's a bounded in-memory ring holding at most slowlog-max-len entries, newest first. Only the time
+// spent running the command is counted, not the time spent reading it or writing its reply.
+
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_LOG_SLOWER_THAN_US: i64 = 10_000;
+pub const DEFAULT_MAX_LEN: usize = 128;
+// Like Redis, only this many arguments (and bytes of each) are kept, so that huge commands don't bloat the log
+const MAX_ARGS: usize = 32;
+const MAX_ARG_LEN: usize = 128;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SlowlogEntry {
+    pub id: u64,
+    // Unix time in seconds at which the command was logged
+    pub timestamp: u64,
+    pub duration_us: u64,
+    pub args: Vec<Bytes>,
+    pub client_addr: String,
+    pub client_name: Vec<u8>,
+}
+
+pub struct Slowlog {
+    entries: Mutex<VecDeque<SlowlogEntry>>,
+    next_id: AtomicU64,
+    // In microseconds; negative turns the slow log off, and 0 logs every command
+    pub log_slower_than: AtomicI64,
+    pub max_len: AtomicUsize,
+}
+
+impl Slowlog {
+    pub fn new(log_slower_than: i64, max_len: usize) -> Self {
+        Slowlog {
+            entries: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(0),
+            log_slower_than: AtomicI64::new(log_slower_than),
+            max_len: AtomicUsize::new(max_len),
+        }
+    }
+
+    fn lock_entries(&self) -> MutexGuard<'_, VecDeque<SlowlogEntry>> {
+        self.entries.lock().unwrap_or_else(|err| {
+            panic!("Failed to lock slow log mutex: {}!", err);
+        })
+    }
+
+    pub fn is_slow(&self, duration: Duration) -> bool {
+        let log_slower_than = self.log_slower_than.load(Ordering::Relaxed);
+        log_slower_than >= 0 && duration.as_micros() >= log_slower_than as u128
+    }
+
+    pub fn record(&self, args: &[Bytes], duration: Duration, client_addr: &str, client_name: &[u8]) {
+        /* Add a command that is_slow, dropping the oldest entries past slowlog-max-len */
+        let mut logged_args = args.iter().take(MAX_ARGS).map(|arg| match arg.len() {
+            arg_len if arg_len > MAX_ARG_LEN => {
+                let mut truncated = arg[..MAX_ARG_LEN].to_vec();
+                truncated.extend_from_slice(format!("... ({} more bytes)", arg_len - MAX_ARG_LEN).as_bytes());
+                Bytes::from(truncated)
+            },
+            _ => arg.clone(),
+        }).collect::<Vec<Bytes>>();
+        // The last argument kept makes way for a note of how many were left out
+        if args.len() > MAX_ARGS {
+            logged_args[MAX_ARGS - 1] = Bytes::from(format!("... ({} more arguments)", args.len() - MAX_ARGS + 1));
+        }
+        let entry = SlowlogEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            duration_us: duration.as_micros() as u64,
+            args: logged_args,
+            client_addr: client_addr.to_string(),
+            client_name: client_name.to_vec(),
+        };
+        let mut entries = self.lock_entries();
+        entries.push_front(entry);
+        entries.truncate(self.max_len.load(Ordering::Relaxed));
+    }
+
+    pub fn latest(&self, count: usize) -> Vec<SlowlogEntry> {
+        self.lock_entries().iter().take(count).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock_entries().len()
+    }
+
+    pub fn reset(&self) {
+        self.lock_entries().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_latest_slow_commands_truncated() {
+        let slowlog = Slowlog::new(1000, 2);
+        assert!(!slowlog.is_slow(Duration::from_micros(999)));
+        assert!(slowlog.is_slow(Duration::from_micros(1000)));
+
+        let args = (0..40).map(|idx| Bytes::from(format!("arg{}", idx))).collect::<Vec<Bytes>>();
+        slowlog.record(&args[..2], Duration::from_millis(1), "127.0.0.1:1", b"");
+        slowlog.record(&[Bytes::from(vec![b'x'; 200])], Duration::from_millis(2), "127.0.0.1:2", b"worker");
+        slowlog.record(&args, Duration::from_millis(3), "127.0.0.1:3", b"");
+        assert_eq!(slowlog.len(), 2);
+        let entries = slowlog.latest(10);
+        assert_eq!((entries[0].id, entries[0].duration_us, entries[1].id), (2, 3000, 1));
+        assert_eq!(entries[0].args.len(), MAX_ARGS);
+        assert_eq!(entries[0].args[MAX_ARGS - 1], Bytes::from("... (9 more arguments)"));
+        assert_eq!(entries[1].args[0], Bytes::from(format!("{}... (72 more bytes)", "x".repeat(MAX_ARG_LEN))));
+        assert_eq!(entries[1].client_name, b"worker");
+
+        slowlog.log_slower_than.store(-1, Ordering::Relaxed);
+        assert!(!slowlog.is_slow(Duration::from_secs(1)));
+        slowlog.reset();
+        assert_eq!(slowlog.len(), 0);
+    }
+}
@@ -0,0 +1,659 @@
+// Sorted set: unique members ordered by a floating point score, with ties broken by member bytes.
+// Like Redis's skiplist encoding, it pairs a member -> score map (for O(1) score lookups) with an ordered
+// index (for ranges and pops); here the ordered index is a BTreeSet instead of a skiplist.
+use crate::keyspace::KeyspaceGuard;
+use crate::resp::RespValue;
+use crate::{arg_to_string, normalize_range, numkeys_key_args, parse_arg, CommandSpec, Connection, KeyEntry, RedisServer, RedisValue, ServerState, WRONGTYPE_ERR};
+use bytes::Bytes;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::ops::{Bound, RangeBounds};
+
+#[derive(Clone, Copy, Debug)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Scores are never NaN, so total_cmp agrees with the usual float ordering (apart from -0.0 < 0.0)
+        self.0.total_cmp(&other.0)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, f64>,
+    ordered: BTreeSet<(Score, Vec<u8>)>,
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> bool {
+        /* Add member with score, or update its score if it's already in the set; returns whether it was added */
+        let prev_score = self.scores.insert(member.clone(), score);
+        if let Some(prev_score) = prev_score {
+            self.ordered.remove(&(Score(prev_score), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        prev_score.is_none()
+    }
+
+    pub fn pop(&mut self, count: usize, highest: bool) -> Vec<(Vec<u8>, f64)> {
+        /* Remove and return up to count members with the lowest (or highest) scores, in the order they were popped */
+        let mut popped = Vec::new();
+        while popped.len() < count {
+            let lowest_or_highest = if highest { self.ordered.pop_last() } else { self.ordered.pop_first() };
+            let Some((score, member)) = lowest_or_highest else {
+                break;
+            };
+            self.scores.remove(&member);
+            popped.push((member, score.0));
+        }
+        popped
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&Vec<u8>, f64)> {
+        /* Members in ascending score order */
+        self.ordered.iter().map(|(score, member)| (member, score.0))
+    }
+
+    pub fn range_by_score(&self, min: Bound<f64>, max: Bound<f64>) -> Vec<(&Vec<u8>, f64)> {
+        /* Members with scores between min and max, in ascending score order */
+        self.iter().filter(|(_, score)| (min, max).contains(score)).collect()
+    }
+
+    pub fn range_by_lex(&self, min: Bound<Vec<u8>>, max: Bound<Vec<u8>>) -> Vec<(&Vec<u8>, f64)> {
+        /*
+        Members between min and max when compared byte by byte, in ascending order
+        Like in Redis, this only makes sense when every member has the same score.
+        */
+        self.iter().filter(|(member, _)| (min.as_ref(), max.as_ref()).contains(member)).collect()
+    }
+}
+
+// Flags of ZADD and GEOADD, which only takes NX, XX and CH; like EXPIRE's, XX can be combined with GT or LT
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct AddOptions {
+    pub(crate) nx: bool,
+    pub(crate) xx: bool,
+    pub(crate) gt: bool,
+    pub(crate) lt: bool,
+    pub(crate) ch: bool,
+}
+
+impl AddOptions {
+    fn allows(&self, prev_score: Option<f64>, score: f64) -> bool {
+        /* Whether the flags allow setting a member's score, given its current one (if it's already in the set) */
+        match prev_score {
+            Some(_) if self.nx => false,
+            None if self.xx => false,
+            Some(prev_score) if self.gt => score > prev_score,
+            Some(prev_score) if self.lt => score < prev_score,
+            _ => true,
+        }
+    }
+}
+
+// What ZRANGE and ZRANGESTORE select members by: rank (the default), BYSCORE or BYLEX
+#[derive(Clone, Debug)]
+enum ZRangeBy {
+    Rank(i64, i64),
+    Score(Bound<f64>, Bound<f64>),
+    Lex(Bound<Vec<u8>>, Bound<Vec<u8>>),
+}
+
+#[derive(Clone, Debug)]
+struct ZRangeQuery {
+    by: ZRangeBy,
+    rev: bool,
+    // Offset and count; a negative count means all remaining members
+    limit: Option<(i64, i64)>,
+    withscores: bool,
+}
+
+impl ZRangeQuery {
+    fn parse(args: &[Bytes]) -> Result<ZRangeQuery, String> {
+        /* Parse `start stop [BYSCORE|BYLEX] [REV] [LIMIT offset count] [WITHSCORES]` */
+        let (mut by_score, mut by_lex, mut rev, mut limit, mut withscores) = (false, false, false, None, false);
+        let mut idx = 2;
+        while idx < args.len() {
+            match arg_to_string(&args[idx]).to_uppercase().as_str() {
+                "BYSCORE" => by_score = true,
+                "BYLEX" => by_lex = true,
+                "REV" => rev = true,
+                "WITHSCORES" => withscores = true,
+                "LIMIT" if idx + 2 < args.len() => {
+                    match (parse_arg::<i64>(&args[idx + 1]), parse_arg::<i64>(&args[idx + 2])) {
+                        (Some(offset), Some(count)) => limit = Some((offset, count)),
+                        _ => return Err("ERR value is not an integer or out of range".to_string()),
+                    }
+                    idx += 2;
+                },
+                _ => return Err("ERR syntax error".to_string()),
+            }
+            idx += 1;
+        }
+        if by_score && by_lex {
+            return Err("ERR syntax error".to_string());
+        }
+        if limit.is_some() && !by_score && !by_lex {
+            return Err("ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX".to_string());
+        }
+        if withscores && by_lex {
+            return Err("ERR syntax error, WITHSCORES not supported in combination with BYLEX".to_string());
+        }
+        // With REV, BYSCORE and BYLEX take the max before the min
+        let (start, stop) = if rev && (by_score || by_lex) { (&args[1], &args[0]) } else { (&args[0], &args[1]) };
+        let by = if by_score {
+            match (Self::parse_score_bound(start), Self::parse_score_bound(stop)) {
+                (Some(min), Some(max)) => ZRangeBy::Score(min, max),
+                _ => return Err("ERR min or max is not a float".to_string()),
+            }
+        } else if by_lex {
+            match (Self::parse_lex_bound(start), Self::parse_lex_bound(stop)) {
+                (Some(min), Some(max)) => ZRangeBy::Lex(min, max),
+                _ => return Err("ERR min or max not valid string range item".to_string()),
+            }
+        } else {
+            match (parse_arg::<i64>(start), parse_arg::<i64>(stop)) {
+                (Some(start), Some(stop)) => ZRangeBy::Rank(start, stop),
+                _ => return Err("ERR value is not an integer or out of range".to_string()),
+            }
+        };
+        Ok(ZRangeQuery { by, rev, limit, withscores })
+    }
+
+    fn parse_score_bound(arg: &[u8]) -> Option<Bound<f64>> {
+        /* A score, exclusive when prefixed with `(`; -inf and +inf work too */
+        match arg.strip_prefix(b"(") {
+            Some(score) => parse_arg::<f64>(score).filter(|score| !score.is_nan()).map(Bound::Excluded),
+            None => parse_arg::<f64>(arg).filter(|score| !score.is_nan()).map(Bound::Included),
+        }
+    }
+
+    fn parse_lex_bound(arg: &[u8]) -> Option<Bound<Vec<u8>>> {
+        /* `[member` (inclusive), `(member` (exclusive), or `-` / `+` for no bound */
+        match arg.split_first() {
+            Some((b'[', member)) => Some(Bound::Included(member.to_vec())),
+            Some((b'(', member)) => Some(Bound::Excluded(member.to_vec())),
+            Some((b'-' | b'+', [])) => Some(Bound::Unbounded),
+            _ => None,
+        }
+    }
+
+    fn select(&self, zset: &SortedSet) -> Vec<(Vec<u8>, f64)> {
+        /* The members of zset (with their scores) picked out by this query, in the order they're replied with */
+        let mut members = match &self.by {
+            ZRangeBy::Rank(start, stop) => {
+                let Some((start, stop)) = normalize_range(*start, *stop, zset.len()) else {
+                    return Vec::new();
+                };
+                let members = zset.iter().map(|(member, score)| (member.clone(), score));
+                return if self.rev {
+                    members.rev().skip(start).take(stop - start + 1).collect()
+                } else {
+                    members.skip(start).take(stop - start + 1).collect()
+                };
+            },
+            ZRangeBy::Score(min, max) => zset.range_by_score(*min, *max),
+            ZRangeBy::Lex(min, max) => zset.range_by_lex(min.clone(), max.clone()),
+        };
+        if self.rev {
+            members.reverse();
+        }
+        let (offset, count) = self.limit.unwrap_or((0, -1));
+        if offset < 0 {
+            return Vec::new();
+        }
+        let count = if count < 0 { usize::MAX } else { count as usize };
+        members.into_iter().skip(offset as usize).take(count).map(|(member, score)| (member.clone(), score)).collect()
+    }
+}
+
+// How ZUNIONSTORE and ZINTERSTORE combine the scores of a member that's in several of the sorted sets
+#[derive(Clone, Copy, Debug)]
+enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn combine(self, score: f64, other_score: f64) -> f64 {
+        match self {
+            // inf + -inf is NaN, which Redis turns into 0
+            Aggregate::Sum => Some(score + other_score).filter(|sum| !sum.is_nan()).unwrap_or(0.0),
+            Aggregate::Min => score.min(other_score),
+            Aggregate::Max => score.max(other_score),
+        }
+    }
+}
+
+// Sorted set members paired with their scores, in the order they're replied with
+type ScoredMembers = Vec<(Vec<u8>, f64)>;
+
+// The sorted set commands, which command_table() registers along with the other modules' commands
+pub(crate) const COMMANDS: &[CommandSpec] = &[
+    CommandSpec { name: "zadd", arity: (3, None), flags: &["write", "denyoom", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_zadd_cmd },
+    CommandSpec { name: "zrange", arity: (3, None), flags: &["readonly"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_zrange_cmd },
+    CommandSpec { name: "zrangestore", arity: (4, None), flags: &["write", "denyoom"], keys: (1, 2, 1), find_keys: None, handler: &RedisServer::handle_zrangestore_cmd },
+    CommandSpec { name: "zunionstore", arity: (3, None), flags: &["write", "denyoom", "movablekeys"], keys: (1, 1, 1), find_keys: Some(RedisServer::zstore_keys), handler: &RedisServer::handle_zunionstore_cmd },
+    CommandSpec { name: "zinterstore", arity: (3, None), flags: &["write", "denyoom", "movablekeys"], keys: (1, 1, 1), find_keys: Some(RedisServer::zstore_keys), handler: &RedisServer::handle_zinterstore_cmd },
+    CommandSpec { name: "zpopmin", arity: (1, Some(2)), flags: &["write", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_zpopmin_cmd },
+    CommandSpec { name: "zpopmax", arity: (1, Some(2)), flags: &["write", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_zpopmax_cmd },
+    CommandSpec { name: "bzpopmin", arity: (2, None), flags: &["write", "blocking", "fast"], keys: (1, -2, 1), find_keys: None, handler: &RedisServer::handle_bzpopmin_cmd },
+    CommandSpec { name: "bzpopmax", arity: (2, None), flags: &["write", "blocking", "fast"], keys: (1, -2, 1), find_keys: None, handler: &RedisServer::handle_bzpopmax_cmd },
+];
+
+impl RedisServer {
+    pub(crate) fn parse_add_options(args: &[Bytes]) -> (AddOptions, usize) {
+        /* Parse the NX, XX, GT, LT and CH flags after the key of ZADD and GEOADD, also returning the index of the next argument */
+        let mut opts = AddOptions::default();
+        let mut arg_idx = 2;
+        while let Some(option_arg) = args.get(arg_idx) {
+            match arg_to_string(option_arg).to_uppercase().as_str() {
+                "NX" => opts.nx = true,
+                "XX" => opts.xx = true,
+                "GT" => opts.gt = true,
+                "LT" => opts.lt = true,
+                "CH" => opts.ch = true,
+                _ => break,
+            }
+            arg_idx += 1;
+        }
+        (opts, arg_idx)
+    }
+
+    pub(crate) fn add_to_sorted_set(state: &ServerState, db: usize, key: &[u8], members: Vec<(Vec<u8>, f64)>, opts: AddOptions) -> Result<usize, ()> {
+        /*
+        Shared implementation of ZADD and GEOADD: add members with their scores to the sorted set at key, creating it if needed
+        NX only adds new members and XX only updates existing ones, while GT and LT only update a member's score when
+        the new one is greater or less (but still add new members). Returns the number of members added, or with CH,
+        the number of members added or moved; Err if key holds another type.
+        */
+        let mut c = state.lock_keys(db, [key]);
+        if Self::get_live_val(state, &mut c, key).is_none() {
+            c.insert(Bytes::copy_from_slice(key), KeyEntry::new(RedisValue::SortedSet(SortedSet::new()), None));
+        }
+        match Self::get_live_val(state, &mut c, key) {
+            Some(RedisValue::SortedSet(zset)) => {
+                let mut num_changed = 0;
+                for (member, score) in members {
+                    let prev_score = zset.score(&member);
+                    if !opts.allows(prev_score, score) {
+                        continue;
+                    }
+                    zset.insert(member, score);
+                    if prev_score.is_none() || (opts.ch && prev_score != Some(score)) {
+                        num_changed += 1;
+                    }
+                }
+                // XX on a new key adds nothing, so don't leave an empty sorted set behind
+                if zset.len() == 0 {
+                    c.remove(key);
+                }
+                // Wake up any clients blocked in BZPOPMIN/BZPOPMAX
+                state.dbs[db].notify_writes();
+                Ok(num_changed)
+            },
+            _ => Err(()),
+        }
+    }
+
+    fn handle_zadd_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        ZADD key [NX|XX] [GT|LT] [CH] score member [score member ...]
+        Add members with the given scores to the sorted set at key; the flags and reply are the same as GEOADD's, which
+        doesn't take GT or LT. Like in Redis, NX can't be combined with GT or LT, but XX can.
+        */
+        let (opts, arg_idx) = Self::parse_add_options(args);
+        let members_args = &args[arg_idx..];
+        let zadd_err = if members_args.is_empty() || members_args.len() % 2 != 0 || (opts.nx && opts.xx) {
+            Some("ERR syntax error")
+        } else if (opts.nx && (opts.gt || opts.lt)) || (opts.gt && opts.lt) {
+            Some("ERR GT, LT, and/or NX options at the same time are not compatible")
+        } else {
+            None
+        };
+        if let Some(zadd_err) = zadd_err {
+            let zadd_err_response = RespValue::Error(zadd_err.to_string());
+            conn.write_reply(&zadd_err_response)?;
+            return Ok(());
+        }
+        let mut members = Vec::with_capacity(members_args.len() / 2);
+        for score_and_member in members_args.chunks(2) {
+            match parse_arg::<f64>(&score_and_member[0]) {
+                Some(score) if !score.is_nan() => members.push((score_and_member[1].to_vec(), score)),
+                _ => {
+                    let zadd_err_response = RespValue::Error("ERR value is not a valid float".to_string());
+                    conn.write_reply(&zadd_err_response)?;
+                    return Ok(());
+                }
+            }
+        }
+        let zadd_resp = match Self::add_to_sorted_set(state, conn.db, &args[1], members, opts) {
+            Ok(num_changed) => RespValue::Integer(num_changed as i64),
+            Err(()) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+        };
+        conn.write_reply(&zadd_resp)?;
+        Ok(())
+    }
+
+    fn zpop_from_key(state: &ServerState, c: &mut KeyspaceGuard, key: &[u8], count: usize, highest: bool) -> Result<Option<ScoredMembers>, ()> {
+        /*
+        Pop up to count of the lowest (or highest) scored members of the sorted set at key, deleting the key if that
+        empties it. Returns the members along with their scores; None if the key doesn't exist.
+        */
+        match Self::get_live_val(state, c, key) {
+            Some(RedisValue::SortedSet(zset)) => {
+                let popped = zset.pop(count, highest);
+                if zset.len() == 0 {
+                    c.remove(key);
+                }
+                Ok(Some(popped))
+            },
+            Some(_) => Err(()),
+            None => Ok(None),
+        }
+    }
+
+    fn zpop_members(conn: &mut Connection, args: &[Bytes], state: &ServerState, highest: bool) -> io::Result<()> {
+        /* Shared implementation of ZPOPMIN and ZPOPMAX, replying with a flat array of members and scores */
+        let count = match args.get(2) {
+            Some(count) => match parse_arg::<usize>(count) {
+                Some(count) => count,
+                None => {
+                    let zpop_err_response = RespValue::Error("ERR value is out of range, must be positive".to_string());
+                    conn.write_reply(&zpop_err_response)?;
+                    return Ok(());
+                }
+            },
+            None => 1,
+        };
+        let mut c = state.lock_keys(conn.db, [&args[1]]);
+        let zpop_resp = match Self::zpop_from_key(state, &mut c, &args[1], count, highest) {
+            Ok(popped) => Self::encode_members_with_scores(&popped.unwrap_or_default(), true),
+            Err(()) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+        };
+        conn.write_reply(&zpop_resp)?;
+        Ok(())
+    }
+
+    fn handle_zpopmin_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Remove and return the members with the lowest scores: ZPOPMIN key [count] */
+        Self::zpop_members(conn, args, state, false)
+    }
+
+    fn handle_zpopmax_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Remove and return the members with the highest scores: ZPOPMAX key [count] */
+        Self::zpop_members(conn, args, state, true)
+    }
+
+    fn blocking_zpop(conn: &mut Connection, args: &[Bytes], state: &ServerState, highest: bool) -> io::Result<()> {
+        /*
+        Shared implementation of BZPOPMIN and BZPOPMAX: pop from the first non-empty sorted set among the keys,
+        waiting for a ZADD if they're all empty. Replies with [key, member, score], or nil on timeout.
+        */
+        let Some(timeout_secs) = Self::parse_timeout(conn, &args[args.len() - 1])? else {
+            return Ok(());
+        };
+        let keys = &args[1..args.len() - 1];
+        Self::block_until(conn, args, state, keys, Some(timeout_secs), |c| {
+            keys.iter().find_map(|key| match Self::zpop_from_key(state, c, key, 1, highest) {
+                Ok(Some(popped)) => {
+                    let (member, score) = popped.into_iter().next()?;
+                    Some(RespValue::Array(vec![
+                        RespValue::BulkString(key.to_vec()),
+                        RespValue::BulkString(member),
+                        RespValue::Double(score),
+                    ]))
+                },
+                Ok(None) => None,
+                Err(()) => Some(RespValue::Error(WRONGTYPE_ERR.to_string())),
+            })
+        })
+    }
+
+    fn handle_bzpopmin_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Blocking ZPOPMIN across several keys: BZPOPMIN key [key ...] timeout */
+        Self::blocking_zpop(conn, args, state, false)
+    }
+
+    fn handle_bzpopmax_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Blocking ZPOPMAX across several keys: BZPOPMAX key [key ...] timeout */
+        Self::blocking_zpop(conn, args, state, true)
+    }
+
+    fn encode_members_with_scores(members: &[(Vec<u8>, f64)], withscores: bool) -> RespValue {
+        /* A flat array of the members, each followed by its score (a double in RESP3) if withscores */
+        let elems = members.iter()
+            .flat_map(|(member, score)| {
+                let score = withscores.then_some(RespValue::Double(*score));
+                std::iter::once(RespValue::BulkString(member.clone())).chain(score)
+            })
+            .collect();
+        RespValue::Array(elems)
+    }
+
+    fn store_sorted_set(c: &mut KeyspaceGuard, state: &ServerState, db: usize, dest_key: Bytes, members: Vec<(Vec<u8>, f64)>) -> usize {
+        /* Overwrite dest_key with a sorted set of members, or delete it if there are none; returns the set's size */
+        if members.is_empty() {
+            c.remove(&dest_key);
+            return 0;
+        }
+        let mut zset = SortedSet::new();
+        for (member, score) in members {
+            zset.insert(member, score);
+        }
+        let num_members = zset.len();
+        c.insert(dest_key, KeyEntry::new(RedisValue::SortedSet(zset), None));
+        // Wake up any clients blocked in BZPOPMIN/BZPOPMAX
+        state.dbs[db].notify_writes();
+        num_members
+    }
+
+    fn handle_zrange_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        ZRANGE key start stop [BYSCORE|BYLEX] [REV] [LIMIT offset count] [WITHSCORES]
+        Reply with the members of the sorted set at key between start and stop, which are ranks by default (negative
+        ones count from the end), scores with BYSCORE, or members with BYLEX. REV goes from the highest to the lowest.
+        */
+        let query = match ZRangeQuery::parse(&args[2..]) {
+            Ok(query) => query,
+            Err(zrange_err) => {
+                let zrange_err_response = RespValue::Error(zrange_err);
+                conn.write_reply(&zrange_err_response)?;
+                return Ok(());
+            }
+        };
+        let zrange_resp = Self::get_key(state, conn.db, &args[1], |val| match val {
+            Some(RedisValue::SortedSet(zset)) => Self::encode_members_with_scores(&query.select(zset), query.withscores),
+            Some(_) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+            None => RespValue::bulk_strings(&[]),
+        });
+        conn.write_reply(&zrange_resp)?;
+        Ok(())
+    }
+
+    fn handle_zrangestore_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        ZRANGESTORE dst src start stop [BYSCORE|BYLEX] [REV] [LIMIT offset count]
+        Store what ZRANGE would reply with in dst, replying with the number of members stored.
+        */
+        let query = ZRangeQuery::parse(&args[3..])
+            .and_then(|query| if query.withscores { Err("ERR syntax error".to_string()) } else { Ok(query) });
+        let query = match query {
+            Ok(query) => query,
+            Err(zrangestore_err) => {
+                let zrangestore_err_response = RespValue::Error(zrangestore_err);
+                conn.write_reply(&zrangestore_err_response)?;
+                return Ok(());
+            }
+        };
+        let mut c = state.lock_keys(conn.db, &args[1..3]);
+        let members = match Self::get_live_val(state, &mut c, &args[2]) {
+            Some(RedisValue::SortedSet(zset)) => Some(query.select(zset)),
+            Some(_) => None,
+            None => Some(Vec::new()),
+        };
+        let zrangestore_resp = match members {
+            Some(members) => RespValue::Integer(Self::store_sorted_set(&mut c, state, conn.db, args[1].clone(), members) as i64),
+            None => RespValue::Error(WRONGTYPE_ERR.to_string()),
+        };
+        conn.write_reply(&zrangestore_resp)?;
+        Ok(())
+    }
+
+    fn combine_sorted_sets(conn: &mut Connection, args: &[Bytes], state: &ServerState, union: bool) -> io::Result<()> {
+        /*
+        Shared implementation of ZUNIONSTORE and ZINTERSTORE:
+        <cmd> destination numkeys key [key ...] [WEIGHTS weight [weight ...]] [AGGREGATE SUM|MIN|MAX]
+        Store the union (or intersection) of the sorted sets at the keys in destination, replying with its size.
+        Each set's scores are multiplied by its weight (1 by default), and the scores of a member in several sets are
+        combined with the aggregate function (SUM by default). Plain sets count as sorted sets where every score is 1.
+        */
+        let cmd_name = arg_to_string(&args[0]).to_lowercase();
+        let num_keys = match parse_arg::<i64>(&args[2]) {
+            Some(num_keys) if num_keys <= 0 => {
+                let zcombine_err_response = RespValue::Error(format!("ERR at least 1 input key is needed for '{}' command", cmd_name));
+                conn.write_reply(&zcombine_err_response)?;
+                return Ok(());
+            },
+            Some(num_keys) if num_keys as usize <= args.len() - 3 => num_keys as usize,
+            Some(_) => {
+                let zcombine_err_response = RespValue::Error("ERR syntax error".to_string());
+                conn.write_reply(&zcombine_err_response)?;
+                return Ok(());
+            },
+            None => {
+                let zcombine_err_response = RespValue::Error("ERR value is not an integer or out of range".to_string());
+                conn.write_reply(&zcombine_err_response)?;
+                return Ok(());
+            },
+        };
+        let keys = &args[3..3 + num_keys];
+        let mut weights = vec![1.0; num_keys];
+        let mut aggregate = Aggregate::Sum;
+        let mut idx = 3 + num_keys;
+        while idx < args.len() {
+            let option = arg_to_string(&args[idx]).to_uppercase();
+            let option_err = match option.as_str() {
+                "WEIGHTS" if idx + num_keys < args.len() => {
+                    let parsed_weights = args[idx + 1..=idx + num_keys].iter()
+                        .map(|weight| parse_arg::<f64>(weight).filter(|weight| !weight.is_nan()))
+                        .collect::<Option<Vec<f64>>>();
+                    idx += num_keys + 1;
+                    match parsed_weights {
+                        Some(parsed_weights) => {
+                            weights = parsed_weights;
+                            None
+                        },
+                        None => Some("ERR weight value is not a float"),
+                    }
+                },
+                "AGGREGATE" if idx + 1 < args.len() => {
+                    let parsed_aggregate = match arg_to_string(&args[idx + 1]).to_uppercase().as_str() {
+                        "SUM" => Some(Aggregate::Sum),
+                        "MIN" => Some(Aggregate::Min),
+                        "MAX" => Some(Aggregate::Max),
+                        _ => None,
+                    };
+                    idx += 2;
+                    match parsed_aggregate {
+                        Some(parsed_aggregate) => {
+                            aggregate = parsed_aggregate;
+                            None
+                        },
+                        None => Some("ERR syntax error"),
+                    }
+                },
+                _ => Some("ERR syntax error"),
+            };
+            if let Some(option_err) = option_err {
+                let zcombine_err_response = RespValue::Error(option_err.to_string());
+                conn.write_reply(&zcombine_err_response)?;
+                return Ok(());
+            }
+        }
+
+        let mut c = state.lock_keys(conn.db, keys.iter().chain([&args[1]]));
+        let mut sources = Vec::with_capacity(num_keys);
+        for (key, weight) in keys.iter().zip(&weights) {
+            let weighted = |score: f64| Some(score * weight).filter(|score| !score.is_nan()).unwrap_or(0.0);
+            let members = match Self::get_live_val(state, &mut c, key) {
+                Some(RedisValue::SortedSet(zset)) => zset.iter().map(|(member, score)| (member.clone(), weighted(score))).collect(),
+                Some(RedisValue::Set(set)) => set.members().into_iter().map(|member| (member, weighted(1.0))).collect(),
+                Some(_) => {
+                    let zcombine_err_response = RespValue::Error(WRONGTYPE_ERR.to_string());
+                    conn.write_reply(&zcombine_err_response)?;
+                    return Ok(());
+                },
+                None => HashMap::new(),
+            };
+            sources.push(members);
+        }
+        let mut combined: HashMap<Vec<u8>, f64> = HashMap::new();
+        if union {
+            for members in &sources {
+                for (member, score) in members {
+                    combined.entry(member.clone())
+                        .and_modify(|combined_score| *combined_score = aggregate.combine(*combined_score, *score))
+                        .or_insert(*score);
+                }
+            }
+        } else {
+            for (member, score) in &sources[0] {
+                let combined_score = sources[1..].iter().try_fold(*score, |combined_score, members| {
+                    members.get(member).map(|score| aggregate.combine(combined_score, *score))
+                });
+                if let Some(combined_score) = combined_score {
+                    combined.insert(member.clone(), combined_score);
+                }
+            }
+        }
+        let num_members = Self::store_sorted_set(&mut c, state, conn.db, args[1].clone(), combined.into_iter().collect());
+        let zcombine_resp = RespValue::Integer(num_members as i64);
+        conn.write_reply(&zcombine_resp)?;
+        Ok(())
+    }
+
+    fn zstore_keys(args: &[Bytes]) -> Option<Vec<&Bytes>> {
+        /* ZUNIONSTORE/ZINTERSTORE destination numkeys key [key ...] ...; the destination comes before numkeys */
+        numkeys_key_args(args, 2).map(|keys| [vec![&args[1]], keys].concat())
+    }
+
+    fn handle_zunionstore_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Store the union of the sorted sets at the keys in destination */
+        Self::combine_sorted_sets(conn, args, state, true)
+    }
+
+    fn handle_zinterstore_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Store the intersection of the sorted sets at the keys in destination */
+        Self::combine_sorted_sets(conn, args, state, false)
+    }
+}
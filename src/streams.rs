@@ -0,0 +1,538 @@
+// Append-only log of field/value entries backing the XADD/XLEN/XRANGE commands: https://redis.io/docs/data-types/streams/
+// Entries are keyed by `<ms>-<seq>` IDs which must strictly increase, so a BTreeMap gives us ordered range scans.
+use crate::resp::RespValue;
+use crate::{arg_to_string, parse_arg, CommandSpec, Connection, KeyEntry, RedisServer, RedisValue, ServerState, WRONGTYPE_ERR};
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::ops::Bound;
+use std::time::Duration;
+use thiserror::Error;
+
+pub type StreamFields = Vec<(Vec<u8>, Vec<u8>)>;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId {
+    // Field order matters: the derived Ord compares ms first and then seq
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId { ms: u64::MAX, seq: u64::MAX };
+
+    pub fn parse(raw: &str, default_seq: u64) -> Result<StreamId, StreamError> {
+        /*
+        Parse an `<ms>-<seq>` ID; a bare `<ms>` is allowed and gets default_seq
+        (0 for the start of a range and u64::MAX for the end)
+        */
+        let (ms, seq) = match raw.split_once('-') {
+            Some((ms, seq)) => (ms, seq.parse::<u64>().map_err(|_| StreamError::InvalidId)?),
+            None => (raw, default_seq),
+        };
+        let ms = ms.parse::<u64>().map_err(|_| StreamError::InvalidId)?;
+        Ok(StreamId { ms, seq })
+    }
+
+    pub fn parse_range_start(raw: &str) -> Result<StreamId, StreamError> {
+        match raw {
+            "-" => Ok(StreamId::MIN),
+            _ => StreamId::parse(raw, 0),
+        }
+    }
+
+    pub fn parse_range_end(raw: &str) -> Result<StreamId, StreamError> {
+        match raw {
+            "+" => Ok(StreamId::MAX),
+            _ => StreamId::parse(raw, u64::MAX),
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum StreamError {
+    #[error("Invalid stream ID specified as stream command argument")]
+    InvalidId,
+    #[error("The ID specified in XADD must be greater than 0-0")]
+    IdZero,
+    #[error("The ID specified in XADD is equal or smaller than the target stream top item")]
+    IdTooSmall,
+    #[error("The stream has exhausted the last possible ID, unable to add more items")]
+    Exhausted,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RedisStream {
+    entries: BTreeMap<StreamId, StreamFields>,
+    // Tracked separately from the entries so IDs keep increasing even after entries are removed
+    last_id: StreamId,
+}
+
+impl RedisStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_entries(entries: Vec<(StreamId, StreamFields)>, last_id: StreamId) -> Self {
+        /* Rebuild a stream from its entries and last ID, e.g. when loading a snapshot */
+        RedisStream { entries: entries.into_iter().collect(), last_id }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    fn next_id(&self, raw_id: &str, now_ms: u64) -> Result<StreamId, StreamError> {
+        /*
+        Resolve the ID for a new entry from the XADD argument:
+        `*` generates both parts, `<ms>-*` generates the sequence, anything else is an explicit ID
+        Generated IDs can run out: past the last sequence number of a millisecond, `*` moves on to the next millisecond,
+        but `<ms>-*` (and `*` after the very last possible ID) can't go anywhere.
+        */
+        let id = match raw_id.split_once('-') {
+            _ if raw_id == "*" => {
+                let ms = now_ms.max(self.last_id.ms);
+                match ms == self.last_id.ms {
+                    true => match self.last_id.seq.checked_add(1) {
+                        Some(seq) => StreamId { ms, seq },
+                        None => StreamId { ms: ms.checked_add(1).ok_or(StreamError::Exhausted)?, seq: 0 },
+                    },
+                    false => StreamId { ms, seq: 0 },
+                }
+            },
+            Some((ms, "*")) => {
+                let ms = ms.parse::<u64>().map_err(|_| StreamError::InvalidId)?;
+                let seq = match ms.cmp(&self.last_id.ms) {
+                    std::cmp::Ordering::Less => return Err(StreamError::IdTooSmall),
+                    std::cmp::Ordering::Equal => self.last_id.seq.checked_add(1).ok_or(StreamError::Exhausted)?,
+                    // 0-0 is never a valid ID, so the first sequence number for ms 0 is 1
+                    std::cmp::Ordering::Greater => if ms == 0 { 1 } else { 0 },
+                };
+                StreamId { ms, seq }
+            },
+            _ => StreamId::parse(raw_id, 0)?,
+        };
+        if id == StreamId::MIN {
+            return Err(StreamError::IdZero);
+        }
+        if id <= self.last_id {
+            return Err(StreamError::IdTooSmall);
+        }
+        Ok(id)
+    }
+
+    pub fn add(&mut self, raw_id: &str, fields: StreamFields, now_ms: u64) -> Result<StreamId, StreamError> {
+        /* Append an entry, enforcing that its ID is greater than every ID previously added */
+        let id = self.next_id(raw_id, now_ms)?;
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        Ok(id)
+    }
+
+    pub fn delete(&mut self, id: &StreamId) -> bool {
+        /* Remove a single entry; last_id is left alone so deleted IDs can't be reused */
+        self.entries.remove(id).is_some()
+    }
+
+    pub fn trim_maxlen(&mut self, max_len: usize) -> usize {
+        /* Evict the oldest entries until at most max_len remain, returning the number evicted */
+        let num_to_remove = self.entries.len().saturating_sub(max_len);
+        for _ in 0..num_to_remove {
+            self.entries.pop_first();
+        }
+        num_to_remove
+    }
+
+    pub fn trim_minid(&mut self, min_id: StreamId) -> usize {
+        /* Evict every entry with an ID lower than min_id, returning the number evicted */
+        let kept = self.entries.split_off(&min_id);
+        let num_removed = self.entries.len();
+        self.entries = kept;
+        num_removed
+    }
+
+    pub fn range(&self, start_bound: Bound<StreamId>, end_bound: Bound<StreamId>, count: Option<usize>) -> Vec<(&StreamId, &StreamFields)> {
+        /* Return up to count entries with IDs within the given bounds, in ID order */
+        if let (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) = (start_bound, end_bound) {
+            // BTreeMap::range panics on inverted (or empty exclusive) bounds, but for XRANGE that's just an empty result
+            let both_excluded = matches!((start_bound, end_bound), (Bound::Excluded(_), Bound::Excluded(_)));
+            if start > end || (start == end && both_excluded) {
+                return Vec::new();
+            }
+        }
+        self.entries
+            .range((start_bound, end_bound))
+            .take(count.unwrap_or(usize::MAX))
+            .collect()
+    }
+}
+
+// The stream commands, which command_table() registers along with the other modules' commands
+pub(crate) const COMMANDS: &[CommandSpec] = &[
+    CommandSpec { name: "xadd", arity: (4, None), flags: &["write", "denyoom", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_xadd_cmd },
+    CommandSpec { name: "xlen", arity: (1, Some(1)), flags: &["readonly", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_xlen_cmd },
+    CommandSpec { name: "xrange", arity: (3, Some(5)), flags: &["readonly"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_xrange_cmd },
+    CommandSpec { name: "xread", arity: (3, None), flags: &["readonly", "blocking", "movablekeys"], keys: (0, 0, 0), find_keys: Some(RedisServer::xread_keys), handler: &RedisServer::handle_xread_cmd },
+    CommandSpec { name: "xdel", arity: (2, None), flags: &["write", "fast"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_xdel_cmd },
+    CommandSpec { name: "xtrim", arity: (3, None), flags: &["write"], keys: (1, 1, 1), find_keys: None, handler: &RedisServer::handle_xtrim_cmd },
+];
+
+impl RedisServer {
+    fn encode_stream_entries(entries: &[(&StreamId, &StreamFields)]) -> RespValue {
+        /* Encode stream entries as a RESP array of [id, [field, value, ...]] pairs */
+        RespValue::Array(entries.iter().map(|(id, fields)| {
+            let fields_and_values = fields.iter()
+                .flat_map(|(field, value)| [field.to_vec(), value.to_vec()])
+                .collect::<Vec<Vec<u8>>>();
+            RespValue::Array(vec![
+                RespValue::BulkString(id.to_string().into_bytes()),
+                RespValue::bulk_strings(&fields_and_values),
+            ])
+        }).collect())
+    }
+
+    fn handle_xadd_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Append an entry to the stream at key, creating the stream if it doesn't exist
+        The ID is either `*` (auto-generated), `<ms>-*` (auto-generated sequence), or explicit.
+        Replies with the ID of the added entry.
+        */
+        if args.len() % 2 != 1 {
+            let xadd_err_response = RespValue::Error("ERR wrong number of arguments for 'xadd' command".to_string());
+            conn.write_reply(&xadd_err_response)?;
+            return Ok(());
+        }
+        let key = args[1].clone();
+        let fields = args[3..]
+            .chunks(2)
+            .map(|pair| (pair[0].to_vec(), pair[1].to_vec()))
+            .collect::<StreamFields>();
+
+        let mut c = state.lock_keys(conn.db, [&key]);
+        if Self::get_live_val(state, &mut c, &key).is_none() {
+            c.insert(key.clone(), KeyEntry::new(RedisValue::Stream(RedisStream::new()), None));
+        }
+        let redis_stream = match Self::get_live_val(state, &mut c, &key) {
+            Some(RedisValue::Stream(redis_stream)) => redis_stream,
+            _ => {
+                let xadd_err_response = RespValue::Error(WRONGTYPE_ERR.to_string());
+                conn.write_reply(&xadd_err_response)?;
+                return Ok(());
+            }
+        };
+        match redis_stream.add(&arg_to_string(&args[2]), fields, Self::curr_time_ms() as u64) {
+            Ok(id) => {
+                // Wake up any clients blocked in XREAD so they can check for the new entry
+                state.dbs[conn.db].notify_writes();
+                let xadd_resp = RespValue::BulkString(id.to_string().into_bytes());
+                conn.write_reply(&xadd_resp)?;
+            },
+            Err(err) => {
+                // Don't leave behind the empty stream created above when the first XADD is rejected
+                if redis_stream.len() == 0 {
+                    c.remove(&key);
+                }
+                let xadd_err_response = RespValue::Error(format!("ERR {}", err));
+                conn.write_reply(&xadd_err_response)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_xlen_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Reply with the number of entries in the stream at key (0 if it doesn't exist) */
+        let xlen_resp = Self::get_key(state, conn.db, &args[1], |val| match val {
+            Some(RedisValue::Stream(redis_stream)) => RespValue::Integer(redis_stream.len() as i64),
+            Some(_) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+            None => RespValue::Integer(0),
+        });
+        conn.write_reply(&xlen_resp)?;
+        Ok(())
+    }
+
+    fn handle_xrange_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Reply with the entries of the stream at key whose IDs are between start and end (inclusive)
+        `-` and `+` are the smallest and largest possible IDs, and IDs without a sequence number match
+        every sequence number for that millisecond.
+        */
+        let start = StreamId::parse_range_start(&arg_to_string(&args[2]));
+        let end = StreamId::parse_range_end(&arg_to_string(&args[3]));
+        let (start, end) = match (start, end) {
+            (Ok(start), Ok(end)) => (start, end),
+            (Err(err), _) | (_, Err(err)) => {
+                let xrange_err_response = RespValue::Error(format!("ERR {}", err));
+                conn.write_reply(&xrange_err_response)?;
+                return Ok(());
+            }
+        };
+        let count = match args.get(4) {
+            Some(option_arg) if option_arg.eq_ignore_ascii_case(b"COUNT") && args.len() == 6 => match parse_arg::<usize>(&args[5]) {
+                Some(count) => Some(count),
+                None => {
+                    let xrange_err_response = RespValue::Error("ERR value is not an integer or out of range".to_string());
+                    conn.write_reply(&xrange_err_response)?;
+                    return Ok(());
+                }
+            },
+            Some(_) => {
+                let xrange_err_response = RespValue::Error("ERR syntax error".to_string());
+                conn.write_reply(&xrange_err_response)?;
+                return Ok(());
+            },
+            None => None,
+        };
+
+        let xrange_resp = Self::get_key(state, conn.db, &args[1], |val| match val {
+            Some(RedisValue::Stream(redis_stream)) => {
+                let entries = redis_stream.range(Bound::Included(start), Bound::Included(end), count);
+                Self::encode_stream_entries(&entries)
+            },
+            Some(_) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+            None => RespValue::Array(Vec::new()),
+        });
+        conn.write_reply(&xrange_resp)?;
+        Ok(())
+    }
+
+    fn xread_keys(args: &[Bytes]) -> Option<Vec<&Bytes>> {
+        /* XREAD [COUNT count] [BLOCK ms] STREAMS key [key ...] id [id ...]; the keys are the first half after STREAMS */
+        let streams_idx = args.iter().position(|arg| arg.eq_ignore_ascii_case(b"STREAMS"))?;
+        let keys_and_ids = &args[streams_idx + 1..];
+        (!keys_and_ids.is_empty() && keys_and_ids.len() % 2 == 0).then(|| keys_and_ids[..keys_and_ids.len() / 2].iter().collect())
+    }
+
+    fn handle_xread_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Reply with the entries of each stream whose IDs are greater than the given ID for that stream
+        The ID `$` means only entries added after this command was issued.
+        With BLOCK, wait up to the given number of milliseconds (forever if 0) for new entries to arrive,
+        replying with a nil array if none do.
+        */
+        let mut count = None;
+        let mut block_ms = None;
+        let mut arg_idx = 1;
+        while arg_idx < args.len() && !args[arg_idx].eq_ignore_ascii_case(b"STREAMS") {
+            let option_val = args.get(arg_idx + 1).and_then(|val| parse_arg::<u64>(val));
+            match (arg_to_string(&args[arg_idx]).to_uppercase().as_str(), option_val) {
+                ("COUNT", Some(val)) => count = Some(val as usize),
+                ("BLOCK", Some(val)) => block_ms = Some(val),
+                ("COUNT" | "BLOCK", None) => {
+                    let xread_err_response = RespValue::Error("ERR value is not an integer or out of range".to_string());
+                    conn.write_reply(&xread_err_response)?;
+                    return Ok(());
+                },
+                _ => {
+                    let xread_err_response = RespValue::Error("ERR syntax error".to_string());
+                    conn.write_reply(&xread_err_response)?;
+                    return Ok(());
+                }
+            }
+            arg_idx += 2;
+        }
+        let streams_args = args.get(arg_idx + 1..).unwrap_or_default();
+        if streams_args.is_empty() || streams_args.len() % 2 != 0 {
+            let xread_err_response = RespValue::Error("ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.".to_string());
+            conn.write_reply(&xread_err_response)?;
+            return Ok(());
+        }
+        let (keys, raw_ids) = streams_args.split_at(streams_args.len() / 2);
+        let keys = keys.to_vec();
+
+        let mut c = state.lock_keys(conn.db, &keys);
+        // Resolve `$` once up front so that entries added while blocked are returned
+        let mut ids = Vec::with_capacity(raw_ids.len());
+        for (key, raw_id) in keys.iter().zip(raw_ids.iter()) {
+            let id = match (raw_id.as_ref(), Self::get_live_val(state, &mut c, key)) {
+                (b"$", Some(RedisValue::Stream(redis_stream))) => Ok(redis_stream.last_id()),
+                (b"$", _) => Ok(StreamId::MIN),
+                (raw_id, _) => StreamId::parse(&arg_to_string(raw_id), 0),
+            };
+            match id {
+                Ok(id) => ids.push(id),
+                Err(err) => {
+                    let xread_err_response = RespValue::Error(format!("ERR {}", err));
+                    conn.write_reply(&xread_err_response)?;
+                    return Ok(());
+                }
+            }
+        }
+
+        let mut streams = Vec::new();
+        for (key, id) in keys.iter().zip(ids.iter()) {
+            match Self::get_live_val(state, &mut c, key) {
+                Some(RedisValue::Stream(redis_stream)) => {
+                    let entries = redis_stream.range(Bound::Excluded(*id), Bound::Unbounded, count);
+                    if !entries.is_empty() {
+                        streams.push(RespValue::Array(vec![
+                            RespValue::BulkString(key.to_vec()),
+                            Self::encode_stream_entries(&entries),
+                        ]));
+                    }
+                },
+                Some(_) => {
+                    let xread_err_response = RespValue::Error(WRONGTYPE_ERR.to_string());
+                    conn.write_reply(&xread_err_response)?;
+                    return Ok(());
+                },
+                None => {},
+            }
+        }
+        if !streams.is_empty() {
+            let xread_resp = RespValue::Array(streams);
+            conn.write_reply(&xread_resp)?;
+            return Ok(());
+        }
+
+        // Nothing to return yet: either give up now, or block until a writer notifies us and check again
+        match block_ms {
+            Some(block_ms) if !conn.in_exec => {
+                // `$` is resolved already, so entries added while blocked aren't missed when the command runs again
+                let mut blocked_args = args.to_vec();
+                let ids_start = blocked_args.len() - ids.len();
+                for (raw_id, id) in blocked_args[ids_start..].iter_mut().zip(ids.iter()) {
+                    *raw_id = Bytes::from(id.to_string());
+                }
+                let timeout = Some(Duration::from_millis(block_ms)).filter(|timeout| !timeout.is_zero());
+                Self::block_for_writes(conn, blocked_args, state, timeout);
+                Ok(())
+            },
+            _ => {
+                let xread_resp = RespValue::NullArray;
+                conn.write_reply(&xread_resp)
+            },
+        }
+    }
+
+    fn handle_xdel_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /* Delete the entries with the given IDs from the stream at key, replying with the number deleted */
+        let ids = match args[2..].iter().map(|raw_id| StreamId::parse(&arg_to_string(raw_id), 0)).collect::<Result<Vec<StreamId>, _>>() {
+            Ok(ids) => ids,
+            Err(err) => {
+                let xdel_err_response = RespValue::Error(format!("ERR {}", err));
+                conn.write_reply(&xdel_err_response)?;
+                return Ok(());
+            }
+        };
+
+        let mut c = state.lock_keys(conn.db, [&args[1]]);
+        let xdel_resp = match Self::get_live_val(state, &mut c, &args[1]) {
+            Some(RedisValue::Stream(redis_stream)) => {
+                let num_deleted = ids.iter().filter(|id| redis_stream.delete(id)).count();
+                RespValue::Integer(num_deleted as i64)
+            },
+            Some(_) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+            None => RespValue::Integer(0),
+        };
+        conn.write_reply(&xdel_resp)?;
+        Ok(())
+    }
+
+    fn handle_xtrim_cmd(conn: &mut Connection, args: &[Bytes], state: &ServerState) -> io::Result<()> {
+        /*
+        Trim the stream at key to at most MAXLEN entries, or drop entries with IDs below MINID
+        The approximate `~` modifier is accepted but trimming is always exact.
+        Replies with the number of entries removed.
+        */
+        // [xtrim, key, strategy, [=|~], threshold]
+        let mut args = args.to_vec();
+        if matches!(args.get(3).map(|arg| arg.as_ref()), Some(b"=" | b"~")) {
+            args.remove(3);
+        }
+        if args.len() != 4 {
+            let xtrim_err_response = RespValue::Error("ERR syntax error".to_string());
+            conn.write_reply(&xtrim_err_response)?;
+            return Ok(());
+        }
+        enum TrimStrategy {
+            MaxLen(usize),
+            MinId(StreamId),
+        }
+        let strategy = match arg_to_string(&args[2]).to_uppercase().as_str() {
+            "MAXLEN" => match parse_arg::<usize>(&args[3]) {
+                Some(max_len) => TrimStrategy::MaxLen(max_len),
+                None => {
+                    let xtrim_err_response = RespValue::Error("ERR value is not an integer or out of range".to_string());
+                    conn.write_reply(&xtrim_err_response)?;
+                    return Ok(());
+                }
+            },
+            "MINID" => match StreamId::parse(&arg_to_string(&args[3]), 0) {
+                Ok(min_id) => TrimStrategy::MinId(min_id),
+                Err(err) => {
+                    let xtrim_err_response = RespValue::Error(format!("ERR {}", err));
+                    conn.write_reply(&xtrim_err_response)?;
+                    return Ok(());
+                }
+            },
+            _ => {
+                let xtrim_err_response = RespValue::Error("ERR syntax error".to_string());
+                conn.write_reply(&xtrim_err_response)?;
+                return Ok(());
+            }
+        };
+
+        let mut c = state.lock_keys(conn.db, [&args[1]]);
+        let xtrim_resp = match Self::get_live_val(state, &mut c, &args[1]) {
+            Some(RedisValue::Stream(redis_stream)) => {
+                let num_removed = match strategy {
+                    TrimStrategy::MaxLen(max_len) => redis_stream.trim_maxlen(max_len),
+                    TrimStrategy::MinId(min_id) => redis_stream.trim_minid(min_id),
+                };
+                RespValue::Integer(num_removed as i64)
+            },
+            Some(_) => RespValue::Error(WRONGTYPE_ERR.to_string()),
+            None => RespValue::Integer(0),
+        };
+        conn.write_reply(&xtrim_resp)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trim_maxlen_keeps_newest_entries_and_last_id() {
+        let mut redis_stream = RedisStream::new();
+        for ms in 1..=10 {
+            redis_stream.add(&format!("{}-0", ms), vec![(b"f".to_vec(), b"v".to_vec())], 0).unwrap();
+        }
+
+        assert_eq!(redis_stream.trim_maxlen(5), 5);
+        let remaining_ids = redis_stream
+            .range(Bound::Unbounded, Bound::Unbounded, None)
+            .into_iter()
+            .map(|(id, _)| id.ms)
+            .collect::<Vec<u64>>();
+        assert_eq!(remaining_ids, vec![6, 7, 8, 9, 10]);
+        // Trimming must not let XADD reuse IDs at or below the last generated one
+        assert_eq!(redis_stream.add("10-0", vec![], 0), Err(StreamError::IdTooSmall));
+    }
+
+    #[test]
+    fn generated_ids_run_out_instead_of_overflowing() {
+        let mut redis_stream = RedisStream::new();
+        redis_stream.add("5-18446744073709551615", vec![], 0).unwrap();
+        assert_eq!(redis_stream.add("5-*", vec![], 0), Err(StreamError::Exhausted));
+        assert_eq!(redis_stream.add("*", vec![], 0), Ok(StreamId { ms: 6, seq: 0 }));
+
+        redis_stream.add("18446744073709551615-18446744073709551615", vec![], 0).unwrap();
+        assert_eq!(redis_stream.add("*", vec![], 0), Err(StreamError::Exhausted));
+        assert_eq!(redis_stream.add("18446744073709551615-*", vec![], 0), Err(StreamError::Exhausted));
+        assert_eq!(redis_stream.len(), 3);
+    }
+}
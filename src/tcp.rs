@@ -0,0 +1,79 @@
+// TCP socket options that neither std nor tokio expose, set with setsockopt directly since std already links libc
+// (like event_loop_redis_server.rs does for epoll). Only Linux's option numbers are known here, so elsewhere they're
+// left at the OS defaults.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    pub const SOL_SOCKET: i32 = 1;
+    pub const SO_KEEPALIVE: i32 = 9;
+    pub const IPPROTO_TCP: i32 = 6;
+    pub const TCP_KEEPIDLE: i32 = 4;
+    pub const TCP_KEEPINTVL: i32 = 5;
+    pub const TCP_KEEPCNT: i32 = 6;
+
+    extern "C" {
+        pub fn setsockopt(sockfd: i32, level: i32, optname: i32, optval: *const std::ffi::c_void, optlen: u32) -> i32;
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_int_option(socket: &impl AsRawFd, level: i32, name: i32, val: i32) -> io::Result<()> {
+    let optval = &val as *const i32 as *const std::ffi::c_void;
+    let ret = unsafe { linux::setsockopt(socket.as_raw_fd(), level, name, optval, std::mem::size_of::<i32>() as u32) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_keepalive(socket: &impl AsRawFd, interval_secs: u64) -> io::Result<()> {
+    /*
+    Turn on TCP keepalive, so that a peer that went away without closing the connection is noticed
+    Like Redis's anetKeepAlive, the first probe goes out after interval_secs of silence, then every third of that, and the
+    connection is dropped after 3 unanswered probes.
+    */
+    let interval_secs = i32::try_from(interval_secs).unwrap_or(i32::MAX);
+    set_int_option(socket, linux::SOL_SOCKET, linux::SO_KEEPALIVE, 1)?;
+    set_int_option(socket, linux::IPPROTO_TCP, linux::TCP_KEEPIDLE, interval_secs)?;
+    set_int_option(socket, linux::IPPROTO_TCP, linux::TCP_KEEPINTVL, (interval_secs / 3).max(1))?;
+    set_int_option(socket, linux::IPPROTO_TCP, linux::TCP_KEEPCNT, 3)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_keepalive(_socket: &impl AsRawFd, _interval_secs: u64) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    extern "C" {
+        fn getsockopt(sockfd: i32, level: i32, optname: i32, optval: *mut std::ffi::c_void, optlen: *mut u32) -> i32;
+    }
+
+    fn get_int_option(socket: &impl AsRawFd, level: i32, name: i32) -> i32 {
+        let (mut val, mut len) = (0i32, std::mem::size_of::<i32>() as u32);
+        let ret = unsafe { getsockopt(socket.as_raw_fd(), level, name, &mut val as *mut i32 as *mut std::ffi::c_void, &mut len) };
+        assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+        val
+    }
+
+    #[test]
+    fn keepalive_probes_follow_the_interval() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert_eq!(get_int_option(&stream, linux::SOL_SOCKET, linux::SO_KEEPALIVE), 0);
+
+        set_keepalive(&stream, 300).unwrap();
+        assert_ne!(get_int_option(&stream, linux::SOL_SOCKET, linux::SO_KEEPALIVE), 0);
+        assert_eq!(get_int_option(&stream, linux::IPPROTO_TCP, linux::TCP_KEEPIDLE), 300);
+        assert_eq!(get_int_option(&stream, linux::IPPROTO_TCP, linux::TCP_KEEPINTVL), 100);
+        assert_eq!(get_int_option(&stream, linux::IPPROTO_TCP, linux::TCP_KEEPCNT), 3);
+    }
+}